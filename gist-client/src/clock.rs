@@ -0,0 +1,66 @@
+//! Estimation of the offset between the local clock and the API server.
+
+use chrono::{DateTime, Duration, Utc};
+use http::{header::DATE, HeaderMap};
+use std::sync::Mutex;
+
+/// The weight of a new sample in the smoothed estimate.
+const SMOOTHING: f64 = 0.2;
+
+/// The estimated offset above which a warning is emitted.
+const WARN_THRESHOLD_MILLIS: f64 = 60_000.0;
+
+/// The offset of the server clock, smoothed over the responses.
+#[derive(Debug, Default)]
+pub(crate) struct ClockSkew {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// The server time minus the local time, in milliseconds.
+    offset_millis: Option<f64>,
+    warned: bool,
+}
+
+impl ClockSkew {
+    /// Update the estimate with the `Date` header of a response received just now.
+    ///
+    /// The header has a resolution of one second, so the estimate is only
+    /// meaningful for the offsets much larger than that.
+    pub(crate) fn observe(&self, headers: &HeaderMap) {
+        let date = match headers
+            .get(DATE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| DateTime::parse_from_rfc2822(value).ok())
+        {
+            Some(date) => date.with_timezone(&Utc),
+            None => return,
+        };
+        let sample = (date - Utc::now()).num_milliseconds() as f64;
+
+        let mut state = self.state.lock().unwrap();
+        let offset = match state.offset_millis {
+            Some(offset) => offset + SMOOTHING * (sample - offset),
+            None => sample,
+        };
+        state.offset_millis = Some(offset);
+
+        let exceeded = offset.abs() > WARN_THRESHOLD_MILLIS;
+        if exceeded && !state.warned {
+            tracing::warn!(
+                "the local clock differs from the server by about {}s",
+                (offset / 1000.0).round()
+            );
+        }
+        state.warned = exceeded;
+    }
+
+    /// Return the estimated offset of the server clock, positive if it is ahead.
+    pub(crate) fn offset(&self) -> Option<Duration> {
+        let state = self.state.lock().unwrap();
+        state
+            .offset_millis
+            .map(|offset| Duration::milliseconds(offset.round() as i64))
+    }
+}
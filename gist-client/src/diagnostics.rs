@@ -0,0 +1,142 @@
+//! Diagnostics of the HTTP exchanges with the API server.
+
+use http::{
+    header::{ETAG, IF_MATCH, IF_NONE_MATCH, LAST_MODIFIED},
+    HeaderMap, Method, Request, StatusCode,
+};
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// A summary of a request and its response.
+#[derive(Debug, Clone)]
+pub struct Diagnostics {
+    pub method: Method,
+    pub status: StatusCode,
+
+    /// Whether `If-None-Match` or `If-Match` was sent with the request.
+    pub conditional: bool,
+
+    /// The validators returned by the server.
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+
+    pub elapsed: Duration,
+
+    /// Whether the connection was opened by an earlier request, if known.
+    pub connection_reused: Option<bool>,
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} in {}ms (conditional: {}, etag: {}, last-modified: {}, connection: {})",
+            self.method,
+            self.status,
+            self.elapsed.as_millis(),
+            if self.conditional { "yes" } else { "no" },
+            self.etag.as_deref().unwrap_or("none"),
+            self.last_modified.as_deref().unwrap_or("none"),
+            match self.connection_reused {
+                Some(true) => "reused",
+                Some(false) => "new",
+                None => "unknown",
+            },
+        )
+    }
+}
+
+/// A redacted snapshot of the headers exchanged in a request.
+#[cfg(feature = "debug-http")]
+#[derive(Debug, Clone)]
+pub struct Exchange {
+    pub url: String,
+    pub diagnostics: Diagnostics,
+    pub request_headers: Vec<(String, String)>,
+    pub response_headers: Vec<(String, String)>,
+}
+
+/// The state captured before a request is sent.
+pub(crate) struct Observer {
+    method: Method,
+    conditional: bool,
+    started: Instant,
+    #[cfg(feature = "debug-http")]
+    url: String,
+    #[cfg(feature = "debug-http")]
+    request_headers: Vec<(String, String)>,
+}
+
+impl Observer {
+    pub(crate) fn new<T>(request: &Request<T>) -> Self {
+        let headers = request.headers();
+        Self {
+            method: request.method().clone(),
+            conditional: headers.contains_key(IF_NONE_MATCH) || headers.contains_key(IF_MATCH),
+            started: Instant::now(),
+            #[cfg(feature = "debug-http")]
+            url: request.uri().to_string(),
+            #[cfg(feature = "debug-http")]
+            request_headers: redact(headers),
+        }
+    }
+
+    pub(crate) fn finish(
+        self,
+        status: StatusCode,
+        headers: &HeaderMap,
+        connection_reused: Option<bool>,
+    ) -> Observed {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value: &http::HeaderValue| value.to_str().ok())
+                .map(ToOwned::to_owned)
+        };
+        let diagnostics = Diagnostics {
+            method: self.method,
+            status,
+            conditional: self.conditional,
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+            elapsed: self.started.elapsed(),
+            connection_reused,
+        };
+        tracing::debug!("HTTP exchange: {}", diagnostics);
+
+        Observed {
+            #[cfg(feature = "debug-http")]
+            exchange: Exchange {
+                url: self.url,
+                diagnostics: diagnostics.clone(),
+                request_headers: self.request_headers,
+                response_headers: redact(headers),
+            },
+            diagnostics,
+        }
+    }
+}
+
+pub(crate) struct Observed {
+    pub(crate) diagnostics: Diagnostics,
+    #[cfg(feature = "debug-http")]
+    pub(crate) exchange: Exchange,
+}
+
+/// Copy the headers, hiding the credentials.
+#[cfg(feature = "debug-http")]
+fn redact(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if name == http::header::AUTHORIZATION {
+                "<redacted>".to_owned()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.as_str().to_owned(), value)
+        })
+        .collect()
+}
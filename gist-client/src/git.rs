@@ -0,0 +1,219 @@
+//! Fetching the content of a Gist through its git repository.
+//!
+//! Unlike the REST API, the git transport neither truncates the files
+//! nor the file list, and carries the binary content as is.
+//!
+//! https://git-scm.com/docs/http-protocol
+
+use crate::{
+    pack::{self, Kind, Objects},
+    Client, Gist, GistFile,
+};
+use http::{
+    header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE},
+    Request, StatusCode,
+};
+use std::collections::HashMap;
+
+/// The files at the head commit of a Gist repository.
+#[derive(Debug)]
+pub struct GitTree {
+    /// The ID of the head commit.
+    pub commit: String,
+    pub files: Vec<GitBlob>,
+}
+
+/// A file in the tree of a Gist repository.
+#[derive(Debug)]
+pub struct GitBlob {
+    pub filename: String,
+    /// The ID of the blob.
+    pub id: String,
+    pub content: Vec<u8>,
+}
+
+impl Client {
+    /// Fetch the files at the head of the Gist repository with a shallow clone.
+    ///
+    /// The URL is the one reported as `Gist::git_pull_url`.
+    pub async fn fetch_git_tree(&self, pull_url: &str) -> anyhow::Result<GitTree> {
+        let head = self.git_head(pull_url).await?;
+        let pack = self.git_upload_pack(pull_url, &head).await?;
+        let objects = Objects::parse(&pack)?;
+        read_tree(&objects, &head)
+    }
+
+    /// Return the ID of the commit referred by `HEAD`.
+    async fn git_head(&self, pull_url: &str) -> anyhow::Result<String> {
+        let url = format!("{}/info/refs?service=git-upload-pack", pull_url);
+        let body = self.git_request(Request::get(url).body(())?).await?;
+
+        let mut pos = 0;
+        let announce = read_pkt_line(&body, &mut pos)?;
+        anyhow::ensure!(
+            announce == Some(&b"# service=git-upload-pack\n"[..]),
+            "the server does not support the smart HTTP protocol"
+        );
+        read_pkt_line(&body, &mut pos)?;
+
+        while let Some(line) = read_pkt_line(&body, &mut pos)? {
+            // The first line carries the capabilities after NUL.
+            let line = line.split(|&b| b == 0).next().unwrap_or(line);
+            let line = std::str::from_utf8(line)?.trim_end();
+            let mut parts = line.splitn(2, ' ');
+            if let (Some(id), Some("HEAD")) = (parts.next(), parts.next()) {
+                return Ok(id.to_owned());
+            }
+        }
+        anyhow::bail!("the repository has no HEAD")
+    }
+
+    /// Request the packfile containing the commit, without its ancestors.
+    async fn git_upload_pack(&self, pull_url: &str, commit: &str) -> anyhow::Result<Vec<u8>> {
+        let mut body = vec![];
+        write_pkt_line(&mut body, &format!("want {} no-progress\n", commit));
+        write_pkt_line(&mut body, "deepen 1\n");
+        body.extend_from_slice(b"0000");
+        write_pkt_line(&mut body, "done\n");
+
+        let url = format!("{}/git-upload-pack", pull_url);
+        let mut request = Request::post(url);
+        request.header(CONTENT_TYPE, "application/x-git-upload-pack-request");
+        request.header(ACCEPT, "application/x-git-upload-pack-result");
+        let response = self.git_request(request.body(body)?).await?;
+
+        // The shallow boundary and the acknowledgement precede the packfile.
+        let mut pos = 0;
+        while !response[pos..].starts_with(b"PACK") {
+            read_pkt_line(&response, &mut pos)?;
+        }
+        Ok(response[pos..].to_vec())
+    }
+
+    async fn git_request<B>(&self, mut request: Request<B>) -> anyhow::Result<Vec<u8>>
+    where
+        B: Into<isahc::Body>,
+    {
+        if let Some(token) = self.token() {
+            let credentials = base64::encode(format!("{}:x-oauth-basic", token));
+            let value = format!("Basic {}", credentials).parse()?;
+            request.headers_mut().insert(AUTHORIZATION, value);
+        }
+
+        let response = self.send(request).await?;
+        match response.status() {
+            StatusCode::OK => (),
+            status => anyhow::bail!("git error: {}", status),
+        }
+
+        self.read_body(response).await
+    }
+}
+
+impl Gist {
+    /// Replace the files with the ones fetched through the git repository.
+    ///
+    /// The files missing in the REST response, due to the truncation of
+    /// the file list, are added without the language.
+    pub fn apply_git_tree(&mut self, tree: GitTree) {
+        let mut files = HashMap::with_capacity(tree.files.len());
+        for blob in tree.files {
+            let mut file = match self.files.remove(&blob.filename) {
+                Some(file) => file,
+                None => GistFile {
+                    filename: blob.filename.clone(),
+                    type_: match std::str::from_utf8(&blob.content) {
+                        Ok(..) => mime::TEXT_PLAIN,
+                        Err(..) => mime::APPLICATION_OCTET_STREAM,
+                    },
+                    language: String::new(),
+                    // The blob ID identifies the content, as the raw URLs do.
+                    raw_url: format!("git:{}", blob.id),
+                    size: 0,
+                    truncated: false,
+                    content: String::new(),
+                    decoded: None,
+                },
+            };
+            file.size = blob.content.len() as u64;
+            file.truncated = false;
+            file.content = String::from_utf8_lossy(&blob.content).into_owned();
+            file.decoded = Some(blob.content);
+            files.insert(blob.filename, file);
+        }
+        self.files = files;
+        self.truncated = false;
+    }
+}
+
+/// Collect the regular files at the top level of the tree of the commit.
+fn read_tree(objects: &Objects, commit: &str) -> anyhow::Result<GitTree> {
+    let data = objects.get(&pack::from_hex(commit)?, Kind::Commit)?;
+    let tree = std::str::from_utf8(data)?
+        .lines()
+        .find_map(|line| line.strip_prefix("tree "))
+        .ok_or_else(|| anyhow::anyhow!("the commit has no tree"))?;
+    let mut data = objects.get(&pack::from_hex(tree)?, Kind::Tree)?;
+
+    let mut files = vec![];
+    while !data.is_empty() {
+        let space = memchr(b' ', data)?;
+        let nul = memchr(0, data)?;
+        anyhow::ensure!(space < nul && data.len() >= nul + 21, "malformed tree");
+        let (mode, name) = (&data[..space], &data[space + 1..nul]);
+        let mut id = [0; 20];
+        id.copy_from_slice(&data[nul + 1..nul + 21]);
+        data = &data[nul + 21..];
+
+        // Gists have neither directories nor symbolic links.
+        if !mode.starts_with(b"100") {
+            continue;
+        }
+        let filename = match std::str::from_utf8(name) {
+            Ok(name) => name.to_owned(),
+            Err(..) => {
+                tracing::warn!("skip the file with a non-UTF-8 name: {:?}", name);
+                continue;
+            }
+        };
+        files.push(GitBlob {
+            filename,
+            id: pack::to_hex(&id),
+            content: objects.get(&id, Kind::Blob)?.to_vec(),
+        });
+    }
+
+    Ok(GitTree {
+        commit: commit.to_owned(),
+        files,
+    })
+}
+
+fn memchr(needle: u8, data: &[u8]) -> anyhow::Result<usize> {
+    data.iter()
+        .position(|&b| b == needle)
+        .ok_or_else(|| anyhow::anyhow!("malformed tree"))
+}
+
+/// Read a pkt-line, returning `None` for a flush packet.
+fn read_pkt_line<'a>(data: &'a [u8], pos: &mut usize) -> anyhow::Result<Option<&'a [u8]>> {
+    let len = data
+        .get(*pos..*pos + 4)
+        .ok_or_else(|| anyhow::anyhow!("unexpected end of response"))?;
+    let len = usize::from_str_radix(std::str::from_utf8(len)?, 16)?;
+    *pos += 4;
+    if len == 0 {
+        return Ok(None);
+    }
+
+    anyhow::ensure!(len >= 4, "invalid pkt-line length");
+    let line = data
+        .get(*pos..*pos + len - 4)
+        .ok_or_else(|| anyhow::anyhow!("unexpected end of response"))?;
+    *pos += len - 4;
+    Ok(Some(line))
+}
+
+fn write_pkt_line(buf: &mut Vec<u8>, line: &str) {
+    buf.extend_from_slice(format!("{:04x}{}", line.len() + 4, line).as_bytes());
+}
@@ -1,29 +1,342 @@
 //! Gist client.
 
+mod clock;
+mod diagnostics;
+#[cfg(feature = "git-transport")]
+mod git;
+mod local;
+#[cfg(feature = "git-transport")]
+mod pack;
+mod pool;
+mod stream;
+
+#[cfg(feature = "debug-http")]
+pub use crate::diagnostics::Exchange;
+#[cfg(feature = "git-transport")]
+pub use crate::git::{GitBlob, GitTree};
+pub use crate::{
+    diagnostics::Diagnostics,
+    local::{NewGistFile, MAX_FILE_SIZE},
+    pool::ConnectionStats,
+    stream::ContentReader,
+};
+
+use crate::{clock::ClockSkew, diagnostics::Observer, pool::ConnectionCounter};
+use anyhow::Context as _;
 use chrono::{DateTime, Utc};
+use futures::{io::AsyncReadExt, stream::StreamExt};
 use http::{
-    header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH},
-    HeaderValue, Request, StatusCode,
+    header::{
+        ACCEPT, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH, RANGE,
+    },
+    HeaderMap, HeaderValue, Request, Response, StatusCode,
 };
-use isahc::RequestExt;
+use isahc::HttpClient;
 use mime::Mime;
 use serde::{ser::SerializeMap, Deserialize, Serialize, Serializer};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    error, fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+const X_RATELIMIT_REMAINING: &str = "x-ratelimit-remaining";
+
+/// The maximum number of updates issued concurrently by `update_multiple_gists`.
+const UPDATE_PARALLELISM: usize = 4;
+
+/// The default limit of the size of a response body.
+pub const DEFAULT_MAX_RESPONSE_SIZE: usize = 50 * 1024 * 1024;
+
+/// The maximum number of files in a response, i.e. the 300 files the API
+/// lists before the truncation, with some slack.
+const MAX_FILES: usize = 1000;
+
+/// The maximum size declared for a file, i.e. the 100 MB the git repository
+/// of a Gist accepts.
+pub(crate) const MAX_DECLARED_FILE_SIZE: u64 = 100 * 1024 * 1024;
+
+/// Derive the fingerprint of an access token: the first 8 hex digits of its
+/// SHA-256 digest.
+///
+/// This is the only place the token is fingerprinted. The digest is
+/// irreversible, so the fingerprint may be stored on disk or logged.
+pub fn token_fingerprint(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .take(4)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
 
 /// The entity tag to specify the revision of Gist content.
 #[derive(Debug, Clone)]
 pub struct ETag(HeaderValue);
 
+/// The error of a request issued by `Client`.
+#[derive(Debug)]
+pub enum ClientError {
+    /// The request was not sent since the rate limit has been exhausted.
+    RateLimited,
+
+    /// The Gist has been edited since the revision specified by the entity tag.
+    Conflict,
+
+    /// The Gist does not exist, or has been deleted.
+    NotFound,
+
+    /// The access to the Gist has been denied.
+    Unauthorized,
+
+    /// The response exceeds the limits, e.g. a body larger than the maximum.
+    Malformed(String),
+
+    /// The request failed.
+    Other(anyhow::Error),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::RateLimited => f.write_str("API rate limit exceeded"),
+            ClientError::Conflict => f.write_str("The Gist has been edited by someone."),
+            ClientError::NotFound => f.write_str("The Gist is not found"),
+            ClientError::Unauthorized => f.write_str("The access to the Gist is denied"),
+            ClientError::Malformed(msg) => write!(f, "The response is malformed: {}", msg),
+            ClientError::Other(err) => fmt::Display::fmt(err, f),
+        }
+    }
+}
+
+impl error::Error for ClientError {}
+
+impl From<anyhow::Error> for ClientError {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast::<ClientError>() {
+            Ok(err) => err,
+            Err(err) => ClientError::Other(err),
+        }
+    }
+}
+
 /// Gist client.
 #[derive(Debug)]
 pub struct Client {
-    token: Option<String>,
+    http: HttpClient,
+    connections: ConnectionCounter,
+    token: Mutex<Option<String>>,
+    max_response_size: usize,
+    rate_remaining: AtomicUsize,
+    clock_skew: ClockSkew,
+    #[cfg(feature = "debug-http")]
+    last_exchange: std::sync::Mutex<Option<Exchange>>,
 }
 
 impl Client {
     /// Create a new Gist client.
     pub fn new(token: Option<String>) -> Self {
-        Self { token }
+        Self {
+            http: pool::build(UPDATE_PARALLELISM),
+            connections: ConnectionCounter::default(),
+            token: Mutex::new(token),
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+            rate_remaining: AtomicUsize::new(usize::MAX),
+            clock_skew: ClockSkew::default(),
+            #[cfg(feature = "debug-http")]
+            last_exchange: std::sync::Mutex::new(None),
+        }
+    }
+
+    /// Return a redacted snapshot of the last request and its response.
+    #[cfg(feature = "debug-http")]
+    pub fn debug_last_exchange(&self) -> Option<Exchange> {
+        self.last_exchange.lock().unwrap().clone()
+    }
+
+    /// Send the request over the shared connections.
+    pub(crate) async fn send<B>(&self, request: Request<B>) -> anyhow::Result<Response<isahc::Body>>
+    where
+        B: Into<isahc::Body>,
+    {
+        let response = self.http.send_async(request).await?;
+        self.connections.observe(&response);
+        Ok(response)
+    }
+
+    fn observe(&self, observer: Observer, response: &Response<isahc::Body>) -> Diagnostics {
+        let observed = observer.finish(
+            response.status(),
+            response.headers(),
+            pool::connection_reused(response),
+        );
+        #[cfg(feature = "debug-http")]
+        self.last_exchange
+            .lock()
+            .unwrap()
+            .replace(observed.exchange);
+        observed.diagnostics
+    }
+
+    /// Keep enough idle connections for `parallelism` requests in flight,
+    /// e.g. the parallelism passed to `fetch_gists_conditional`.
+    pub fn set_download_parallelism(&mut self, parallelism: usize) {
+        self.http = pool::build(parallelism);
+    }
+
+    /// Return the numbers of the connections opened and reused so far.
+    pub fn connection_stats(&self) -> ConnectionStats {
+        self.connections.stats()
+    }
+
+    /// Set the maximum size of a response body, beyond which the request
+    /// fails with `ClientError::Malformed`.
+    pub fn set_max_response_size(&mut self, size: usize) {
+        self.max_response_size = size;
+    }
+
+    /// Read the body of the response up to `max_response_size`.
+    ///
+    /// The declared length is checked first, and the read is stopped just
+    /// past the limit, so an oversized body is never buffered as a whole.
+    pub(crate) async fn read_body(
+        &self,
+        response: Response<isahc::Body>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let limit = self.max_response_size;
+        let declared = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok()?.parse::<u64>().ok());
+        if let Some(len) = declared {
+            if len > limit as u64 {
+                return Err(malformed(format!(
+                    "the body of {} bytes exceeds the limit of {} bytes",
+                    len, limit
+                )));
+            }
+        }
+
+        let mut body = Vec::with_capacity(declared.map_or(0, |len| len as usize));
+        response
+            .into_body()
+            .take(limit as u64 + 1)
+            .read_to_end(&mut body)
+            .await?;
+        if body.len() > limit {
+            return Err(malformed(format!(
+                "the body exceeds the limit of {} bytes",
+                limit
+            )));
+        }
+        Ok(body)
+    }
+
+    /// Replace the access token used for the subsequent requests.
+    pub fn refresh_token(&self, new_token: Option<String>) {
+        *self.token.lock().unwrap() = new_token;
+    }
+
+    fn token(&self) -> Option<String> {
+        self.token.lock().unwrap().clone()
+    }
+
+    /// Return the fingerprint of the access token, which tells the identities
+    /// apart without revealing the token.
+    pub fn identity(&self) -> Option<String> {
+        self.token.lock().unwrap().as_deref().map(token_fingerprint)
+    }
+
+    /// Return whether the requests are sent with an access token.
+    pub fn is_authenticated(&self) -> bool {
+        self.token.lock().unwrap().is_some()
+    }
+
+    /// Return the number of requests remaining in the current rate limit window.
+    ///
+    /// The value is `None` until the first response is received.
+    pub fn rate_remaining(&self) -> Option<usize> {
+        match self.rate_remaining.load(Ordering::SeqCst) {
+            n if n == usize::MAX => None,
+            n => Some(n),
+        }
+    }
+
+    fn update_rate_remaining(&self, headers: &HeaderMap) {
+        let remaining = headers
+            .get(X_RATELIMIT_REMAINING)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+        if let Some(remaining) = remaining {
+            self.rate_remaining.store(remaining, Ordering::SeqCst);
+        }
+    }
+
+    /// Return the estimated offset of the server clock from the local one,
+    /// positive if the server is ahead.
+    ///
+    /// The value is `None` until the first response is received.
+    pub fn clock_skew(&self) -> Option<chrono::Duration> {
+        self.clock_skew.offset()
+    }
+
+    /// Return the current time as seen by the server.
+    ///
+    /// This should be used instead of the local time when comparing with
+    /// the timestamps returned by the API, such as `Gist::updated_at`.
+    pub fn server_now(&self) -> DateTime<Utc> {
+        Utc::now() + self.clock_skew().unwrap_or_else(chrono::Duration::zero)
+    }
+
+    /// Fetch multiple gists concurrently, with at most `parallelism` requests in flight.
+    ///
+    /// Each request is a pair of the Gist ID and the entity tag used for
+    /// the conditional request. The results are returned in the same order
+    /// as the requests. Once the rate limit is exhausted, the remaining
+    /// requests are not sent and are reported as `ClientError::RateLimited`.
+    pub async fn fetch_gists_conditional(
+        &self,
+        requests: &[(&str, Option<&ETag>)],
+        parallelism: usize,
+    ) -> Vec<Result<Option<(Gist, Option<ETag>)>, ClientError>> {
+        futures::stream::iter(requests)
+            .map(|&(gist_id, etag)| async move {
+                if self.rate_remaining() == Some(0) {
+                    return Err(ClientError::RateLimited);
+                }
+                self.fetch_gist(gist_id, etag)
+                    .await
+                    .map_err(ClientError::from)
+            })
+            .buffered(std::cmp::max(parallelism, 1))
+            .collect()
+            .await
+    }
+
+    /// Update multiple gists concurrently.
+    ///
+    /// Each update is a tuple of the Gist ID, the entity tag used for the
+    /// conditional request and the patch. At most four requests are in flight
+    /// at once, and the results are returned in the same order as the updates.
+    pub async fn update_multiple_gists(
+        &self,
+        updates: Vec<(&str, Option<&ETag>, GistPatch<'_>)>,
+    ) -> Vec<Result<(Gist, Option<ETag>), ClientError>> {
+        futures::stream::iter(updates)
+            .map(|(gist_id, etag, patch)| async move {
+                if self.rate_remaining() == Some(0) {
+                    return Err(ClientError::RateLimited);
+                }
+                self.update_gist(gist_id, etag, patch)
+                    .await
+                    .map_err(ClientError::from)
+            })
+            .buffered(UPDATE_PARALLELISM)
+            .collect()
+            .await
     }
 
     /// Fetch a single gist with the specific ID.
@@ -34,13 +347,25 @@ impl Client {
         gist_id: &str,
         etag: Option<&ETag>,
     ) -> anyhow::Result<Option<(Gist, Option<ETag>)>> {
-        let response = {
+        self.fetch_gist_with_media(gist_id, etag, GistMediaType::Json)
+            .await
+    }
+
+    /// Fetch a single gist with the specific ID, in the specified media type.
+    ///
+    /// The content of the files is decoded regardless of the media type,
+    /// and is available through `GistFile::content_bytes`.
+    pub async fn fetch_gist_with_media(
+        &self,
+        gist_id: &str,
+        etag: Option<&ETag>,
+        media: GistMediaType,
+    ) -> anyhow::Result<Option<(Gist, Option<ETag>)>> {
+        let request = {
             let url = format!("https://api.github.com/gists/{id}", id = gist_id);
             let mut request = Request::get(url);
-            // TODO: specify the custom media types
-            // https://developer.github.com/v3/gists/#custom-media-types
-            request.header(ACCEPT, "application/vnd.github.v3+json");
-            if let Some(ref token) = self.token {
+            request.header(ACCEPT, media.accept());
+            if let Some(token) = self.token() {
                 request.header(AUTHORIZATION, format!("token {token}", token = token));
             }
 
@@ -48,13 +373,118 @@ impl Client {
                 request.header(IF_NONE_MATCH, &etag.0);
             }
 
-            request.body(())?.send_async().await?
+            request.body(())?
         };
+        let observer = Observer::new(&request);
+        let response = self.send(request).await?;
+        self.update_rate_remaining(response.headers());
+        self.clock_skew.observe(response.headers());
+        let diagnostics = self.observe(observer, &response);
+
+        let result: anyhow::Result<_> = async move {
+            match response.status() {
+                StatusCode::OK => (),
+                StatusCode::NOT_MODIFIED => return Ok(None),
+                StatusCode::NOT_FOUND => return Err(ClientError::NotFound.into()),
+                // An exhausted rate limit is reported as 403 as well.
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+                    if self.rate_remaining() != Some(0) =>
+                {
+                    return Err(ClientError::Unauthorized.into())
+                }
+                status => return Err(anyhow::anyhow!("API error: {}", status)),
+            }
+
+            if let Some(content_type) = response.headers().get(CONTENT_TYPE) {
+                let mime: Mime = content_type.to_str()?.parse()?;
+                anyhow::ensure!(
+                    mime.type_() == "application" && mime.subtype() == "json",
+                    "content type is not JSON"
+                );
+            }
+
+            let etag = response.headers().get(ETAG).map(|etag| ETag(etag.clone()));
+
+            let body = self.read_body(response).await?;
+            let mut gist = Gist::from_json(&body)?;
+
+            anyhow::ensure!(gist.id == gist_id, "Gist ID is mismatched");
+
+            if media == GistMediaType::Base64 {
+                for file in gist.files.values_mut() {
+                    file.decode_base64()
+                        .with_context(|| format!("invalid base64 content: {}", file.filename))?;
+                }
+            }
+
+            Ok(Some((gist, etag)))
+        }
+        .await;
+
+        result.context(diagnostics)
+    }
+
+    /// Fetch the response of `GET /gists/{id}` as it is, e.g. to be saved
+    /// and loaded by `Gist::from_json` later.
+    ///
+    /// The response is validated as a Gist before it is returned.
+    pub async fn fetch_gist_json(&self, gist_id: &str) -> anyhow::Result<Vec<u8>> {
+        let response = {
+            let url = format!("https://api.github.com/gists/{id}", id = gist_id);
+            let mut request = Request::get(url);
+            request.header(ACCEPT, GistMediaType::Json.accept());
+            if let Some(token) = self.token() {
+                request.header(AUTHORIZATION, format!("token {token}", token = token));
+            }
+
+            self.send(request.body(())?).await?
+        };
+        self.update_rate_remaining(response.headers());
+        self.clock_skew.observe(response.headers());
+
+        match response.status() {
+            StatusCode::OK => (),
+            StatusCode::NOT_FOUND => return Err(ClientError::NotFound.into()),
+            status => return Err(anyhow::anyhow!("API error: {}", status)),
+        }
+
+        let body = self.read_body(response).await?;
+        let gist = Gist::from_json(&body)?;
+        anyhow::ensure!(gist.id == gist_id, "Gist ID is mismatched");
+
+        Ok(body)
+    }
+
+    /// Fetch a specific revision of a gist.
+    ///
+    /// Returns `None` if the revision does not exist.
+    ///
+    /// https://developer.github.com/v3/gists/#get-a-specific-revision-of-a-gist
+    pub async fn fetch_gist_revision(
+        &self,
+        gist_id: &str,
+        sha: &str,
+    ) -> anyhow::Result<Option<Gist>> {
+        let response = {
+            let url = format!(
+                "https://api.github.com/gists/{id}/{sha}",
+                id = gist_id,
+                sha = sha
+            );
+            let mut request = Request::get(url);
+            request.header(ACCEPT, "application/vnd.github.v3+json");
+            if let Some(token) = self.token() {
+                request.header(AUTHORIZATION, format!("token {token}", token = token));
+            }
+
+            self.send(request.body(())?).await?
+        };
+        self.update_rate_remaining(response.headers());
+        self.clock_skew.observe(response.headers());
 
         match response.status() {
             StatusCode::OK => (),
-            StatusCode::NOT_MODIFIED => return Ok(None),
-            StatusCode::NOT_FOUND => return Err(anyhow::anyhow!("The Gist is not found")),
+            StatusCode::NOT_FOUND | StatusCode::UNPROCESSABLE_ENTITY => return Ok(None),
             status => return Err(anyhow::anyhow!("API error: {}", status)),
         }
 
@@ -66,14 +496,38 @@ impl Client {
             );
         }
 
-        let etag = response.headers().get(ETAG).map(|etag| ETag(etag.clone()));
-
-        let body = response.into_body().text_async().await?;
-        let gist: Gist = serde_json::from_str(&body)?;
+        let body = self.read_body(response).await?;
+        let gist = Gist::from_json(&body)?;
 
         anyhow::ensure!(gist.id == gist_id, "Gist ID is mismatched");
 
-        Ok(Some((gist, etag)))
+        Ok(Some(gist))
+    }
+
+    /// Start to download the raw content of a file from the specified offset.
+    ///
+    /// The raw URLs are served from another host, so the access token is
+    /// never sent with this request.
+    pub async fn fetch_raw(&self, raw_url: &str, offset: u64) -> anyhow::Result<ContentReader> {
+        let mut request = Request::get(raw_url);
+        if offset > 0 {
+            request.header(RANGE, format!("bytes={}-", offset));
+        }
+        let response = self.send(request.body(())?).await?;
+
+        let position = match response.status() {
+            StatusCode::PARTIAL_CONTENT => offset,
+            // The server may ignore the range and send the entire content.
+            StatusCode::OK => 0,
+            status => {
+                return Err(anyhow::anyhow!(
+                    "failed to fetch the raw content: {}",
+                    status
+                ))
+            }
+        };
+
+        Ok(ContentReader::new(response.into_body(), position))
     }
 
     /// Edit the content of a Gist file.
@@ -85,14 +539,12 @@ impl Client {
         etag: Option<&ETag>,
         patch: GistPatch<'_>,
     ) -> anyhow::Result<(Gist, Option<ETag>)> {
-        let response = {
+        let request = {
             let url = format!("https://api.github.com/gists/{id}", id = gist_id);
             let mut request = Request::patch(url);
-            // TODO: specify the custom media types
-            // https://developer.github.com/v3/gists/#custom-media-types
-            request.header(ACCEPT, "application/vnd.github.v3+json");
+            request.header(ACCEPT, GistMediaType::Json.accept());
             request.header(CONTENT_TYPE, "application/json; charset=utf-8");
-            if let Some(ref token) = self.token {
+            if let Some(token) = self.token() {
                 request.header(AUTHORIZATION, format!("token {token}", token = token));
             }
 
@@ -100,55 +552,262 @@ impl Client {
                 request.header(IF_MATCH, &etag.0);
             }
 
-            request
-                .body(serde_json::to_string(&patch)?)?
-                .send_async()
-                .await?
+            request.body(serde_json::to_string(&patch)?)?
         };
+        let observer = Observer::new(&request);
+        let response = self.send(request).await?;
+        self.update_rate_remaining(response.headers());
+        self.clock_skew.observe(response.headers());
+        let diagnostics = self.observe(observer, &response);
 
-        match response.status() {
-            StatusCode::OK => (),
-            StatusCode::NOT_FOUND => return Err(anyhow::anyhow!("The Gist is not found")),
-            StatusCode::PRECONDITION_FAILED => {
-                return Err(anyhow::anyhow!("The Gist has been edited by someone."))
+        let result: anyhow::Result<_> = async move {
+            match response.status() {
+                StatusCode::OK => (),
+                StatusCode::NOT_FOUND => return Err(ClientError::NotFound.into()),
+                // An exhausted rate limit is reported as 403 as well.
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+                    if self.rate_remaining() != Some(0) =>
+                {
+                    return Err(ClientError::Unauthorized.into())
+                }
+                StatusCode::PRECONDITION_FAILED => return Err(ClientError::Conflict.into()),
+                status => return Err(anyhow::anyhow!("API error: {}", status)),
             }
-            status => return Err(anyhow::anyhow!("API error: {}", status)),
+
+            if let Some(content_type) = response.headers().get(CONTENT_TYPE) {
+                let mime: Mime = content_type.to_str()?.parse()?;
+                anyhow::ensure!(
+                    mime.type_() == "application" && mime.subtype() == "json",
+                    "content type is not JSON"
+                );
+            }
+
+            let etag = response.headers().get(ETAG).map(|etag| ETag(etag.clone()));
+
+            let body = self.read_body(response).await?;
+            let gist = Gist::from_json(&body)?;
+
+            anyhow::ensure!(gist.id == gist_id, "Gist ID is mismatched");
+
+            Ok((gist, etag))
         }
+        .await;
 
-        if let Some(content_type) = response.headers().get(CONTENT_TYPE) {
-            let mime: Mime = content_type.to_str()?.parse()?;
-            anyhow::ensure!(
-                mime.type_() == "application" && mime.subtype() == "json",
-                "content type is not JSON"
-            );
+        result.context(diagnostics)
+    }
+
+    /// Fork the gist into the account of the access token.
+    pub async fn fork_gist(&self, gist_id: &str) -> anyhow::Result<(Gist, Option<ETag>)> {
+        let request = {
+            let url = format!("https://api.github.com/gists/{id}/forks", id = gist_id);
+            let mut request = Request::post(url);
+            request.header(ACCEPT, "application/vnd.github.v3+json");
+            match self.token() {
+                Some(token) => {
+                    request.header(AUTHORIZATION, format!("token {token}", token = token));
+                }
+                None => anyhow::bail!("an access token is required to fork a gist"),
+            }
+
+            request.body(())?
+        };
+        let observer = Observer::new(&request);
+        let response = self.send(request).await?;
+        self.update_rate_remaining(response.headers());
+        self.clock_skew.observe(response.headers());
+        let diagnostics = self.observe(observer, &response);
+
+        let result: anyhow::Result<_> = async move {
+            match response.status() {
+                StatusCode::CREATED => (),
+                StatusCode::NOT_FOUND => return Err(ClientError::NotFound.into()),
+                // An exhausted rate limit is reported as 403 as well.
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+                    if self.rate_remaining() != Some(0) =>
+                {
+                    return Err(ClientError::Unauthorized.into())
+                }
+                status => return Err(anyhow::anyhow!("API error: {}", status)),
+            }
+
+            let etag = response.headers().get(ETAG).map(|etag| ETag(etag.clone()));
+
+            let body = self.read_body(response).await?;
+            let gist = Gist::from_json(&body)?;
+
+            Ok((gist, etag))
         }
+        .await;
 
-        let etag = response.headers().get(ETAG).map(|etag| ETag(etag.clone()));
+        result.context(diagnostics)
+    }
 
-        let body = response.into_body().text_async().await?;
-        let gist: Gist = serde_json::from_str(&body)?;
+    /// Create a new gist, which requires an access token.
+    pub async fn create_gist(&self, gist: NewGist<'_>) -> anyhow::Result<(Gist, Option<ETag>)> {
+        let request = {
+            let mut request = Request::post("https://api.github.com/gists");
+            request.header(ACCEPT, "application/vnd.github.v3+json");
+            request.header(CONTENT_TYPE, "application/json; charset=utf-8");
+            match self.token() {
+                Some(token) => {
+                    request.header(AUTHORIZATION, format!("token {token}", token = token));
+                }
+                None => anyhow::bail!("an access token is required to create a gist"),
+            }
 
-        anyhow::ensure!(gist.id == gist_id, "Gist ID is mismatched");
+            request.body(serde_json::to_string(&gist)?)?
+        };
+        let observer = Observer::new(&request);
+        let response = self.send(request).await?;
+        self.update_rate_remaining(response.headers());
+        self.clock_skew.observe(response.headers());
+        let diagnostics = self.observe(observer, &response);
+
+        let result: anyhow::Result<_> = async move {
+            match response.status() {
+                StatusCode::CREATED => (),
+                // An exhausted rate limit is reported as 403 as well.
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+                    if self.rate_remaining() != Some(0) =>
+                {
+                    return Err(ClientError::Unauthorized.into())
+                }
+                status => return Err(anyhow::anyhow!("API error: {}", status)),
+            }
+
+            let etag = response.headers().get(ETAG).map(|etag| ETag(etag.clone()));
+
+            let body = self.read_body(response).await?;
+            let gist = Gist::from_json(&body)?;
+
+            Ok((gist, etag))
+        }
+        .await;
+
+        result.context(diagnostics)
+    }
+
+    /// Delete a gist, which requires an access token.
+    ///
+    /// https://developer.github.com/v3/gists/#delete-a-gist
+    pub async fn delete_gist(&self, gist_id: &str) -> anyhow::Result<()> {
+        let request = {
+            let url = format!("https://api.github.com/gists/{id}", id = gist_id);
+            let mut request = Request::delete(url);
+            request.header(ACCEPT, "application/vnd.github.v3+json");
+            match self.token() {
+                Some(token) => {
+                    request.header(AUTHORIZATION, format!("token {token}", token = token));
+                }
+                None => anyhow::bail!("an access token is required to delete a gist"),
+            }
+
+            request.body(())?
+        };
+        let observer = Observer::new(&request);
+        let response = self.send(request).await?;
+        self.update_rate_remaining(response.headers());
+        self.clock_skew.observe(response.headers());
+        let diagnostics = self.observe(observer, &response);
 
-        Ok((gist, etag))
+        let result: anyhow::Result<_> = match response.status() {
+            StatusCode::NO_CONTENT => Ok(()),
+            StatusCode::NOT_FOUND => Err(ClientError::NotFound.into()),
+            // An exhausted rate limit is reported as 403 as well.
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+                if self.rate_remaining() != Some(0) =>
+            {
+                Err(ClientError::Unauthorized.into())
+            }
+            status => Err(anyhow::anyhow!("API error: {}", status)),
+        };
+
+        result.context(diagnostics)
     }
 }
 
+fn malformed(msg: String) -> anyhow::Error {
+    ClientError::Malformed(msg).into()
+}
+
 /// A Gist received from the server.
 #[derive(Debug, Deserialize)]
 pub struct Gist {
     pub id: String,
+    pub html_url: String,
     pub description: String,
     pub public: bool,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
     pub files: HashMap<String, GistFile>,
 
+    /// The URL to clone the repository of the Gist.
+    #[serde(default)]
+    pub git_pull_url: String,
+
     /// Indicates that the entire file list is truncated since the total
     /// number of files is larger than 300.
     ///
     /// See [the trunctation section](https://developer.github.com/v3/gists/#truncation) for details.
     pub truncated: bool,
+
+    /// The revisions of the Gist, the newest first.
+    #[serde(default)]
+    pub history: Vec<GistHistory>,
+}
+
+impl Gist {
+    /// Deserialize a Gist from a response of the API, refusing the absurd
+    /// numbers of files and sizes before any buffer is allocated for them.
+    pub fn from_json(body: &[u8]) -> anyhow::Result<Self> {
+        let gist: Gist = serde_json::from_slice(body)?;
+        if gist.files.len() > MAX_FILES {
+            return Err(malformed(format!(
+                "{} files exceed the maximum of {}",
+                gist.files.len(),
+                MAX_FILES
+            )));
+        }
+        if let Some(file) = gist
+            .files
+            .values()
+            .find(|file| file.size > MAX_DECLARED_FILE_SIZE)
+        {
+            return Err(malformed(format!(
+                "the size of {:?} is too large: {} bytes",
+                file.filename, file.size
+            )));
+        }
+        Ok(gist)
+    }
+}
+
+/// A revision of a Gist.
+#[derive(Debug, Deserialize)]
+pub struct GistHistory {
+    pub version: String,
+    pub committed_at: DateTime<Utc>,
+}
+
+/// The representation of the file content in the responses.
+///
+/// https://developer.github.com/v3/gists/#custom-media-types
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum GistMediaType {
+    /// The content as is, which cannot carry the bytes invalid as UTF-8.
+    #[default]
+    Json,
+    /// The content encoded in base64.
+    Base64,
+}
+
+impl GistMediaType {
+    fn accept(self) -> &'static str {
+        match self {
+            GistMediaType::Json => "application/vnd.github.v3+json",
+            GistMediaType::Base64 => "application/vnd.github.v3.base64+json",
+        }
+    }
 }
 
 /// A file contained in a Gist.
@@ -161,7 +820,49 @@ pub struct GistFile {
     pub raw_url: String,
     pub size: u64,
     pub truncated: bool,
+    /// The content, lossily converted to UTF-8 if fetched in base64.
     pub content: String,
+    #[serde(skip)]
+    decoded: Option<Vec<u8>>,
+}
+
+impl GistFile {
+    /// Return whether the file is a text file, according to its MIME type.
+    pub fn is_text(&self) -> bool {
+        self.type_.type_() == mime::TEXT
+    }
+
+    /// Return the exact bytes of the content.
+    pub fn content_bytes(&self) -> &[u8] {
+        match self.decoded {
+            Some(ref decoded) => &decoded[..],
+            None => self.content.as_bytes(),
+        }
+    }
+
+    /// Take the exact bytes of the content.
+    pub fn into_content_bytes(self) -> Vec<u8> {
+        match self.decoded {
+            Some(decoded) => decoded,
+            None => self.content.into_bytes(),
+        }
+    }
+
+    fn decode_base64(&mut self) -> anyhow::Result<()> {
+        let mut encoded: Vec<u8> = self
+            .content
+            .bytes()
+            .filter(|b| !b.is_ascii_whitespace())
+            .collect();
+        if self.truncated {
+            // The truncation may split a quantum of 4 characters.
+            encoded.truncate(encoded.len() / 4 * 4);
+        }
+        let decoded = base64::decode(&encoded)?;
+        self.content = String::from_utf8_lossy(&decoded).into_owned();
+        self.decoded = Some(decoded);
+        Ok(())
+    }
 }
 
 fn parse_mime<'de, D>(de: D) -> Result<Mime, D::Error>
@@ -173,17 +874,32 @@ where
 }
 
 pub struct GistPatch<'a> {
-    pub files: &'a [(&'a str, Option<&'a str>)],
+    /// The changes keyed by the current filenames on the Gist.
+    ///
+    /// A file is deleted if its change is `None`.
+    pub files: &'a [(&'a str, Option<GistPatchFile<'a>>)],
     pub description: Option<&'a str>,
 }
 
+/// The change of a file in a Gist.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize)]
+pub struct GistPatchFile<'a> {
+    /// The new filename, if the file is renamed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filename: Option<&'a str>,
+
+    /// The new content, if the file is modified.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<&'a str>,
+}
+
 impl Serialize for GistPatch<'_> {
     fn serialize<S>(&self, se: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
         let mut map = se.serialize_map(Some(2))?;
-        map.serialize_entry("files", &self.files)?;
+        map.serialize_entry("files", &GistPatchFiles(self.files))?;
         if let Some(description) = self.description {
             map.serialize_entry("description", description)?;
         }
@@ -191,9 +907,33 @@ impl Serialize for GistPatch<'_> {
     }
 }
 
-struct GistPatchFiles<'a>(&'a [(&'a str, Option<&'a str>)]);
+/// A Gist to be created.
+#[derive(Debug)]
+pub struct NewGist<'a> {
+    /// The pairs of the filename and the content.
+    pub files: &'a [(&'a str, &'a str)],
+    pub description: Option<&'a str>,
+    pub public: bool,
+}
 
-impl Serialize for GistPatchFiles<'_> {
+impl Serialize for NewGist<'_> {
+    fn serialize<S>(&self, se: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = se.serialize_map(Some(3))?;
+        map.serialize_entry("files", &NewGistFiles(self.files))?;
+        if let Some(description) = self.description {
+            map.serialize_entry("description", description)?;
+        }
+        map.serialize_entry("public", &self.public)?;
+        map.end()
+    }
+}
+
+struct NewGistFiles<'a>(&'a [(&'a str, &'a str)]);
+
+impl Serialize for NewGistFiles<'_> {
     fn serialize<S>(&self, se: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
@@ -202,15 +942,123 @@ impl Serialize for GistPatchFiles<'_> {
         for &(filename, content) in self.0 {
             map.serialize_entry(
                 filename,
-                &content.map(|content| GistPatchFile { filename, content }),
+                &GistPatchFile {
+                    filename: None,
+                    content: Some(content),
+                },
             )?;
         }
         map.end()
     }
 }
 
-#[derive(Serialize)]
-struct GistPatchFile<'a> {
-    filename: &'a str,
-    content: &'a str,
+struct GistPatchFiles<'a>(&'a [(&'a str, Option<GistPatchFile<'a>>)]);
+
+impl Serialize for GistPatchFiles<'_> {
+    fn serialize<S>(&self, se: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = se.serialize_map(Some(self.0.len()))?;
+        for (filename, file) in self.0 {
+            map.serialize_entry(filename, file)?;
+        }
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(body: isahc::Body, content_length: Option<u64>) -> Response<isahc::Body> {
+        let mut response = Response::builder();
+        if let Some(len) = content_length {
+            response.header(CONTENT_LENGTH, len);
+        }
+        response.body(body).unwrap()
+    }
+
+    fn client(max_response_size: usize) -> Client {
+        let mut client = Client::new(None);
+        client.set_max_response_size(max_response_size);
+        client
+    }
+
+    fn is_malformed(err: &anyhow::Error) -> bool {
+        matches!(
+            err.downcast_ref::<ClientError>(),
+            Some(ClientError::Malformed(..))
+        )
+    }
+
+    fn gist_json(files: usize, size: u64) -> String {
+        let files: Vec<String> = (0..files)
+            .map(|i| {
+                format!(
+                    r#""f{i}.txt": {{"filename": "f{i}.txt", "type": "text/plain",
+                        "language": "Text", "raw_url": "", "size": {size},
+                        "truncated": false, "content": "x"}}"#,
+                    i = i,
+                    size = size
+                )
+            })
+            .collect();
+        format!(
+            r#"{{"id": "0123abc", "html_url": "", "description": "", "public": false,
+                "created_at": "2020-01-01T00:00:00Z", "updated_at": "2020-01-01T00:00:00Z",
+                "files": {{{}}}, "truncated": false}}"#,
+            files.join(",")
+        )
+    }
+
+    #[test]
+    fn read_body_within_the_limit() {
+        let client = client(16);
+        let body = futures::executor::block_on(
+            client.read_body(response(isahc::Body::from("0123456789abcdef"), Some(16))),
+        )
+        .unwrap();
+        assert_eq!(body, b"0123456789abcdef");
+    }
+
+    #[test]
+    fn read_body_rejects_the_declared_length() {
+        // The body is never read, since the length exceeds the limit.
+        let client = client(16);
+        let body = isahc::Body::reader(futures::io::repeat(b'x'));
+        let err =
+            futures::executor::block_on(client.read_body(response(body, Some(500 * 1024 * 1024))))
+                .unwrap_err();
+        assert!(is_malformed(&err), "{}", err);
+    }
+
+    #[test]
+    fn read_body_stops_past_the_limit() {
+        // An endless body without the length is read up to the limit.
+        let client = client(1024);
+        let body = isahc::Body::reader(futures::io::repeat(b'x'));
+        let err = futures::executor::block_on(client.read_body(response(body, None))).unwrap_err();
+        assert!(is_malformed(&err), "{}", err);
+
+        // A body lying about its length is cut as well.
+        let body = isahc::Body::reader(futures::io::repeat(b'x'));
+        let err =
+            futures::executor::block_on(client.read_body(response(body, Some(16)))).unwrap_err();
+        assert!(is_malformed(&err), "{}", err);
+    }
+
+    #[test]
+    fn from_json_checks_the_files() {
+        let gist = Gist::from_json(gist_json(2, 1).as_bytes()).unwrap();
+        assert_eq!(gist.files.len(), 2);
+
+        let err = Gist::from_json(gist_json(MAX_FILES + 1, 1).as_bytes()).unwrap_err();
+        assert!(is_malformed(&err), "{}", err);
+
+        // The declared size is refused, whatever the actual content.
+        Gist::from_json(gist_json(1, MAX_DECLARED_FILE_SIZE).as_bytes()).unwrap();
+        let err = Gist::from_json(gist_json(1, MAX_DECLARED_FILE_SIZE + 1).as_bytes()).unwrap_err();
+        assert!(is_malformed(&err), "{}", err);
+    }
 }
@@ -0,0 +1,273 @@
+//! Conversion between the Gist files and the files in a local directory.
+
+use crate::{Gist, GistFile};
+use chrono::{DateTime, Utc};
+use filetime::FileTime;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// The maximum size of a file, beyond which the API truncates the content.
+pub const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+/// A local file to be uploaded to a Gist.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewGistFile {
+    pub filename: String,
+    pub content: String,
+}
+
+impl NewGistFile {
+    /// Read a local file, failing if it is not valid UTF-8 or larger
+    /// than `MAX_FILE_SIZE`.
+    pub fn from_path(path: &Path) -> anyhow::Result<Self> {
+        let filename = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow::anyhow!("invalid filename: {:?}", path))?
+            .to_owned();
+
+        let metadata = fs::metadata(path)?;
+        anyhow::ensure!(metadata.is_file(), "not a regular file: {:?}", path);
+        anyhow::ensure!(
+            metadata.len() <= MAX_FILE_SIZE,
+            "the file is larger than {} bytes: {:?}",
+            MAX_FILE_SIZE,
+            path
+        );
+
+        let content = String::from_utf8(fs::read(path)?)
+            .map_err(|_| anyhow::anyhow!("the content is not valid UTF-8: {:?}", path))?;
+
+        Ok(Self { filename, content })
+    }
+
+    /// Read the regular files directly under the directory accepted by
+    /// the filter, sorted by the filename.
+    ///
+    /// Fails if two of the filenames differ only in case, since they
+    /// cannot be written back to a case-insensitive filesystem.
+    pub fn read_dir<F>(dir: &Path, mut filter: F) -> anyhow::Result<Vec<Self>>
+    where
+        F: FnMut(&Path) -> bool,
+    {
+        let mut files = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if !path.is_file() || !filter(&path) {
+                continue;
+            }
+            files.push(Self::from_path(&path)?);
+        }
+
+        files.sort_by(|a, b| a.filename.cmp(&b.filename));
+        check_collisions(files.iter().map(|file| &*file.filename))?;
+        Ok(files)
+    }
+
+    /// Return the pair of the filename and the content, as in `NewGist::files`.
+    pub fn as_pair(&self) -> (&str, &str) {
+        (&self.filename, &self.content)
+    }
+}
+
+impl GistFile {
+    /// Create a file from the local content, which has no raw URL
+    /// until it is uploaded.
+    pub fn from_local(filename: String, content: Vec<u8>) -> Self {
+        let type_ = match std::str::from_utf8(&content) {
+            Ok(..) => mime::TEXT_PLAIN,
+            Err(..) => mime::APPLICATION_OCTET_STREAM,
+        };
+        Self {
+            filename,
+            type_,
+            language: String::new(),
+            raw_url: String::new(),
+            size: content.len() as u64,
+            truncated: false,
+            content: String::from_utf8_lossy(&content).into_owned(),
+            decoded: Some(content),
+        }
+    }
+
+    /// Write the content into the directory, with the modification time set.
+    ///
+    /// Fails if the content is truncated in the API response.
+    pub fn write_to(&self, dir: &Path, mtime: DateTime<Utc>) -> anyhow::Result<PathBuf> {
+        anyhow::ensure!(
+            !self.truncated,
+            "the content is truncated: {:?}",
+            self.filename
+        );
+        anyhow::ensure!(
+            is_plain_filename(&self.filename),
+            "invalid filename: {:?}",
+            self.filename
+        );
+
+        let path = dir.join(&self.filename);
+        fs::write(&path, self.content_bytes())?;
+        let mtime = FileTime::from_unix_time(mtime.timestamp(), mtime.timestamp_subsec_nanos());
+        filetime::set_file_mtime(&path, mtime)?;
+        Ok(path)
+    }
+}
+
+impl Gist {
+    /// Write all files into the directory, in the order of the filename.
+    ///
+    /// The modification times are set to the last update of the Gist.
+    pub fn write_all_to(&self, dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        anyhow::ensure!(!self.truncated, "the file list is truncated");
+
+        let mut files: Vec<&GistFile> = self.files.values().collect();
+        files.sort_by(|a, b| a.filename.cmp(&b.filename));
+        check_collisions(files.iter().map(|file| &*file.filename))?;
+
+        files
+            .into_iter()
+            .map(|file| file.write_to(dir, self.updated_at))
+            .collect()
+    }
+}
+
+/// Return whether the name refers to a file directly under a directory.
+fn is_plain_filename(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains('/')
+}
+
+fn check_collisions<'a>(names: impl Iterator<Item = &'a str>) -> anyhow::Result<()> {
+    let mut folded: Vec<String> = names.map(str::to_lowercase).collect();
+    folded.sort();
+    if let Some(pair) = folded.windows(2).find(|pair| pair[0] == pair[1]) {
+        anyhow::bail!("the filenames collide ignoring case: {:?}", pair[0]);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone as _;
+
+    /// An empty directory removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "gist-client-local-test-{}-{}",
+                std::process::id(),
+                name
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn gist(files: &[(&str, &str)], updated_at: DateTime<Utc>) -> Gist {
+        Gist {
+            id: "0123abc".into(),
+            html_url: String::new(),
+            description: String::new(),
+            public: false,
+            created_at: updated_at,
+            updated_at,
+            files: files
+                .iter()
+                .map(|&(name, content)| {
+                    let file = GistFile::from_local(name.into(), content.into());
+                    (name.to_owned(), file)
+                })
+                .collect(),
+            git_pull_url: String::new(),
+            truncated: false,
+            history: vec![],
+        }
+    }
+
+    #[test]
+    fn create_and_read_back() {
+        let dir = TempDir::new("round-trip");
+        let updated_at = Utc.timestamp_opt(1_500_000_000, 123_000_000).unwrap();
+        let gist = gist(&[("b.txt", "b\n"), ("a.sh", "#!/bin/sh\n")], updated_at);
+
+        let paths = gist.write_all_to(&dir.0).unwrap();
+        assert_eq!(paths, [dir.0.join("a.sh"), dir.0.join("b.txt")]);
+        let mtime = FileTime::from_last_modification_time(&fs::metadata(&paths[0]).unwrap());
+        assert_eq!(mtime, FileTime::from_unix_time(1_500_000_000, 123_000_000));
+
+        let files = NewGistFile::read_dir(&dir.0, |_| true).unwrap();
+        assert_eq!(
+            files.iter().map(NewGistFile::as_pair).collect::<Vec<_>>(),
+            [("a.sh", "#!/bin/sh\n"), ("b.txt", "b\n")]
+        );
+        let files = NewGistFile::read_dir(&dir.0, |path| path.extension() == Some("sh".as_ref()));
+        assert_eq!(files.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn update_overwrites_the_content() {
+        let dir = TempDir::new("update");
+        let first = Utc.timestamp_opt(1_500_000_000, 0).unwrap();
+        gist(&[("a.txt", "a longer first version\n")], first)
+            .write_all_to(&dir.0)
+            .unwrap();
+        let second = Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+        gist(&[("a.txt", "second\n")], second)
+            .write_all_to(&dir.0)
+            .unwrap();
+
+        let file = NewGistFile::from_path(&dir.0.join("a.txt")).unwrap();
+        assert_eq!(file.content, "second\n");
+        let metadata = fs::metadata(dir.0.join("a.txt")).unwrap();
+        let mtime = FileTime::from_last_modification_time(&metadata);
+        assert_eq!(mtime.unix_seconds(), 1_600_000_000);
+    }
+
+    #[test]
+    fn binary_content_is_written_as_is() {
+        let dir = TempDir::new("binary");
+        let file = GistFile::from_local("a.bin".into(), vec![0, 0xff, 0xfe]);
+        assert_eq!(file.type_, mime::APPLICATION_OCTET_STREAM);
+        let path = file.write_to(&dir.0, Utc::now()).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), [0, 0xff, 0xfe]);
+        assert!(NewGistFile::from_path(&path).is_err());
+    }
+
+    #[test]
+    fn reject_the_unsafe_files() {
+        let dir = TempDir::new("unsafe");
+        for name in &["", ".", "..", "../a.txt", "dir/a.txt"] {
+            let file = GistFile::from_local((*name).into(), b"a".to_vec());
+            assert!(file.write_to(&dir.0, Utc::now()).is_err(), "{:?}", name);
+        }
+        let mut truncated = GistFile::from_local("a.txt".into(), b"a".to_vec());
+        truncated.truncated = true;
+        assert!(truncated.write_to(&dir.0, Utc::now()).is_err());
+        assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 0);
+
+        let colliding = gist(&[("a.txt", "a"), ("A.TXT", "b")], Utc::now());
+        assert!(colliding.write_all_to(&dir.0).is_err());
+        assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn reject_the_large_files() {
+        let dir = TempDir::new("large");
+        let path = dir.0.join("large.txt");
+        fs::write(&path, vec![b'a'; MAX_FILE_SIZE as usize + 1]).unwrap();
+        assert!(NewGistFile::from_path(&path).is_err());
+        fs::write(&path, vec![b'a'; MAX_FILE_SIZE as usize]).unwrap();
+        assert!(NewGistFile::from_path(&path).is_ok());
+    }
+}
@@ -0,0 +1,413 @@
+//! Decoding of the packfiles sent by `git-upload-pack`.
+//!
+//! https://git-scm.com/docs/pack-format
+
+use crate::MAX_DECLARED_FILE_SIZE;
+use flate2::bufread::ZlibDecoder;
+use sha1::{Digest, Sha1};
+use std::{collections::HashMap, convert::TryInto, io::Read};
+
+/// The ID of an object, i.e. the SHA-1 of its content.
+pub(crate) type ObjectId = [u8; 20];
+
+/// The type of an object.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Commit,
+    Tree,
+    Blob,
+    Tag,
+}
+
+impl Kind {
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(Kind::Commit),
+            2 => Some(Kind::Tree),
+            3 => Some(Kind::Blob),
+            4 => Some(Kind::Tag),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Commit => "commit",
+            Kind::Tree => "tree",
+            Kind::Blob => "blob",
+            Kind::Tag => "tag",
+        }
+    }
+}
+
+const OFS_DELTA: u8 = 6;
+const REF_DELTA: u8 = 7;
+
+/// An entry of the packfile, which may be a delta against another object.
+enum Entry {
+    Object(Kind, Vec<u8>),
+    Delta(Base, Vec<u8>),
+}
+
+/// The base object of a delta.
+#[derive(Copy, Clone)]
+enum Base {
+    /// The entry at the offset in the packfile.
+    Offset(usize),
+    Id(ObjectId),
+}
+
+/// The objects contained in a packfile, with the deltas resolved.
+#[derive(Debug, Default)]
+pub(crate) struct Objects {
+    objects: HashMap<ObjectId, (Kind, Vec<u8>)>,
+}
+
+impl Objects {
+    /// Decode the packfile.
+    ///
+    /// The trailing checksum is not verified, since the transport is
+    /// already protected by TLS.
+    pub(crate) fn parse(pack: &[u8]) -> anyhow::Result<Self> {
+        anyhow::ensure!(pack.len() >= 12 && &pack[..4] == b"PACK", "not a packfile");
+        let version = u32::from_be_bytes(pack[4..8].try_into().unwrap());
+        anyhow::ensure!(
+            version == 2 || version == 3,
+            "unsupported packfile version: {}",
+            version
+        );
+        let count = u32::from_be_bytes(pack[8..12].try_into().unwrap()) as usize;
+
+        // Every entry takes two bytes at least, so the count in the header
+        // cannot reserve more than the packfile holds.
+        let mut entries = Vec::with_capacity(count.min(pack.len() / 2));
+        let mut pos = 12;
+        for _ in 0..count {
+            let start = pos;
+            let (code, size) = read_header(pack, &mut pos)?;
+            let entry = match code {
+                OFS_DELTA => {
+                    let offset = read_offset(pack, &mut pos)?;
+                    let base = start
+                        .checked_sub(offset)
+                        .ok_or_else(|| anyhow::anyhow!("invalid delta offset"))?;
+                    Entry::Delta(Base::Offset(base), inflate(pack, &mut pos, size)?)
+                }
+                REF_DELTA => {
+                    let id = pack
+                        .get(pos..pos + 20)
+                        .ok_or_else(|| anyhow::anyhow!("unexpected end of packfile"))?;
+                    pos += 20;
+                    let base = Base::Id(id.try_into().unwrap());
+                    Entry::Delta(base, inflate(pack, &mut pos, size)?)
+                }
+                code => {
+                    let kind = Kind::from_code(code)
+                        .ok_or_else(|| anyhow::anyhow!("unknown object type: {}", code))?;
+                    Entry::Object(kind, inflate(pack, &mut pos, size)?)
+                }
+            };
+            entries.push((start, entry));
+        }
+
+        Self::resolve(entries)
+    }
+
+    /// Apply the deltas until every entry is resolved.
+    ///
+    /// The bases of the ref-deltas may appear later in the pack, so
+    /// the unresolved ones are retried while progress is made.
+    fn resolve(entries: Vec<(usize, Entry)>) -> anyhow::Result<Self> {
+        let mut objects = Objects::default();
+        let mut by_offset: HashMap<usize, ObjectId> = HashMap::new();
+
+        let mut pending = entries;
+        loop {
+            let mut unresolved = vec![];
+            let before = pending.len();
+            for (offset, entry) in pending {
+                let (base, delta) = match entry {
+                    Entry::Object(kind, data) => {
+                        by_offset.insert(offset, objects.insert(kind, data));
+                        continue;
+                    }
+                    Entry::Delta(base, delta) => (base, delta),
+                };
+                let id = match base {
+                    Base::Offset(base_offset) => by_offset.get(&base_offset).copied(),
+                    Base::Id(id) => Some(id),
+                };
+                match id.and_then(|id| objects.objects.get(&id)) {
+                    Some(&(kind, ref base_data)) => {
+                        let data = apply_delta(base_data, &delta)?;
+                        by_offset.insert(offset, objects.insert(kind, data));
+                    }
+                    None => unresolved.push((offset, Entry::Delta(base, delta))),
+                }
+            }
+
+            if unresolved.is_empty() {
+                return Ok(objects);
+            }
+            anyhow::ensure!(
+                unresolved.len() < before,
+                "the bases of {} deltas are missing",
+                unresolved.len()
+            );
+            pending = unresolved;
+        }
+    }
+
+    fn insert(&mut self, kind: Kind, data: Vec<u8>) -> ObjectId {
+        let mut hasher = Sha1::new();
+        hasher.update(format!("{} {}\0", kind.name(), data.len()).as_bytes());
+        hasher.update(&data);
+        let mut id = [0; 20];
+        id.copy_from_slice(&hasher.finalize());
+        self.objects.insert(id, (kind, data));
+        id
+    }
+
+    /// Return the content of the object with the specified type.
+    pub(crate) fn get(&self, id: &ObjectId, kind: Kind) -> anyhow::Result<&[u8]> {
+        match self.objects.get(id) {
+            Some((k, data)) if *k == kind => Ok(&data[..]),
+            Some((k, _)) => {
+                anyhow::bail!("{} is a {}, not a {}", to_hex(id), k.name(), kind.name())
+            }
+            None => anyhow::bail!("{} is missing in the packfile", to_hex(id)),
+        }
+    }
+}
+
+/// Read the type and the uncompressed size of an entry.
+fn read_header(pack: &[u8], pos: &mut usize) -> anyhow::Result<(u8, usize)> {
+    let mut c = next_byte(pack, pos)?;
+    let code = (c >> 4) & 0x07;
+    let mut size = (c & 0x0f) as usize;
+    let mut shift = 4;
+    while c & 0x80 != 0 {
+        c = next_byte(pack, pos)?;
+        size |= ((c & 0x7f) as usize) << shift;
+        shift += 7;
+    }
+    Ok((code, size))
+}
+
+/// Read the distance to the base of an offset delta.
+fn read_offset(pack: &[u8], pos: &mut usize) -> anyhow::Result<usize> {
+    let mut c = next_byte(pack, pos)?;
+    let mut offset = (c & 0x7f) as usize;
+    while c & 0x80 != 0 {
+        c = next_byte(pack, pos)?;
+        offset = ((offset + 1) << 7) | (c & 0x7f) as usize;
+    }
+    Ok(offset)
+}
+
+fn next_byte(data: &[u8], pos: &mut usize) -> anyhow::Result<u8> {
+    let c = *data
+        .get(*pos)
+        .ok_or_else(|| anyhow::anyhow!("unexpected end of data"))?;
+    *pos += 1;
+    Ok(c)
+}
+
+/// Refuse the absurd object sizes before allocating the buffers for them.
+fn check_size(size: usize) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        size as u64 <= MAX_DECLARED_FILE_SIZE,
+        "the object size is too large: {} bytes",
+        size
+    );
+    Ok(())
+}
+
+/// Decompress the zlib stream at the position, advancing it past the stream.
+fn inflate(pack: &[u8], pos: &mut usize, size: usize) -> anyhow::Result<Vec<u8>> {
+    check_size(size)?;
+    let mut decoder = ZlibDecoder::new(&pack[*pos..]);
+    let mut data = Vec::with_capacity(size);
+    decoder.read_to_end(&mut data)?;
+    anyhow::ensure!(data.len() == size, "the object size is mismatched");
+    *pos += decoder.total_in() as usize;
+    Ok(data)
+}
+
+/// Reconstruct an object from its base and the delta.
+fn apply_delta(base: &[u8], delta: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut pos = 0;
+    let base_size = read_varint(delta, &mut pos)?;
+    anyhow::ensure!(base_size == base.len(), "the delta base size is mismatched");
+    let size = read_varint(delta, &mut pos)?;
+    check_size(size)?;
+
+    let mut data = Vec::with_capacity(size);
+    while pos < delta.len() {
+        let c = next_byte(delta, &mut pos)?;
+        if c & 0x80 != 0 {
+            // Copy from the base.
+            let mut offset = 0;
+            for i in 0..4 {
+                if c & (1 << i) != 0 {
+                    offset |= (next_byte(delta, &mut pos)? as usize) << (8 * i);
+                }
+            }
+            let mut len = 0;
+            for i in 0..3 {
+                if c & (0x10 << i) != 0 {
+                    len |= (next_byte(delta, &mut pos)? as usize) << (8 * i);
+                }
+            }
+            if len == 0 {
+                len = 0x10000;
+            }
+            let chunk = base
+                .get(offset..offset + len)
+                .ok_or_else(|| anyhow::anyhow!("the delta copies out of the base"))?;
+            data.extend_from_slice(chunk);
+        } else if c != 0 {
+            // Insert the literal bytes.
+            let chunk = delta
+                .get(pos..pos + c as usize)
+                .ok_or_else(|| anyhow::anyhow!("unexpected end of delta"))?;
+            data.extend_from_slice(chunk);
+            pos += c as usize;
+        } else {
+            anyhow::bail!("invalid delta instruction");
+        }
+    }
+
+    anyhow::ensure!(data.len() == size, "the delta result size is mismatched");
+    Ok(data)
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> anyhow::Result<usize> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let c = next_byte(data, pos)?;
+        value |= ((c & 0x7f) as usize) << shift;
+        shift += 7;
+        if c & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+pub(crate) fn to_hex(id: &ObjectId) -> String {
+    id.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn from_hex(s: &str) -> anyhow::Result<ObjectId> {
+    anyhow::ensure!(s.len() == 40, "invalid object ID: {:?}", s);
+    let mut id = [0; 20];
+    for (i, byte) in id.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16)
+            .map_err(|_| anyhow::anyhow!("invalid object ID: {:?}", s))?;
+    }
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A packfile of two commits of a file, created by `git pack-objects
+    /// --delta-base-offset`, where the first version of the file is stored
+    /// as an offset delta against the second.
+    const PACK: &[u8] = include_bytes!("../tests/fixtures/gist.pack");
+
+    const FIRST_BLOB: &str = "78fff4a604898b3ad2d0012433b504ef5d6a078b";
+    const SECOND_BLOB: &str = "7e17d2ebec2849f7b28475851153b0a12a5f911d";
+    const SECOND_COMMIT: &str = "d1ab21ffe080c0a5c04143f2600239d6baaa6972";
+
+    fn content(changed: bool) -> String {
+        let mut content = String::new();
+        for i in 1..=40 {
+            if changed && i == 20 {
+                content += "line twenty of the gist file\n";
+            } else {
+                content += &format!("line {} of the gist file\n", i);
+            }
+        }
+        if changed {
+            content += "tail\n";
+        }
+        content
+    }
+
+    #[test]
+    fn parse_fixture() {
+        let objects = Objects::parse(PACK).unwrap();
+        assert_eq!(objects.objects.len(), 6);
+
+        let first = objects.get(&from_hex(FIRST_BLOB).unwrap(), Kind::Blob);
+        assert_eq!(first.unwrap(), content(false).as_bytes());
+        let second = objects.get(&from_hex(SECOND_BLOB).unwrap(), Kind::Blob);
+        assert_eq!(second.unwrap(), content(true).as_bytes());
+
+        let commit = objects
+            .get(&from_hex(SECOND_COMMIT).unwrap(), Kind::Commit)
+            .unwrap();
+        assert!(commit.starts_with(b"tree 143ef0e83050170eb5d0078ae5ddfb33b5d19b41\n"));
+    }
+
+    #[test]
+    fn get_checks_the_kind() {
+        let objects = Objects::parse(PACK).unwrap();
+        let id = from_hex(SECOND_COMMIT).unwrap();
+        assert!(objects.get(&id, Kind::Blob).is_err());
+        assert!(objects.get(&[0; 20], Kind::Blob).is_err());
+    }
+
+    #[test]
+    fn reject_malformed_packs() {
+        assert!(Objects::parse(b"").is_err());
+        assert!(Objects::parse(b"NOTAPACKFILE").is_err());
+
+        let mut version = PACK.to_vec();
+        version[7] = 4;
+        assert!(Objects::parse(&version).is_err());
+
+        assert!(Objects::parse(&PACK[..PACK.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn reject_absurd_sizes() {
+        // A blob declaring 2^39 bytes, with no data behind the header.
+        let mut pack = b"PACK\0\0\0\x02\0\0\0\x01".to_vec();
+        pack.extend_from_slice(&[0xb0, 0xff, 0xff, 0xff, 0xff, 0x7f]);
+        let err = Objects::parse(&pack).unwrap_err();
+        assert!(err.to_string().contains("too large"), "{}", err);
+
+        // The count of the entries is not trusted for the allocation.
+        let mut count = PACK[..12].to_vec();
+        count[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(Objects::parse(&count).is_err());
+    }
+
+    #[test]
+    fn apply_delta_copies_and_inserts() {
+        let base = b"hello, world";
+        // base size 12, result size 13: copy 7 bytes at 0, insert "gist!",
+        // and copy 1 byte at 11.
+        let delta = [
+            12, 13, 0x90, 7, 5, b'g', b'i', b's', b't', b'!', 0x91, 11, 1,
+        ];
+        assert_eq!(apply_delta(base, &delta).unwrap(), b"hello, gist!d");
+
+        // The base size is mismatched.
+        assert!(apply_delta(b"hello", &delta).is_err());
+        // The copy is out of the base.
+        assert!(apply_delta(base, &[12, 4, 0x91, 10, 4]).is_err());
+    }
+
+    #[test]
+    fn test_hex() {
+        let id = from_hex(SECOND_BLOB).unwrap();
+        assert_eq!(to_hex(&id), SECOND_BLOB);
+        assert!(from_hex("7e17d2").is_err());
+        assert!(from_hex(&"g".repeat(40)).is_err());
+    }
+}
@@ -0,0 +1,72 @@
+//! The HTTP client shared by the requests, so that the periodic refreshes
+//! and the bursts of raw fetches reuse the warm connections rather than
+//! paying a TLS handshake each.
+
+use crate::UPDATE_PARALLELISM;
+use http::Response;
+use isahc::{config::VersionNegotiation, HttpClient, ResponseExt as _};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// The interval of the TCP keep-alive probes, shorter than the idle timeout
+/// of the usual NAT gateways, so that the connection survives between the
+/// refreshes.
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+/// Build the HTTP client keeping enough idle connections for `parallelism`
+/// requests in flight.
+///
+/// HTTP/2 is used with prior knowledge, multiplexing the concurrent requests
+/// to api.github.com over a single connection. The hosts choosing HTTP/1.1
+/// in the TLS negotiation are still served.
+pub(crate) fn build(parallelism: usize) -> HttpClient {
+    HttpClient::builder()
+        .version_negotiation(VersionNegotiation::http2())
+        // One more for the raw content, served from another host.
+        .connection_cache_size(std::cmp::max(parallelism, UPDATE_PARALLELISM) + 1)
+        .tcp_keepalive(TCP_KEEPALIVE)
+        .metrics(true)
+        .build()
+        .expect("failed to initialize the HTTP client")
+}
+
+/// Return whether the response was received over a connection opened by an
+/// earlier request, which curl reports as no time spent on connecting.
+///
+/// Returns `None` if the metrics are not collected.
+pub(crate) fn connection_reused<T>(response: &Response<T>) -> Option<bool> {
+    let metrics = response.metrics()?;
+    Some(metrics.connect_time() == Duration::from_secs(0))
+}
+
+/// The numbers of the connections opened and reused by the requests.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    pub opened: u64,
+    pub reused: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionCounter {
+    opened: AtomicU64,
+    reused: AtomicU64,
+}
+
+impl ConnectionCounter {
+    pub(crate) fn observe<T>(&self, response: &Response<T>) {
+        match connection_reused(response) {
+            Some(true) => self.reused.fetch_add(1, Ordering::Relaxed),
+            Some(false) => self.opened.fetch_add(1, Ordering::Relaxed),
+            None => return,
+        };
+    }
+
+    pub(crate) fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            opened: self.opened.load(Ordering::Relaxed),
+            reused: self.reused.load(Ordering::Relaxed),
+        }
+    }
+}
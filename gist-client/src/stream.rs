@@ -0,0 +1,68 @@
+//! Streaming of the raw content of files.
+
+use futures::io::AsyncReadExt;
+use isahc::Body;
+use std::io;
+
+/// The size of the buffer used to skip the content.
+const SKIP_BUF_SIZE: usize = 8192;
+
+/// A reader of the raw content of a file, which is consumed sequentially.
+pub struct ContentReader {
+    body: Body,
+    position: u64,
+}
+
+impl std::fmt::Debug for ContentReader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ContentReader")
+            .field("position", &self.position)
+            .finish()
+    }
+}
+
+impl ContentReader {
+    pub(crate) fn new(body: Body, position: u64) -> Self {
+        Self { body, position }
+    }
+
+    /// Return the offset of the next byte to be read.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Read at most `size` bytes at `offset`.
+    ///
+    /// The offset must not be behind the current position. The returned
+    /// data is shorter than `size` only at the end of the content.
+    pub async fn read_at(&mut self, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+        if offset < self.position {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the offset is behind the current position",
+            ));
+        }
+
+        let mut skip_buf = [0u8; SKIP_BUF_SIZE];
+        while self.position < offset {
+            let len = std::cmp::min((offset - self.position) as usize, SKIP_BUF_SIZE);
+            match self.body.read(&mut skip_buf[..len]).await? {
+                0 => return Ok(Vec::new()),
+                n => self.position += n as u64,
+            }
+        }
+
+        let mut data = vec![0u8; size];
+        let mut filled = 0;
+        while filled < size {
+            match self.body.read(&mut data[filled..]).await? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        data.truncate(filled);
+        self.position += filled as u64;
+
+        Ok(data)
+    }
+}
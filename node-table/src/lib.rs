@@ -1,15 +1,56 @@
 //! In-memory node table.
 
+// The handlers follow the `W: ?Sized` signature of `Filesystem::call`.
+#![allow(clippy::multiple_bound_locations)]
+
 use crossbeam::atomic::AtomicCell;
 use futures::{io::AsyncWrite, lock::Mutex};
 use indexmap::map::{Entry as MapEntry, IndexMap};
 use polyfuse::{op, Context, DirEntry, FileAttr, Forget};
 use std::{
+    cmp::Ordering,
     ffi::{OsStr, OsString},
-    io,
+    fmt, io,
     sync::{Arc, Weak},
 };
 
+/// An error number reported by the operations on the node table.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Errno(i32);
+
+impl Errno {
+    pub const ENOENT: Self = Self(libc::ENOENT);
+    pub const EEXIST: Self = Self(libc::EEXIST);
+    pub const ENOTDIR: Self = Self(libc::ENOTDIR);
+    pub const ENOTSUP: Self = Self(libc::ENOTSUP);
+    pub const EPERM: Self = Self(libc::EPERM);
+
+    /// Return the raw value, as passed to `Context::reply_err`.
+    pub fn raw(self) -> i32 {
+        self.0
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        io::Error::from_raw_os_error(self.0).fmt(f)
+    }
+}
+
+impl std::error::Error for Errno {}
+
+impl From<Errno> for i32 {
+    fn from(errno: Errno) -> Self {
+        errno.0
+    }
+}
+
+impl From<Errno> for io::Error {
+    fn from(errno: Errno) -> Self {
+        io::Error::from_raw_os_error(errno.0)
+    }
+}
+
 /// In-memory inode table.
 ///
 /// The instance of this type manages the hierarchical structure
@@ -26,6 +67,15 @@ struct NodeTableInner {
     next_ino: AtomicCell<u64>,
 }
 
+/// A node prepared by `NodeTable::pending_node`, which is not linked
+/// to the table until committed.
+#[derive(Debug)]
+pub struct PendingNode {
+    parent: Node,
+    name: OsString,
+    attr: FileAttr,
+}
+
 /// A handle of the node linked to `NodeTable`.
 #[derive(Debug)]
 pub struct Node {
@@ -47,10 +97,15 @@ enum NodeKind {
     Dir(Mutex<DirNode>),
 }
 
+/// A directory.
+///
+/// The directory entries are serialized on every readdir call rather than
+/// kept in memory, since they are much larger than the names.
 #[derive(Debug)]
 struct DirNode {
-    children: IndexMap<OsString, (Weak<NodeInner>, DirEntry)>,
-    dirents: [DirEntry; 2],
+    ino: u64,
+    parent: u64,
+    children: IndexMap<OsString, Weak<NodeInner>>,
 }
 
 impl NodeTable {
@@ -68,8 +123,9 @@ impl NodeTable {
             nodeid: 1,
             attr: AtomicCell::new(root_attr),
             kind: NodeKind::Dir(Mutex::new(DirNode {
+                ino: 1,
+                parent: 1, // the root is its own parent.
                 children: IndexMap::new(),
-                dirents: [DirEntry::dir(".", 1, 1), DirEntry::dir("..", 1, 2)],
             })),
             nlookup: AtomicCell::new(1),
         });
@@ -87,6 +143,19 @@ impl NodeTable {
         }
     }
 
+    /// Return the number of bytes used to store the directory entries.
+    pub async fn entries_bytes(&self) -> usize {
+        // Release the table before locking the directories, as `lookup` does.
+        let nodes: Vec<_> = self.global.nodes.lock().await.values().cloned().collect();
+        let mut total = 0;
+        for node in nodes {
+            if let NodeKind::Dir(ref dir) = node.kind {
+                total += dir.lock().await.entries_bytes();
+            }
+        }
+        total
+    }
+
     /// Create a handle of the root inode.
     pub fn root(&self) -> Node {
         Node {
@@ -108,16 +177,25 @@ impl NodeTable {
 
     /// Lookup an inode by parent inode number and name.
     ///
+    /// The special names `.` and `..` are resolved to the directory itself
+    /// and its parent, respectively.
+    ///
     /// This method increments the lookup count for the corresponding node.
     pub async fn lookup(&self, parent: u64, name: &OsStr) -> Option<Node> {
         let parent = self.global.nodes.lock().await.get(&parent)?.clone();
         match parent.kind {
             NodeKind::Dir(ref dir) => {
                 let dir = dir.lock().await;
-                let node = &dir.children.get(name)?.0;
+                let node = if name == "." {
+                    Arc::downgrade(&parent)
+                } else if name == ".." {
+                    Arc::downgrade(self.global.nodes.lock().await.get(&dir.parent)?)
+                } else {
+                    dir.children.get(name)?.clone()
+                };
                 node.upgrade()?.nlookup.fetch_add(1);
                 Some(Node {
-                    inner: node.clone(),
+                    inner: node,
                     global: Arc::downgrade(&self.global),
                 })
             }
@@ -125,6 +203,58 @@ impl NodeTable {
         }
     }
 
+    /// Prepare a new node under the specified directory without inserting it.
+    ///
+    /// The node becomes visible only after `PendingNode::commit` is called.
+    pub async fn pending_node(
+        &self,
+        parent: u64,
+        name: OsString,
+        attr: FileAttr,
+    ) -> Result<PendingNode, Errno> {
+        let parent = self.get(parent).await.ok_or(Errno::ENOENT)?;
+        match parent.inner.upgrade().ok_or(Errno::ENOENT)?.kind {
+            NodeKind::Dir(ref dir) => {
+                let dir = dir.lock().await;
+                if let Some(node) = dir.children.get(&name) {
+                    if node.upgrade().is_some() {
+                        return Err(Errno::EEXIST);
+                    }
+                }
+            }
+            _ => return Err(Errno::ENOTDIR),
+        }
+        Ok(PendingNode { parent, name, attr })
+    }
+
+    /// Create a regular file node that is not linked to any directory.
+    ///
+    /// Such a node never appears in the directory entries and can only
+    /// be reached by its inode number.
+    pub async fn new_detached(&self, attr: FileAttr) -> Result<Node, Errno> {
+        if attr.mode() & libc::S_IFMT != libc::S_IFREG {
+            return Err(Errno::ENOTSUP);
+        }
+
+        let mut attr = attr;
+        let ino = self.global.next_ino.fetch_add(1);
+        attr.set_ino(ino);
+
+        let inner = Arc::new(NodeInner {
+            nodeid: ino,
+            attr: AtomicCell::new(attr),
+            kind: NodeKind::File,
+            nlookup: AtomicCell::new(0),
+        });
+        let inner_ptr = Arc::downgrade(&inner);
+        self.global.nodes.lock().await.insert(ino, inner);
+
+        Ok(Node {
+            inner: inner_ptr,
+            global: Arc::downgrade(&self.global),
+        })
+    }
+
     /// Decrease the lookup counts of the specified inodes.
     pub async fn forget(&self, forgets: &[Forget]) {
         let nodes = self.global.nodes.lock().await;
@@ -136,6 +266,19 @@ impl NodeTable {
     }
 }
 
+impl PendingNode {
+    /// Insert the node into the table.
+    ///
+    /// This fails with `EEXIST` if another node with the same name has been
+    /// created in the meantime.
+    pub async fn commit(self) -> Result<Node, Errno> {
+        self.parent.new_child(self.name, self.attr).await
+    }
+
+    /// Discard the node without touching the table.
+    pub fn rollback(self) {}
+}
+
 impl Node {
     /// Return the identifier of the associated inode.
     ///
@@ -161,55 +304,214 @@ impl Node {
     }
 
     /// Create a new node onto the specified directory.
-    pub async fn new_child(&self, name: OsString, attr: FileAttr) -> Result<Node, i32> {
+    pub async fn new_child(&self, name: OsString, attr: FileAttr) -> Result<Node, Errno> {
+        self.insert_child(name, attr, None).await
+    }
+
+    /// Create a new node onto the specified directory, reusing the inode number
+    /// assigned to a removed node.
+    ///
+    /// A new inode number is assigned if the specified one is in use.
+    pub async fn new_child_with_ino(
+        &self,
+        name: OsString,
+        attr: FileAttr,
+        ino: u64,
+    ) -> Result<Node, Errno> {
+        self.insert_child(name, attr, Some(ino)).await
+    }
+
+    async fn insert_child(
+        &self,
+        name: OsString,
+        attr: FileAttr,
+        reused_ino: Option<u64>,
+    ) -> Result<Node, Errno> {
         let global = self.global.upgrade().expect("the node table is died");
         let parent = self.inner.upgrade().expect("the node is died");
 
-        let mut attr = attr;
-        let ino = global.next_ino.load();
-        attr.set_ino(ino);
-
-        let kind = match attr.mode() & libc::S_IFMT {
-            libc::S_IFDIR => NodeKind::Dir(Mutex::new(DirNode {
-                children: IndexMap::new(),
-                dirents: [
-                    DirEntry::dir(".", ino, 1),
-                    DirEntry::dir("..", parent.nodeid, 2),
-                ],
-            })),
-            libc::S_IFREG => NodeKind::File,
-            _ => return Err(libc::ENOTSUP),
+        let is_dir = match attr.mode() & libc::S_IFMT {
+            libc::S_IFDIR => true,
+            libc::S_IFREG => false,
+            _ => return Err(Errno::ENOTSUP),
         };
 
+        match parent.kind {
+            NodeKind::Dir(ref dir) => {
+                let mut dir = dir.lock().await;
+                let entry = match dir.children.entry(name) {
+                    // The entry of a removed node is replaced.
+                    MapEntry::Occupied(entry) if entry.get().upgrade().is_none() => {
+                        entry.into_mut()
+                    }
+                    MapEntry::Occupied(..) => return Err(Errno::EEXIST),
+                    MapEntry::Vacant(entry) => entry.insert(Weak::new()),
+                };
+
+                let mut nodes = global.nodes.lock().await;
+                let ino = match reused_ino {
+                    Some(ino) if ino < global.next_ino.load() && !nodes.contains_key(&ino) => ino,
+                    _ => global.next_ino.fetch_add(1),
+                };
+
+                let mut attr = attr;
+                attr.set_ino(ino);
+                let kind = if is_dir {
+                    NodeKind::Dir(Mutex::new(DirNode {
+                        ino,
+                        parent: parent.nodeid,
+                        children: IndexMap::new(),
+                    }))
+                } else {
+                    NodeKind::File
+                };
+
+                let inner = Arc::new(NodeInner {
+                    nodeid: ino,
+                    attr: AtomicCell::new(attr),
+                    kind,
+                    nlookup: AtomicCell::new(0),
+                });
+                let inner_ptr = Arc::downgrade(&inner);
+                nodes.insert(ino, inner);
+                *entry = inner_ptr.clone();
+
+                Ok(Node {
+                    inner: inner_ptr,
+                    global: self.global.clone(),
+                })
+            }
+            _ => Err(Errno::ENOTDIR),
+        }
+    }
+
+    /// Add another entry of an existing file to this directory, as link(2).
+    ///
+    /// The link count of the file is incremented.
+    pub async fn link_child(&self, name: OsString, target: &Node) -> Result<(), Errno> {
+        let parent = self.inner.upgrade().expect("the node is died");
+        let target = target.inner.upgrade().ok_or(Errno::ENOENT)?;
+        if let NodeKind::Dir(..) = target.kind {
+            return Err(Errno::EPERM);
+        }
+
         match parent.kind {
             NodeKind::Dir(ref dir) => {
                 let mut dir = dir.lock().await;
                 match dir.children.entry(name) {
-                    MapEntry::Occupied(..) => Err(libc::EEXIST),
+                    MapEntry::Occupied(entry) if entry.get().upgrade().is_none() => {
+                        *entry.into_mut() = Arc::downgrade(&target);
+                    }
+                    MapEntry::Occupied(..) => return Err(Errno::EEXIST),
                     MapEntry::Vacant(entry) => {
-                        global.next_ino.fetch_add(1);
-                        let inner = Arc::new(NodeInner {
-                            nodeid: ino,
-                            attr: AtomicCell::new(attr),
-                            kind,
-                            nlookup: AtomicCell::new(0),
-                        });
-                        let inner_ptr = Arc::downgrade(&inner);
-
-                        let mut nodes = global.nodes.lock().await;
-                        nodes.insert(ino, inner);
-
-                        let dirent = DirEntry::new(entry.key(), ino, (entry.index() + 3) as u64);
-                        entry.insert((inner_ptr.clone(), dirent));
-
-                        Ok(Node {
-                            inner: inner_ptr,
-                            global: self.global.clone(),
-                        })
+                        entry.insert(Arc::downgrade(&target));
                     }
                 }
+
+                let mut attr = target.attr.load();
+                attr.set_nlink(attr.nlink() + 1);
+                target.attr.store(attr);
+                Ok(())
+            }
+            _ => Err(Errno::ENOTDIR),
+        }
+    }
+
+    /// Change the name of a child node in this directory.
+    ///
+    /// The position of the entry in the directory is preserved.
+    pub async fn rename_child(&self, name: &OsStr, newname: OsString) -> Result<(), Errno> {
+        let parent = self.inner.upgrade().expect("the node is died");
+        match parent.kind {
+            NodeKind::Dir(ref dir) => {
+                let mut dir = dir.lock().await;
+                if !dir.children.contains_key(name) {
+                    return Err(Errno::ENOENT);
+                }
+                if dir.children.contains_key(&newname) {
+                    return Err(Errno::EEXIST);
+                }
+
+                dir.children = std::mem::take(&mut dir.children)
+                    .into_iter()
+                    .map(|(key, node)| {
+                        if key != name {
+                            return (key, node);
+                        }
+                        (newname.clone(), node)
+                    })
+                    .collect();
+
+                Ok(())
+            }
+            _ => Err(Errno::ENOTDIR),
+        }
+    }
+
+    /// Reorder the entries of this directory by the comparator of the names
+    /// and the attributes, putting the entries of the removed nodes last.
+    ///
+    /// The sort is stable, so the offsets are kept unless the order changes.
+    /// Otherwise, as on the removal of an entry, a listing in progress may
+    /// see an entry twice or miss it.
+    pub async fn sort_children_by<F>(&self, mut compare: F) -> Result<(), Errno>
+    where
+        F: FnMut((&OsStr, &FileAttr), (&OsStr, &FileAttr)) -> Ordering,
+    {
+        let parent = self.inner.upgrade().expect("the node is died");
+        match parent.kind {
+            NodeKind::Dir(ref dir) => {
+                let mut dir = dir.lock().await;
+                dir.children
+                    .sort_by(|a_name, a, b_name, b| match (a.upgrade(), b.upgrade()) {
+                        (Some(a), Some(b)) => {
+                            compare((a_name, &a.attr.load()), (b_name, &b.attr.load()))
+                        }
+                        (Some(..), None) => Ordering::Less,
+                        (None, Some(..)) => Ordering::Greater,
+                        (None, None) => Ordering::Equal,
+                    });
+                Ok(())
             }
-            _ => Err(libc::ENOTDIR),
+            _ => Err(Errno::ENOTDIR),
+        }
+    }
+
+    /// Return the number of the subdirectories, or `None` if this node is not a directory.
+    pub async fn num_subdirs(&self) -> Option<usize> {
+        let inner = self.inner.upgrade()?;
+        match inner.kind {
+            NodeKind::Dir(ref dir) => {
+                let dir = dir.lock().await;
+                let num_subdirs = dir
+                    .children
+                    .values()
+                    .filter_map(Weak::upgrade)
+                    .filter(|child| match child.kind {
+                        NodeKind::Dir(..) => true,
+                        NodeKind::File => false,
+                    })
+                    .count();
+                Some(num_subdirs)
+            }
+            NodeKind::File => None,
+        }
+    }
+
+    /// Remove a child node from this directory.
+    ///
+    /// The inode itself remains in the table, so the opened handles stay valid.
+    pub async fn remove_child(&self, name: &OsStr) -> Result<(), Errno> {
+        let parent = self.inner.upgrade().expect("the node is died");
+        match parent.kind {
+            NodeKind::Dir(ref dir) => {
+                let mut dir = dir.lock().await;
+                match dir.children.shift_remove(name) {
+                    Some(..) => Ok(()),
+                    None => Err(Errno::ENOENT),
+                }
+            }
+            _ => Err(Errno::ENOTDIR),
         }
     }
 
@@ -232,18 +534,43 @@ impl Node {
         match self.inner.upgrade() {
             Some(node) => match node.kind {
                 NodeKind::Dir(ref dir) => dir.lock().await.reply_readdir(cx, op).await,
-                _ => cx.reply_err(libc::ENOTDIR).await,
+                _ => cx.reply_err(Errno::ENOTDIR.raw()).await,
             },
-            None => cx.reply_err(libc::ENOENT).await,
+            None => cx.reply_err(Errno::ENOENT.raw()).await,
         }
     }
 }
 
 impl DirNode {
-    fn entries<'a>(&'a self) -> impl Iterator<Item = &'a DirEntry> + 'a {
-        self.dirents
+    /// Serialize the entries starting at the specified offset.
+    ///
+    /// The offset of an entry is its position in the directory, where
+    /// `.` and `..` come first.
+    fn entries(&self, offset: usize) -> impl Iterator<Item = DirEntry> + '_ {
+        let dots = vec![(".", self.ino), ("..", self.parent)];
+        let dots = dots
+            .into_iter()
+            .enumerate()
+            .skip(offset)
+            .map(|(i, (name, ino))| DirEntry::dir(name, ino, (i + 1) as u64));
+        let children = self
+            .children
             .iter()
-            .chain(self.children.values().map(|&(_, ref entry)| entry))
+            .enumerate()
+            .skip(offset.saturating_sub(2))
+            .map(|(i, (name, node))| {
+                let ino = node.upgrade().map_or(0, |node| node.nodeid);
+                DirEntry::new(name, ino, (i + 3) as u64)
+            });
+        dots.chain(children)
+    }
+
+    /// Return the number of bytes used to store the entries.
+    fn entries_bytes(&self) -> usize {
+        self.children
+            .keys()
+            .map(|name| name.len() + std::mem::size_of::<Weak<NodeInner>>())
+            .sum()
     }
 
     async fn reply_readdir<W: ?Sized>(
@@ -254,19 +581,85 @@ impl DirNode {
     where
         W: AsyncWrite + Unpin,
     {
-        let offset = op.offset() as usize;
-        let mut total_len = 0;
-        let bufsize = op.size() as usize;
-
-        let entries: Vec<&[u8]> = self
-            .entries()
-            .skip(offset)
-            .map(|entry| entry.as_ref())
-            .take_while(|entry| {
-                total_len += entry.len();
-                total_len <= bufsize
-            })
-            .collect();
+        let entries = readdir_window(self.entries(op.offset() as usize), op.size() as usize);
+        let entries: Vec<&[u8]> = entries.iter().map(|entry| entry.as_ref()).collect();
         op.reply_vectored(cx, &entries[..]).await
     }
 }
+
+/// Take the leading entries fitting in the reply buffer of `bufsize` bytes.
+///
+/// An entry exactly filling the rest of the buffer is included, and the
+/// listing stops at the first one that does not fit, so that it is
+/// resumed from that entry by the next request.
+fn readdir_window<I>(entries: I, bufsize: usize) -> Vec<I::Item>
+where
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    let mut total_len = 0;
+    entries
+        .into_iter()
+        .take_while(|entry| {
+            total_len += entry.as_ref().len();
+            total_len <= bufsize
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(lens: &[usize], bufsize: usize) -> Vec<usize> {
+        let entries = lens.iter().map(|&len| vec![0u8; len]);
+        readdir_window(entries, bufsize)
+            .iter()
+            .map(Vec::len)
+            .collect()
+    }
+
+    #[test]
+    fn test_readdir_window() {
+        // exact fit
+        assert_eq!(window(&[32, 32, 40], 104), vec![32, 32, 40]);
+        assert_eq!(window(&[32, 32, 40], 64), vec![32, 32]);
+        // one byte short
+        assert_eq!(window(&[32, 32, 40], 103), vec![32, 32]);
+        assert_eq!(window(&[32, 32, 40], 63), vec![32]);
+        // empty budget
+        assert_eq!(window(&[32, 32, 40], 0), Vec::<usize>::new());
+        assert_eq!(window(&[], 4096), Vec::<usize>::new());
+        // a smaller entry after the one not fitting is not skipped to
+        assert_eq!(window(&[32, 80, 32], 64), vec![32]);
+    }
+
+    #[test]
+    fn test_readdir_window_resumes_at_the_offset() {
+        let dir = DirNode {
+            ino: 2,
+            parent: 1,
+            children: ["a", "b", "c"]
+                .iter()
+                .map(|name| (OsString::from(name), Weak::new()))
+                .collect(),
+        };
+        let len = |offset| dir.entries(offset).next().unwrap().as_ref().len();
+        let offsets = |offset, bufsize| -> Vec<u64> {
+            readdir_window(dir.entries(offset), bufsize)
+                .iter()
+                .map(|entry| entry.offset())
+                .collect()
+        };
+
+        assert_eq!(dir.entries(0).count(), 5);
+        // The first request stops before the entry not fitting...
+        let bufsize = len(0) + len(1) + len(2);
+        assert_eq!(offsets(0, bufsize), vec![1, 2, 3]);
+        assert_eq!(offsets(0, bufsize - 1), vec![1, 2]);
+        // ...and the next one resumes from it.
+        assert_eq!(offsets(2, 4096), vec![3, 4, 5]);
+        assert_eq!(offsets(3, len(3)), vec![4]);
+        assert_eq!(offsets(5, 4096), Vec::<u64>::new());
+    }
+}
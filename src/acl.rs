@@ -0,0 +1,118 @@
+//! Encoding of POSIX ACLs in the format of `system.posix_acl_access`.
+//!
+//! See `linux/posix_acl_xattr.h` for the layout.
+
+use std::convert::TryInto;
+
+pub const POSIX_ACL_ACCESS: &str = "system.posix_acl_access";
+
+const POSIX_ACL_XATTR_VERSION: u32 = 0x0002;
+
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+const HEADER_LEN: usize = 4;
+const ENTRY_LEN: usize = 8;
+
+/// Encode the minimal ACL equivalent to the permission bits of `mode`.
+pub fn from_mode(mode: u32) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_LEN + 3 * ENTRY_LEN);
+    buf.extend_from_slice(&POSIX_ACL_XATTR_VERSION.to_le_bytes());
+    for &(tag, shift) in &[(ACL_USER_OBJ, 6), (ACL_GROUP_OBJ, 3), (ACL_OTHER, 0)] {
+        buf.extend_from_slice(&tag.to_le_bytes());
+        buf.extend_from_slice(&(((mode >> shift) & 0o7) as u16).to_le_bytes());
+        buf.extend_from_slice(&ACL_UNDEFINED_ID.to_le_bytes());
+    }
+    buf
+}
+
+/// Compute the mode whose permission bits reflect the encoded ACL.
+///
+/// As in the kernel, the group class bits are taken from the mask entry
+/// if it is present. Returns `None` if the ACL is malformed.
+pub fn to_mode(acl: &[u8], mode: u32) -> Option<u32> {
+    if acl.len() < HEADER_LEN || !(acl.len() - HEADER_LEN).is_multiple_of(ENTRY_LEN) {
+        return None;
+    }
+    if u32::from_le_bytes(acl[..HEADER_LEN].try_into().ok()?) != POSIX_ACL_XATTR_VERSION {
+        return None;
+    }
+
+    let mut user = None;
+    let mut group = None;
+    let mut mask = None;
+    let mut other = None;
+    for entry in acl[HEADER_LEN..].chunks(ENTRY_LEN) {
+        let tag = u16::from_le_bytes(entry[0..2].try_into().ok()?);
+        let perm = u32::from(u16::from_le_bytes(entry[2..4].try_into().ok()?) & 0o7);
+        match tag {
+            ACL_USER_OBJ => user = Some(perm),
+            ACL_GROUP_OBJ => group = Some(perm),
+            ACL_MASK => mask = Some(perm),
+            ACL_OTHER => other = Some(perm),
+            _ => (),
+        }
+    }
+
+    let group = mask.or(group)?;
+    Some((mode & !0o777) | (user? << 6) | (group << 3) | other?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(tag: u16, perm: u16) -> Vec<u8> {
+        let mut entry = tag.to_le_bytes().to_vec();
+        entry.extend_from_slice(&perm.to_le_bytes());
+        entry.extend_from_slice(&ACL_UNDEFINED_ID.to_le_bytes());
+        entry
+    }
+
+    #[test]
+    fn round_trip() {
+        for &mode in &[0o644, 0o755, 0o600, 0o000, 0o777, 0o4751] {
+            let acl = from_mode(mode);
+            assert_eq!(acl.len(), HEADER_LEN + 3 * ENTRY_LEN);
+            let mode = libc::S_IFREG | mode;
+            assert_eq!(to_mode(&acl, mode), Some(mode), "{:o}", mode);
+        }
+    }
+
+    #[test]
+    fn encoded_layout() {
+        let mut expected = POSIX_ACL_XATTR_VERSION.to_le_bytes().to_vec();
+        expected.extend(entry(ACL_USER_OBJ, 0o6));
+        expected.extend(entry(ACL_GROUP_OBJ, 0o4));
+        expected.extend(entry(ACL_OTHER, 0o0));
+        assert_eq!(from_mode(0o640), expected);
+    }
+
+    #[test]
+    fn mask_overrides_group() {
+        let mut acl = POSIX_ACL_XATTR_VERSION.to_le_bytes().to_vec();
+        acl.extend(entry(ACL_USER_OBJ, 0o7));
+        acl.extend(entry(ACL_GROUP_OBJ, 0o7));
+        acl.extend(entry(ACL_MASK, 0o5));
+        acl.extend(entry(ACL_OTHER, 0o1));
+        assert_eq!(to_mode(&acl, libc::S_IFREG), Some(libc::S_IFREG | 0o751));
+    }
+
+    #[test]
+    fn reject_malformed() {
+        let acl = from_mode(0o644);
+        // Truncated in the middle of an entry.
+        assert_eq!(to_mode(&acl[..acl.len() - 1], 0), None);
+        assert_eq!(to_mode(&acl[..2], 0), None);
+        // Unknown version.
+        let mut unknown = acl.clone();
+        unknown[0] = 0x01;
+        assert_eq!(to_mode(&unknown, 0), None);
+        // Missing the entry of the others.
+        assert_eq!(to_mode(&acl[..HEADER_LEN + 2 * ENTRY_LEN], 0), None);
+    }
+}
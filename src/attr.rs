@@ -0,0 +1,147 @@
+//! Construction of the file attributes.
+
+use chrono::{DateTime, Utc};
+use gist_client::GistFile;
+use polyfuse::FileAttr;
+
+/// The owner of the nodes.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct OwnerIds {
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+}
+
+impl OwnerIds {
+    /// Return the IDs of the user running the process.
+    pub(crate) fn current() -> Self {
+        Self {
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        }
+    }
+}
+
+impl Default for OwnerIds {
+    fn default() -> Self {
+        Self::current()
+    }
+}
+
+/// Create the attribute of a node with the specified mode, including the file type.
+pub(crate) fn new_attr(mode: u32, nlink: u32, ids: OwnerIds) -> FileAttr {
+    let mut attr = FileAttr::default();
+    attr.set_mode(mode);
+    attr.set_nlink(nlink);
+    attr.set_uid(ids.uid);
+    attr.set_gid(ids.gid);
+    attr
+}
+
+/// Create the attribute of a Gist file with the specified permission bits.
+///
+/// The timestamps are taken from the last update of the Gist, since
+/// the API does not report them per file.
+pub(crate) fn attr_from_gist_file(
+    gist_file: &GistFile,
+    perm: u32,
+    updated_at: DateTime<Utc>,
+    ids: OwnerIds,
+) -> FileAttr {
+    let mut attr = new_attr(libc::S_IFREG | perm, 1, ids);
+    attr.set_size(gist_file.size);
+    set_times(&mut attr, updated_at);
+    attr
+}
+
+/// Initialize all the timestamps, e.g. with the last update of the Gist.
+///
+/// Afterwards, the writes bump the modification and the change times by
+/// `touch_modified`, and the other changes of the inode bump the change
+/// time by `touch_changed`. The attribute of FUSE has no birth time, so
+/// the creation of the Gist is not reported.
+pub(crate) fn set_times(attr: &mut FileAttr, time: DateTime<Utc>) {
+    let (sec, nsec) = to_timespec(time);
+    attr.set_atime(sec, nsec);
+    attr.set_mtime(sec, nsec);
+    attr.set_ctime(sec, nsec);
+}
+
+/// Bump the modification and the change times, on a write.
+pub(crate) fn touch_modified(attr: &mut FileAttr, time: DateTime<Utc>) {
+    let (sec, nsec) = to_timespec(time);
+    attr.set_mtime(sec, nsec);
+    attr.set_ctime(sec, nsec);
+}
+
+/// Bump the change time, on a change of the metadata, e.g. chmod(2) or rename(2).
+pub(crate) fn touch_changed(attr: &mut FileAttr, time: DateTime<Utc>) {
+    let (sec, nsec) = to_timespec(time);
+    attr.set_ctime(sec, nsec);
+}
+
+/// Convert the time into the seconds and the nanoseconds since the epoch.
+///
+/// The times before the epoch are clamped to the epoch, and the leap
+/// seconds are folded into the last nanosecond of the preceding second.
+pub(crate) fn to_timespec(time: DateTime<Utc>) -> (u64, u32) {
+    match time.timestamp() {
+        sec if sec < 0 => (0, 0),
+        sec => (sec as u64, time.timestamp_subsec_nanos().min(999_999_999)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, TimeZone as _};
+
+    #[test]
+    fn pre_epoch_time_is_clamped() {
+        let time = Utc.with_ymd_and_hms(1969, 12, 31, 23, 59, 59).unwrap();
+        assert_eq!(to_timespec(time), (0, 0));
+        let time = Utc.with_ymd_and_hms(1900, 1, 1, 0, 0, 0).unwrap();
+        assert_eq!(to_timespec(time), (0, 0));
+    }
+
+    #[test]
+    fn epoch_is_zero() {
+        let time = Utc.timestamp_opt(0, 0).unwrap();
+        assert_eq!(to_timespec(time), (0, 0));
+    }
+
+    #[test]
+    fn far_future_time_is_kept() {
+        let time = Utc.with_ymd_and_hms(9999, 12, 31, 23, 59, 59).unwrap();
+        assert_eq!(to_timespec(time), (253_402_300_799, 0));
+        let (sec, nsec) = to_timespec(DateTime::<Utc>::MAX_UTC);
+        assert_eq!(sec, DateTime::<Utc>::MAX_UTC.timestamp() as u64);
+        assert_eq!(nsec, 999_999_999);
+    }
+
+    #[test]
+    fn leap_second_is_folded() {
+        let time = NaiveDate::from_ymd_opt(2016, 12, 31)
+            .unwrap()
+            .and_hms_nano_opt(23, 59, 59, 1_500_000_000)
+            .unwrap();
+        let time = Utc.from_utc_datetime(&time);
+        assert_eq!(to_timespec(time), (1_483_228_799, 999_999_999));
+    }
+
+    #[test]
+    fn set_times_sets_all_the_timestamps() {
+        let mut attr = FileAttr::default();
+        let time = Utc.timestamp_opt(1_500_000_000, 42).unwrap();
+        set_times(&mut attr, time);
+        assert_eq!(attr.atime(), (1_500_000_000, 42));
+        assert_eq!(attr.mtime(), (1_500_000_000, 42));
+        assert_eq!(attr.ctime(), (1_500_000_000, 42));
+
+        let later = Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+        touch_changed(&mut attr, later);
+        assert_eq!(attr.mtime(), (1_500_000_000, 42));
+        assert_eq!(attr.ctime(), (1_600_000_000, 0));
+        touch_modified(&mut attr, later);
+        assert_eq!(attr.mtime(), (1_600_000_000, 0));
+    }
+}
@@ -0,0 +1,46 @@
+//! Audit log of the write operations.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{io, path::Path};
+use tokio::{fs::File, io::AsyncWriteExt, sync::Mutex};
+
+/// A write operation recorded in the audit log.
+#[derive(Debug, Serialize)]
+pub struct WriteRecord {
+    pub timestamp: DateTime<Utc>,
+    pub pid: u32,
+    pub ino: u64,
+    pub offset: u64,
+    pub size: u32,
+}
+
+/// A log file to which the records are appended as JSON lines.
+#[derive(Debug)]
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    /// Open the log file for appending, creating it if it does not exist.
+    pub async fn open(path: &Path) -> io::Result<Self> {
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub async fn record(&self, record: &WriteRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        // Each record is written at once, so the lines are never interleaved.
+        let mut file = self.file.lock().await;
+        file.write_all(&line[..]).await?;
+        file.flush().await
+    }
+}
@@ -0,0 +1,80 @@
+//! Local backups of the content written through the mount.
+
+use chrono::{NaiveDateTime, Utc};
+use std::{io, path::PathBuf};
+
+/// The suffix appended to the filename of a backup, sortable in time order.
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%S%6fZ";
+
+/// A directory keeping the recent versions of the files written locally,
+/// as `<dir>/<gist_id>/<filename>.<timestamp>`.
+#[derive(Debug)]
+pub struct Backups {
+    dir: PathBuf,
+    versions: usize,
+}
+
+impl Backups {
+    /// Keep at most `versions` backups per file, or all of them if zero.
+    pub fn new(dir: PathBuf, gist_id: &str, versions: usize) -> Self {
+        Self {
+            dir: dir.join(gist_id),
+            versions,
+        }
+    }
+
+    /// Save the content as a new version of the file and remove the old versions.
+    pub async fn save(&self, filename: &str, content: &[u8]) -> io::Result<PathBuf> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+
+        let timestamp = Utc::now().format(TIMESTAMP_FORMAT);
+        let path = self.dir.join(format!("{}.{}", filename, timestamp));
+
+        // The partial backup is never seen under the final name.
+        let temp = self.dir.join(format!(".{}.{}.tmp", filename, timestamp));
+        tokio::fs::write(&temp, content).await?;
+        tokio::fs::rename(&temp, &path).await?;
+
+        if self.versions > 0 {
+            self.rotate(filename).await?;
+        }
+        Ok(path)
+    }
+
+    async fn rotate(&self, filename: &str) -> io::Result<()> {
+        let mut versions = self.versions_of(filename).await?;
+        if versions.len() <= self.versions {
+            return Ok(());
+        }
+        versions.sort();
+        let stale = versions.len() - self.versions;
+        for path in versions.drain(..stale) {
+            tracing::debug!("remove the old backup: {:?}", path);
+            tokio::fs::remove_file(&path).await?;
+        }
+        Ok(())
+    }
+
+    /// List the backups of the file, ignoring the ones of the other
+    /// files whose names share the prefix.
+    async fn versions_of(&self, filename: &str) -> io::Result<Vec<PathBuf>> {
+        let prefix = format!("{}.", filename);
+        let mut versions = vec![];
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let is_version = name
+                .to_str()
+                .and_then(|name| name.strip_prefix(&*prefix))
+                .is_some_and(is_timestamp);
+            if is_version {
+                versions.push(entry.path());
+            }
+        }
+        Ok(versions)
+    }
+}
+
+fn is_timestamp(s: &str) -> bool {
+    NaiveDateTime::parse_from_str(s, TIMESTAMP_FORMAT).is_ok()
+}
@@ -0,0 +1,182 @@
+//! The source of time for the timers, replaceable to drive them without
+//! waiting.
+
+use futures::{
+    channel::oneshot,
+    future::{BoxFuture, FutureExt},
+};
+use std::{
+    fmt,
+    ops::Deref,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// A clock read by the debounce timers, the dirty age check and the rate limits.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Return a future completed once the duration has elapsed on this clock.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// The monotonic clock of the system, with the timers of tokio.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        tokio::time::delay_for(duration).boxed()
+    }
+}
+
+/// A clock which stands still until advanced by `advance`, completing the
+/// sleeps whose deadlines have passed.
+#[derive(Debug)]
+pub struct MockClock {
+    start: Instant,
+    state: Mutex<MockState>,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    elapsed: Duration,
+    sleepers: Vec<(Duration, oneshot::Sender<()>)>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            state: Mutex::default(),
+        }
+    }
+
+    /// Move the clock forward, waking up the sleeps which have expired.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.elapsed += duration;
+        let elapsed = state.elapsed;
+        let (expired, pending) = state
+            .sleepers
+            .drain(..)
+            .partition(|&(deadline, _)| deadline <= elapsed);
+        state.sleepers = pending;
+        drop(state);
+
+        for (_, sleeper) in expired {
+            let _ = sleeper.send(());
+        }
+    }
+
+    /// Return the number of the sleeps not expired yet.
+    pub fn sleepers(&self) -> usize {
+        self.state.lock().unwrap().sleepers.len()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + self.state.lock().unwrap().elapsed
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let mut state = self.state.lock().unwrap();
+        if duration == Duration::from_secs(0) {
+            return futures::future::ready(()).boxed();
+        }
+        let (tx, rx) = oneshot::channel();
+        let deadline = state.elapsed + duration;
+        state.sleepers.push((deadline, tx));
+        // A dropped clock never advances, so the sleep is just completed.
+        rx.map(drop).boxed()
+    }
+}
+
+/// The clock shared by the filesystem and the files, the system one by default.
+#[derive(Debug, Clone)]
+pub(crate) struct SharedClock(Arc<dyn Clock>);
+
+impl SharedClock {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> Self {
+        Self(clock)
+    }
+}
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        Self(Arc::new(SystemClock))
+    }
+}
+
+impl Deref for SharedClock {
+    type Target = dyn Clock;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_clock_stands_still() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(clock.now(), start + Duration::from_secs(3));
+    }
+
+    #[test]
+    fn mock_clock_completes_expired_sleeps() {
+        let clock = MockClock::new();
+        let mut short = clock.sleep(Duration::from_secs(1));
+        let mut long = clock.sleep(Duration::from_secs(5));
+        assert_eq!(clock.sleepers(), 2);
+        assert!((&mut short).now_or_never().is_none());
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(clock.sleepers(), 1);
+        assert!((&mut short).now_or_never().is_some());
+        assert!((&mut long).now_or_never().is_none());
+
+        // The deadline is relative to the time the sleep started.
+        let mut later = clock.sleep(Duration::from_secs(1));
+        clock.advance(Duration::from_secs(3));
+        assert!((&mut long).now_or_never().is_none());
+        assert!((&mut later).now_or_never().is_some());
+
+        clock.advance(Duration::from_secs(1));
+        assert!(long.now_or_never().is_some());
+        assert_eq!(clock.sleepers(), 0);
+    }
+
+    #[test]
+    fn mock_clock_completes_zero_sleeps() {
+        let clock = MockClock::new();
+        assert!(clock.sleep(Duration::from_secs(0)).now_or_never().is_some());
+        assert_eq!(clock.sleepers(), 0);
+    }
+
+    #[test]
+    fn mock_clock_completes_sleeps_once_dropped() {
+        let clock = MockClock::new();
+        let sleep = clock.sleep(Duration::from_secs(1));
+        drop(clock);
+        assert!(sleep.now_or_never().is_some());
+    }
+}
@@ -0,0 +1,365 @@
+//! Resolution of the conflicts with the edits made by another writer.
+
+use std::{
+    fs, io,
+    os::unix::fs::{DirBuilderExt as _, OpenOptionsExt as _},
+    path::PathBuf,
+    process::Stdio,
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use tokio::process::Command;
+
+/// How the local changes are reconciled when the Gist has been edited
+/// by another writer since the last refresh.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum ConflictStrategy {
+    /// Report the conflict as a failed upload.
+    #[default]
+    Fail,
+
+    /// Merge the local and the remote changes.
+    ///
+    /// If the changes overlap, the file takes the remote content and
+    /// `.conflict.<filename>` is created on the mount with the conflict
+    /// markers. Removing it clears the conflict.
+    ThreeWayMerge,
+
+    /// Overwrite the remote changes with the local content.
+    PreferLocal,
+
+    /// Discard the local changes.
+    PreferRemote,
+}
+
+impl FromStr for ConflictStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(ConflictStrategy::Fail),
+            "merge" => Ok(ConflictStrategy::ThreeWayMerge),
+            "local" => Ok(ConflictStrategy::PreferLocal),
+            "remote" => Ok(ConflictStrategy::PreferRemote),
+            s => anyhow::bail!("unknown conflict strategy: {:?}", s),
+        }
+    }
+}
+
+/// The outcome of the resolution of a file.
+#[derive(Debug)]
+pub(crate) enum Resolution {
+    /// Upload the content.
+    Upload(String),
+
+    /// Take the remote content and discard the local changes.
+    Discard,
+
+    /// Take the remote content and keep the content with the conflict markers aside.
+    Conflicted(String),
+}
+
+/// Resolve the local content against the remote one, both derived from `base`.
+///
+/// `remote` is `None` if the file has been deleted from the Gist,
+/// in which case the local content is uploaded as a new file.
+pub(crate) fn resolve(
+    strategy: ConflictStrategy,
+    base: &str,
+    local: &str,
+    remote: Option<&str>,
+) -> Resolution {
+    let remote = match remote {
+        Some(remote) => remote,
+        None => return Resolution::Upload(local.to_owned()),
+    };
+    match strategy {
+        ConflictStrategy::Fail | ConflictStrategy::PreferLocal => {
+            Resolution::Upload(local.to_owned())
+        }
+        ConflictStrategy::PreferRemote => Resolution::Discard,
+        ConflictStrategy::ThreeWayMerge => match diffy::merge(base, local, remote) {
+            Ok(merged) => Resolution::Upload(merged),
+            Err(conflicted) => Resolution::Conflicted(conflicted),
+        },
+    }
+}
+
+/// An external command resolving the conflicts left by the three-way merge,
+/// e.g. `meld %base %local %remote --output %merged`.
+///
+/// The arguments are split on the whitespace without any quoting, and
+/// `%base`, `%local`, `%remote` and `%merged` in them are replaced with
+/// the paths of the temporary files. The content written to `%merged` is
+/// adopted if the command exits successfully.
+#[derive(Debug, Clone)]
+pub struct ConflictCommand {
+    program: String,
+    args: Vec<String>,
+}
+
+impl FromStr for ConflictCommand {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace().map(str::to_owned);
+        let program = words
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("the conflict command is empty"))?;
+        Ok(Self {
+            program,
+            args: words.collect(),
+        })
+    }
+}
+
+/// The paths substituted into the arguments of the conflict command.
+#[derive(Debug)]
+struct MergePaths {
+    base: PathBuf,
+    local: PathBuf,
+    remote: PathBuf,
+    merged: PathBuf,
+}
+
+impl MergePaths {
+    fn substitute(&self, arg: &str) -> String {
+        // `%merged` is replaced first, since no other placeholder is its prefix.
+        arg.replace("%merged", &self.merged.to_string_lossy())
+            .replace("%base", &self.base.to_string_lossy())
+            .replace("%local", &self.local.to_string_lossy())
+            .replace("%remote", &self.remote.to_string_lossy())
+    }
+}
+
+impl ConflictCommand {
+    /// Return the arguments with the placeholders replaced.
+    fn args(&self, paths: &MergePaths) -> Vec<String> {
+        self.args.iter().map(|arg| paths.substitute(arg)).collect()
+    }
+
+    /// Run the command on the three versions of the file, returning the
+    /// merged content, or `None` if the command has failed or has written
+    /// nothing to `%merged`.
+    pub(crate) async fn run(
+        &self,
+        filename: &str,
+        base: &[u8],
+        local: &[u8],
+        remote: &[u8],
+        timeout: Duration,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let dir = MergeDir::create()?;
+        let paths = MergePaths {
+            base: dir.write("base", filename, base)?,
+            local: dir.write("local", filename, local)?,
+            remote: dir.write("remote", filename, remote)?,
+            merged: dir.path("merged", filename),
+        };
+
+        let child = Command::new(&self.program)
+            .args(self.args(&paths))
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        let status = match tokio::time::timeout(timeout, child).await {
+            Ok(status) => status?,
+            Err(..) => {
+                tracing::warn!(
+                    "the conflict command has not exited in {:?}: filename={:?}",
+                    timeout,
+                    filename
+                );
+                return Ok(None);
+            }
+        };
+        if !status.success() {
+            tracing::warn!(
+                "the conflict command exited with {}: filename={:?}",
+                status,
+                filename
+            );
+            return Ok(None);
+        }
+
+        match fs::read(&paths.merged) {
+            Ok(merged) => Ok(Some(merged)),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                tracing::warn!(
+                    "the conflict command wrote no merged content: filename={:?}",
+                    filename
+                );
+                Ok(None)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// A directory private to the user holding the versions of a file, removed on drop.
+///
+/// The contents may be secret, so the directory and the files are
+/// accessible only by the owner.
+#[derive(Debug)]
+struct MergeDir(PathBuf);
+
+impl MergeDir {
+    fn create() -> io::Result<Self> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "gist-fs-merge.{}.{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        // A directory left by someone else is never reused.
+        fs::DirBuilder::new().mode(0o700).create(&path)?;
+        Ok(Self(path))
+    }
+
+    /// Return the path of a version, keeping the filename for the tools
+    /// which choose the syntax by the extension.
+    fn path(&self, version: &str, filename: &str) -> PathBuf {
+        self.0
+            .join(format!("{}.{}", version, filename.replace('/', "_")))
+    }
+
+    fn write(&self, version: &str, filename: &str, content: &[u8]) -> io::Result<PathBuf> {
+        use std::io::Write as _;
+
+        let path = self.path(version, filename);
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?
+            .write_all(content)?;
+        Ok(path)
+    }
+}
+
+impl Drop for MergeDir {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.0) {
+            tracing::warn!("failed to remove {}: {}", self.0.display(), err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(command: &str, local: &str, remote: &str) -> Option<Vec<u8>> {
+        let command: ConflictCommand = command.parse().unwrap();
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime
+            .block_on(command.run(
+                "dir/a.txt",
+                b"base\n",
+                local.as_bytes(),
+                remote.as_bytes(),
+                Duration::from_secs(10),
+            ))
+            .unwrap()
+    }
+
+    #[test]
+    fn strategies() {
+        assert_eq!(
+            "fail".parse::<ConflictStrategy>().unwrap(),
+            ConflictStrategy::Fail
+        );
+        assert_eq!(
+            "merge".parse::<ConflictStrategy>().unwrap(),
+            ConflictStrategy::ThreeWayMerge
+        );
+        assert!("Merge".parse::<ConflictStrategy>().is_err());
+
+        let resolve = |strategy| resolve(strategy, "base\n", "local\n", Some("remote\n"));
+        assert!(
+            matches!(resolve(ConflictStrategy::Fail), Resolution::Upload(ref s) if s == "local\n")
+        );
+        assert!(matches!(
+            resolve(ConflictStrategy::PreferLocal),
+            Resolution::Upload(ref s) if s == "local\n"
+        ));
+        assert!(matches!(
+            resolve(ConflictStrategy::PreferRemote),
+            Resolution::Discard
+        ));
+    }
+
+    #[test]
+    fn deleted_remote_is_recreated() {
+        let resolution = resolve(ConflictStrategy::PreferRemote, "base\n", "local\n", None);
+        assert!(matches!(resolution, Resolution::Upload(ref s) if s == "local\n"));
+    }
+
+    #[test]
+    fn merge_of_the_separate_changes() {
+        let base = "a\nb\nc\nd\ne\n";
+        let local = "A\nb\nc\nd\ne\n";
+        let remote = "a\nb\nc\nd\nE\n";
+        match resolve(ConflictStrategy::ThreeWayMerge, base, local, Some(remote)) {
+            Resolution::Upload(merged) => assert_eq!(merged, "A\nb\nc\nd\nE\n"),
+            resolution => panic!("unexpected resolution: {:?}", resolution),
+        }
+    }
+
+    #[test]
+    fn merge_of_the_overlapping_changes() {
+        match resolve(ConflictStrategy::ThreeWayMerge, "a\n", "b\n", Some("c\n")) {
+            Resolution::Conflicted(content) => {
+                assert!(content.contains("<<<<<<<"), "{}", content);
+                assert!(content.contains("b\n") && content.contains("c\n"));
+                assert!(content.contains(">>>>>>>"), "{}", content);
+            }
+            resolution => panic!("unexpected resolution: {:?}", resolution),
+        }
+    }
+
+    #[test]
+    fn merge_paths_keep_the_filename() {
+        let dir = MergeDir::create().unwrap();
+        let path = dir.path("local", "dir/a.txt");
+        assert_eq!(path.file_name().unwrap(), "local.dir_a.txt");
+        assert_eq!(path.parent(), Some(&*dir.0));
+
+        let paths = MergePaths {
+            base: "/b".into(),
+            local: "/l".into(),
+            remote: "/r".into(),
+            merged: "/m".into(),
+        };
+        assert_eq!(paths.substitute("%base,%local,%remote"), "/b,/l,/r");
+        assert_eq!(paths.substitute("--output=%merged"), "--output=/m");
+
+        let root = dir.0.clone();
+        drop(dir);
+        assert!(!root.exists(), "the directory is left on drop");
+    }
+
+    #[test]
+    fn command_parses_the_words() {
+        let command: ConflictCommand = " meld  %base %local\t%remote ".parse().unwrap();
+        assert_eq!(command.program, "meld");
+        assert_eq!(command.args, ["%base", "%local", "%remote"]);
+        assert!("  ".parse::<ConflictCommand>().is_err());
+    }
+
+    #[test]
+    fn command_output_is_adopted() {
+        assert_eq!(
+            run("cp %remote %merged", "local\n", "remote\n"),
+            Some(b"remote\n".to_vec())
+        );
+    }
+
+    #[test]
+    fn command_failure_is_ignored() {
+        assert_eq!(run("false", "local\n", "remote\n"), None);
+        // The command has succeeded without writing the merged content.
+        assert_eq!(run("true", "local\n", "remote\n"), None);
+    }
+}
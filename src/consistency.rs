@@ -0,0 +1,28 @@
+//! Selection of how fresh the content is when a file is opened.
+
+use std::str::FromStr;
+
+/// How the content of the clean files is kept up to date.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Consistency {
+    /// Serve the content cached since the last refresh.
+    #[default]
+    Cached,
+
+    /// Revalidate the content with a conditional request on every open
+    /// of a clean file, so the changes by other machines are visible
+    /// immediately.
+    Strict,
+}
+
+impl FromStr for Consistency {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cached" => Ok(Consistency::Cached),
+            "strict" => Ok(Consistency::Strict),
+            s => anyhow::bail!("unknown consistency mode: {:?}", s),
+        }
+    }
+}
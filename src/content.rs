@@ -0,0 +1,102 @@
+//! The content of the files kept in memory.
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use std::{
+    io::{Read, Write},
+    sync::{Arc, Mutex},
+};
+
+/// The content of a file, which is compressed while it is large and clean.
+#[derive(Debug, Clone)]
+pub(crate) enum Content {
+    Plain(Arc<Vec<u8>>),
+    Compressed(Arc<Compressed>),
+}
+
+/// The content compressed with gzip.
+#[derive(Debug)]
+pub(crate) struct Compressed {
+    data: Vec<u8>,
+    len: usize,
+
+    /// The decompressed content kept while the file is opened, so that
+    /// the sequential reads do not decompress the whole content every time.
+    window: Mutex<Option<Arc<Vec<u8>>>>,
+}
+
+impl Content {
+    /// Compress the content, which blocks the current thread.
+    pub(crate) fn compress(content: &[u8]) -> Self {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        encoder
+            .write_all(content)
+            .expect("writing to a Vec never fails");
+        let data = encoder.finish().expect("writing to a Vec never fails");
+        Content::Compressed(Arc::new(Compressed {
+            data,
+            len: content.len(),
+            window: Mutex::new(None),
+        }))
+    }
+
+    /// Return the length of the plain content.
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Content::Plain(content) => content.len(),
+            Content::Compressed(compressed) => compressed.len,
+        }
+    }
+
+    /// Return the plain content, decompressing it if needed.
+    pub(crate) fn bytes(&self) -> Arc<Vec<u8>> {
+        match self {
+            Content::Plain(content) => content.clone(),
+            Content::Compressed(compressed) => {
+                let window = compressed.window.lock().unwrap().clone();
+                window.unwrap_or_else(|| Arc::new(compressed.decompress()))
+            }
+        }
+    }
+
+    /// Return the plain content, keeping the decompressed one for the
+    /// subsequent reads until `close_window` is called.
+    pub(crate) fn bytes_for_read(&self) -> Arc<Vec<u8>> {
+        match self {
+            Content::Plain(content) => content.clone(),
+            Content::Compressed(compressed) => compressed
+                .window
+                .lock()
+                .unwrap()
+                .get_or_insert_with(|| Arc::new(compressed.decompress()))
+                .clone(),
+        }
+    }
+
+    /// Drop the decompressed content kept for the reads.
+    pub(crate) fn close_window(&self) {
+        if let Content::Compressed(compressed) = self {
+            compressed.window.lock().unwrap().take();
+        }
+    }
+
+    /// Return the plain content to be modified, decompressing it if needed.
+    pub(crate) fn make_mut(&mut self) -> &mut Vec<u8> {
+        if let Content::Compressed(..) = self {
+            *self = Content::Plain(self.bytes());
+        }
+        match self {
+            Content::Plain(content) => Arc::make_mut(content),
+            Content::Compressed(..) => unreachable!(),
+        }
+    }
+}
+
+impl Compressed {
+    fn decompress(&self) -> Vec<u8> {
+        let mut content = Vec::with_capacity(self.len);
+        GzDecoder::new(&self.data[..])
+            .read_to_end(&mut content)
+            .expect("the compressed content is corrupted");
+        content
+    }
+}
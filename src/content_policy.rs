@@ -0,0 +1,130 @@
+//! Policies on the content of the files uploaded to the Gist.
+
+use std::{error, fmt};
+
+/// A rule the content of each file must satisfy to be uploaded.
+///
+/// The content is checked as a whole on upload rather than on every
+/// write, since a write may leave it incomplete, e.g. in the middle of
+/// a UTF-8 sequence.
+pub trait ContentPolicy: fmt::Debug + Send + Sync {
+    fn check(&self, filename: &str, content: &[u8]) -> Result<(), PolicyViolation>;
+}
+
+/// The content violating a policy, reported to `fsync(2)` with the error code.
+#[derive(Debug, Clone)]
+pub struct PolicyViolation {
+    pub message: String,
+    pub error_code: i32,
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl error::Error for PolicyViolation {}
+
+impl PolicyViolation {
+    fn new(error_code: i32, filename: &str, reason: impl fmt::Display) -> Self {
+        Self {
+            message: format!("{:?} violates the content policy: {}", filename, reason),
+            error_code,
+        }
+    }
+}
+
+impl Default for Box<dyn ContentPolicy> {
+    fn default() -> Self {
+        Box::new(CompositePolicy::default())
+    }
+}
+
+/// Limit the size of the files in bytes.
+#[derive(Debug)]
+pub struct MaxFileSizePolicy(pub u64);
+
+impl ContentPolicy for MaxFileSizePolicy {
+    fn check(&self, filename: &str, content: &[u8]) -> Result<(), PolicyViolation> {
+        if content.len() as u64 > self.0 {
+            return Err(PolicyViolation::new(
+                libc::EFBIG,
+                filename,
+                format_args!("larger than {} bytes", self.0),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Reject the content containing the NUL bytes.
+#[derive(Debug, Default)]
+pub struct NoNulBytesPolicy;
+
+impl ContentPolicy for NoNulBytesPolicy {
+    fn check(&self, filename: &str, content: &[u8]) -> Result<(), PolicyViolation> {
+        if let Some(pos) = content.iter().position(|&b| b == 0) {
+            return Err(PolicyViolation::new(
+                libc::EINVAL,
+                filename,
+                format_args!("a NUL byte at offset {}", pos),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Reject the content which is not valid UTF-8.
+#[derive(Debug, Default)]
+pub struct Utf8OnlyPolicy;
+
+impl ContentPolicy for Utf8OnlyPolicy {
+    fn check(&self, filename: &str, content: &[u8]) -> Result<(), PolicyViolation> {
+        std::str::from_utf8(content)
+            .map(drop)
+            .map_err(|err| PolicyViolation::new(libc::EILSEQ, filename, err))
+    }
+}
+
+/// Limit the width of each line in characters, or in bytes if the line
+/// is not valid UTF-8.
+#[derive(Debug)]
+pub struct MaxLineWidthPolicy(pub usize);
+
+impl ContentPolicy for MaxLineWidthPolicy {
+    fn check(&self, filename: &str, content: &[u8]) -> Result<(), PolicyViolation> {
+        for (i, line) in content.split(|&b| b == b'\n').enumerate() {
+            let line = match line.split_last() {
+                Some((b'\r', line)) => line,
+                _ => line,
+            };
+            let width = match std::str::from_utf8(line) {
+                Ok(line) => line.chars().count(),
+                Err(..) => line.len(),
+            };
+            if width > self.0 {
+                return Err(PolicyViolation::new(
+                    libc::EINVAL,
+                    filename,
+                    format_args!("line {} is wider than {}", i + 1, self.0),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Apply the policies in order, failing with the first violation.
+///
+/// The empty composite permits any content.
+#[derive(Debug, Default)]
+pub struct CompositePolicy(pub Vec<Box<dyn ContentPolicy>>);
+
+impl ContentPolicy for CompositePolicy {
+    fn check(&self, filename: &str, content: &[u8]) -> Result<(), PolicyViolation> {
+        self.0
+            .iter()
+            .try_for_each(|policy| policy.check(filename, content))
+    }
+}
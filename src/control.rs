@@ -0,0 +1,288 @@
+//! Virtual control files exposed under `.gistfs`.
+
+use crate::{
+    attr::{self, OwnerIds},
+    state::Outcome,
+    timefmt::TimeFormat,
+};
+use chrono::{DateTime, Utc};
+use crossbeam::atomic::AtomicCell;
+use futures::lock::Mutex;
+use gist_client::ClientError;
+use node_table::{Errno, Node, NodeTable};
+use std::{collections::VecDeque, fmt};
+
+/// The name of the control directory placed at the root.
+pub const CONTROL_DIR: &str = ".gistfs";
+
+/// The name of the control directory used when the Gist contains
+/// a file named `CONTROL_DIR`.
+pub const CONTROL_DIR_FALLBACK: &str = ".gistfs-ctl";
+
+/// The maximum number of entries retained in the error log.
+const ERROR_LOG_CAPACITY: usize = 100;
+
+/// The number of consecutive refreshes failing with `NotFound` or
+/// `Unauthorized` after which the mount is considered orphaned.
+const ORPHAN_THRESHOLD: usize = 3;
+
+/// The nodes of the control directory and its files.
+#[derive(Debug)]
+pub struct ControlDir {
+    pub dir: Node,
+    pub errors: Node,
+    pub stats: Node,
+    pub inflight: Node,
+    pub info: Node,
+
+    /// The versions kept by the undo history, if enabled.
+    pub undo: Node,
+    relocated: AtomicCell<bool>,
+}
+
+impl ControlDir {
+    pub async fn new(node_table: &NodeTable, owner: OwnerIds) -> Result<Self, Errno> {
+        let dir = node_table
+            .root()
+            .new_child(
+                CONTROL_DIR.into(),
+                attr::new_attr(libc::S_IFDIR | 0o755, 2, owner),
+            )
+            .await?;
+        let errors = dir
+            .new_child(
+                "errors".into(),
+                attr::new_attr(libc::S_IFREG | 0o644, 1, owner),
+            )
+            .await?;
+        let stats = dir
+            .new_child(
+                "stats".into(),
+                attr::new_attr(libc::S_IFREG | 0o444, 1, owner),
+            )
+            .await?;
+        let inflight = dir
+            .new_child(
+                "inflight".into(),
+                attr::new_attr(libc::S_IFREG | 0o444, 1, owner),
+            )
+            .await?;
+        let info = dir
+            .new_child(
+                "info".into(),
+                attr::new_attr(libc::S_IFREG | 0o444, 1, owner),
+            )
+            .await?;
+
+        let undo = dir
+            .new_child(
+                "undo".into(),
+                attr::new_attr(libc::S_IFDIR | 0o555, 2, owner),
+            )
+            .await?;
+
+        Ok(Self {
+            dir,
+            errors,
+            stats,
+            inflight,
+            info,
+            undo,
+            relocated: AtomicCell::new(false),
+        })
+    }
+
+    /// Return the current name of the control directory.
+    pub fn name(&self) -> &'static str {
+        if self.relocated.load() {
+            CONTROL_DIR_FALLBACK
+        } else {
+            CONTROL_DIR
+        }
+    }
+
+    /// Move the control directory out of the way of a Gist file named `CONTROL_DIR`.
+    ///
+    /// The files in the Gist always take precedence over the virtual entries.
+    pub async fn relocate(&self, node_table: &NodeTable) -> Result<(), Errno> {
+        if self.relocated.load() {
+            return Ok(());
+        }
+
+        node_table
+            .root()
+            .rename_child(CONTROL_DIR.as_ref(), CONTROL_DIR_FALLBACK.into())
+            .await?;
+        self.relocated.store(true);
+
+        tracing::warn!(
+            "the Gist contains a file named {:?}; the control directory is moved to {:?}",
+            CONTROL_DIR,
+            CONTROL_DIR_FALLBACK
+        );
+        Ok(())
+    }
+
+    /// Return whether the specified inode belongs to the control directory.
+    pub fn contains(&self, ino: u64) -> bool {
+        ino == self.dir.nodeid() || self.is_file(ino)
+    }
+
+    /// Return whether the specified inode is one of the control files.
+    pub fn is_file(&self, ino: u64) -> bool {
+        [&self.errors, &self.stats, &self.inflight, &self.info]
+            .iter()
+            .any(|node| ino == node.nodeid())
+    }
+}
+
+/// The kind of background operation that failed.
+#[derive(Debug, Copy, Clone)]
+pub enum ErrorKind {
+    Refresh,
+    Flush,
+    Conflict,
+    Orphaned,
+    Fork,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Refresh => f.write_str("refresh"),
+            ErrorKind::Flush => f.write_str("flush"),
+            ErrorKind::Conflict => f.write_str("conflict"),
+            ErrorKind::Orphaned => f.write_str("orphaned"),
+            ErrorKind::Fork => f.write_str("fork"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ErrorEntry {
+    timestamp: DateTime<Utc>,
+    kind: ErrorKind,
+    message: String,
+}
+
+/// A bounded log of the errors that occurred in background operations.
+///
+/// The messages are taken from the errors returned by the Gist client,
+/// which never include the access token or the file contents.
+#[derive(Debug, Default)]
+pub struct ErrorLog {
+    entries: Mutex<VecDeque<ErrorEntry>>,
+    another_writer: AtomicCell<bool>,
+    last_refresh: AtomicCell<Option<Outcome>>,
+    last_flush: AtomicCell<Option<Outcome>>,
+    inaccessible: AtomicCell<usize>,
+}
+
+impl ErrorLog {
+    /// Append an entry, discarding the oldest one if the log is full.
+    pub async fn record(&self, kind: ErrorKind, err: &anyhow::Error) {
+        let mut entries = self.entries.lock().await;
+        if entries.len() >= ERROR_LOG_CAPACITY {
+            entries.pop_front();
+        }
+        entries.push_back(ErrorEntry {
+            timestamp: Utc::now(),
+            kind,
+            message: format!("{:#}", err),
+        });
+    }
+
+    /// Remember the outcome of a refresh and log its error, if any.
+    ///
+    /// The mount becomes orphaned once the Gist has been reported as deleted
+    /// or inaccessible by several refreshes in a row, and recovers on the
+    /// next successful refresh.
+    pub async fn refreshed(&self, result: &anyhow::Result<()>) {
+        self.last_refresh.store(Some(Outcome::new(result.is_ok())));
+        let err = match result {
+            Ok(()) => {
+                if self.inaccessible.swap(0) >= ORPHAN_THRESHOLD {
+                    tracing::info!("the Gist is accessible again");
+                }
+                return;
+            }
+            Err(err) => err,
+        };
+
+        self.record(ErrorKind::Refresh, err).await;
+        match err.downcast_ref::<ClientError>() {
+            Some(ClientError::NotFound) | Some(ClientError::Unauthorized) => {
+                if self.inaccessible.fetch_add(1) + 1 == ORPHAN_THRESHOLD {
+                    let err = anyhow::anyhow!(
+                        "the Gist is deleted or no longer accessible; \
+                         the mount is read-only and the local changes are kept"
+                    );
+                    tracing::error!("{}", err);
+                    self.record(ErrorKind::Orphaned, &err).await;
+                }
+            }
+            _ => self.inaccessible.store(0),
+        }
+    }
+
+    /// Return whether the Gist is considered to be deleted or inaccessible.
+    pub fn orphaned(&self) -> bool {
+        self.inaccessible.load() >= ORPHAN_THRESHOLD
+    }
+
+    /// Remember the outcome of an upload and log its error, if any.
+    ///
+    /// A rejected conditional request means that the Gist has been edited
+    /// by another writer, which is remembered for the lifetime of the mount.
+    pub async fn flushed<T>(&self, result: &anyhow::Result<T>) {
+        self.last_flush.store(Some(Outcome::new(result.is_ok())));
+        match result {
+            Ok(..) => (),
+            Err(err) => match err.downcast_ref::<ClientError>() {
+                Some(ClientError::Conflict) => {
+                    self.another_writer.store(true);
+                    self.record(ErrorKind::Conflict, err).await;
+                }
+                _ => self.record(ErrorKind::Flush, err).await,
+            },
+        }
+    }
+
+    pub fn last_refresh(&self) -> Option<Outcome> {
+        self.last_refresh.load()
+    }
+
+    pub fn last_flush(&self) -> Option<Outcome> {
+        self.last_flush.load()
+    }
+
+    /// Return whether an edit by another writer has been detected.
+    ///
+    /// Unlike the entries, this flag is not cleared by `clear`.
+    pub fn another_writer(&self) -> bool {
+        self.another_writer.load()
+    }
+
+    pub async fn clear(&self) {
+        self.entries.lock().await.clear();
+    }
+
+    pub async fn len(&self) -> usize {
+        self.entries.lock().await.len()
+    }
+
+    /// Render the entries as the content of `.gistfs/errors`.
+    pub async fn render(&self, time_format: &TimeFormat) -> String {
+        let entries = self.entries.lock().await;
+        let mut rendered = String::new();
+        for entry in &*entries {
+            rendered += &format!(
+                "{} {}: {}\n",
+                time_format.render(entry.timestamp),
+                entry.kind,
+                entry.message
+            );
+        }
+        rendered
+    }
+}
@@ -0,0 +1,99 @@
+//! Errors reported by the public API of the library.
+
+use gist_client::ClientError;
+#[cfg(feature = "fuse")]
+use node_table::Errno;
+use std::{error, fmt, io, time::Duration};
+
+/// An error reported by `GistFs`.
+#[derive(Debug)]
+pub enum Error {
+    /// The request to the Gist API failed.
+    Client(ClientError),
+
+    /// An I/O error, e.g. on opening the audit log.
+    Io(io::Error),
+
+    /// The Gist is no longer accessible, so the local changes cannot be uploaded.
+    Orphaned,
+
+    /// The upload did not complete within the grace period on shutdown.
+    ShutdownTimeout {
+        grace: Duration,
+        /// The names of the files whose changes are abandoned.
+        abandoned: Vec<String>,
+    },
+
+    /// The operation was cancelled by an interrupt, e.g. before the mount.
+    Aborted,
+
+    /// Any other failure, e.g. a malformed response.
+    Other(anyhow::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Client(err) => fmt::Display::fmt(err, f),
+            Error::Io(err) => fmt::Display::fmt(err, f),
+            Error::Orphaned => {
+                f.write_str("the Gist is no longer accessible; the local changes are not uploaded")
+            }
+            Error::ShutdownTimeout { grace, abandoned } => write!(
+                f,
+                "the upload did not complete within {:?}; {} files are not uploaded",
+                grace,
+                abandoned.len()
+            ),
+            Error::Aborted => f.write_str("aborted by the user"),
+            Error::Other(err) => write!(f, "{:#}", err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Client(err) => Some(err),
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    /// Recover the typed error, unless it is wrapped with a context
+    /// which would be lost.
+    fn from(err: anyhow::Error) -> Self {
+        if err.chain().count() > 1 {
+            return Error::Other(err);
+        }
+        let err = match err.downcast::<ClientError>() {
+            Ok(err) => return Error::Client(err),
+            Err(err) => err,
+        };
+        match err.downcast::<io::Error>() {
+            Ok(err) => Error::Io(err),
+            Err(err) => Error::Other(err),
+        }
+    }
+}
+
+impl From<ClientError> for Error {
+    fn from(err: ClientError) -> Self {
+        Error::Client(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+#[cfg(feature = "fuse")]
+impl From<Errno> for Error {
+    fn from(errno: Errno) -> Self {
+        Error::Io(errno.into())
+    }
+}
@@ -0,0 +1,53 @@
+//! The Gist the mount reads from and uploads to, which is switched to
+//! a fork on the first write with `--fork-on-write`.
+
+use chrono::{DateTime, Utc};
+use std::sync::{Arc, RwLock};
+
+#[derive(Debug)]
+pub(crate) struct GistTarget {
+    id: RwLock<Arc<str>>,
+    fork: RwLock<Option<ForkInfo>>,
+}
+
+/// The record of the switch from the original Gist to its fork.
+#[derive(Debug, Clone)]
+pub(crate) struct ForkInfo {
+    pub(crate) original: Arc<str>,
+    pub(crate) fork: Arc<str>,
+    pub(crate) forked_at: DateTime<Utc>,
+}
+
+impl GistTarget {
+    pub(crate) fn new(id: Arc<str>) -> Self {
+        Self {
+            id: RwLock::new(id),
+            fork: RwLock::new(None),
+        }
+    }
+
+    /// Return the ID of the Gist currently targeted.
+    pub(crate) fn get(&self) -> Arc<str> {
+        self.id.read().unwrap().clone()
+    }
+
+    pub(crate) fn fork_info(&self) -> Option<ForkInfo> {
+        self.fork.read().unwrap().clone()
+    }
+
+    pub(crate) fn is_forked(&self) -> bool {
+        self.fork.read().unwrap().is_some()
+    }
+
+    /// Switch the target to the fork, keeping the original ID for the record.
+    pub(crate) fn switch_to_fork(&self, fork: Arc<str>) -> ForkInfo {
+        let mut id = self.id.write().unwrap();
+        let info = ForkInfo {
+            original: std::mem::replace(&mut *id, fork.clone()),
+            fork,
+            forked_at: Utc::now(),
+        };
+        *self.fork.write().unwrap() = Some(info.clone());
+        info
+    }
+}
@@ -0,0 +1,6102 @@
+//! The filesystem serving a Gist over FUSE.
+
+use crate::{
+    acl,
+    attr::{self, OwnerIds},
+    audit::{AuditLog, WriteRecord},
+    backup::Backups,
+    clock::{Clock, SharedClock},
+    conflict::{self, ConflictCommand, ConflictStrategy, Resolution},
+    consistency::Consistency,
+    content::Content,
+    content_policy::{CompositePolicy, ContentPolicy, PolicyViolation},
+    control::{ControlDir, ErrorKind, ErrorLog, CONTROL_DIR},
+    error::Error,
+    fork::GistTarget,
+    inflight::{self, InflightOps},
+    kind::{self, InodeKind, OpKind},
+    ledger::{self, Pending},
+    lock_order::{self, OrderedGuard, OrderedMutex},
+    order::FileOrder,
+    permission::Permissions,
+    policy::ExecPolicy,
+    ratelimit::{RequestBudget, UploadLimiter},
+    remote::Remote,
+    resolver::{IdentityResolver, NameResolver},
+    revision::{self, RevisionFile, Revisions, UndoTree},
+    sanitize_filename,
+    shutdown::Shutdown,
+    snapshot::{self, SnapshotMetadata},
+    state::{DirtyFile, GistMetadata, MountState, StateSocket},
+    timefmt::TimeFormat,
+    transform::{ContentTransformer, IdentityTransformer},
+    transport::Transport,
+    undo::UndoHistory,
+};
+use anyhow::Context as _;
+use chrono::Utc;
+use crossbeam::atomic::AtomicCell;
+use futures::{channel::mpsc, io::AsyncWrite, lock::Mutex, stream::StreamExt as _};
+use gist_client::{
+    Client, ClientError, ContentReader, ETag, Gist, GistFile, GistMediaType, GistPatch,
+    GistPatchFile,
+};
+use mime::Mime;
+use node_table::{Errno, Node, NodeTable};
+use polyfuse::{
+    op,
+    reply::{ReplyAttr, ReplyEntry, ReplyOpen, ReplyOpendir, ReplyWrite, ReplyXattr},
+    Context, FileAttr, Filesystem, Operation,
+};
+use regex::Regex;
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    io,
+    os::unix::ffi::OsStrExt as _,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+use tracing::Instrument as _;
+use unicode_normalization::UnicodeNormalization;
+
+/// The extended attribute of the root directory holding the number of
+/// files waiting for upload.
+const PENDING_OPERATIONS_XATTR: &str = "user.gistfs.pending_operations";
+
+/// The default maximum number of file handles opened simultaneously.
+const DEFAULT_MAX_OPEN_HANDLES: usize = 4096;
+
+/// The largest content a file may grow to by writes and truncations, the
+/// 100 MB the git repository of a Gist accepts.
+const MAX_CONTENT_SIZE: u64 = 100 * 1024 * 1024;
+
+/// The remaining rate limit below which the strict consistency falls back
+/// to the cached content, leaving the budget for the uploads.
+const STRICT_MIN_RATE_REMAINING: usize = 50;
+
+/// The extended attributes of the files, reporting the type detected by the Gist.
+const MIME_TYPE_XATTR: &str = "user.gist.type";
+const LANGUAGE_XATTR: &str = "user.gist.language";
+
+/// The extended attribute of the files, reporting whether the local
+/// content has been uploaded.
+const SYNCED_XATTR: &str = "user.gist.synced";
+
+/// The extended attribute of the root directory reporting whether the Gist
+/// is public, which the API does not allow to change in place.
+const PUBLIC_XATTR: &str = "user.gist.public";
+
+/// The suffix of the aliases listing the files with the local changes.
+const UNSYNCED_SUFFIX: &str = ".unsynced";
+
+/// The content uploaded in place of an empty file, which the Gist rejects.
+const EMPTY_FILE_PLACEHOLDER: &str = "\n";
+
+/// The period of inactivity after the last write before the dirty files
+/// are uploaded.
+const FLUSH_DELAY: Duration = Duration::from_secs(1);
+
+/// How long the writes must pause before a long-lived dirty file is
+/// uploaded, so that a burst of writes is not torn.
+const DIRTY_AGE_SETTLE: Duration = Duration::from_millis(200);
+
+/// How long the conflict command may take, e.g. waiting for the user in
+/// a merge tool, before the conflict is left to the `.conflict` file.
+const CONFLICT_COMMAND_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+pub struct GistFs {
+    client: Arc<Client>,
+
+    /// The Gist the changes are uploaded to, which is the client itself
+    /// unless replaced by the tests.
+    remote: Arc<dyn Remote>,
+
+    gist_id: Arc<GistTarget>,
+    fork_on_write: bool,
+    forking: Mutex<()>,
+    offline: bool,
+    name_resolver: Box<dyn NameResolver>,
+    mark_unsynced: bool,
+    node_table: Arc<NodeTable>,
+    files: Arc<GistFiles>,
+    handles: Arc<FileHandles>,
+    control: ControlDir,
+    errors: Arc<ErrorLog>,
+    exec_policy: ExecPolicy,
+    acls: Mutex<HashMap<u64, Vec<u8>>>,
+    read_only: bool,
+    negative_entry_valid_secs: u64,
+    permissions: Permissions,
+    max_mtime_offset: Option<Duration>,
+    revisions: Arc<Revisions>,
+    time_format: TimeFormat,
+    case_insensitive: bool,
+    always_refresh_on_opendir: bool,
+    local_hard_links: bool,
+    audit_log: Option<AuditLog>,
+    backups: Option<Backups>,
+    inflight: InflightOps,
+    shutdown: Arc<Shutdown>,
+    shutdown_grace: Duration,
+    max_dirty_age: Option<Duration>,
+    transport: Transport,
+    consistency: Consistency,
+    revalidation: Mutex<()>,
+    revalidations: AtomicCell<u64>,
+    fuse_session_options: Vec<OsString>,
+    owner: OwnerIds,
+    noise_filter: bool,
+    show_diff: bool,
+    min_write_size: usize,
+    min_write_count: u32,
+    uploads: Arc<UploadLimiter>,
+    budget: Arc<RequestBudget>,
+}
+
+/// The handles to the shared state needed to take a snapshot of the mount.
+#[derive(Clone)]
+struct StateSource {
+    client: Arc<Client>,
+    gist_id: Arc<GistTarget>,
+    files: Arc<GistFiles>,
+    errors: Arc<ErrorLog>,
+    node_table: Arc<NodeTable>,
+    handles: Arc<FileHandles>,
+    uploads: Arc<UploadLimiter>,
+    budget: Arc<RequestBudget>,
+    read_only: bool,
+}
+
+impl StateSource {
+    async fn snapshot(&self) -> MountState {
+        let num_errors = self.errors.len().await;
+        let (num_files, dirty_files) = self.files.stats().await;
+        let orphaned = self.errors.orphaned();
+        let (open_handles, max_open_handles) = self.handles.stats().await;
+        let fork = self.gist_id.fork_info();
+        let connections = self.client.connection_stats();
+        MountState {
+            gist_id: self.gist_id.get().to_string(),
+            forked_from: fork.as_ref().map(|fork| fork.original.to_string()),
+            forked_at: fork.as_ref().map(|fork| fork.forked_at),
+            read_only: (self.read_only && fork.is_none()) || orphaned,
+            degraded: num_errors > 0 || orphaned,
+            errors: num_errors,
+            files: num_files,
+            dirty_files,
+            last_refresh: self.errors.last_refresh(),
+            last_flush: self.errors.last_flush(),
+            rate_remaining: self.client.rate_remaining(),
+            another_writer: self.errors.another_writer(),
+            orphaned,
+            metadata: self.files.metadata.lock().await.clone(),
+            entries_bytes: self.node_table.entries_bytes().await,
+            clock_skew_secs: self.client.clock_skew().map(|skew| skew.num_seconds()),
+            open_handles,
+            max_open_handles,
+            queued_uploads: self.uploads.queued(),
+            request_budget: self.budget.state(self.client.rate_remaining()),
+            connections_opened: connections.opened,
+            connections_reused: connections.reused,
+        }
+    }
+}
+
+/// A builder for `GistFs`.
+#[derive(Debug)]
+pub struct GistFsBuilder {
+    client: Client,
+    gist_id: String,
+    exec_policy: ExecPolicy,
+    normalize_unicode: bool,
+    streaming: bool,
+    read_only: bool,
+    negative_entry_valid_secs: u64,
+    writable_group: Option<u32>,
+    max_mtime_offset: Option<Duration>,
+    time_format: TimeFormat,
+    case_insensitive: bool,
+    always_refresh_on_opendir: bool,
+    local_hard_links: bool,
+    conflict_resolution: ConflictStrategy,
+    conflict_command: Option<ConflictCommand>,
+    transport: Transport,
+    consistency: Consistency,
+    fuse_session_options: Vec<OsString>,
+    max_open_handles: usize,
+    shutdown_grace: Duration,
+    max_dirty_age: Option<Duration>,
+    audit_log: Option<PathBuf>,
+    backup_on_release: Option<PathBuf>,
+    backup_versions: usize,
+    undo_dir: Option<PathBuf>,
+    undo_versions: usize,
+    owner: OwnerIds,
+    sanitize_filenames: bool,
+    noise_filter: bool,
+    show_diff: bool,
+    min_write_size: usize,
+    min_write_count: u32,
+    max_uploads_per_minute: u32,
+    rate_limit_share: u8,
+    fork_on_write: bool,
+    mime_filter: Option<Regex>,
+    empty_file_placeholder: bool,
+    name_resolver: Box<dyn NameResolver>,
+    mark_unsynced: bool,
+    content_transformer: Box<dyn ContentTransformer>,
+    content_policy: Box<dyn ContentPolicy>,
+    compress_threshold_bytes: usize,
+    clock: SharedClock,
+    offline: bool,
+    file_order: FileOrder,
+}
+
+impl GistFsBuilder {
+    /// Set the policy deciding which files are marked as executable.
+    pub fn exec_policy(&mut self, policy: ExecPolicy) -> &mut Self {
+        self.exec_policy = policy;
+        self
+    }
+
+    /// Apply Unicode NFC normalization to the content before uploading.
+    ///
+    /// The local content is kept as written.
+    pub fn normalize_unicode(&mut self, enabled: bool) -> &mut Self {
+        self.normalize_unicode = enabled;
+        self
+    }
+
+    /// Read the files truncated in the API response from their raw URLs.
+    ///
+    /// Such files are served without loading the entire content into
+    /// memory, and cannot be modified.
+    pub fn streaming(&mut self, enabled: bool) -> &mut Self {
+        self.streaming = enabled;
+        self
+    }
+
+    /// Reject all modifications of the Gist files.
+    pub fn read_only(&mut self, enabled: bool) -> &mut Self {
+        self.read_only = enabled;
+        self
+    }
+
+    /// Set how long the kernel may cache the absence of a name, in seconds.
+    ///
+    /// The negative caching is disabled when set to 0.
+    pub fn negative_entry_valid_secs(&mut self, secs: u64) -> &mut Self {
+        self.negative_entry_valid_secs = secs;
+        self
+    }
+
+    /// Allow the members of the specified group to modify the files.
+    ///
+    /// By default, only the user who mounted the filesystem may modify them.
+    pub fn writable_group(&mut self, gid: Option<u32>) -> &mut Self {
+        self.writable_group = gid;
+        self
+    }
+
+    /// Set how far in the future the modification time may be set.
+    ///
+    /// The check is disabled when set to `None`.
+    pub fn max_mtime_offset(&mut self, offset: Option<Duration>) -> &mut Self {
+        self.max_mtime_offset = offset;
+        self
+    }
+
+    /// Set how the timestamps are rendered in the control files.
+    pub fn time_format(&mut self, time_format: TimeFormat) -> &mut Self {
+        self.time_format = time_format;
+        self
+    }
+
+    /// Look up the files ignoring case, as on case-insensitive filesystems.
+    ///
+    /// The creation of a file whose name differs only in case from
+    /// an existing one is rejected.
+    pub fn case_insensitive(&mut self, enabled: bool) -> &mut Self {
+        self.case_insensitive = enabled;
+        self
+    }
+
+    /// Fetch the whole content, ignoring the cached entity tag, when the root
+    /// directory is opened by opendir(3), e.g. for `ls` in a shell.
+    pub fn always_refresh_on_opendir(&mut self, enabled: bool) -> &mut Self {
+        self.always_refresh_on_opendir = enabled;
+        self
+    }
+
+    /// Allow link(2) to add another name of a file, which exists only on the mount.
+    ///
+    /// The file is deleted from the Gist only after all of its names are removed.
+    pub fn local_hard_links(&mut self, enabled: bool) -> &mut Self {
+        self.local_hard_links = enabled;
+        self
+    }
+
+    /// Set how the local changes are reconciled with the edits made
+    /// by another writer.
+    ///
+    /// By default, the upload fails and the conflict is logged.
+    pub fn conflict_resolution(&mut self, strategy: ConflictStrategy) -> &mut Self {
+        self.conflict_resolution = strategy;
+        self
+    }
+
+    /// Set the command resolving the conflicts left by the three-way merge.
+    ///
+    /// The command runs in the background once the `.conflict` file is
+    /// created, and the merged content replaces the file and removes the
+    /// `.conflict` file if the command succeeds.
+    pub fn conflict_command(&mut self, command: ConflictCommand) -> &mut Self {
+        self.conflict_command = Some(command);
+        self
+    }
+
+    /// Set how the content of the files is fetched.
+    pub fn transport(&mut self, transport: Transport) -> &mut Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Set whether the clean files are revalidated against the Gist on open.
+    ///
+    /// By default, the content cached since the last refresh is served.
+    pub fn consistency(&mut self, consistency: Consistency) -> &mut Self {
+        self.consistency = consistency;
+        self
+    }
+
+    /// Set the raw options passed to the FUSE session on mount, e.g.
+    /// `["-o", "max_read=131072"]`.
+    pub fn fuse_session_options(&mut self, options: Vec<OsString>) -> &mut Self {
+        self.fuse_session_options = options;
+        self
+    }
+
+    /// Set the maximum number of file handles opened simultaneously,
+    /// beyond which open(2) fails with `EMFILE`.
+    pub fn max_open_handles(&mut self, max: usize) -> &mut Self {
+        self.max_open_handles = max;
+        self
+    }
+
+    /// Set how long the pending uploads may take on shutdown before abandoned.
+    pub fn shutdown_grace(&mut self, grace: Duration) -> &mut Self {
+        self.shutdown_grace = grace;
+        self
+    }
+
+    /// Upload the files kept dirty for longer than the specified duration
+    /// even while they are opened for writing, or never if `None`.
+    ///
+    /// The upload waits for a pause between the writes.
+    pub fn max_dirty_age(&mut self, age: Option<Duration>) -> &mut Self {
+        self.max_dirty_age = age;
+        self
+    }
+
+    /// Set the owner of the files, which defaults to the user running the process.
+    ///
+    /// The owner is also the user allowed to modify the files.
+    pub fn owner(&mut self, uid: u32, gid: u32) -> &mut Self {
+        self.owner = OwnerIds { uid, gid };
+        self
+    }
+
+    /// Trim the trailing whitespace of the filenames, which some editors
+    /// leave by accident.
+    ///
+    /// The files on the Gist with such names are renamed on the next upload.
+    pub fn sanitize_filenames(&mut self, enabled: bool) -> &mut Self {
+        self.sanitize_filenames = enabled;
+        self
+    }
+
+    /// Answer the lookups of the names in `GistFs::COMMON_NOISE_FILES`
+    /// missing in the Gist without the rest of the lookup.
+    ///
+    /// Enabled by default. The Gist files with such names are still found.
+    pub fn noise_filter(&mut self, enabled: bool) -> &mut Self {
+        self.noise_filter = enabled;
+        self
+    }
+
+    /// Prepend the pending changes of the modified text files, as a unified
+    /// diff from the uploaded content, to the content read through the
+    /// handles opened for reading.
+    ///
+    /// The writes are unaffected, so the prefix is never uploaded.
+    pub fn show_diff(&mut self, enabled: bool) -> &mut Self {
+        self.show_diff = enabled;
+        self
+    }
+
+    /// Defer the upload of the written file until its size reaches the
+    /// specified number of bytes, or until it is closed.
+    ///
+    /// The check is disabled when set to 0.
+    pub fn min_write_size(&mut self, size: usize) -> &mut Self {
+        self.min_write_size = size;
+        self
+    }
+
+    /// Defer the upload of the written file until the specified number of
+    /// writes are made, or until it is closed.
+    ///
+    /// The check is disabled when set to 0.
+    pub fn min_write_count(&mut self, count: u32) -> &mut Self {
+        self.min_write_count = count;
+        self
+    }
+
+    /// Limit the number of the uploads per minute, or zero for no limit.
+    ///
+    /// The uploads beyond the limit wait in the background, while the
+    /// writes keep succeeding locally.
+    pub fn max_uploads_per_minute(&mut self, max: u32) -> &mut Self {
+        self.max_uploads_per_minute = max;
+        self
+    }
+
+    /// Limit the requests of this mount to the percentage of the remaining
+    /// rate limit per hour, or zero for no limit.
+    ///
+    /// The refreshes beyond the share serve the cached content, while the
+    /// fetches of the revisions wait for the budget. An fsync(2) is
+    /// uploaded beyond the share.
+    pub fn rate_limit_share(&mut self, percent: u8) -> &mut Self {
+        self.rate_limit_share = percent;
+        self
+    }
+
+    /// Fork the Gist on the first modification of a read-only mount, and
+    /// upload the changes to the fork rather than the original.
+    ///
+    /// The modification fails with `EROFS` if the fork fails.
+    pub fn fork_on_write(&mut self, enabled: bool) -> &mut Self {
+        self.fork_on_write = enabled;
+        self
+    }
+
+    /// Set the order of the files in the directory listing and in the stats.
+    ///
+    /// The entries are reordered as the files are added or renamed.
+    pub fn file_order(&mut self, order: FileOrder) -> &mut Self {
+        self.file_order = order;
+        self
+    }
+
+    /// Serve the files loaded by `load_gist` without any network access,
+    /// which makes the mount read-only.
+    ///
+    /// Neither the refreshes nor the uploads are issued, and the truncated
+    /// content is served as it is rather than streamed.
+    pub fn offline(&mut self, enabled: bool) -> &mut Self {
+        self.offline = enabled;
+        self
+    }
+
+    /// Mount only the files whose MIME type reported by the Gist matches
+    /// the pattern, e.g. `^text/x-python$`.
+    ///
+    /// The files are checked on every refresh, so a file created locally
+    /// disappears if the Gist detects another type.
+    pub fn mime_filter(&mut self, pattern: Option<Regex>) -> &mut Self {
+        self.mime_filter = pattern;
+        self
+    }
+
+    /// Upload a newline in place of the empty content of the files, which
+    /// the Gist rejects.
+    ///
+    /// Enabled by default. When disabled, the empty files are kept dirty
+    /// and are not uploaded until they get some content.
+    pub fn empty_file_placeholder(&mut self, enabled: bool) -> &mut Self {
+        self.empty_file_placeholder = enabled;
+        self
+    }
+
+    /// Set the transformation applied to the names before they are looked up.
+    pub fn name_resolver(&mut self, resolver: impl NameResolver + 'static) -> &mut Self {
+        self.name_resolver = Box::new(resolver);
+        self
+    }
+
+    /// List the files with the local changes under the additional names
+    /// `<filename>.unsynced`, which disappear once the changes are uploaded.
+    ///
+    /// Regardless of this, the files report whether they are uploaded
+    /// in the extended attribute `user.gist.synced`.
+    pub fn mark_unsynced(&mut self, enabled: bool) -> &mut Self {
+        self.mark_unsynced = enabled;
+        self
+    }
+
+    /// Set the transformation applied to the content of the files on upload.
+    ///
+    /// The upload fails if the transformation fails, which `fsync(2)`
+    /// reports as `EIO`.
+    pub fn content_transformer(
+        &mut self,
+        transformer: impl ContentTransformer + 'static,
+    ) -> &mut Self {
+        self.content_transformer = Box::new(transformer);
+        self
+    }
+
+    /// Set the policy the content of the files must satisfy to be uploaded.
+    ///
+    /// The content is checked after the transformation, and `fsync(2)`
+    /// fails with the error code of the violation.
+    pub fn content_policy(&mut self, policy: impl ContentPolicy + 'static) -> &mut Self {
+        self.content_policy = Box::new(policy);
+        self
+    }
+
+    /// Set the clock driving the debounce timers, the dirty age check and
+    /// the rate limits, e.g. a `MockClock` to advance them without waiting.
+    pub fn clock(&mut self, clock: Arc<dyn Clock>) -> &mut Self {
+        self.clock = SharedClock::new(clock);
+        self
+    }
+
+    /// Keep the content of the clean files larger than the threshold
+    /// compressed in memory, or zero to disable the compression.
+    ///
+    /// The content is decompressed while the file is opened or modified.
+    pub fn compress_threshold_bytes(&mut self, threshold: usize) -> &mut Self {
+        self.compress_threshold_bytes = threshold;
+        self
+    }
+
+    /// Append a record of every write operation to the specified file.
+    pub fn audit_log(&mut self, path: PathBuf) -> &mut Self {
+        self.audit_log = Some(path);
+        self
+    }
+
+    /// Save the content of every file closed after writing into the directory,
+    /// before it is uploaded.
+    pub fn backup_on_release(&mut self, dir: Option<PathBuf>) -> &mut Self {
+        self.backup_on_release = dir;
+        self
+    }
+
+    /// Set the number of the backups kept per file, or zero to keep all of them.
+    pub fn backup_versions(&mut self, versions: usize) -> &mut Self {
+        self.backup_versions = versions;
+        self
+    }
+
+    /// Keep the recently uploaded versions of the files in the directory,
+    /// served under `.gistfs/undo/<filename>/<timestamp>`.
+    pub fn undo_dir(&mut self, dir: Option<PathBuf>) -> &mut Self {
+        self.undo_dir = dir;
+        self
+    }
+
+    /// Set the number of the uploaded versions kept per file, or zero to keep none.
+    pub fn undo_versions(&mut self, versions: usize) -> &mut Self {
+        self.undo_versions = versions;
+        self
+    }
+
+    pub async fn build(self) -> Result<GistFs, Error> {
+        let node_table = NodeTable::new(attr::new_attr(libc::S_IFDIR | 0o755, 2, self.owner));
+
+        let control = ControlDir::new(&node_table, self.owner).await?;
+
+        let audit_log = match self.audit_log {
+            Some(ref path) => Some(
+                AuditLog::open(path)
+                    .await
+                    .with_context(|| format!("failed to open the audit log {:?}", path))?,
+            ),
+            None => None,
+        };
+
+        let (gist_id, backup_versions) = (&self.gist_id, self.backup_versions);
+        let backups = self
+            .backup_on_release
+            .map(|dir| Backups::new(dir, gist_id, backup_versions));
+
+        let revisions = Arc::new(Revisions::default());
+        let undo = match self.undo_dir {
+            Some(ref dir) if self.undo_versions > 0 => {
+                let node = node_table
+                    .get(control.undo.nodeid())
+                    .await
+                    .ok_or(Errno::ENOENT)?;
+                let history = UndoHistory::new(dir.clone(), &self.gist_id, self.undo_versions);
+                let undo = UndoTree::new(node, history, self.owner, revisions.clone());
+                if let Err(err) = undo.load().await {
+                    tracing::warn!("failed to load the undo history from {:?}: {:#}", dir, err);
+                }
+                Some(undo)
+            }
+            _ => None,
+        };
+
+        let (merges_tx, merges_rx) = match self.conflict_command {
+            Some(..) => {
+                let (tx, rx) = mpsc::unbounded();
+                (Some(tx), Some(rx))
+            }
+            None => (None, None),
+        };
+
+        let client = Arc::new(self.client);
+        let errors = Arc::new(ErrorLog::default());
+        let fs = GistFs {
+            transport: self.transport,
+            consistency: self.consistency,
+            revalidation: Mutex::new(()),
+            revalidations: AtomicCell::new(0),
+            fuse_session_options: self.fuse_session_options,
+            remote: client.clone(),
+            client,
+            gist_id: Arc::new(GistTarget::new(self.gist_id.into())),
+            fork_on_write: self.fork_on_write && !self.offline,
+            forking: Mutex::new(()),
+            offline: self.offline,
+            name_resolver: self.name_resolver,
+            mark_unsynced: self.mark_unsynced,
+            node_table: Arc::new(node_table),
+            files: Arc::new(GistFiles {
+                normalize_unicode: self.normalize_unicode,
+                streaming: self.streaming && !self.offline,
+                conflict_resolution: self.conflict_resolution,
+                conflict_merges: merges_tx,
+                undo,
+                owner: self.owner,
+                sanitize_filenames: self.sanitize_filenames,
+                compress_threshold: self.compress_threshold_bytes,
+                mime_filter: self.mime_filter,
+                empty_file_placeholder: self.empty_file_placeholder,
+                content_transformer: self.content_transformer,
+                content_policy: self.content_policy,
+                clock: self.clock.clone(),
+                file_order: self.file_order,
+                errors: errors.clone(),
+                ..GistFiles::default()
+            }),
+            handles: Arc::new(FileHandles::new(self.max_open_handles)),
+            control,
+            errors,
+            exec_policy: self.exec_policy,
+            acls: Mutex::default(),
+            read_only: self.read_only || self.offline,
+            negative_entry_valid_secs: self.negative_entry_valid_secs,
+            permissions: Permissions::new(self.owner.uid, self.writable_group),
+            owner: self.owner,
+            noise_filter: self.noise_filter,
+            show_diff: self.show_diff,
+            min_write_size: self.min_write_size,
+            min_write_count: self.min_write_count,
+            uploads: Arc::new(UploadLimiter::new(
+                self.max_uploads_per_minute,
+                self.clock.clone(),
+            )),
+            budget: Arc::new(RequestBudget::new(
+                self.rate_limit_share,
+                self.clock.clone(),
+            )),
+            max_mtime_offset: self.max_mtime_offset,
+            revisions,
+            time_format: self.time_format,
+            case_insensitive: self.case_insensitive,
+            always_refresh_on_opendir: self.always_refresh_on_opendir,
+            local_hard_links: self.local_hard_links,
+            inflight: InflightOps::default(),
+            shutdown: Arc::new(Shutdown::default()),
+            shutdown_grace: self.shutdown_grace,
+            max_dirty_age: self.max_dirty_age,
+            audit_log,
+            backups,
+        };
+        fs.spawn_dirty_age_check();
+        if let (Some(command), Some(merges)) = (self.conflict_command, merges_rx) {
+            fs.spawn_conflict_command(command, merges);
+        }
+        Ok(fs)
+    }
+}
+
+impl GistFs {
+    /// The names probed repeatedly by the desktop tools, such as Finder.
+    ///
+    /// The names starting with `._`, used by AppleDouble, are also filtered.
+    pub const COMMON_NOISE_FILES: &'static [&'static str] = &[
+        ".DS_Store",
+        ".Spotlight-V100",
+        ".Trashes",
+        ".fseventsd",
+        ".localized",
+        ".hidden",
+        ".metadata_never_index",
+        "DCIM",
+        "Thumbs.db",
+        "desktop.ini",
+    ];
+
+    pub async fn new(client: Client, gist_id: String) -> Result<Self, Error> {
+        Self::builder(client, gist_id).build().await
+    }
+
+    pub fn builder(client: Client, gist_id: String) -> GistFsBuilder {
+        GistFsBuilder {
+            client,
+            gist_id,
+            exec_policy: ExecPolicy::default(),
+            normalize_unicode: false,
+            streaming: false,
+            read_only: false,
+            negative_entry_valid_secs: 5,
+            writable_group: None,
+            max_mtime_offset: Some(Duration::from_secs(365 * 24 * 60 * 60)),
+            time_format: TimeFormat::default(),
+            case_insensitive: false,
+            always_refresh_on_opendir: false,
+            local_hard_links: false,
+            conflict_resolution: ConflictStrategy::default(),
+            conflict_command: None,
+            transport: Transport::default(),
+            consistency: Consistency::default(),
+            fuse_session_options: vec![],
+            max_open_handles: DEFAULT_MAX_OPEN_HANDLES,
+            shutdown_grace: Duration::from_secs(5),
+            max_dirty_age: Some(Duration::from_secs(5 * 60)),
+            audit_log: None,
+            backup_on_release: None,
+            backup_versions: 5,
+            undo_dir: None,
+            undo_versions: 5,
+            owner: OwnerIds::current(),
+            sanitize_filenames: false,
+            noise_filter: true,
+            show_diff: false,
+            min_write_size: 0,
+            min_write_count: 0,
+            max_uploads_per_minute: 0,
+            rate_limit_share: 0,
+            fork_on_write: false,
+            mime_filter: None,
+            empty_file_placeholder: true,
+            name_resolver: Box::new(IdentityResolver),
+            mark_unsynced: false,
+            content_transformer: Box::new(IdentityTransformer),
+            content_policy: Box::new(CompositePolicy::default()),
+            clock: SharedClock::default(),
+            offline: false,
+            file_order: FileOrder::default(),
+            compress_threshold_bytes: 0,
+        }
+    }
+
+    /// Return the options to mount the FUSE session with.
+    pub fn fuse_session_options(&self) -> Vec<OsString> {
+        let mut options = vec!["-o".into(), "fsname=gistfs".into()];
+        options.extend(self.fuse_session_options.iter().cloned());
+        options
+    }
+
+    /// Return the client shared with the background tasks.
+    pub fn client(&self) -> Arc<Client> {
+        self.client.clone()
+    }
+
+    /// Replace the access token, e.g. when it has been rotated.
+    ///
+    /// The entity tag obtained by another token is dropped, since a
+    /// `304 Not Modified` to it would confirm the content the new token
+    /// may not be allowed to see.
+    pub fn refresh_token(&self, new_token: Option<String>) {
+        let identity = self.client.identity();
+        self.client.refresh_token(new_token);
+        if self.client.identity() != identity {
+            let files = self.files.clone();
+            tokio::spawn(async move {
+                files.etag.lock().await.take();
+            });
+        }
+    }
+
+    /// Return the MIME type of the file reported by the Gist.
+    ///
+    /// The files created locally have no type until they are uploaded.
+    pub async fn mime_of(&self, ino: u64) -> Option<Mime> {
+        let file = self.files.get(ino).await?;
+        file.content_type().map(|(mime, _)| mime)
+    }
+
+    /// Fetch the content of the Gist, failing with `Error::Aborted` if
+    /// `abort` is called before it completes.
+    pub async fn fetch_gist(&self) -> Result<(), Error> {
+        match self.shutdown.run(self.refresh(false)).await {
+            Some(result) => Ok(result?),
+            None => Err(Error::Aborted),
+        }
+    }
+
+    /// Cancel the network operations in progress and the later ones without
+    /// uploading anything, e.g. on an interrupt before the mount.
+    pub fn abort(&self) {
+        self.shutdown.trigger();
+    }
+
+    /// Fetch the content of the Gist, without the conditional request if forced.
+    async fn refresh(&self, force: bool) -> anyhow::Result<()> {
+        if self.offline {
+            return Ok(());
+        }
+        let result = self.fetch_gist_inner(force).await;
+        self.errors.refreshed(&result).await;
+        result
+    }
+
+    /// Revalidate the cached content with a conditional request, as the
+    /// strict consistency requires before opening a clean file.
+    ///
+    /// The concurrent opens share a single request, and the cached content
+    /// is served when the rate limit is running out.
+    async fn revalidate(&self) -> anyhow::Result<()> {
+        if !self.within_budget() {
+            return Ok(());
+        }
+        if self
+            .client
+            .rate_remaining()
+            .is_some_and(|remaining| remaining < STRICT_MIN_RATE_REMAINING)
+        {
+            tracing::warn!("the rate limit is running out; serve the cached content");
+            return Ok(());
+        }
+
+        let seen = self.revalidations.load();
+        let _guard = self.revalidation.lock().await;
+        if self.revalidations.load() != seen {
+            // Another open has revalidated while waiting for the lock.
+            return Ok(());
+        }
+        let result = self.refresh(false).await;
+        self.revalidations.store(seen + 1);
+        result
+    }
+
+    /// Take a request from the budget of the refresh, or serve the cached
+    /// content if the share of the rate limit is exhausted.
+    fn within_budget(&self) -> bool {
+        let ok = self.budget.try_consume(self.client.rate_remaining());
+        if !ok {
+            tracing::warn!("the share of the rate limit is exhausted; serve the cached content");
+        }
+        ok
+    }
+
+    // TODO:
+    // * invalidate the old files
+    async fn fetch_gist_inner(&self, force: bool) -> anyhow::Result<()> {
+        tracing::debug!("fetch Gist content");
+        // The cached entity tag is kept for the conditional uploads.
+        let etag = if force {
+            None
+        } else {
+            self.files.etag.lock().await.clone()
+        };
+        let media = match self.transport {
+            Transport::Rest => self.files.media_type(),
+            // The content in the response is replaced with the one from git.
+            #[cfg(feature = "git-transport")]
+            Transport::Git => GistMediaType::Json,
+        };
+        let mut response = self
+            .client
+            .fetch_gist_with_media(&self.gist_id.get(), etag.as_ref(), media)
+            .await?;
+
+        // The binary files are mangled in the default media type, so they are
+        // fetched again in base64 rather than from their raw URLs.
+        let has_binary = |(gist, _): &(Gist, _)| gist.files.values().any(|f| !f.is_text());
+        if self.transport == Transport::Rest
+            && media == GistMediaType::Json
+            && response.as_ref().is_some_and(has_binary)
+        {
+            tracing::debug!("fetch Gist content again in base64");
+            response = self
+                .client
+                .fetch_gist_with_media(&self.gist_id.get(), None, GistMediaType::Base64)
+                .await?;
+        }
+
+        #[cfg(feature = "git-transport")]
+        let response = match response {
+            Some((mut gist, etag)) if self.transport == Transport::Git => {
+                tracing::debug!("fetch Gist content through git");
+                let tree = self.client.fetch_git_tree(&gist.git_pull_url).await?;
+                gist.apply_git_tree(tree);
+                Some((gist, etag))
+            }
+            response => response,
+        };
+
+        if let Some((gist, etag)) = response {
+            tracing::debug!("update Gist content: gist={:?}, etag={:?}", gist, etag);
+            self.files
+                .update(
+                    gist,
+                    etag,
+                    &self.node_table,
+                    &self.control,
+                    &self.exec_policy,
+                )
+                .await?;
+        } else {
+            tracing::debug!("use cached Gist content");
+        }
+
+        Ok(())
+    }
+
+    /// Cancel the pending downloads and upload the dirty files within the grace period.
+    ///
+    /// The handlers waiting for the network fail with `EINTR` afterwards.
+    pub async fn shutdown(&self) -> Result<(), Error> {
+        self.shutdown.trigger();
+        match tokio::time::timeout(self.shutdown_grace, self.flush_all()).await {
+            Ok(result) => result,
+            Err(..) => {
+                let (_, dirty_files) = self.files.stats().await;
+                for file in &dirty_files {
+                    tracing::error!("the upload of {:?} is abandoned", file.filename);
+                }
+                Err(Error::ShutdownTimeout {
+                    grace: self.shutdown_grace,
+                    abandoned: dirty_files.into_iter().map(|file| file.filename).collect(),
+                })
+            }
+        }
+    }
+
+    /// Upload all dirty files, including the ones still opened for writing.
+    ///
+    /// This method is intended to be called when the filesystem is unmounted.
+    pub async fn flush_all(&self) -> Result<(), Error> {
+        if self.offline {
+            return Ok(());
+        }
+        if self.errors.orphaned() {
+            return Err(Error::Orphaned);
+        }
+
+        let result = self
+            .files
+            .flush(
+                &*self.remote,
+                &self.gist_id.get(),
+                &self.node_table,
+                FlushReason::Unmount,
+            )
+            .await;
+        self.errors.flushed(&result).await;
+        Ok(result?)
+    }
+
+    /// Save the current content of the files into a directory, along with
+    /// the metadata of the Gist in `__gist_metadata__.json`.
+    ///
+    /// The local changes not uploaded yet are included. The directory is
+    /// replaced as a whole, so that a partial snapshot is never seen.
+    pub async fn export_snapshot(&self, path: PathBuf) -> Result<(), Error> {
+        let files: Vec<Arc<GistFileNode>> =
+            self.files.files.lock().await.values().cloned().collect();
+
+        let mut contents = Vec::with_capacity(files.len());
+        for file in files.into_iter().filter(|file| !file.is_conflict()) {
+            if file.is_streamed() {
+                tracing::warn!(
+                    "skip the file too large to be cached: {:?}",
+                    file.filename()
+                );
+                continue;
+            }
+            let (content, _) = file.snapshot().await;
+            contents.push((file.filename(), content));
+        }
+
+        let metadata = SnapshotMetadata {
+            gist_id: self.gist_id.get().to_string(),
+            identity: self.client.identity(),
+            gist: self.files.metadata.lock().await.clone(),
+            exported_at: Utc::now(),
+        };
+        snapshot::write(&path, &metadata, &contents).await?;
+
+        tracing::info!("exported {} files to {:?}", contents.len(), path);
+        Ok(())
+    }
+
+    /// Create the filesystem from a snapshot written by `export_snapshot`,
+    /// without fetching the Gist.
+    pub async fn from_snapshot(path: PathBuf, client: Client) -> Result<Self, Error> {
+        let gist_id = Self::snapshot_gist_id(&path).await?;
+        let fs = Self::new(client, gist_id).await?;
+        fs.import_snapshot(path).await?;
+        Ok(fs)
+    }
+
+    /// Return the ID of the Gist saved in a snapshot.
+    pub async fn snapshot_gist_id(path: &Path) -> Result<String, Error> {
+        Ok(snapshot::read_metadata(path).await?.gist_id)
+    }
+
+    /// Populate the files from a snapshot of the same Gist, in place of
+    /// the initial fetch.
+    ///
+    /// The files are served as the copies of the Gist, so the local changes
+    /// saved in the snapshot are not uploaded. They are replaced with the
+    /// content of the Gist on the next refresh.
+    pub async fn import_snapshot(&self, path: PathBuf) -> Result<(), Error> {
+        let metadata = snapshot::read_metadata(&path).await?;
+        if metadata.gist_id != *self.gist_id.get() {
+            return Err(Error::Other(anyhow::anyhow!(
+                "the snapshot is of another Gist: {}",
+                metadata.gist_id
+            )));
+        }
+        // The Gist may look different to another token, e.g. a secret one
+        // visible only to its owner, so the snapshot is not served then.
+        if let Some(ref identity) = metadata.identity {
+            if self.client.identity().as_ref() != Some(identity) {
+                tracing::warn!(
+                    "the snapshot was taken by another token; fetch the Gist instead of {:?}",
+                    path
+                );
+                return Ok(());
+            }
+        }
+        let files = snapshot::read_files(&path).await?;
+        tracing::info!("import {} files from {:?}", files.len(), path);
+
+        let exported_at = metadata.exported_at;
+        let gist = metadata.gist.unwrap_or_else(|| GistMetadata {
+            description: String::new(),
+            public: false,
+            updated_at: exported_at,
+        });
+        let gist = Gist {
+            id: metadata.gist_id,
+            html_url: String::new(),
+            description: gist.description,
+            public: gist.public,
+            created_at: gist.updated_at,
+            updated_at: gist.updated_at,
+            files: files
+                .into_iter()
+                .map(|(filename, content)| {
+                    (filename.clone(), GistFile::from_local(filename, content))
+                })
+                .collect(),
+            git_pull_url: String::new(),
+            truncated: false,
+            history: vec![],
+        };
+        // Without the entity tag, the next refresh fetches the whole Gist.
+        self.files
+            .update(
+                gist,
+                None,
+                &self.node_table,
+                &self.control,
+                &self.exec_policy,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Populate the files from a Gist obtained elsewhere, e.g. a response
+    /// of the API saved by `gist-fs pull --json` and parsed by `Gist::from_json`.
+    pub async fn load_gist(&self, gist: Gist) -> Result<(), Error> {
+        if gist.id != *self.gist_id.get() {
+            return Err(Error::Other(anyhow::anyhow!(
+                "the snapshot is of another Gist: {}",
+                gist.id
+            )));
+        }
+        tracing::info!("load {} files of the Gist {}", gist.files.len(), gist.id);
+        self.files
+            .update(
+                gist,
+                None,
+                &self.node_table,
+                &self.control,
+                &self.exec_policy,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Take a snapshot of the mount state.
+    pub async fn mount_state(&self) -> MountState {
+        self.state_source().snapshot().await
+    }
+
+    fn state_source(&self) -> StateSource {
+        StateSource {
+            client: self.client.clone(),
+            gist_id: self.gist_id.clone(),
+            files: self.files.clone(),
+            errors: self.errors.clone(),
+            node_table: self.node_table.clone(),
+            handles: self.handles.clone(),
+            uploads: self.uploads.clone(),
+            budget: self.budget.clone(),
+            read_only: self.read_only,
+        }
+    }
+
+    /// Serve the mount state on a Unix domain socket at the specified path.
+    ///
+    /// The socket is removed when the returned value is dropped.
+    pub fn serve_state(&self, path: PathBuf) -> io::Result<StateSocket> {
+        let source = self.state_source();
+        StateSocket::bind(path, move || {
+            let source = source.clone();
+            async move { source.snapshot().await }
+        })
+    }
+
+    /// Render the content of a control file.
+    async fn render_control(&self, ino: u64) -> Option<String> {
+        if ino == self.control.errors.nodeid() {
+            Some(self.errors.render(&self.time_format).await)
+        } else if ino == self.control.stats.nodeid() {
+            Some(self.mount_state().await.render_stats(&self.time_format))
+        } else if ino == self.control.inflight.nodeid() {
+            Some(self.inflight.render())
+        } else if ino == self.control.info.nodeid() {
+            Some(self.mount_state().await.render_info(&self.time_format))
+        } else {
+            None
+        }
+    }
+
+    /// Arm the debounce timer for the dirty files.
+    ///
+    /// The timer is disarmed when another write arrives before it fires,
+    /// and is re-armed as long as a write session on the file is open.
+    fn schedule_flush(&self, file: &Arc<GistFileNode>) {
+        file.writes_since_flush.store(0);
+
+        let client = self.client.clone();
+        let remote = self.remote.clone();
+        let gist_id = self.gist_id.clone();
+        let files = self.files.clone();
+        let errors = self.errors.clone();
+        let file = file.clone();
+        let generation = file.generation.load();
+        let shutdown = self.shutdown.clone();
+        let node_table = self.node_table.clone();
+        let uploads = self.uploads.clone();
+        let budget = self.budget.clone();
+
+        tokio::spawn(lock_order::scope(async move {
+            loop {
+                // The pending changes are left to the upload on shutdown.
+                if shutdown.run(files.clock.sleep(FLUSH_DELAY)).await.is_none() {
+                    return;
+                }
+
+                if file.generation.load() != generation {
+                    // The newer write has armed its own timer.
+                    return;
+                }
+
+                if file.writers.load() > 0 {
+                    tracing::debug!("re-arm the flush timer: filename={:?}", file.filename());
+                    continue;
+                }
+
+                if errors.orphaned() {
+                    tracing::error!(
+                        "the Gist is no longer accessible; skip the upload of {:?}",
+                        file.filename()
+                    );
+                    return;
+                }
+
+                // The changes are still uploaded on shutdown, regardless of the limit.
+                let slot = match shutdown.run(uploads.acquire()).await {
+                    Some(slot) => slot,
+                    None => return,
+                };
+                if !files.has_pending().await {
+                    // Another upload has carried the changes.
+                    return;
+                }
+                let remaining = || client.rate_remaining();
+                if shutdown.run(budget.consume(remaining)).await.is_none() {
+                    return;
+                }
+                slot.commit();
+
+                let reason = FlushReason::Timer;
+                let result = files
+                    .flush(&*remote, &gist_id.get(), &node_table, reason)
+                    .await;
+                if let Err(ref err) = result {
+                    tracing::error!("flush failed: {:#}", err);
+                }
+                errors.flushed(&result).await;
+                return;
+            }
+        }));
+    }
+
+    /// Periodically upload the files kept dirty for longer than
+    /// `max_dirty_age` by a long-lived writer.
+    ///
+    /// The debounce timers never fire while the writer keeps the file open.
+    fn spawn_dirty_age_check(&self) {
+        let max_age = match self.max_dirty_age {
+            Some(max_age) => max_age,
+            None => return,
+        };
+
+        let client = self.client.clone();
+        let remote = self.remote.clone();
+        let gist_id = self.gist_id.clone();
+        let files = self.files.clone();
+        let errors = self.errors.clone();
+        let shutdown = self.shutdown.clone();
+        let node_table = self.node_table.clone();
+        let uploads = self.uploads.clone();
+        let budget = self.budget.clone();
+
+        tokio::spawn(lock_order::scope(async move {
+            while shutdown.run(files.clock.sleep(FLUSH_DELAY)).await.is_some() {
+                if errors.orphaned() || !files.has_overdue(max_age).await {
+                    continue;
+                }
+                let slot = match shutdown.run(uploads.acquire()).await {
+                    Some(slot) => slot,
+                    None => return,
+                };
+                let remaining = || client.rate_remaining();
+                if shutdown.run(budget.consume(remaining)).await.is_none() {
+                    return;
+                }
+                slot.commit();
+
+                tracing::info!("upload the files dirty for more than {:?}", max_age);
+                let reason = FlushReason::DirtyAge(max_age);
+                let result = files
+                    .flush(&*remote, &gist_id.get(), &node_table, reason)
+                    .await;
+                if let Err(ref err) = result {
+                    tracing::error!("flush failed: {:#}", err);
+                }
+                errors.flushed(&result).await;
+            }
+        }));
+    }
+
+    /// Run the conflict command on the conflicts left by the merge, one at
+    /// a time, and upload the merged contents.
+    ///
+    /// The triggering upload has already completed with the remote content,
+    /// so the merged one is uploaded as a new change.
+    fn spawn_conflict_command(
+        &self,
+        command: ConflictCommand,
+        mut merges: mpsc::UnboundedReceiver<ConflictMerge>,
+    ) {
+        let client = self.client.clone();
+        let remote = self.remote.clone();
+        let gist_id = self.gist_id.clone();
+        let files = self.files.clone();
+        let errors = self.errors.clone();
+        let shutdown = self.shutdown.clone();
+        let node_table = self.node_table.clone();
+        let uploads = self.uploads.clone();
+        let budget = self.budget.clone();
+
+        tokio::spawn(lock_order::scope(async move {
+            while let Some(Some(merge)) = shutdown.run(merges.next()).await {
+                tracing::info!("run the conflict command: filename={:?}", merge.filename);
+                // The command is killed and its files are removed on shutdown.
+                let merged = command.run(
+                    &merge.filename,
+                    &merge.base,
+                    &merge.local,
+                    &merge.remote,
+                    CONFLICT_COMMAND_TIMEOUT,
+                );
+                let merged = match shutdown.run(merged).await {
+                    Some(Ok(Some(merged))) => merged,
+                    Some(Ok(None)) => continue,
+                    Some(Err(err)) => {
+                        tracing::error!("failed to run the conflict command: {:#}", err);
+                        continue;
+                    }
+                    None => return,
+                };
+                match files.adopt_merge(&node_table, &merge, merged).await {
+                    Ok(true) => {
+                        tracing::info!("adopt the merged content: filename={:?}", merge.filename)
+                    }
+                    Ok(false) => {
+                        tracing::warn!(
+                            "the file has changed during the merge; keep the conflict: filename={:?}",
+                            merge.filename
+                        );
+                        continue;
+                    }
+                    Err(err) => {
+                        tracing::error!("failed to adopt the merged content: {:#}", err);
+                        continue;
+                    }
+                }
+
+                if errors.orphaned() {
+                    continue;
+                }
+                let slot = match shutdown.run(uploads.acquire()).await {
+                    Some(slot) => slot,
+                    None => return,
+                };
+                let remaining = || client.rate_remaining();
+                if shutdown.run(budget.consume(remaining)).await.is_none() {
+                    return;
+                }
+                slot.commit();
+                let result = files
+                    .flush(&*remote, &gist_id.get(), &node_table, FlushReason::Timer)
+                    .await;
+                if let Err(ref err) = result {
+                    tracing::error!("flush failed: {:#}", err);
+                }
+                errors.flushed(&result).await;
+            }
+        }));
+    }
+
+    async fn do_lookup<W: ?Sized>(
+        &self,
+        cx: &mut Context<'_, W>,
+        op: op::Lookup<'_>,
+    ) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        if self.noise_filter
+            && op.parent() == 1
+            && is_noise(op.name())
+            && !self.has_file(op.name()).await
+        {
+            return cx.reply_err(libc::ENOENT).await;
+        }
+        if let Some(errno) = self.check_kind(op.parent(), OpKind::Lookup).await {
+            return cx.reply_err(errno).await;
+        }
+
+        if self.mark_unsynced && op.parent() == 1 {
+            self.files.sync_unsynced_aliases(&self.node_table).await;
+        }
+
+        let resolved = self.name_resolver.resolve(op.name());
+        let mut name = &*resolved;
+        // Not in POSIX: the empty names from the paths with `//`, produced
+        // by some tools, are resolved to the directory itself as `.` is.
+        if name.as_bytes().iter().all(|&b| b == b'/') {
+            name = OsStr::new(".");
+        }
+        if self.files.sanitize_filenames {
+            if let Some(trimmed) = name.to_str().map(sanitize_filename) {
+                if !trimmed.is_empty() && trimmed.len() != name.len() {
+                    tracing::warn!("trim the trailing whitespace of {:?}", name);
+                    name = OsStr::new(trimmed);
+                }
+            }
+        }
+
+        let mut node = self.node_table.lookup(op.parent(), name).await;
+        if node.is_none() && op.parent() == 1 && self.case_insensitive {
+            if let Some(name) = name.to_str() {
+                if let Some(file) = self.files.find_folded(name).await {
+                    let filename = file.filename();
+                    node = self.node_table.lookup(1, OsStr::new(&*filename)).await;
+                }
+            }
+        }
+
+        let attr = match node {
+            Some(node) => Some(node.attr()),
+            None if op.parent() == 1 => match name.to_str().and_then(revision::parse_name) {
+                Some((filename, sha)) => {
+                    match self.shutdown.run(self.lookup_revision(filename, sha)).await {
+                        None => return cx.reply_err(libc::EINTR).await,
+                        Some(Ok(file)) => file.map(|file| file.node.attr()),
+                        Some(Err(err)) => {
+                            tracing::error!("failed to fetch the revision {}: {:#}", sha, err);
+                            return cx.reply_err(libc::EIO).await;
+                        }
+                    }
+                }
+                None => None,
+            },
+            None => None,
+        };
+
+        match attr {
+            Some(attr) => {
+                let mut reply = ReplyEntry::new(attr);
+                reply.entry_valid(0, 0);
+                reply.attr_valid(0, 0);
+                op.reply(cx, reply).await
+            }
+            None if self.negative_entry_valid_secs > 0 => {
+                // An entry with the inode number 0 lets the kernel cache
+                // the absence of the name.
+                let mut reply = ReplyEntry::new(FileAttr::default());
+                reply.entry_valid(self.negative_entry_valid_secs, 0);
+                op.reply(cx, reply).await
+            }
+            None => cx.reply_err(libc::ENOENT).await,
+        }
+    }
+
+    /// Return whether the Gist has a file with the name.
+    async fn has_file(&self, name: &OsStr) -> bool {
+        match name.to_str() {
+            Some(name) => self.files.find(name).await.is_some(),
+            None => false,
+        }
+    }
+
+    async fn do_getattr<W: ?Sized>(
+        &self,
+        cx: &mut Context<'_, W>,
+        op: op::Getattr<'_>,
+    ) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let node = match self.node_table.get(op.ino()).await {
+            Some(node) => node,
+            None => return cx.reply_err(libc::ENOENT).await,
+        };
+
+        if let Some(content) = self.render_control(op.ino()).await {
+            let mut attr = node.attr();
+            attr.set_size(content.len() as u64);
+            node.set_attr(attr);
+        } else if let Some(file) = self.files.get(op.ino()).await {
+            file.validate_size();
+        }
+
+        let mut attr = node.attr();
+        if op.ino() == 1 {
+            attr.set_nlink(self.files.root_nlink().await);
+        } else if let Some(num_subdirs) = node.num_subdirs().await {
+            // Each subdirectory links to the directory by its `..`.
+            attr.set_nlink(2 + num_subdirs as u32);
+        }
+
+        let mut reply = ReplyAttr::new(attr);
+        reply.attr_valid(0, 0);
+        op.reply(cx, reply).await
+    }
+
+    async fn do_opendir<W: ?Sized>(
+        &self,
+        cx: &mut Context<'_, W>,
+        op: op::Opendir<'_>,
+    ) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        if self.node_table.get(op.ino()).await.is_none() {
+            return cx.reply_err(libc::ENOENT).await;
+        }
+        if let Some(errno) = self.check_kind(op.ino(), OpKind::Opendir).await {
+            return cx.reply_err(errno).await;
+        }
+        let is_control = op.ino() == self.control.dir.nodeid();
+
+        // Directories can only be opened for reading, as in open(2).
+        let flags = op.flags() as i32;
+        if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            return cx.reply_err(libc::EISDIR).await;
+        }
+        if flags & (libc::O_CREAT | libc::O_EXCL) == libc::O_CREAT | libc::O_EXCL {
+            return cx.reply_err(libc::EEXIST).await;
+        }
+
+        if self.mark_unsynced && op.ino() == 1 {
+            self.files.sync_unsynced_aliases(&self.node_table).await;
+        }
+
+        if !is_control && self.within_budget() {
+            // opendir(3) opens the directory with these flags, unlike
+            // the programs walking the tree with openat(2).
+            let force = self.always_refresh_on_opendir
+                && op.ino() == 1
+                && flags & (libc::O_DIRECTORY | libc::O_CLOEXEC)
+                    == libc::O_DIRECTORY | libc::O_CLOEXEC;
+            if force {
+                tracing::debug!("force the refresh on opendir(3)");
+            }
+            let result = match self.shutdown.run(self.refresh(force)).await {
+                Some(result) => result,
+                None => return cx.reply_err(libc::EINTR).await,
+            };
+            if let Err(err) = result {
+                tracing::error!("fetch failed: {}", err);
+                // Keep serving the cached files so that the local changes
+                // can be rescued.
+                if !self.errors.orphaned() {
+                    return cx.reply_err(libc::EIO).await;
+                }
+            }
+        }
+
+        let mut reply = ReplyOpendir::new(0);
+        reply.cache_dir(false);
+        op.reply(cx, reply).await
+    }
+
+    async fn do_open<W: ?Sized>(&self, cx: &mut Context<'_, W>, op: op::Open<'_>) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let node = match self.node_table.get(op.ino()).await {
+            Some(node) => node,
+            None => return cx.reply_err(libc::ENOENT).await,
+        };
+        if let Some(errno) = kind::mismatch(InodeKind::of(&node.attr()), OpKind::Open) {
+            return cx.reply_err(errno).await;
+        }
+
+        if self.revisions.get(op.ino()).await.is_some() {
+            // The files at past revisions can never be modified.
+            if op.flags() as i32 & libc::O_ACCMODE != libc::O_RDONLY {
+                return cx.reply_err(libc::EROFS).await;
+            }
+            return op.reply(cx, ReplyOpen::new(self.handles.allocate())).await;
+        }
+
+        let mask = match op.flags() as i32 & libc::O_ACCMODE {
+            libc::O_RDONLY => libc::R_OK,
+            libc::O_WRONLY => libc::W_OK,
+            _ => libc::R_OK | libc::W_OK,
+        };
+        if !self
+            .permissions
+            .check(&node.attr(), cx.uid(), cx.gid(), mask)
+        {
+            return cx.reply_err(libc::EACCES).await;
+        }
+
+        if self.control.is_file(op.ino()) {
+            // The content of control files is rendered on every read.
+            let mut reply = ReplyOpen::new(self.handles.allocate());
+            reply.direct_io(true);
+            return op.reply(cx, reply).await;
+        }
+
+        let mut file = match self.files.get(op.ino()).await {
+            Some(file) => file,
+            None => return cx.reply_err(libc::ENOENT).await,
+        };
+
+        // The local changes are newer than the Gist, so the dirty files
+        // are exempt from the revalidation.
+        if self.consistency == Consistency::Strict && !file.is_dirty() {
+            match self.shutdown.run(self.revalidate()).await {
+                Some(Ok(())) => (),
+                Some(Err(err)) => {
+                    tracing::warn!("revalidation failed; serve the cached content: {}", err)
+                }
+                None => return cx.reply_err(libc::EINTR).await,
+            }
+            // The file may have been deleted on the Gist.
+            file = match self.files.get(op.ino()).await {
+                Some(file) => file,
+                None => return cx.reply_err(libc::ENOENT).await,
+            };
+        }
+
+        let writable = op.flags() as i32 & libc::O_ACCMODE != libc::O_RDONLY;
+        if writable && !self.may_write().await {
+            return cx.reply_err(libc::EROFS).await;
+        }
+        if writable && file.is_streamed() {
+            // Only the truncated part of the content is available locally.
+            return cx.reply_err(libc::EPERM).await;
+        }
+        if writable && (file.is_conflict() || self.exec_policy.is_read_only(&file.filename())) {
+            return cx.reply_err(libc::EPERM).await;
+        }
+        let fh = match self.handles.open(file, writable).await {
+            Ok(fh) => fh,
+            Err(errno) => return cx.reply_err(errno).await,
+        };
+
+        let mut reply = ReplyOpen::new(fh);
+        if self.show_diff && !writable {
+            // The diff makes the content longer than the size of the file.
+            reply.direct_io(true);
+        }
+        op.reply(cx, reply).await
+    }
+
+    /// Create a new file on the Gist.
+    ///
+    /// The node is inserted into the table only after the Gist has been
+    /// updated successfully, so a failed request leaves no trace behind.
+    async fn do_create<W: ?Sized>(
+        &self,
+        cx: &mut Context<'_, W>,
+        op: op::Create<'_>,
+    ) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        if let Some(errno) = self.check_kind(op.parent(), OpKind::Create).await {
+            return cx.reply_err(errno).await;
+        }
+        if !self.permissions.may_write(cx.uid(), cx.gid()) {
+            return cx.reply_err(libc::EACCES).await;
+        }
+        if !self.may_write().await {
+            return cx.reply_err(libc::EROFS).await;
+        }
+        if op.parent() != 1 {
+            return cx.reply_err(libc::EPERM).await;
+        }
+        if self.handles.is_full().await {
+            // Fail before the file is created on the Gist.
+            return cx.reply_err(libc::EMFILE).await;
+        }
+
+        let mut filename = match op.name().to_str() {
+            Some(name) => name.to_owned(),
+            None => return cx.reply_err(libc::EINVAL).await,
+        };
+        if self.files.sanitize_filenames {
+            let trimmed = sanitize_filename(&filename);
+            if !trimmed.is_empty() && trimmed != filename {
+                tracing::warn!("trim the trailing whitespace of {:?}", filename);
+                filename = trimmed.to_owned();
+            }
+        }
+        if self.case_insensitive && self.files.find_folded(&filename).await.is_some() {
+            return cx.reply_err(libc::EEXIST).await;
+        }
+        if is_auto_name(&filename) {
+            tracing::warn!("the Gist may assign another name to {:?}", filename);
+        }
+
+        let mut attr = attr::new_attr(
+            libc::S_IFREG | (op.mode() & !op.umask() & 0o7777),
+            1,
+            self.owner,
+        );
+        attr::set_times(&mut attr, Utc::now());
+
+        let pending = match self
+            .node_table
+            .pending_node(op.parent(), filename.clone().into(), attr)
+            .await
+        {
+            Ok(pending) => pending,
+            Err(errno) => return cx.reply_err(errno.raw()).await,
+        };
+
+        let result = self
+            .files
+            .create(
+                &*self.remote,
+                &self.gist_id.get(),
+                &self.node_table,
+                &filename,
+            )
+            .await;
+        self.errors.flushed(&result).await;
+        let assigned = match result {
+            Ok(assigned) => assigned,
+            Err(err) => {
+                tracing::error!("create failed: {:#}", err);
+                pending.rollback();
+                return cx.reply_err(libc::EIO).await;
+            }
+        };
+
+        let node = match pending.commit().await {
+            Ok(node) => node,
+            Err(errno) => return cx.reply_err(errno.raw()).await,
+        };
+        let uploaded = assigned.is_some();
+        let assigned = assigned.unwrap_or_else(|| filename.clone());
+        if assigned != filename {
+            // The entry is never cached, so the next lookup finds the assigned name.
+            tracing::warn!(
+                "the Gist has assigned another name: requested={:?}, assigned={:?}",
+                filename,
+                assigned
+            );
+            let renamed = self
+                .node_table
+                .root()
+                .rename_child(OsStr::new(&filename), assigned.clone().into())
+                .await;
+            if let Err(errno) = renamed {
+                return cx.reply_err(errno.raw()).await;
+            }
+            filename = assigned;
+        }
+        let file = Arc::new(GistFileNode::new(
+            node,
+            filename,
+            Vec::new(),
+            &self.files.clock,
+        ));
+        file.mode_fixed.store(true);
+        if !uploaded {
+            // The file is created on the Gist by the first flush with content.
+            file.set_remote(None);
+            if file.modified() {
+                self.files.pending_uploads.fetch_add(1);
+            }
+        }
+        self.files.insert(file.clone()).await;
+        self.files.sort_entries(&self.node_table).await;
+
+        let writable = op.open_flags() as i32 & libc::O_ACCMODE != libc::O_RDONLY;
+        let mut entry = ReplyEntry::new(file.node.attr());
+        entry.entry_valid(0, 0);
+        entry.attr_valid(0, 0);
+        let fh = match self.handles.open(file, writable).await {
+            Ok(fh) => fh,
+            Err(errno) => return cx.reply_err(errno).await,
+        };
+
+        op.reply(cx, entry, ReplyOpen::new(fh)).await
+    }
+
+    async fn do_rename<W: ?Sized>(
+        &self,
+        cx: &mut Context<'_, W>,
+        op: op::Rename<'_>,
+    ) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        if !self.permissions.may_write(cx.uid(), cx.gid()) {
+            return cx.reply_err(libc::EACCES).await;
+        }
+        if !self.may_write().await {
+            return cx.reply_err(libc::EROFS).await;
+        }
+        if op.parent() != 1 || op.newparent() != 1 {
+            return cx.reply_err(libc::EPERM).await;
+        }
+        // The Gist cannot swap the names of two files in a single patch.
+        if op.flags() & !libc::RENAME_NOREPLACE != 0 {
+            return cx.reply_err(libc::EINVAL).await;
+        }
+        let noreplace = op.flags() & libc::RENAME_NOREPLACE != 0;
+
+        let (name, newname) = match (op.name().to_str(), op.newname().to_str()) {
+            (Some(name), Some(newname)) => (name, newname),
+            _ => return cx.reply_err(libc::EINVAL).await,
+        };
+        if name == self.control.name() {
+            return cx.reply_err(libc::EPERM).await;
+        }
+        if self.exec_policy.is_read_only(name) || self.exec_policy.is_read_only(newname) {
+            return cx.reply_err(libc::EPERM).await;
+        }
+
+        let file = match self.files.find(name).await {
+            Some(file) => file,
+            None => return cx.reply_err(libc::ENOENT).await,
+        };
+        if file.is_conflict() {
+            return cx.reply_err(libc::EPERM).await;
+        }
+        if is_auto_name(newname) {
+            tracing::warn!("the Gist may assign another name to {:?}", newname);
+        }
+        if self.case_insensitive && self.files.collides_folded(&file, newname).await {
+            return cx.reply_err(libc::EEXIST).await;
+        }
+
+        let rename = match self
+            .files
+            .begin_rename(&self.node_table, file.clone(), newname, noreplace)
+            .await
+        {
+            Ok(rename) => rename,
+            Err(errno) => return cx.reply_err(errno).await,
+        };
+
+        let result = rename
+            .upload(&*self.remote, &self.gist_id.get(), &self.node_table)
+            .await;
+        self.errors.flushed(&result).await;
+        if let Err(err) = result {
+            tracing::error!("rename failed: {:#}", err);
+            rename.rollback().await;
+            return cx.reply_err(libc::EIO).await;
+        }
+        rename.commit().await;
+        file.touch_changed();
+        self.files.sort_entries(&self.node_table).await;
+        if file.remote().is_none() {
+            // The file is created under the new name by the flush.
+            self.schedule_flush(&file);
+        }
+
+        // The new name may change the executable bit.
+        let content = file.content.lock().await.bytes();
+        file.apply_exec_policy(&self.exec_policy, &content[..]);
+
+        op.reply(cx).await
+    }
+
+    async fn do_unlink<W: ?Sized>(
+        &self,
+        cx: &mut Context<'_, W>,
+        op: op::Unlink<'_>,
+    ) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        if !self.permissions.may_write(cx.uid(), cx.gid()) {
+            return cx.reply_err(libc::EACCES).await;
+        }
+        if !self.may_write().await {
+            return cx.reply_err(libc::EROFS).await;
+        }
+        if op.parent() != 1 {
+            return cx.reply_err(libc::EPERM).await;
+        }
+
+        let name = match op.name().to_str() {
+            Some(name) => name,
+            None => return cx.reply_err(libc::ENOENT).await,
+        };
+        if name == self.control.name() {
+            return cx.reply_err(libc::EISDIR).await;
+        }
+        if self.exec_policy.is_read_only(name) {
+            return cx.reply_err(libc::EPERM).await;
+        }
+
+        match self.files.unlink(&self.node_table, name).await {
+            // The deletion is uploaded along with the other pending changes.
+            Ok(Some(file)) => self.schedule_flush(&file),
+            Ok(None) => (),
+            Err(errno) => return cx.reply_err(errno).await,
+        }
+
+        op.reply(cx).await
+    }
+
+    /// Add a local name of a file.
+    ///
+    /// GitHub has no notion of hard links, so the new name is never uploaded
+    /// unless the original name is removed.
+    async fn do_link<W: ?Sized>(&self, cx: &mut Context<'_, W>, op: op::Link<'_>) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        if !self.local_hard_links {
+            return cx.reply_err(libc::EPERM).await;
+        }
+        if !self.permissions.may_write(cx.uid(), cx.gid()) {
+            return cx.reply_err(libc::EACCES).await;
+        }
+        if !self.may_write().await {
+            return cx.reply_err(libc::EROFS).await;
+        }
+        if op.newparent() != 1 {
+            return cx.reply_err(libc::EPERM).await;
+        }
+
+        let newname = match op.newname().to_str() {
+            Some(name) => name,
+            None => return cx.reply_err(libc::EINVAL).await,
+        };
+        if newname == self.control.name() {
+            return cx.reply_err(libc::EEXIST).await;
+        }
+
+        let attr = match self.files.link(&self.node_table, op.ino(), newname).await {
+            Ok(attr) => attr,
+            Err(errno) => return cx.reply_err(errno).await,
+        };
+
+        let mut entry = ReplyEntry::new(attr);
+        entry.entry_valid(0, 0);
+        entry.attr_valid(0, 0);
+        op.reply(cx, entry).await
+    }
+
+    async fn do_read<W: ?Sized>(&self, cx: &mut Context<'_, W>, op: op::Read<'_>) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        if let Some(errno) = self.check_kind(op.ino(), OpKind::Read).await {
+            return cx.reply_err(errno).await;
+        }
+
+        // A zero-length read never touches the content, so reply
+        // before acquiring any locks.
+        if op.size() == 0 {
+            return op.reply(cx, &[]).await;
+        }
+
+        if let Some(content) = self.render_control(op.ino()).await {
+            let offset = std::cmp::min(op.offset() as usize, content.len());
+            let content = &content.as_bytes()[offset..];
+            let len = std::cmp::min(content.len(), op.size() as usize);
+            return op.reply(cx, &content[..len]).await;
+        }
+
+        if let Some(file) = self.revisions.get(op.ino()).await {
+            let offset = std::cmp::min(op.offset() as usize, file.content.len());
+            let content = &file.content[offset..];
+            let len = std::cmp::min(content.len(), op.size() as usize);
+            return op.reply(cx, &content[..len]).await;
+        }
+
+        match self.files.get(op.ino()).await {
+            Some(file) => {
+                if let Some(content) = self.render_diff(op.fh(), &file).await {
+                    let offset = std::cmp::min(op.offset() as usize, content.len());
+                    let content = &content[offset..];
+                    let len = std::cmp::min(content.len(), op.size() as usize);
+                    return op.reply(cx, &content[..len]).await;
+                }
+
+                // The read is dropped on cancellation, releasing the context.
+                let result = self.shutdown.run(file.read(cx, op, &self.client)).await;
+                match result {
+                    Some(result) => result,
+                    None => cx.reply_err(libc::EINTR).await,
+                }
+            }
+            None => cx.reply_err(libc::ENOENT).await,
+        }
+    }
+
+    /// Render the content of a modified text file prefixed with the diff
+    /// of the pending changes, if enabled for the handle.
+    async fn render_diff(&self, fh: u64, file: &GistFileNode) -> Option<Vec<u8>> {
+        if !self.show_diff || !file.is_dirty() {
+            return None;
+        }
+        let handle = self.handles.get(fh).await?;
+        if handle.writable {
+            // The prefix would be written back on read-modify-write.
+            return None;
+        }
+        let is_text = file
+            .content_type()
+            .is_none_or(|(ty, _)| ty.type_() == mime::TEXT);
+        if !is_text {
+            return None;
+        }
+
+        let base = file.base();
+        let current = file.content.lock().await.bytes();
+        let base = std::str::from_utf8(&base).ok()?;
+        let current = std::str::from_utf8(&current).ok()?;
+        let patch = diffy::create_patch(base, current);
+        Some(format!("# PENDING CHANGES:\n{}{}", patch, current).into_bytes())
+    }
+
+    async fn do_write<W: ?Sized, T>(
+        &self,
+        cx: &mut Context<'_, W>,
+        op: op::Write<'_>,
+        data: T,
+    ) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+        T: AsRef<[u8]>,
+    {
+        if let Some(errno) = self.check_kind(op.ino(), OpKind::Write).await {
+            return cx.reply_err(errno).await;
+        }
+        if !self.client.is_authenticated() {
+            // The content could never be uploaded.
+            tracing::warn!("a GitHub access token is required to modify the Gist");
+            return cx.reply_err(libc::EPERM).await;
+        }
+
+        let file = match self.handles.get(op.fh()).await {
+            Some(handle) if handle.writable => handle.file,
+            _ => return cx.reply_err(libc::EBADF).await,
+        };
+
+        let content = data.as_ref();
+
+        if let Err(errno) = self.break_local_links(&file).await {
+            return cx.reply_err(errno.raw()).await;
+        }
+
+        match file.write(op.offset(), content, &self.exec_policy).await {
+            Ok(true) => {
+                self.files.pending_uploads.fetch_add(1);
+            }
+            Ok(false) => (),
+            Err(errno) => return cx.reply_err(errno).await,
+        }
+        let writes = file.writes_since_flush.fetch_add(1) + 1;
+        if self.is_write_buffered(file.node.attr().size(), writes) {
+            tracing::debug!("defer the upload: filename={:?}", file.filename());
+        } else {
+            self.schedule_flush(&file);
+        }
+
+        if let Some(ref audit_log) = self.audit_log {
+            let record = WriteRecord {
+                timestamp: Utc::now(),
+                pid: cx.pid(),
+                ino: op.ino(),
+                offset: op.offset(),
+                size: op.size(),
+            };
+            if let Err(err) = audit_log.record(&record).await {
+                tracing::error!("failed to write the audit log: {}", err);
+            }
+        }
+
+        let size = op.size();
+        op.reply(cx, ReplyWrite::new(size)).await
+    }
+
+    async fn do_setattr<W: ?Sized>(
+        &self,
+        cx: &mut Context<'_, W>,
+        op: op::Setattr<'_>,
+    ) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        if !self.permissions.may_write(cx.uid(), cx.gid()) {
+            return cx.reply_err(libc::EACCES).await;
+        }
+
+        if self.revisions.get(op.ino()).await.is_some() {
+            return cx.reply_err(libc::EROFS).await;
+        }
+
+        if op.ino() == self.control.errors.nodeid() {
+            // Truncating the error log clears it.
+            return match op.size() {
+                Some(0) => {
+                    self.errors.clear().await;
+                    let mut reply = ReplyAttr::new(self.control.errors.attr());
+                    reply.attr_valid(0, 0);
+                    op.reply(cx, reply).await
+                }
+                _ => cx.reply_err(libc::EPERM).await,
+            };
+        }
+
+        let file = match self.files.get(op.ino()).await {
+            Some(file) => file,
+            None => return cx.reply_err(libc::EPERM).await,
+        };
+        if file.is_conflict() || self.exec_policy.is_read_only(&file.filename()) {
+            return cx.reply_err(libc::EPERM).await;
+        }
+
+        if op.size().is_some() && !self.may_write().await {
+            return cx.reply_err(libc::EROFS).await;
+        }
+        if op.size().is_some() && file.is_streamed() {
+            return cx.reply_err(libc::EPERM).await;
+        }
+        // Checked ahead of the other attributes not to apply them partially.
+        if op.size().is_some_and(|size| size > MAX_CONTENT_SIZE) {
+            return cx.reply_err(libc::EFBIG).await;
+        }
+
+        // `UTIME_NOW` is reported with the flag, and `UTIME_OMIT` as `None`.
+        let now = attr::to_timespec(Utc::now());
+        let mtime = match op.mtime() {
+            Some((_, _, true)) => Some(now),
+            Some((sec, nsec, false)) => {
+                if !self.is_valid_mtime(sec) {
+                    return cx.reply_err(libc::EINVAL).await;
+                }
+                Some((sec, nsec))
+            }
+            None => None,
+        };
+        let atime = match op.atime() {
+            Some((_, _, true)) => Some(now),
+            Some((sec, nsec, false)) => Some((sec, nsec)),
+            None => None,
+        };
+
+        if let Some(mode) = op.mode() {
+            file.chmod(mode);
+            // The stored ACL no longer matches the permission bits.
+            self.acls.lock().await.remove(&op.ino());
+        }
+
+        if op.mode().is_some() || mtime.is_some() || atime.is_some() {
+            let mut attr = file.node.attr();
+            if let Some((sec, nsec)) = mtime {
+                attr.set_mtime(sec, nsec);
+            }
+            if let Some((sec, nsec)) = atime {
+                attr.set_atime(sec, nsec);
+            }
+            attr::touch_changed(&mut attr, Utc::now());
+            file.node.set_attr(attr);
+        }
+
+        if let Some(size) = op.size() {
+            if let Err(errno) = self.break_local_links(&file).await {
+                return cx.reply_err(errno.raw()).await;
+            }
+            match file.truncate(size, &self.exec_policy).await {
+                Ok(true) => {
+                    self.files.pending_uploads.fetch_add(1);
+                }
+                Ok(false) => (),
+                Err(errno) => return cx.reply_err(errno).await,
+            }
+            self.schedule_flush(&file);
+        }
+
+        let mut reply = ReplyAttr::new(file.node.attr());
+        reply.attr_valid(0, 0);
+        op.reply(cx, reply).await
+    }
+
+    /// Return the error of applying the operation to the inode if its kind
+    /// does not match.
+    ///
+    /// The missing inodes are left to the handlers.
+    async fn check_kind(&self, ino: u64, op: OpKind) -> Option<i32> {
+        let node = self.node_table.get(ino).await?;
+        kind::mismatch(InodeKind::of(&node.attr()), op)
+    }
+
+    /// Return whether the upload of the written file is deferred until
+    /// it is closed, given its size and the number of the deferred writes.
+    fn is_write_buffered(&self, size: u64, writes: u32) -> bool {
+        if self.min_write_size == 0 && self.min_write_count == 0 {
+            return false;
+        }
+        let size_reached = self.min_write_size > 0 && size >= self.min_write_size as u64;
+        let count_reached = self.min_write_count > 0 && writes >= self.min_write_count;
+        !size_reached && !count_reached
+    }
+
+    /// Return whether the modifications are rejected, either by the option
+    /// or because the Gist is no longer accessible.
+    fn is_read_only(&self) -> bool {
+        (self.read_only && !self.gist_id.is_forked()) || self.errors.orphaned()
+    }
+
+    /// Return whether the mount accepts the modifications, forking the
+    /// Gist on the first attempt if `fork_on_write` is enabled.
+    async fn may_write(&self) -> bool {
+        if self.fork_on_write && self.is_read_only() && !self.errors.orphaned() {
+            let _guard = self.forking.lock().await;
+            if !self.gist_id.is_forked() {
+                if let Err(err) = self.fork().await {
+                    tracing::error!("failed to fork the Gist; stay read-only: {:#}", err);
+                    self.errors.record(ErrorKind::Fork, &err).await;
+                }
+            }
+        }
+        !self.is_read_only()
+    }
+
+    /// Fork the Gist and upload the subsequent changes to the fork.
+    ///
+    /// The files are kept in memory as they are, since the fork has the
+    /// same content as the original.
+    async fn fork(&self) -> anyhow::Result<()> {
+        let original = self.gist_id.get();
+        let (gist, etag) = self.client.fork_gist(&original).await?;
+        // The entity tag of the original Gist is meaningless for the fork.
+        *self.files.etag.lock().await = etag;
+        let fork = self.gist_id.switch_to_fork(gist.id.into());
+        tracing::info!(
+            "forked the Gist {} into {} at {}; the changes are uploaded to the fork",
+            fork.original,
+            fork.fork,
+            fork.forked_at
+        );
+        Ok(())
+    }
+
+    /// Return whether the modification time may be set to the specified value.
+    ///
+    /// The value must be representable as `chrono::DateTime` and must not be
+    /// too far in the future, which usually indicates a broken tool.
+    fn is_valid_mtime(&self, sec: u64) -> bool {
+        if sec > i64::MAX as u64 {
+            return false;
+        }
+        match self.max_mtime_offset {
+            Some(offset) => {
+                let limit = Utc::now()
+                    .timestamp()
+                    .saturating_add(offset.as_secs() as i64);
+                sec as i64 <= limit
+            }
+            None => true,
+        }
+    }
+
+    /// Freeze the local links of the file before it is modified, so the
+    /// modification is not visible through the other names.
+    ///
+    /// The handles do not tell which name the file was opened with, so
+    /// the file on the Gist takes the modification and each of the local
+    /// links becomes a read-only copy of the current content.
+    async fn break_local_links(&self, file: &GistFileNode) -> Result<(), Errno> {
+        if file.node.attr().nlink() <= 1 {
+            return Ok(());
+        }
+
+        let ino = file.node.nodeid();
+        let mut links = self.files.links.lock().await;
+        let aliases: Vec<String> = links
+            .iter()
+            .filter(|(_, &linked)| linked == ino)
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        let (content, _) = file.snapshot().await;
+        let root = self.node_table.root();
+        for alias in aliases {
+            let mut attr = file.node.attr();
+            attr.set_mode(libc::S_IFREG | (attr.mode() & 0o555));
+            // Incremented by the link below.
+            attr.set_nlink(0);
+            let node = self.node_table.new_detached(attr).await?;
+
+            root.remove_child(OsStr::new(&alias)).await?;
+            root.link_child(alias.clone().into(), &node).await?;
+            file.unlink_one();
+            tracing::debug!("freeze the local link {:?}", alias);
+
+            links.insert(alias, node.nodeid());
+            let copy = RevisionFile {
+                node,
+                content: content.clone(),
+            };
+            self.revisions.insert_copy(Arc::new(copy)).await;
+        }
+
+        Ok(())
+    }
+
+    /// Resolve the file at the specified revision, fetching it on the first lookup.
+    ///
+    /// Returns `None` if the revision or the file does not exist.
+    async fn lookup_revision(
+        &self,
+        filename: &str,
+        sha: &str,
+    ) -> anyhow::Result<Option<Arc<RevisionFile>>> {
+        if let Some(file) = self.revisions.find(filename, sha).await {
+            return Ok(Some(file));
+        }
+        if self.offline {
+            return Ok(None);
+        }
+
+        let client = &self.client;
+        self.budget.consume(|| client.rate_remaining()).await;
+        let gist_id = self.gist_id.get();
+        let mut gist = match self.client.fetch_gist_revision(&gist_id, sha).await? {
+            Some(gist) => gist,
+            None => return Ok(None),
+        };
+        let gist_file = match gist.files.remove(filename) {
+            Some(gist_file) => gist_file,
+            None => return Ok(None),
+        };
+
+        let committed_at = gist
+            .history
+            .iter()
+            .find(|entry| entry.version.starts_with(sha))
+            .map_or(gist.updated_at, |entry| entry.committed_at);
+        let content = gist_file.into_content_bytes();
+
+        let mut attr = attr::new_attr(libc::S_IFREG | 0o444, 1, self.owner);
+        attr.set_size(content.len() as u64);
+        attr::set_times(&mut attr, committed_at);
+
+        let node = self.node_table.new_detached(attr).await?;
+        let file = Arc::new(RevisionFile {
+            node,
+            content: Arc::new(content),
+        });
+        self.revisions.insert(filename, sha, file.clone()).await;
+
+        Ok(Some(file))
+    }
+
+    /// Upload the file written through the handle on close, so that
+    /// `close(2)` reports the failure.
+    async fn do_flush<W: ?Sized>(
+        &self,
+        cx: &mut Context<'_, W>,
+        op: op::Flush<'_>,
+    ) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let file = match self.handles.get(op.fh()).await {
+            Some(handle) if handle.writable => handle.file,
+            Some(_) => return op.reply(cx).await,
+            None => return cx.reply_err(libc::EBADF).await,
+        };
+        if !file.is_dirty() {
+            return op.reply(cx).await;
+        }
+
+        if self.errors.orphaned() {
+            tracing::error!("the Gist is no longer accessible; the content is not uploaded");
+            return cx.reply_err(libc::EROFS).await;
+        }
+        // Left to the timer, which waits for the limits.
+        let slot = match self.uploads.acquire_now() {
+            Some(slot) => slot,
+            None => {
+                tracing::debug!("queue the upload: filename={:?}", file.filename());
+                self.schedule_flush(&file);
+                return op.reply(cx).await;
+            }
+        };
+        if !self.budget.try_consume(self.client.rate_remaining()) {
+            self.schedule_flush(&file);
+            return op.reply(cx).await;
+        }
+        slot.commit();
+
+        file.writes_since_flush.store(0);
+        let reason = FlushReason::Close(file.node.nodeid());
+        let result = self
+            .files
+            .flush(&*self.remote, &self.gist_id.get(), &self.node_table, reason)
+            .await;
+        self.errors.flushed(&result).await;
+        match result {
+            Ok(()) => op.reply(cx).await,
+            Err(err) => {
+                tracing::error!("flush on close failed: {:#}", err);
+                let errno = err
+                    .downcast_ref::<PolicyViolation>()
+                    .map_or(libc::EIO, |violation| violation.error_code);
+                cx.reply_err(errno).await
+            }
+        }
+    }
+
+    async fn do_fsync<W: ?Sized>(
+        &self,
+        cx: &mut Context<'_, W>,
+        op: op::Fsync<'_>,
+    ) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let file = match self.handles.get(op.fh()).await {
+            Some(handle) => handle.file,
+            None => return cx.reply_err(libc::EBADF).await,
+        };
+
+        if self.errors.orphaned() {
+            tracing::error!("the Gist is no longer accessible; the content is not uploaded");
+            return cx.reply_err(libc::EROFS).await;
+        }
+
+        // The writer calling fsync considers the content complete,
+        // so its own write session does not hold the upload back.
+        let reason = FlushReason::Fsync(file.node.nodeid());
+        // The caller waits for the upload, so it also waits for the token.
+        let slot = match self.shutdown.run(self.uploads.acquire()).await {
+            Some(slot) => slot,
+            None => return cx.reply_err(libc::EINTR).await,
+        };
+        if !self.budget.try_consume(self.client.rate_remaining()) {
+            tracing::warn!("upload beyond the share of the rate limit on fsync");
+            self.budget.borrow();
+        }
+        slot.commit();
+        let result = self
+            .files
+            .flush(&*self.remote, &self.gist_id.get(), &self.node_table, reason)
+            .await;
+        self.errors.flushed(&result).await;
+        match result {
+            Ok(()) => op.reply(cx).await,
+            Err(err) => {
+                tracing::error!("flush failed: {:#}", err);
+                let errno = err
+                    .downcast_ref::<PolicyViolation>()
+                    .map_or(libc::EIO, |violation| violation.error_code);
+                cx.reply_err(errno).await
+            }
+        }
+    }
+
+    async fn do_getxattr<W: ?Sized>(
+        &self,
+        cx: &mut Context<'_, W>,
+        op: op::Getxattr<'_>,
+    ) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let node = match self.node_table.get(op.ino()).await {
+            Some(node) => node,
+            None => return cx.reply_err(libc::ENOENT).await,
+        };
+
+        let value = if op.ino() == 1 && op.name() == PENDING_OPERATIONS_XATTR {
+            self.files.pending_uploads.load().to_string().into_bytes()
+        } else if op.ino() == 1 && op.name() == PUBLIC_XATTR {
+            match *self.files.metadata.lock().await {
+                Some(ref metadata) => metadata.public.to_string().into_bytes(),
+                None => return cx.reply_err(libc::ENODATA).await,
+            }
+        } else if op.name() == MIME_TYPE_XATTR {
+            match self.mime_of(op.ino()).await {
+                Some(mime) => mime.to_string().into_bytes(),
+                None => return cx.reply_err(libc::ENODATA).await,
+            }
+        } else if op.name() == SYNCED_XATTR {
+            match self.files.get(op.ino()).await {
+                Some(file) => (!file.is_dirty()).to_string().into_bytes(),
+                None => return cx.reply_err(libc::ENODATA).await,
+            }
+        } else if op.name() == LANGUAGE_XATTR {
+            let content_type = match self.files.get(op.ino()).await {
+                Some(file) => file.content_type(),
+                None => None,
+            };
+            match content_type {
+                Some((_, language)) => language.into_bytes(),
+                None => return cx.reply_err(libc::ENODATA).await,
+            }
+        } else if op.name() == acl::POSIX_ACL_ACCESS {
+            match self.acls.lock().await.get(&op.ino()) {
+                Some(value) => value.clone(),
+                None => acl::from_mode(node.attr().mode()),
+            }
+        } else {
+            return cx.reply_err(libc::ENODATA).await;
+        };
+
+        match op.size() {
+            0 => op.reply_size(cx, ReplyXattr::new(value.len() as u32)).await,
+            size if (size as usize) < value.len() => cx.reply_err(libc::ERANGE).await,
+            _ => op.reply(cx, value).await,
+        }
+    }
+
+    /// Store the ACL and reflect it to the permission bits.
+    ///
+    /// The ACLs are local to the mount and are never uploaded to the Gist.
+    async fn do_setxattr<W: ?Sized>(
+        &self,
+        cx: &mut Context<'_, W>,
+        op: op::Setxattr<'_>,
+    ) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        if !self.permissions.may_write(cx.uid(), cx.gid()) {
+            return cx.reply_err(libc::EACCES).await;
+        }
+
+        let node = match self.node_table.get(op.ino()).await {
+            Some(node) => node,
+            None => return cx.reply_err(libc::ENOENT).await,
+        };
+
+        if op.ino() == 1 && op.name() == PUBLIC_XATTR {
+            tracing::warn!(
+                "the visibility of a Gist cannot be changed in place; \
+                 run `gist-fs convert-visibility {} --to <public|secret>` \
+                 to copy it into a new Gist instead",
+                self.gist_id.get()
+            );
+            return cx.reply_err(libc::ENOTSUP).await;
+        }
+        if op.name() != acl::POSIX_ACL_ACCESS {
+            return cx.reply_err(libc::ENOTSUP).await;
+        }
+
+        let mode = match acl::to_mode(op.value(), node.attr().mode()) {
+            Some(mode) => mode,
+            None => return cx.reply_err(libc::EINVAL).await,
+        };
+        match self.files.get(op.ino()).await {
+            Some(file) => file.chmod(mode),
+            None => {
+                let mut attr = node.attr();
+                attr.set_mode(mode);
+                node.set_attr(attr);
+            }
+        }
+
+        self.acls
+            .lock()
+            .await
+            .insert(op.ino(), op.value().to_owned());
+
+        op.reply(cx).await
+    }
+
+    /// Answer access(2) with the same rules as the ones enforced by
+    /// the other operations.
+    async fn do_access<W: ?Sized>(
+        &self,
+        cx: &mut Context<'_, W>,
+        op: op::Access<'_>,
+    ) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let node = match self.node_table.get(op.ino()).await {
+            Some(node) => node,
+            None => return cx.reply_err(libc::ENOENT).await,
+        };
+
+        if self
+            .permissions
+            .check(&node.attr(), cx.uid(), cx.gid(), op.mask() as i32)
+        {
+            op.reply(cx).await
+        } else {
+            cx.reply_err(libc::EACCES).await
+        }
+    }
+
+    async fn do_release<W: ?Sized>(
+        &self,
+        cx: &mut Context<'_, W>,
+        op: op::Release<'_>,
+    ) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        if let Some(handle) = self.handles.release(op.fh()).await {
+            if handle.writable && handle.file.is_dirty() {
+                self.backup(&handle.file).await;
+            }
+            if handle.file.writes_since_flush.load() > 0 {
+                // The deferred writes are uploaded once the file is closed.
+                self.schedule_flush(&handle.file);
+            }
+            if !self.handles.is_open(&handle.file).await {
+                handle.file.close_window().await;
+            }
+        }
+        op.reply(cx).await
+    }
+
+    /// Save the current content of the file into the backup directory, if any.
+    ///
+    /// The failure is only logged, so that the upload is not prevented.
+    async fn backup(&self, file: &GistFileNode) {
+        if let Some(ref backups) = self.backups {
+            let filename = file.filename();
+            let (content, _) = file.snapshot().await;
+            match backups.save(&filename, &content).await {
+                Ok(path) => tracing::debug!("backed up {:?} to {:?}", filename, path),
+                Err(err) => tracing::warn!("failed to back up {:?}: {}", filename, err),
+            }
+        }
+    }
+}
+
+#[polyfuse::async_trait]
+impl<T> Filesystem<T> for GistFs
+where
+    T: AsRef<[u8]>,
+{
+    async fn call<W: ?Sized>(&self, cx: &mut Context<'_, W>, op: Operation<'_, T>) -> io::Result<()>
+    where
+        T: Send + 'async_trait,
+        W: AsyncWrite + Unpin + Send,
+    {
+        // The operation is listed in `.gistfs/inflight` until the handler returns,
+        // and the logs are tagged with the ID assigned to the request.
+        let (name, ino) = inflight::describe(&op);
+        let inflight = self.inflight.register(name, ino);
+        let span = tracing::debug_span!("op", id = inflight.id(), op = name, ino);
+
+        async move {
+            let result = lock_order::scope(self.dispatch(cx, op)).await;
+            match result {
+                Err(err) if is_disconnected(&err) => {
+                    tracing::error!("the connection to the kernel is lost: {}", err);
+                    Err(err)
+                }
+                Err(err) => {
+                    // A failed request must not stop serving the others.
+                    tracing::error!("failed to reply: {}", err);
+                    if let Err(err) = cx.reply_err(libc::EIO).await {
+                        if is_disconnected(&err) {
+                            return Err(err);
+                        }
+                        // The kernel rejects the reply to a request already
+                        // answered or interrupted.
+                        tracing::debug!("failed to reply EIO: {}", err);
+                    }
+                    Ok(())
+                }
+                Ok(()) => Ok(()),
+            }
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+impl GistFs {
+    #[allow(clippy::cognitive_complexity)]
+    async fn dispatch<W: ?Sized, T>(
+        &self,
+        cx: &mut Context<'_, W>,
+        op: Operation<'_, T>,
+    ) -> io::Result<()>
+    where
+        T: AsRef<[u8]>,
+        W: AsyncWrite + Unpin,
+    {
+        match op {
+            Operation::Lookup(op) => self.do_lookup(cx, op).await?,
+
+            Operation::Forget(forgets) => self.node_table.forget(forgets).await,
+
+            Operation::Getattr(op) => self.do_getattr(cx, op).await?,
+
+            Operation::Setattr(op) => self.do_setattr(cx, op).await?,
+
+            Operation::Opendir(op) => self.do_opendir(cx, op).await?,
+
+            Operation::Readdir(op) => match self.node_table.get(op.ino()).await {
+                Some(node) => match kind::mismatch(InodeKind::of(&node.attr()), OpKind::Readdir) {
+                    Some(errno) => cx.reply_err(errno).await?,
+                    None => node.readdir(cx, op).await?,
+                },
+                None => cx.reply_err(libc::ENOENT).await?,
+            },
+
+            Operation::Create(op) => self.do_create(cx, op).await?,
+            Operation::Rename(op) => self.do_rename(cx, op).await?,
+            Operation::Unlink(op) => self.do_unlink(cx, op).await?,
+            Operation::Link(op) => self.do_link(cx, op).await?,
+            Operation::Open(op) => self.do_open(cx, op).await?,
+            Operation::Read(op) => self.do_read(cx, op).await?,
+            Operation::Write(op, data) => self.do_write(cx, op, data).await?,
+            Operation::Flush(op) => self.do_flush(cx, op).await?,
+            Operation::Fsync(op) => self.do_fsync(cx, op).await?,
+            Operation::Release(op) => self.do_release(cx, op).await?,
+
+            Operation::Access(op) => self.do_access(cx, op).await?,
+
+            Operation::Getxattr(op) => self.do_getxattr(cx, op).await?,
+            Operation::Setxattr(op) => self.do_setxattr(cx, op).await?,
+
+            _ => (),
+        }
+
+        Ok(())
+    }
+}
+
+/// Return whether the error means the connection to the kernel is gone,
+/// in which case no more requests can be answered.
+fn is_disconnected(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(libc::ENODEV) | Some(libc::EBADF) | Some(libc::EPIPE) => true,
+        _ => matches!(
+            err.kind(),
+            io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionAborted
+        ),
+    }
+}
+
+// ==== Files ====
+
+/// The files of the Gist and the state of their uploads.
+///
+/// The locks are acquired in the order documented in `lock_order`.
+#[derive(Default)]
+struct GistFiles {
+    etag: Mutex<Option<ETag>>,
+    files: OrderedMutex<HashMap<u64, Arc<GistFileNode>>, lock_order::Files>,
+    flush_lock: OrderedMutex<(), lock_order::Flush>,
+    normalize_unicode: bool,
+
+    /// The files removed locally whose deletion has not been uploaded.
+    unlinked: OrderedMutex<Vec<Arc<GistFileNode>>, lock_order::Unlinked>,
+
+    metadata: Mutex<Option<GistMetadata>>,
+    streaming: bool,
+
+    /// The number of files modified locally whose content has not been uploaded.
+    pending_uploads: AtomicCell<u64>,
+
+    conflict_resolution: ConflictStrategy,
+
+    /// The conflicts handed to the conflict command.
+    conflict_merges: Option<mpsc::UnboundedSender<ConflictMerge>>,
+
+    /// The recently uploaded versions of the files.
+    undo: Option<UndoTree>,
+
+    owner: OwnerIds,
+    sanitize_filenames: bool,
+
+    /// The size above which the clean files are compressed in memory,
+    /// or zero to disable the compression.
+    compress_threshold: usize,
+
+    /// The inode numbers assigned to the filenames, kept after the files
+    /// are removed so that a re-added file gets the same number.
+    filename_to_ino: OrderedMutex<HashMap<String, u64>, lock_order::FilenameToIno>,
+
+    /// The names added by link(2), which exist only on the mount.
+    links: OrderedMutex<HashMap<String, u64>, lock_order::Links>,
+
+    /// Whether the Gist contains the files whose MIME type is not text.
+    has_binary: AtomicCell<bool>,
+
+    /// The pattern of the MIME types of the files to be mounted.
+    mime_filter: Option<Regex>,
+
+    /// Whether a newline is uploaded in place of the empty content,
+    /// rather than keeping the file dirty.
+    empty_file_placeholder: bool,
+
+    /// The aliases `<filename>.unsynced` of the dirty files, by their inode numbers.
+    unsynced_aliases: Mutex<HashMap<u64, String>>,
+
+    content_transformer: Box<dyn ContentTransformer>,
+    content_policy: Box<dyn ContentPolicy>,
+    clock: SharedClock,
+    file_order: FileOrder,
+
+    /// The log shared with the mount, recording the files skipped by an upload.
+    errors: Arc<ErrorLog>,
+}
+
+impl GistFiles {
+    /// Return the media type to fetch the content of the Gist in.
+    fn media_type(&self) -> GistMediaType {
+        if self.has_binary.load() {
+            GistMediaType::Base64
+        } else {
+            GistMediaType::Json
+        }
+    }
+
+    async fn get(&self, ino: u64) -> Option<Arc<GistFileNode>> {
+        let files = self.files.lock().await;
+        files.get(&ino).cloned()
+    }
+
+    async fn find(&self, filename: &str) -> Option<Arc<GistFileNode>> {
+        let files = self.files.lock().await;
+        files
+            .values()
+            .find(|file| *file.filename() == *filename)
+            .cloned()
+    }
+
+    /// Find a file whose name matches the specified one ignoring case.
+    async fn find_folded(&self, filename: &str) -> Option<Arc<GistFileNode>> {
+        let files = self.files.lock().await;
+        files
+            .values()
+            .find(|file| unicase::eq(&*file.filename(), filename))
+            .cloned()
+    }
+
+    /// Return whether renaming the file collides with another file whose
+    /// name differs from the new name only in case.
+    ///
+    /// The file of the exact name is replaced by the rename instead.
+    async fn collides_folded(&self, file: &Arc<GistFileNode>, newname: &str) -> bool {
+        self.find_folded(newname)
+            .await
+            .is_some_and(|other| !Arc::ptr_eq(&other, file) && *other.filename() != *newname)
+    }
+
+    /// Return the raw URL to stream the content from, if the content
+    /// in the API response is truncated.
+    fn stream_source(&self, gist_file: &GistFile) -> Option<String> {
+        if self.streaming && gist_file.truncated {
+            Some(gist_file.raw_url.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Return the number of files and the dirty ones with their sizes.
+    async fn stats(&self) -> (usize, Vec<DirtyFile>) {
+        let files: Vec<_> = self.files.lock().await.values().cloned().collect();
+
+        let mut dirty_files = vec![];
+        for file in files.iter().filter(|file| file.is_dirty()) {
+            dirty_files.push(DirtyFile {
+                filename: file.filename().to_string(),
+                size: file.content.lock().await.len(),
+            });
+        }
+        let order = self.file_order;
+        dirty_files.sort_by(|a, b| {
+            order
+                .compare_names(&a.filename, &b.filename)
+                .unwrap_or(Ordering::Equal)
+        });
+        (files.len(), dirty_files)
+    }
+
+    async fn update(
+        &self,
+        gist: Gist,
+        etag: Option<ETag>,
+        node_table: &NodeTable,
+        control: &ControlDir,
+        exec_policy: &ExecPolicy,
+    ) -> anyhow::Result<()> {
+        // The metadata of the Gist is updated regardless of the files.
+        let mut root_attr = node_table.root().attr();
+        attr::set_times(&mut root_attr, gist.updated_at);
+        node_table.root().set_attr(root_attr);
+        self.metadata.lock().await.replace(GistMetadata {
+            description: gist.description,
+            public: gist.public,
+            updated_at: gist.updated_at,
+        });
+        self.has_binary
+            .store(gist.files.values().any(|gist_file| !gist_file.is_text()));
+
+        let old_files = {
+            let mut files = self.files.lock().await;
+
+            let mut new_files = HashMap::with_capacity(files.len());
+
+            // The conflict files are unknown to the Gist.
+            let conflicts: Vec<u64> = files
+                .iter()
+                .filter(|(_, file)| file.is_conflict())
+                .map(|(ino, _)| *ino)
+                .collect();
+            for ino in conflicts {
+                new_files.insert(ino, files.remove(&ino).unwrap());
+            }
+
+            // The files are visited in no particular order, so the trimmed
+            // name is checked against all the names of the Gist.
+            let gist_names: HashSet<String> = if self.sanitize_filenames {
+                gist.files.keys().cloned().collect()
+            } else {
+                HashSet::new()
+            };
+
+            for (filename, gist_file) in gist.files {
+                if let Some(ref pattern) = self.mime_filter {
+                    if !pattern.is_match(gist_file.type_.as_ref()) {
+                        tracing::debug!(
+                            "skip the file of the filtered type: filename={:?}, type={}",
+                            filename,
+                            gist_file.type_
+                        );
+                        continue;
+                    }
+                }
+                if self.is_unlinked(&filename).await {
+                    tracing::debug!("skip the file to be deleted: filename={:?}", filename);
+                    continue;
+                }
+
+                let ino = files
+                    .iter()
+                    .find(|(_, file)| file.remote().as_deref() == Some(&*filename))
+                    .map(|(ino, _)| *ino);
+                match ino {
+                    Some(ino) => {
+                        let file = files.remove(&ino).unwrap();
+                        // The protected files always follow the Gist.
+                        let protected = exec_policy.is_read_only(&file.filename());
+                        let discarded = protected && file.mark_synced(file.generation.load()).await;
+                        if discarded {
+                            self.pending_uploads.fetch_sub(1);
+                        }
+                        if !protected && (file.is_dirty() || file.writers.load() > 0) {
+                            tracing::debug!(
+                                "keep the local content: filename={:?}",
+                                gist_file.filename
+                            );
+                        } else if !discarded && file.is_same_origin(&gist_file) {
+                            tracing::debug!("unchanged file: filename={:?}", gist_file.filename);
+                        } else {
+                            tracing::debug!(
+                                "update an exist file: filename={:?}",
+                                gist_file.filename
+                            );
+                            file.set_origin(&gist_file.raw_url, gist_file.size);
+                            file.set_content_type(&gist_file);
+                            file.set_stream(self.stream_source(&gist_file)).await;
+                            let size = gist_file.size;
+                            file.update_content(size, gist_file.into_content_bytes(), exec_policy)
+                                .await;
+                            // The clean file adopts the time of the update on the Gist.
+                            let mut attr = file.node.attr();
+                            attr::set_times(&mut attr, gist.updated_at);
+                            file.node.set_attr(attr);
+                        }
+                        new_files.insert(ino, file);
+                    }
+                    None => {
+                        tracing::debug!("new file: filename={:?}", gist_file.filename);
+                        if filename == CONTROL_DIR {
+                            control.relocate(node_table).await?;
+                        }
+
+                        let attr = attr::attr_from_gist_file(
+                            &gist_file,
+                            exec_policy.permissions(&filename, gist_file.content_bytes()),
+                            gist.updated_at,
+                            self.owner,
+                        );
+
+                        let mut local = filename.clone();
+                        if self.sanitize_filenames {
+                            let trimmed = sanitize_filename(&filename);
+                            let taken = gist_names.contains(trimmed)
+                                || files
+                                    .values()
+                                    .chain(new_files.values())
+                                    .any(|file| *file.filename() == *trimmed);
+                            if trimmed != filename && !trimmed.is_empty() && !taken {
+                                tracing::warn!(
+                                    "trim the trailing whitespace of {:?}; \
+                                     the file is renamed on the next upload",
+                                    filename
+                                );
+                                local = trimmed.to_owned();
+                            }
+                        }
+
+                        let ino = self.filename_to_ino.lock().await.get(&local).copied();
+                        let node = match ino {
+                            Some(ino) => {
+                                node_table
+                                    .root()
+                                    .new_child_with_ino(local.clone().into(), attr, ino)
+                                    .await
+                            }
+                            None => {
+                                node_table
+                                    .root()
+                                    .new_child(local.clone().into(), attr)
+                                    .await
+                            }
+                        }?;
+                        self.filename_to_ino
+                            .lock()
+                            .await
+                            .insert(local.clone(), node.nodeid());
+
+                        let stream = self.stream_source(&gist_file);
+                        let (raw_url, size) = (gist_file.raw_url.clone(), gist_file.size);
+                        let content_type = (gist_file.type_.clone(), gist_file.language.clone());
+                        // The content is moved rather than copied, and shared with the base.
+                        let file = GistFileNode::new(
+                            node,
+                            local,
+                            gist_file.into_content_bytes(),
+                            &self.clock,
+                        );
+                        file.set_remote(Some(filename.as_str().into()));
+                        file.set_origin(&raw_url, size);
+                        *file.content_type.write().unwrap() = Some(content_type);
+                        file.set_stream(stream).await;
+                        new_files.insert(file.node.attr().ino(), Arc::new(file));
+                    }
+                }
+            }
+
+            std::mem::replace(&mut *files, new_files)
+        };
+
+        for (ino, file) in old_files {
+            tracing::debug!("remove a file: ino={}, filename={:?}", ino, file.filename());
+            self.filename_to_ino
+                .lock()
+                .await
+                .insert(file.filename().to_string(), ino);
+            if file.mark_synced(file.generation.load()).await {
+                // The local changes are discarded along with the file.
+                self.pending_uploads.fetch_sub(1);
+            }
+            file.node.remove().await;
+        }
+
+        if let Some(etag) = etag {
+            self.etag.lock().await.replace(etag);
+        }
+        self.sort_entries(node_table).await;
+        self.compress_clean_files().await;
+
+        Ok(())
+    }
+
+    /// Reorder the entries of the root directory by `file_order`.
+    async fn sort_entries(&self, node_table: &NodeTable) {
+        let order = self.file_order;
+        if order == FileOrder::None {
+            return;
+        }
+        let result = node_table
+            .root()
+            .sort_children_by(|a, b| order.compare_entries(a, b).unwrap_or(Ordering::Equal))
+            .await;
+        if let Err(errno) = result {
+            tracing::warn!("failed to sort the files: {}", errno);
+        }
+    }
+
+    /// Compress the large files without the local changes in the background.
+    async fn compress_clean_files(&self) {
+        if self.compress_threshold == 0 {
+            return;
+        }
+        let threshold = self.compress_threshold;
+        let files = self.files.lock().await;
+        for file in files.values().filter(|file| !file.is_dirty()) {
+            let file = file.clone();
+            tokio::spawn(async move { file.compress(threshold).await });
+        }
+    }
+
+    /// Add the `.unsynced` aliases of the dirty files and remove the ones
+    /// of the files uploaded, renamed or removed since.
+    ///
+    /// The entries are never cached by the kernel, so the removed aliases
+    /// disappear on the next lookup.
+    async fn sync_unsynced_aliases(&self, node_table: &NodeTable) {
+        let files = self.files.lock().await;
+        let mut aliases = self.unsynced_aliases.lock().await;
+
+        let stale: Vec<u64> = aliases
+            .iter()
+            .filter(|(ino, alias)| match files.get(ino) {
+                Some(file) => {
+                    !file.is_dirty() || **alias != format!("{}{}", file.filename(), UNSYNCED_SUFFIX)
+                }
+                None => true,
+            })
+            .map(|(ino, _)| *ino)
+            .collect();
+        for ino in stale {
+            let alias = aliases.remove(&ino).unwrap();
+            // The alias is gone along with the removed file.
+            let _ = node_table.root().remove_child(OsStr::new(&alias)).await;
+            if let Some(file) = files.get(&ino) {
+                file.unlink_one();
+            }
+        }
+
+        let dirty = files
+            .iter()
+            .filter(|(_, file)| file.is_dirty() && !file.is_conflict());
+        for (&ino, file) in dirty {
+            if aliases.contains_key(&ino) {
+                continue;
+            }
+            let alias = format!("{}{}", file.filename(), UNSYNCED_SUFFIX);
+            match node_table
+                .root()
+                .link_child(alias.clone().into(), &file.node)
+                .await
+            {
+                Ok(()) => {
+                    aliases.insert(ino, alias);
+                }
+                Err(errno) => tracing::debug!("skip the alias {:?}: {}", alias, errno),
+            }
+        }
+    }
+
+    /// Return whether any file opened for writing has been dirty for too long.
+    async fn has_overdue(&self, max_age: Duration) -> bool {
+        let files = self.files.lock().await;
+        files
+            .values()
+            .any(|file| file.writers.load() > 0 && file.is_overdue(max_age))
+    }
+
+    /// Return whether any change is left to be uploaded.
+    async fn has_pending(&self) -> bool {
+        if !self.unlinked.lock().await.is_empty() {
+            return true;
+        }
+        let files = self.files.lock().await;
+        files
+            .values()
+            .any(|file| file.is_dirty() || file.is_renamed())
+    }
+
+    /// Upload the pending changes of the files to the Gist in a single request.
+    ///
+    /// The pending deletions are drained from the ledger and restored if
+    /// the upload fails.
+    async fn flush(
+        &self,
+        client: &dyn Remote,
+        gist_id: &str,
+        node_table: &NodeTable,
+        reason: FlushReason,
+    ) -> anyhow::Result<()> {
+        let _guard = self.flush_lock.lock().await;
+
+        let files: Vec<Arc<GistFileNode>> = {
+            let files = self.files.lock().await;
+            files
+                .values()
+                .filter(|file| !file.is_conflict() && (file.is_dirty() || file.is_renamed()))
+                .filter(|file| {
+                    let permitted = reason.permits(file);
+                    if !permitted {
+                        tracing::debug!(
+                            "skip the file in a write session: filename={:?}",
+                            file.filename()
+                        );
+                    }
+                    permitted
+                })
+                .cloned()
+                .collect()
+        };
+        let unlinked = std::mem::take(&mut *self.unlinked.lock().await);
+        if files.is_empty() && unlinked.is_empty() {
+            return Ok(());
+        }
+
+        let mut result = self
+            .flush_files(client, gist_id, node_table, &files, &unlinked)
+            .await;
+        if self.conflict_resolution != ConflictStrategy::Fail && is_conflict(&result) {
+            tracing::info!("the Gist has been edited by another writer; resolve the conflict");
+            let resolved = self
+                .resolve_conflicts(client, gist_id, node_table, &files)
+                .await;
+            result = match resolved {
+                Ok(()) => {
+                    self.flush_files(client, gist_id, node_table, &files, &unlinked)
+                        .await
+                }
+                Err(err) => Err(err.context("failed to resolve the conflict")),
+            };
+            if result.is_ok() {
+                // The other files may have been changed as well.
+                self.etag.lock().await.take();
+            }
+        }
+        let skipped = match result {
+            Ok(skipped) => skipped,
+            Err(err) => {
+                let mut pending = self.unlinked.lock().await;
+                let unlinked_later = std::mem::replace(&mut *pending, unlinked);
+                pending.extend(unlinked_later);
+                return Err(err);
+            }
+        };
+        self.compress_clean_files().await;
+
+        // The failure of the file the caller waits for is returned, and
+        // the others are only logged.
+        let mut failure = None;
+        for (ino, err) in skipped {
+            if failure.is_none() && reason.is_for(ino) {
+                failure = Some(err);
+            } else {
+                self.errors.record(ErrorKind::Flush, &err).await;
+            }
+        }
+        failure.map_or(Ok(()), Err)
+    }
+
+    /// Upload the files, returning the ones skipped for their content
+    /// along with the errors.
+    ///
+    /// The skipped files are kept dirty, and never block the others.
+    ///
+    /// The caller must hold `flush_lock`.
+    async fn flush_files(
+        &self,
+        client: &dyn Remote,
+        gist_id: &str,
+        node_table: &NodeTable,
+        files: &[Arc<GistFileNode>],
+        unlinked: &[Arc<GistFileNode>],
+    ) -> anyhow::Result<Vec<(u64, anyhow::Error)>> {
+        let mut snapshots = Vec::with_capacity(files.len());
+        let mut skipped = vec![];
+        for file in files {
+            let filename = file.filename();
+            let content = if file.is_dirty() {
+                let (content, generation) = file.snapshot().await;
+                let mut content = match self.prepare_content(&filename, &content) {
+                    Ok(content) => content,
+                    Err(err) => {
+                        tracing::error!("skip the upload of {:?}: {:#}", filename, err);
+                        skipped.push((file.node.nodeid(), err));
+                        continue;
+                    }
+                };
+                if content.is_empty() {
+                    if !self.empty_file_placeholder {
+                        tracing::warn!(
+                            "the Gist rejects the empty content; keep {:?} unsent",
+                            filename
+                        );
+                        continue;
+                    }
+                    tracing::info!("upload a newline in place of the empty {:?}", filename);
+                    content.push_str(EMPTY_FILE_PLACEHOLDER);
+                }
+                Some((content, generation))
+            } else {
+                None
+            };
+            snapshots.push((file, file.remote(), filename, content));
+        }
+        let remote_names: Vec<_> = unlinked.iter().map(|file| file.remote()).collect();
+
+        let pending: Vec<Pending<'_>> = snapshots
+            .iter()
+            .map(|(_, remote, filename, content)| Pending {
+                remote: remote.as_deref(),
+                local: Some(&**filename),
+                content: content.as_ref().map(|(content, _)| &**content),
+            })
+            .chain(remote_names.iter().map(|remote| Pending {
+                remote: remote.as_deref(),
+                local: None,
+                content: None,
+            }))
+            .collect();
+        let patch_files = ledger::reduce(&pending[..]);
+        let remaining = {
+            let files = self.files.lock().await;
+            files.values().filter(|file| !file.is_conflict()).count()
+        };
+        ledger::ensure_not_emptied(&patch_files[..], remaining)?;
+
+        if !patch_files.is_empty() {
+            tracing::debug!("upload {} file(s)", patch_files.len());
+
+            // isahc reports no upload progress, so the throughput is
+            // recorded once the request completes.
+            let filenames: Vec<&str> = patch_files.iter().map(|(filename, _)| *filename).collect();
+            let bytes: usize = patch_files
+                .iter()
+                .filter_map(|(_, file)| file.as_ref()?.content)
+                .map(str::len)
+                .sum();
+            let span = tracing::info_span!(
+                "upload",
+                filename = %filenames.join(", "),
+                bytes,
+                elapsed_ms = tracing::field::Empty
+            );
+            let started = Instant::now();
+            self.patch(client, gist_id, node_table, &patch_files[..])
+                .instrument(span.clone())
+                .await?;
+            let elapsed_ms = started.elapsed().as_millis() as u64;
+            span.record("elapsed_ms", elapsed_ms);
+            span.in_scope(|| tracing::info!(bytes, elapsed_ms, "uploaded"));
+        }
+
+        let secret = self
+            .metadata
+            .lock()
+            .await
+            .as_ref()
+            .is_none_or(|metadata| !metadata.public);
+        for (file, _, _, content) in snapshots {
+            // The name may have been replaced with the one the Gist assigned.
+            file.set_remote(Some(file.filename()));
+            if let Some((content, generation)) = content {
+                if let Some(ref undo) = self.undo {
+                    let filename = file.filename();
+                    if let Err(err) = undo.record(&filename, content.as_bytes(), secret).await {
+                        tracing::warn!("failed to record the upload of {:?}: {:#}", filename, err);
+                    }
+                }
+                file.set_base(content.into_bytes());
+                if file.mark_synced(generation).await {
+                    self.pending_uploads.fetch_sub(1);
+                }
+            }
+        }
+        for file in unlinked {
+            if file.mark_synced(file.generation.load()).await {
+                self.pending_uploads.fetch_sub(1);
+            }
+        }
+
+        Ok(skipped)
+    }
+
+    /// Convert the local content into the one uploaded to the Gist.
+    fn prepare_content(&self, filename: &str, content: &[u8]) -> anyhow::Result<String> {
+        let content = self
+            .content_transformer
+            .transform(filename, content)
+            .with_context(|| format!("failed to transform {:?}", filename))?;
+        self.content_policy.check(filename, &content)?;
+        let content = String::from_utf8(content)
+            .map_err(|_| anyhow::anyhow!("the content is not valid UTF-8: {:?}", filename))?;
+        if self.normalize_unicode {
+            return Ok(content.nfc().collect());
+        }
+        Ok(content)
+    }
+
+    /// Reconcile the dirty files with the latest content of the Gist.
+    ///
+    /// The contents with the conflict markers are kept on the mount as
+    /// `.conflict.<filename>`, and the others are left to the subsequent upload.
+    ///
+    /// The caller must hold `flush_lock`.
+    async fn resolve_conflicts(
+        &self,
+        client: &dyn Remote,
+        gist_id: &str,
+        node_table: &NodeTable,
+        files: &[Arc<GistFileNode>],
+    ) -> anyhow::Result<()> {
+        let (gist, etag) = client
+            .fetch_gist(gist_id)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("the Gist is not returned"))?;
+
+        let mut conflicts = vec![];
+        let mut merges = vec![];
+        for file in files.iter().filter(|file| file.is_dirty()) {
+            let filename = file.filename();
+            let remote = file.remote().and_then(|remote| gist.files.get(&*remote));
+            if let Some(remote) = remote {
+                anyhow::ensure!(
+                    !remote.truncated,
+                    "the remote content is truncated: {:?}",
+                    filename
+                );
+            }
+
+            let (local, generation) = file.snapshot().await;
+            let local = std::str::from_utf8(&local[..])
+                .map_err(|_| anyhow::anyhow!("the content is not valid UTF-8: {:?}", filename))?;
+            let base = file.base();
+            let base = String::from_utf8_lossy(&base[..]);
+            let remote = remote.map(|remote| &*remote.content);
+
+            let resolution = conflict::resolve(self.conflict_resolution, &base, local, remote);
+            tracing::debug!("resolve the conflict: filename={:?}", filename);
+            let discarded = match resolution {
+                Resolution::Upload(content) => {
+                    if content != local {
+                        file.replace_content(content.into_bytes(), generation).await;
+                    }
+                    false
+                }
+                Resolution::Discard => true,
+                Resolution::Conflicted(content) => {
+                    tracing::warn!(
+                        "the changes conflict with the remote ones: filename={:?}",
+                        filename
+                    );
+                    conflicts.push((format!(".conflict.{}", filename), content));
+                    merges.push(ConflictMerge {
+                        ino: file.node.nodeid(),
+                        filename: filename.to_string(),
+                        base: base.as_bytes().to_vec(),
+                        local: local.as_bytes().to_vec(),
+                        remote: remote.unwrap_or("").as_bytes().to_vec(),
+                        generation,
+                    });
+                    true
+                }
+            };
+            if discarded {
+                let remote = remote.unwrap_or("").as_bytes().to_vec();
+                if file.replace_content(remote, generation).await
+                    && file.mark_synced(generation).await
+                {
+                    self.pending_uploads.fetch_sub(1);
+                }
+            }
+            if let Some(remote) = remote {
+                file.set_base(remote.as_bytes().to_vec());
+            }
+        }
+
+        *self.etag.lock().await = etag;
+
+        for (filename, content) in conflicts {
+            self.add_conflict(node_table, filename, content).await?;
+        }
+
+        // The command is started once the `.conflict` files to be replaced exist.
+        if let Some(ref sender) = self.conflict_merges {
+            for merge in merges {
+                let _ = sender.unbounded_send(merge);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Add the file with the conflict markers, which exists only on the
+    /// mount and replaces the one left by the previous conflict.
+    async fn add_conflict(
+        &self,
+        node_table: &NodeTable,
+        filename: String,
+        content: String,
+    ) -> anyhow::Result<()> {
+        let mut files = self.files.lock().await;
+        let existing = files
+            .iter()
+            .find(|(_, file)| *file.filename() == *filename)
+            .map(|(ino, file)| (*ino, file.is_conflict()));
+        match existing {
+            Some((ino, true)) => {
+                node_table
+                    .root()
+                    .remove_child(OsStr::new(&filename))
+                    .await?;
+                files.remove(&ino);
+            }
+            Some((_, false)) => {
+                tracing::warn!("the name of the conflict file is taken: {:?}", filename);
+                return Ok(());
+            }
+            None => (),
+        }
+
+        let mut attr = attr::new_attr(libc::S_IFREG | 0o444, 1, self.owner);
+        attr.set_size(content.len() as u64);
+        attr::set_times(&mut attr, Utc::now());
+        let node = node_table
+            .root()
+            .new_child(filename.clone().into(), attr)
+            .await?;
+        let file = GistFileNode::new(node, filename, content.into_bytes(), &self.clock);
+        file.set_remote(None);
+        file.is_conflict.store(true);
+        files.insert(file.node.nodeid(), Arc::new(file));
+        Ok(())
+    }
+
+    /// Replace the content of the file with the one merged by the conflict
+    /// command and remove its `.conflict` file, returning whether the
+    /// content has been adopted.
+    ///
+    /// The merge is dropped if the file has been modified or renamed since
+    /// the conflict, and the `.conflict` file is left to the user.
+    async fn adopt_merge(
+        &self,
+        node_table: &NodeTable,
+        merge: &ConflictMerge,
+        merged: Vec<u8>,
+    ) -> anyhow::Result<bool> {
+        let mut files = self.files.lock().await;
+        let file = match files.get(&merge.ino) {
+            Some(file) if *file.filename() == *merge.filename => file.clone(),
+            _ => return Ok(false),
+        };
+        {
+            let mut content = file.content.lock().await;
+            if file.generation.load() != merge.generation {
+                return Ok(false);
+            }
+            file.set_size(merged.len() as u64);
+            *content = Content::Plain(Arc::new(merged));
+            if file.modified() {
+                self.pending_uploads.fetch_add(1);
+            }
+        }
+
+        let conflict = format!(".conflict.{}", merge.filename);
+        let ino = files
+            .iter()
+            .find(|(_, file)| file.is_conflict() && *file.filename() == *conflict)
+            .map(|(ino, _)| *ino);
+        if let Some(ino) = ino {
+            node_table
+                .root()
+                .remove_child(OsStr::new(&conflict))
+                .await?;
+            files.remove(&ino);
+        }
+
+        Ok(true)
+    }
+
+    /// Create an empty file on the Gist, returning the name the Gist has
+    /// assigned to it.
+    ///
+    /// The Gist rejects the empty content, so the placeholder is uploaded
+    /// instead. Without the placeholder, nothing is uploaded and `None` is
+    /// returned, leaving the file to the first flush after it gets content.
+    async fn create(
+        &self,
+        client: &dyn Remote,
+        gist_id: &str,
+        node_table: &NodeTable,
+        filename: &str,
+    ) -> anyhow::Result<Option<String>> {
+        if !self.empty_file_placeholder {
+            return Ok(None);
+        }
+
+        let _guard = self.flush_lock.lock().await;
+        let file = GistPatchFile {
+            filename: None,
+            content: Some(EMPTY_FILE_PLACEHOLDER),
+        };
+        let gist = self
+            .patch(client, gist_id, node_table, &[(filename, Some(file))])
+            .await?;
+        let assigned = {
+            let files = self.files.lock().await;
+            let is_known = |name: &str| files.values().any(|file| *file.filename() == *name);
+            assigned_filenames(&[(filename, Some(EMPTY_FILE_PLACEHOLDER))], &gist, is_known)
+                .pop()
+                .map_or_else(|| filename.to_owned(), |(_, assigned)| assigned)
+        };
+
+        // A pending deletion of the same name has been overwritten.
+        self.unlinked
+            .lock()
+            .await
+            .retain(|file| file.remote().as_deref() != Some(filename));
+
+        Ok(Some(assigned))
+    }
+
+    /// Send a patch to the Gist and remember the new entity tag, returning
+    /// the updated Gist.
+    ///
+    /// The local files are renamed to the names the Gist has assigned.
+    ///
+    /// The caller must hold `flush_lock`.
+    async fn patch(
+        &self,
+        client: &dyn Remote,
+        gist_id: &str,
+        node_table: &NodeTable,
+        files: &[(&str, Option<GistPatchFile<'_>>)],
+    ) -> anyhow::Result<Gist> {
+        let etag = self.etag.lock().await.clone();
+        let (gist, etag) = client
+            .update_gist(
+                gist_id,
+                etag.as_ref(),
+                GistPatch {
+                    files,
+                    description: None,
+                },
+            )
+            .await
+            .with_context(|| {
+                let filenames: Vec<&str> = files.iter().map(|&(name, _)| name).collect();
+                format!("failed to upload {:?}", filenames)
+            })?;
+
+        if let Some(etag) = etag {
+            self.etag.lock().await.replace(etag);
+        }
+
+        self.adopt_assigned_names(node_table, &gist, files).await?;
+
+        Ok(gist)
+    }
+
+    /// Rename the local files to the names the Gist has assigned in the
+    /// response to a patch, which may differ from the requested ones.
+    ///
+    /// The caller must hold `flush_lock`.
+    async fn adopt_assigned_names(
+        &self,
+        node_table: &NodeTable,
+        gist: &Gist,
+        patch_files: &[(&str, Option<GistPatchFile<'_>>)],
+    ) -> anyhow::Result<()> {
+        let requested: Vec<(&str, Option<&str>)> = patch_files
+            .iter()
+            .filter_map(|(name, file)| {
+                let file = file.as_ref()?;
+                Some((file.filename.unwrap_or(name), file.content))
+            })
+            .collect();
+
+        let files = self.files.lock().await;
+        let mut filename_to_ino = self.filename_to_ino.lock().await;
+        let known: Vec<Arc<str>> = {
+            let links = self.links.lock().await;
+            let unlinked = self.unlinked.lock().await;
+            files
+                .values()
+                .flat_map(|file| vec![Some(file.filename()), file.remote()])
+                .chain(unlinked.iter().map(|file| file.remote()))
+                .flatten()
+                .chain(links.keys().map(|name| name.as_str().into()))
+                .collect()
+        };
+        let renames = assigned_filenames(&requested[..], gist, |name| {
+            known.iter().any(|known| **known == *name)
+        });
+        if renames.is_empty() {
+            return Ok(());
+        }
+
+        for (requested, assigned) in renames {
+            let file = match files
+                .values()
+                .find(|file| *file.filename() == *requested && !file.is_conflict())
+            {
+                Some(file) => file,
+                // The file created by the patch is not added yet.
+                None => continue,
+            };
+            tracing::warn!(
+                "the Gist has assigned another name: requested={:?}, assigned={:?}",
+                requested,
+                assigned
+            );
+            node_table
+                .root()
+                .rename_child(OsStr::new(&requested), assigned.clone().into())
+                .await?;
+            let assigned: Arc<str> = assigned.into();
+            file.set_filename(assigned.clone());
+            file.set_remote(Some(assigned.clone()));
+            if let Some(ino) = filename_to_ino.remove(&requested) {
+                filename_to_ino.insert(assigned.to_string(), ino);
+            }
+        }
+        drop(filename_to_ino);
+        drop(files);
+
+        self.sort_entries(node_table).await;
+        Ok(())
+    }
+
+    /// Return the link count of the root, which counts a link for each file.
+    async fn root_nlink(&self) -> u32 {
+        2 + self.files.lock().await.len() as u32
+    }
+
+    async fn insert(&self, file: Arc<GistFileNode>) {
+        self.filename_to_ino
+            .lock()
+            .await
+            .insert(file.filename().to_string(), file.node.nodeid());
+        self.files.lock().await.insert(file.node.nodeid(), file);
+    }
+
+    /// Remove the name of a file, returning the file whose rename or deletion
+    /// is to be uploaded, if any.
+    async fn unlink(
+        &self,
+        node_table: &NodeTable,
+        filename: &str,
+    ) -> Result<Option<Arc<GistFileNode>>, i32> {
+        let mut files = self.files.lock().await;
+        let mut links = self.links.lock().await;
+
+        if let Some(ino) = links.remove(filename) {
+            // The local link is removed without touching the Gist.
+            node_table.root().remove_child(OsStr::new(filename)).await?;
+            if let Some(file) = files.get(&ino) {
+                file.unlink_one();
+            }
+            return Ok(None);
+        }
+
+        let ino = files
+            .iter()
+            .find(|(_, file)| *file.filename() == *filename)
+            .map(|(ino, _)| *ino)
+            .ok_or(libc::ENOENT)?;
+
+        // A Gist cannot be left without files, unless the file survives
+        // under another name.
+        let is_last = !files[&ino].is_conflict()
+            && links.values().all(|&linked| linked != ino)
+            && files
+                .iter()
+                .all(|(&other, file)| other == ino || file.is_conflict());
+        if is_last {
+            return Err(libc::EPERM);
+        }
+
+        node_table.root().remove_child(OsStr::new(filename)).await?;
+
+        if files[&ino].is_conflict() {
+            // The conflict is cleared without touching the Gist.
+            files.remove(&ino);
+            return Ok(None);
+        }
+
+        let alias = links
+            .iter()
+            .find(|(_, &linked)| linked == ino)
+            .map(|(name, _)| name.clone());
+        if let Some(alias) = alias {
+            // The file survives under another name, which is uploaded as a rename.
+            links.remove(&alias);
+            let file = files[&ino].clone();
+            file.unlink_one();
+            file.set_filename(alias.into());
+            return Ok(Some(file));
+        }
+
+        let file = files.remove(&ino).unwrap();
+        self.unlinked.lock().await.push(file.clone());
+
+        Ok(Some(file))
+    }
+
+    /// Add a local name of the file, which is never uploaded.
+    async fn link(&self, node_table: &NodeTable, ino: u64, newname: &str) -> Result<FileAttr, i32> {
+        let files = self.files.lock().await;
+        let file = files.get(&ino).ok_or(libc::EPERM)?;
+        if file.is_conflict() {
+            return Err(libc::EPERM);
+        }
+        node_table
+            .root()
+            .link_child(newname.into(), &file.node)
+            .await?;
+        self.links.lock().await.insert(newname.to_owned(), ino);
+        file.touch_changed();
+        Ok(file.node.attr())
+    }
+
+    /// Return whether the deletion of the specified file is waiting for upload.
+    async fn is_unlinked(&self, remote: &str) -> bool {
+        self.unlinked
+            .lock()
+            .await
+            .iter()
+            .any(|file| file.remote().as_deref() == Some(remote))
+    }
+
+    /// Rename a file locally, returning a guard that reverts the rename
+    /// unless it is committed.
+    ///
+    /// As rename(2), the file with the new name is replaced, and it is
+    /// dropped on the commit.
+    ///
+    /// The guard holds `flush_lock`, so no upload observes the new name
+    /// before the rename is sent to the Gist.
+    async fn begin_rename(
+        &self,
+        node_table: &NodeTable,
+        file: Arc<GistFileNode>,
+        newname: &str,
+        noreplace: bool,
+    ) -> Result<RenameGuard<'_>, i32> {
+        let lock = self.flush_lock.lock().await;
+
+        let parent = node_table.root();
+        let oldname = file.filename();
+        let newname: Arc<str> = newname.into();
+        let target = self.find(&newname).await;
+        if noreplace && target.is_some() {
+            return Err(libc::EEXIST);
+        }
+        let replaced = target.filter(|target| !Arc::ptr_eq(target, &file));
+        if let Some(ref target) = replaced {
+            parent.remove_child(OsStr::new(&*newname)).await?;
+            target.unlink_one();
+        }
+        let mut rename = Rename {
+            parent,
+            file,
+            oldname,
+            newname,
+            replaced,
+        };
+
+        if let Err(errno) = rename
+            .parent
+            .rename_child(
+                OsStr::new(&*rename.oldname),
+                rename.newname.to_string().into(),
+            )
+            .await
+        {
+            rename.restore_replaced().await;
+            return Err(errno.into());
+        }
+        rename.file.set_filename(rename.newname.clone());
+
+        Ok(RenameGuard {
+            _lock: lock,
+            files: self,
+            rename: Some(rename),
+        })
+    }
+}
+
+/// A rename applied locally but not yet confirmed by the Gist.
+///
+/// Dropping the guard without calling `commit` restores the old name.
+struct RenameGuard<'a> {
+    _lock: OrderedGuard<'a, ()>,
+    files: &'a GistFiles,
+    rename: Option<Rename>,
+}
+
+impl RenameGuard<'_> {
+    /// Return the name of the replaced file on the Gist, if uploaded.
+    fn replaced_remote(&self) -> Option<Arc<str>> {
+        let rename = self.rename.as_ref().unwrap();
+        rename.replaced.as_ref().and_then(|target| target.remote())
+    }
+
+    /// Rename the file on the Gist, deleting the replaced file in the
+    /// same patch.
+    ///
+    /// The file never uploaded has nothing to rename on the Gist, and is
+    /// left to the flush creating it under the new name.
+    async fn upload(
+        &self,
+        client: &dyn Remote,
+        gist_id: &str,
+        node_table: &NodeTable,
+    ) -> anyhow::Result<()> {
+        let rename = self.rename.as_ref().unwrap();
+        let remote = rename.file.remote();
+        let replaced = self.replaced_remote();
+        let mut pending = vec![];
+        if let Some(ref remote) = remote {
+            pending.push(Pending {
+                remote: Some(remote),
+                local: Some(&rename.newname),
+                content: None,
+            });
+        }
+        if let Some(ref replaced) = replaced {
+            pending.push(Pending {
+                remote: Some(replaced),
+                local: None,
+                content: None,
+            });
+        }
+        let patch_files = ledger::reduce(&pending[..]);
+        if !patch_files.is_empty() {
+            self.files
+                .patch(client, gist_id, node_table, &patch_files[..])
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Keep the new name, or the one the Gist has assigned instead, and
+    /// drop the replaced file.
+    async fn commit(mut self) {
+        if let Some(rename) = self.rename.take() {
+            if rename.file.remote().is_some() {
+                rename.file.set_remote(Some(rename.file.filename()));
+            }
+            if let Some(target) = rename.replaced {
+                self.files.files.lock().await.remove(&target.node.nodeid());
+                if target.mark_synced(target.generation.load()).await {
+                    // The local changes are discarded along with the file.
+                    self.files.pending_uploads.fetch_sub(1);
+                }
+            }
+        }
+    }
+
+    /// Restore the old name.
+    async fn rollback(mut self) {
+        if let Some(rename) = self.rename.take() {
+            rename.revert().await;
+        }
+    }
+}
+
+impl Drop for RenameGuard<'_> {
+    fn drop(&mut self) {
+        // The request has been cancelled in the middle of the upload.
+        if let Some(rename) = self.rename.take() {
+            tokio::spawn(rename.revert());
+        }
+    }
+}
+
+struct Rename {
+    parent: Node,
+    file: Arc<GistFileNode>,
+    oldname: Arc<str>,
+    newname: Arc<str>,
+
+    /// The file previously named `newname`, kept until the commit.
+    replaced: Option<Arc<GistFileNode>>,
+}
+
+impl Rename {
+    async fn revert(mut self) {
+        tracing::debug!(
+            "revert the rename: {:?} -> {:?}",
+            self.newname,
+            self.oldname
+        );
+        if let Err(errno) = self
+            .parent
+            .rename_child(OsStr::new(&*self.newname), self.oldname.to_string().into())
+            .await
+        {
+            tracing::error!("failed to restore the name {:?}: {}", self.oldname, errno);
+            return;
+        }
+        self.file.set_filename(self.oldname.clone());
+        self.restore_replaced().await;
+    }
+
+    /// Link the replaced file back to the new name.
+    async fn restore_replaced(&mut self) {
+        if let Some(target) = self.replaced.take() {
+            let name = self.newname.to_string().into();
+            if let Err(errno) = self.parent.link_child(name, &target.node).await {
+                tracing::error!("failed to restore {:?}: {}", self.newname, errno);
+            }
+        }
+    }
+}
+
+/// A conflict left by the three-way merge, to be resolved by the conflict command.
+#[derive(Debug)]
+struct ConflictMerge {
+    ino: u64,
+    filename: String,
+    base: Vec<u8>,
+    local: Vec<u8>,
+    remote: Vec<u8>,
+
+    /// The generation of the file holding the remote content.
+    generation: u64,
+}
+
+/// The trigger of an upload.
+#[derive(Debug, Copy, Clone)]
+enum FlushReason {
+    /// The debounce timer has fired.
+    Timer,
+
+    /// `fsync(2)` was called on a handle of the file with the specified inode number.
+    Fsync(u64),
+
+    /// A handle of the file with the specified inode number is being closed.
+    Close(u64),
+
+    /// The file has been dirty for longer than the duration.
+    DirtyAge(Duration),
+
+    /// The filesystem is being unmounted.
+    Unmount,
+}
+
+impl FlushReason {
+    /// Return whether the file may be uploaded for this reason.
+    ///
+    /// The files in the middle of a write session are held back so that
+    /// a torn content is never uploaded.
+    fn permits(self, file: &GistFileNode) -> bool {
+        match self {
+            FlushReason::Timer => file.writers.load() == 0,
+            FlushReason::Fsync(ino) | FlushReason::Close(ino) => {
+                file.writers.load() == 0 || file.node.nodeid() == ino
+            }
+            FlushReason::DirtyAge(max_age) => file.writers.load() == 0 || file.is_overdue(max_age),
+            FlushReason::Unmount => true,
+        }
+    }
+
+    /// Return whether the upload has been requested for the file.
+    fn is_for(self, ino: u64) -> bool {
+        match self {
+            FlushReason::Fsync(target) | FlushReason::Close(target) => target == ino,
+            _ => false,
+        }
+    }
+}
+
+/// Return whether the name is one of the noise probed by the desktop tools.
+fn is_noise(name: &OsStr) -> bool {
+    match name.to_str() {
+        Some(name) => name.starts_with("._") || GistFs::COMMON_NOISE_FILES.contains(&name),
+        None => false,
+    }
+}
+
+/// Return whether the name is of the form `gistfileN.txt`, which the Gist
+/// assigns to the files without names and may reassign to another file.
+fn is_auto_name(name: &str) -> bool {
+    name.strip_prefix("gistfile")
+        .and_then(|name| name.strip_suffix(".txt"))
+        .is_some_and(|number| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Match the requested names missing from the patched Gist with the names
+/// the Gist has assigned instead, returning the pairs of them.
+///
+/// The candidates are the names in the Gist which are neither requested
+/// nor known locally. A candidate is matched by the content, or taken if
+/// it is the only one left for the only missing name.
+fn assigned_filenames(
+    requested: &[(&str, Option<&str>)],
+    gist: &Gist,
+    is_known: impl Fn(&str) -> bool,
+) -> Vec<(String, String)> {
+    let missing: Vec<(&str, Option<&str>)> = requested
+        .iter()
+        .copied()
+        .filter(|(name, _)| !gist.files.contains_key(*name))
+        .collect();
+    if missing.is_empty() {
+        return vec![];
+    }
+
+    let mut candidates: Vec<&GistFile> = gist
+        .files
+        .iter()
+        .filter(|(name, _)| {
+            !is_known(name)
+                && requested
+                    .iter()
+                    .all(|&(requested, _)| requested != name.as_str())
+        })
+        .map(|(_, file)| file)
+        .collect();
+    candidates.sort_by(|a, b| a.filename.cmp(&b.filename));
+
+    let mut assigned = vec![];
+    for &(name, content) in &missing {
+        let matched = candidates.iter().position(|candidate| match content {
+            Some(content) => !candidate.truncated && candidate.content == content,
+            None => false,
+        });
+        // The only file left is the one assigned.
+        let sole = missing.len() == 1 && candidates.len() == 1;
+        let matched = matched.or(if sole { Some(0) } else { None });
+        match matched {
+            Some(i) => assigned.push((name.to_owned(), candidates.remove(i).filename.clone())),
+            None => tracing::warn!("the patched Gist has no file {:?}", name),
+        }
+    }
+    assigned
+}
+
+/// Return whether the upload was rejected since the Gist has been edited by another writer.
+fn is_conflict<T>(result: &anyhow::Result<T>) -> bool {
+    match result {
+        Err(err) => matches!(err.downcast_ref(), Some(ClientError::Conflict)),
+        Ok(..) => false,
+    }
+}
+
+// ==== FileNode ====
+
+#[derive(Debug)]
+struct GistFileNode {
+    node: Node,
+
+    /// The name of the file, which changes on rename(2).
+    filename: RwLock<Arc<str>>,
+
+    /// The name of the file on the Gist as of the last upload.
+    remote: RwLock<Option<Arc<str>>>,
+
+    /// The URL and the size of the content last received from the Gist.
+    ///
+    /// The raw URL contains the hash of the blob, so the content is left
+    /// as it is while the origin is unchanged.
+    origin: RwLock<Option<(String, u64)>>,
+
+    /// The MIME type and the language of the file reported by the Gist.
+    content_type: RwLock<Option<(Mime, String)>>,
+
+    /// The source of the content too large to be included in the API response.
+    ///
+    /// The lock is held across the fetch of a range, so `streamed` tells
+    /// whether it is set without waiting for the reads.
+    stream: Mutex<Option<ContentStream>>,
+    streamed: AtomicCell<bool>,
+
+    /// The content last received from or uploaded to the Gist,
+    /// used as the common ancestor when merging the changes.
+    base: RwLock<Content>,
+
+    /// The cached content, shared with the in-flight reads.
+    ///
+    /// Reads hold the lock only to clone the pointer, so replying to
+    /// a read never blocks the writers.
+    content: OrderedMutex<Content, lock_order::Content>,
+
+    /// The number of modifications applied to the local content.
+    generation: AtomicCell<u64>,
+
+    /// The generation of the local content last uploaded to the Gist.
+    synced: AtomicCell<u64>,
+
+    /// The number of writes whose upload has not been scheduled yet.
+    writes_since_flush: AtomicCell<u32>,
+
+    /// The number of write sessions currently opened on this file.
+    writers: AtomicCell<usize>,
+
+    /// Whether the permission bits have been set explicitly by chmod(2).
+    mode_fixed: AtomicCell<bool>,
+
+    /// When the local content became different from the Gist.
+    dirty_since: AtomicCell<Option<Instant>>,
+
+    /// When the content was last modified locally.
+    last_write: AtomicCell<Option<Instant>>,
+
+    /// Whether the file holds the conflict markers of a merge, which
+    /// exists only on the mount and is never uploaded.
+    is_conflict: AtomicCell<bool>,
+
+    clock: SharedClock,
+}
+
+impl GistFileNode {
+    fn new(node: Node, filename: String, content: impl Into<Vec<u8>>, clock: &SharedClock) -> Self {
+        let content = Arc::new(content.into());
+        Self {
+            node,
+            remote: RwLock::new(Some(filename.as_str().into())),
+            filename: RwLock::new(filename.into()),
+            origin: RwLock::new(None),
+            content_type: RwLock::new(None),
+            stream: Mutex::new(None),
+            streamed: AtomicCell::new(false),
+            base: RwLock::new(Content::Plain(content.clone())),
+            content: OrderedMutex::new(Content::Plain(content)),
+            generation: AtomicCell::new(0),
+            synced: AtomicCell::new(0),
+            writes_since_flush: AtomicCell::new(0),
+            writers: AtomicCell::new(0),
+            mode_fixed: AtomicCell::new(false),
+            dirty_since: AtomicCell::new(None),
+            last_write: AtomicCell::new(None),
+            is_conflict: AtomicCell::new(false),
+            clock: clock.clone(),
+        }
+    }
+
+    fn filename(&self) -> Arc<str> {
+        self.filename.read().unwrap().clone()
+    }
+
+    fn set_filename(&self, filename: Arc<str>) {
+        *self.filename.write().unwrap() = filename;
+    }
+
+    fn remote(&self) -> Option<Arc<str>> {
+        self.remote.read().unwrap().clone()
+    }
+
+    fn set_remote(&self, remote: Option<Arc<str>>) {
+        *self.remote.write().unwrap() = remote;
+    }
+
+    fn is_same_origin(&self, gist_file: &GistFile) -> bool {
+        match *self.origin.read().unwrap() {
+            Some((ref raw_url, size)) => *raw_url == gist_file.raw_url && size == gist_file.size,
+            None => false,
+        }
+    }
+
+    fn set_origin(&self, raw_url: &str, size: u64) {
+        *self.origin.write().unwrap() = Some((raw_url.to_owned(), size));
+    }
+
+    fn content_type(&self) -> Option<(Mime, String)> {
+        self.content_type.read().unwrap().clone()
+    }
+
+    fn set_content_type(&self, gist_file: &GistFile) {
+        *self.content_type.write().unwrap() =
+            Some((gist_file.type_.clone(), gist_file.language.clone()));
+    }
+
+    /// Return whether the file has been renamed since the last upload.
+    fn is_renamed(&self) -> bool {
+        self.remote().as_deref() != Some(&*self.filename())
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.generation.load() != self.synced.load()
+    }
+
+    fn is_conflict(&self) -> bool {
+        self.is_conflict.load()
+    }
+
+    fn set_size(&self, size: u64) {
+        let mut attr = self.node.attr();
+        attr.set_size(size);
+        self.node.set_attr(attr);
+    }
+
+    /// Bump the change time, on a change of the inode other than the content.
+    fn touch_changed(&self) {
+        let mut attr = self.node.attr();
+        attr::touch_changed(&mut attr, Utc::now());
+        self.node.set_attr(attr);
+    }
+
+    /// Correct the size in the attribute if it disagrees with the cached content.
+    ///
+    /// The size reported by the API may differ from the length of the content
+    /// received, e.g. when the content is truncated.
+    ///
+    /// The size is left as it is while the content is locked, e.g. by a
+    /// write which sets the size itself, so that `getattr` never waits.
+    fn validate_size(&self) {
+        if self.is_streamed() {
+            // The cached content is truncated.
+            return;
+        }
+        let content = match self.content.try_lock() {
+            Some(content) => content,
+            None => return,
+        };
+        if self.node.attr().size() != content.len() as u64 {
+            tracing::debug!(
+                "correct the file size: filename={:?}, size={}",
+                self.filename(),
+                content.len()
+            );
+            self.set_size(content.len() as u64);
+        }
+    }
+
+    /// Re-evaluate the permission bits unless they were set by chmod(2).
+    ///
+    /// The mode is local to the mount, so this never marks the file dirty.
+    fn apply_exec_policy(&self, policy: &ExecPolicy, content: &[u8]) {
+        if self.mode_fixed.load() {
+            return;
+        }
+        let mut attr = self.node.attr();
+        attr.set_mode(libc::S_IFREG | policy.permissions(&self.filename(), content));
+        self.node.set_attr(attr);
+    }
+
+    /// Decrement the link count after one of the names is removed.
+    fn unlink_one(&self) {
+        let mut attr = self.node.attr();
+        attr.set_nlink(attr.nlink().saturating_sub(1));
+        self.node.set_attr(attr);
+    }
+
+    /// Set the permission bits, which stick for the lifetime of the mount.
+    fn chmod(&self, mode: u32) {
+        let mut attr = self.node.attr();
+        attr.set_mode(libc::S_IFREG | (mode & 0o7777));
+        self.node.set_attr(attr);
+        self.mode_fixed.store(true);
+    }
+
+    async fn update_content(&self, size: u64, content: impl Into<Vec<u8>>, policy: &ExecPolicy) {
+        let content = Arc::new(content.into());
+        let mut guard = self.content.lock().await;
+        *guard = Content::Plain(content.clone());
+        *self.base.write().unwrap() = guard.clone();
+        self.set_size(size);
+        self.apply_exec_policy(policy, &content[..]);
+    }
+
+    /// Write the data to the content, returning whether the file has become dirty.
+    ///
+    /// Fails with `EFBIG` if the content would grow beyond `MAX_CONTENT_SIZE`.
+    async fn write(&self, offset: u64, data: &[u8], policy: &ExecPolicy) -> Result<bool, i32> {
+        let end = offset
+            .checked_add(data.len() as u64)
+            .filter(|&end| end <= MAX_CONTENT_SIZE)
+            .ok_or(libc::EFBIG)?;
+        let (offset, end) = (offset as usize, end as usize);
+
+        let mut guard = self.content.lock().await;
+        let content = guard.make_mut();
+
+        if content.len() < end {
+            content.resize(end, 0);
+        }
+        content[offset..end].copy_from_slice(data);
+
+        self.set_size(content.len() as u64);
+        self.apply_exec_policy(policy, &content[..]);
+        Ok(self.modified())
+    }
+
+    /// Resize the content, returning whether the file has become dirty.
+    ///
+    /// Fails with `EFBIG` if the size is beyond `MAX_CONTENT_SIZE`.
+    async fn truncate(&self, size: u64, policy: &ExecPolicy) -> Result<bool, i32> {
+        if size > MAX_CONTENT_SIZE {
+            return Err(libc::EFBIG);
+        }
+
+        let mut guard = self.content.lock().await;
+        let content = guard.make_mut();
+        content.resize(size as usize, 0);
+
+        self.set_size(size);
+        self.apply_exec_policy(policy, &content[..]);
+        Ok(self.modified())
+    }
+
+    /// Advance the generation, returning whether the file has become dirty.
+    fn modified(&self) -> bool {
+        let mut attr = self.node.attr();
+        attr::touch_modified(&mut attr, Utc::now());
+        self.node.set_attr(attr);
+
+        let now = self.clock.now();
+        self.last_write.store(Some(now));
+        let became_dirty = self.generation.fetch_add(1) == self.synced.load();
+        if became_dirty {
+            self.dirty_since.store(Some(now));
+        }
+        became_dirty
+    }
+
+    /// Return whether the file has been dirty for longer than the duration,
+    /// and the writes have settled.
+    fn is_overdue(&self, max_age: Duration) -> bool {
+        let now = self.clock.now();
+        let aged = self
+            .dirty_since
+            .load()
+            .is_some_and(|since| now.duration_since(since) >= max_age);
+        let settled = self
+            .last_write
+            .load()
+            .is_none_or(|at| now.duration_since(at) >= DIRTY_AGE_SETTLE);
+        aged && settled
+    }
+
+    fn base(&self) -> Arc<Vec<u8>> {
+        self.base.read().unwrap().bytes()
+    }
+
+    fn set_base(&self, base: impl Into<Vec<u8>>) {
+        *self.base.write().unwrap() = Content::Plain(Arc::new(base.into()));
+    }
+
+    /// Replace the content unless it has been modified since the specified generation.
+    ///
+    /// The generation is left as it is, so the file stays dirty.
+    async fn replace_content(&self, content: Vec<u8>, generation: u64) -> bool {
+        let mut guard = self.content.lock().await;
+        if self.generation.load() != generation {
+            return false;
+        }
+        self.set_size(content.len() as u64);
+        *guard = Content::Plain(Arc::new(content));
+        true
+    }
+
+    /// Record the generation of the content uploaded to the Gist,
+    /// returning whether the file has become clean.
+    async fn mark_synced(&self, generation: u64) -> bool {
+        let _content = self.content.lock().await;
+        let was_dirty = self.is_dirty();
+        self.synced.store(generation);
+        // The writes after the snapshot are counted from now on.
+        self.dirty_since.store(if self.is_dirty() {
+            Some(self.clock.now())
+        } else {
+            None
+        });
+        was_dirty && !self.is_dirty()
+    }
+
+    /// Take the current content along with its generation.
+    async fn snapshot(&self) -> (Arc<Vec<u8>>, u64) {
+        let content = self.content.lock().await;
+        (content.bytes(), self.generation.load())
+    }
+
+    /// Compress the content of a clean file larger than the threshold.
+    ///
+    /// The compression runs on a blocking thread, and the result is
+    /// discarded if the file is modified in the meantime.
+    async fn compress(&self, threshold: usize) {
+        let (content, generation) = {
+            let guard = self.content.lock().await;
+            match *guard {
+                Content::Plain(ref content) if content.len() > threshold && !self.is_dirty() => {
+                    (content.clone(), self.generation.load())
+                }
+                _ => return,
+            }
+        };
+
+        // The base usually equals to the content of a clean file, and
+        // would otherwise keep the plain content alive.
+        let base = match *self.base.read().unwrap() {
+            Content::Plain(ref base) => Some(base.clone()),
+            Content::Compressed(..) => None,
+        };
+        let shared = base
+            .as_ref()
+            .is_some_and(|base| Arc::ptr_eq(base, &content) || base[..] == content[..]);
+
+        let task = {
+            let content = content.clone();
+            let base = base.clone().filter(|_| !shared);
+            tokio::task::spawn_blocking(move || {
+                let base = base.map(|base| Content::compress(&base));
+                (Content::compress(&content), base)
+            })
+        };
+        let (compressed, compressed_base) = match task.await {
+            Ok(compressed) => compressed,
+            Err(err) => {
+                tracing::error!("failed to compress the content: {}", err);
+                return;
+            }
+        };
+
+        let mut guard = self.content.lock().await;
+        match *guard {
+            Content::Plain(ref current)
+                if Arc::ptr_eq(current, &content) && self.generation.load() == generation => {}
+            _ => return,
+        }
+        tracing::debug!("compress the content: filename={:?}", self.filename());
+        *guard = compressed.clone();
+
+        // The base may have been replaced by an upload in the meantime.
+        let mut current_base = self.base.write().unwrap();
+        let unchanged = match (&*current_base, base) {
+            (Content::Plain(ref current), Some(ref base)) => Arc::ptr_eq(current, base),
+            _ => false,
+        };
+        if unchanged {
+            *current_base = compressed_base.unwrap_or(compressed);
+        }
+    }
+
+    /// Drop the decompressed content kept for the reads.
+    async fn close_window(&self) {
+        self.content.lock().await.close_window();
+    }
+
+    fn is_streamed(&self) -> bool {
+        self.streamed.load()
+    }
+
+    async fn set_stream(&self, raw_url: Option<String>) {
+        let mut stream = self.stream.lock().await;
+        self.streamed.store(raw_url.is_some());
+        *stream = raw_url.map(|raw_url| ContentStream {
+            raw_url,
+            reader: None,
+        });
+    }
+
+    async fn read<W: ?Sized>(
+        &self,
+        cx: &mut Context<'_, W>,
+        op: op::Read<'_>,
+        client: &Client,
+    ) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut stream = self.stream.lock().await;
+        if let Some(ref mut stream) = *stream {
+            return match stream.read(client, op.offset(), op.size() as usize).await {
+                Ok(data) => op.reply(cx, &data[..]).await,
+                Err(err) => {
+                    tracing::error!("failed to read the raw content: {:#}", err);
+                    cx.reply_err(libc::EIO).await
+                }
+            };
+        }
+        drop(stream);
+
+        let content = self.content.lock().await.bytes_for_read();
+        let data = read_range(&content, op.offset(), op.size());
+        op.reply(cx, data).await
+    }
+}
+
+/// Return the part of the content read at the offset, which is empty past the end.
+fn read_range(content: &[u8], offset: u64, size: u32) -> &[u8] {
+    let offset = std::cmp::min(offset, content.len() as u64) as usize;
+    let content = &content[offset..];
+    let len = std::cmp::min(content.len(), size as usize);
+    &content[..len]
+}
+
+/// A stream of the raw content of a file, restarted on backward reads.
+#[derive(Debug)]
+struct ContentStream {
+    raw_url: String,
+    reader: Option<ContentReader>,
+}
+
+impl ContentStream {
+    async fn read(&mut self, client: &Client, offset: u64, size: usize) -> anyhow::Result<Vec<u8>> {
+        let mut reader = match self.reader.take() {
+            Some(reader) if reader.position() <= offset => reader,
+            _ => client.fetch_raw(&self.raw_url, offset).await?,
+        };
+        let data = reader.read_at(offset, size).await?;
+        self.reader = Some(reader);
+        Ok(data)
+    }
+}
+
+// ==== FileHandles ====
+
+struct FileHandles {
+    handles: Mutex<HashMap<u64, FileHandle>>,
+    next_fh: AtomicCell<u64>,
+
+    /// The maximum number of handles opened simultaneously.
+    max_handles: usize,
+
+    /// The largest number of handles ever opened simultaneously.
+    high_water: AtomicCell<usize>,
+}
+
+#[derive(Clone)]
+struct FileHandle {
+    file: Arc<GistFileNode>,
+    writable: bool,
+}
+
+impl FileHandles {
+    fn new(max_handles: usize) -> Self {
+        Self {
+            handles: Mutex::default(),
+            next_fh: AtomicCell::new(0),
+            max_handles,
+            high_water: AtomicCell::new(0),
+        }
+    }
+
+    /// Register a new handle and start a write session if requested.
+    ///
+    /// Fails with `EMFILE` if too many handles are opened.
+    async fn open(&self, file: Arc<GistFileNode>, writable: bool) -> Result<u64, i32> {
+        let mut handles = self.handles.lock().await;
+        if handles.len() >= self.max_handles {
+            tracing::warn!("too many open handles: {}", handles.len());
+            return Err(libc::EMFILE);
+        }
+
+        if writable {
+            file.writers.fetch_add(1);
+        }
+
+        let fh = self.allocate();
+        handles.insert(fh, FileHandle { file, writable });
+        // The updates are serialized by the lock of the table.
+        if handles.len() > self.high_water.load() {
+            self.high_water.store(handles.len());
+        }
+        Ok(fh)
+    }
+
+    /// Return whether any handle refers to the file.
+    async fn is_open(&self, file: &Arc<GistFileNode>) -> bool {
+        let handles = self.handles.lock().await;
+        handles
+            .values()
+            .any(|handle| Arc::ptr_eq(&handle.file, file))
+    }
+
+    /// Return whether no more handles can be opened.
+    async fn is_full(&self) -> bool {
+        self.handles.lock().await.len() >= self.max_handles
+    }
+
+    /// Return the number of the open handles and the high-water mark.
+    async fn stats(&self) -> (usize, usize) {
+        let len = self.handles.lock().await.len();
+        (len, self.high_water.load())
+    }
+
+    /// Allocate a handle number without registering any file.
+    fn allocate(&self) -> u64 {
+        self.next_fh.fetch_add(1)
+    }
+
+    async fn get(&self, fh: u64) -> Option<FileHandle> {
+        self.handles.lock().await.get(&fh).cloned()
+    }
+
+    /// Remove the handle and end its write session.
+    async fn release(&self, fh: u64) -> Option<FileHandle> {
+        let handle = self.handles.lock().await.remove(&fh)?;
+        if handle.writable {
+            handle.file.writers.fetch_sub(1);
+        }
+        Some(handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::MockClock, content_policy::NoNulBytesPolicy, remote::Fetched};
+    use futures::{
+        executor::block_on,
+        future::{self, BoxFuture, FutureExt as _},
+        io::AsyncRead,
+    };
+    use polyfuse::{request::BytesBuffer, Notifier, Session, SessionInitializer};
+    use std::{
+        collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, VecDeque},
+        convert::TryInto as _,
+        hash::{Hash, Hasher},
+        io::{IoSlice, IoSliceMut},
+        pin::Pin,
+        task::{self, Poll},
+    };
+
+    fn node_table() -> NodeTable {
+        NodeTable::new(attr::new_attr(
+            libc::S_IFDIR | 0o755,
+            2,
+            OwnerIds::current(),
+        ))
+    }
+
+    async fn add_file(node_table: &NodeTable, files: &GistFiles, filename: &str) {
+        let attr = attr::new_attr(libc::S_IFREG | 0o644, 1, OwnerIds::current());
+        let node = node_table
+            .root()
+            .new_child(filename.into(), attr)
+            .await
+            .unwrap();
+        let file = GistFileNode::new(node, filename.to_owned(), "content", &files.clock);
+        files.insert(Arc::new(file)).await;
+    }
+
+    /// Return the raw URL of the content, which changes along with it.
+    fn raw_url(filename: &str, content: &str) -> String {
+        let mut hasher = DefaultHasher::new();
+        content.hash(&mut hasher);
+        format!(
+            "https://gist.githubusercontent.com/raw/{:016x}/{}",
+            hasher.finish(),
+            filename
+        )
+    }
+
+    fn gist(files: &[(&str, &str)]) -> Gist {
+        let files: serde_json::Map<_, _> = files
+            .iter()
+            .map(|&(filename, content)| {
+                let file = serde_json::json!({
+                    "filename": filename,
+                    "type": "text/plain",
+                    "language": "Text",
+                    "raw_url": raw_url(filename, content),
+                    "size": content.len(),
+                    "truncated": false,
+                    "content": content,
+                });
+                (filename.to_owned(), file)
+            })
+            .collect();
+        let gist = serde_json::json!({
+            "id": "0123abc",
+            "html_url": "https://gist.github.com/0123abc",
+            "description": "",
+            "public": false,
+            "created_at": "2020-01-02T03:04:05Z",
+            "updated_at": "2020-01-02T03:04:05Z",
+            "files": files,
+            "truncated": false,
+        });
+        Gist::from_json(gist.to_string().as_bytes()).unwrap()
+    }
+
+    const FUSE_LOOKUP: u32 = 1;
+    const FUSE_SETATTR: u32 = 4;
+    const FUSE_OPEN: u32 = 14;
+    const FUSE_READ: u32 = 15;
+    const FUSE_WRITE: u32 = 16;
+    const FUSE_FSYNC: u32 = 20;
+    const FUSE_SETXATTR: u32 = 21;
+    const FUSE_GETXATTR: u32 = 22;
+    const FUSE_FLUSH: u32 = 25;
+    const FUSE_CREATE: u32 = 35;
+    const FUSE_RENAME2: u32 = 45;
+    const FATTR_SIZE: u32 = 1 << 3;
+    const FUSE_INIT: u32 = 26;
+
+    /// An in-memory `/dev/fuse`, handing out one request per read.
+    #[derive(Default)]
+    struct Channel {
+        requests: VecDeque<Vec<u8>>,
+        replies: Vec<u8>,
+    }
+
+    impl AsyncRead for Channel {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut task::Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            self.poll_read_vectored(cx, &mut [IoSliceMut::new(buf)])
+        }
+
+        fn poll_read_vectored(
+            self: Pin<&mut Self>,
+            _: &mut task::Context<'_>,
+            bufs: &mut [IoSliceMut<'_>],
+        ) -> Poll<io::Result<usize>> {
+            let request = match self.get_mut().requests.pop_front() {
+                Some(request) => request,
+                None => return Poll::Ready(Err(io::Error::from_raw_os_error(libc::ENODEV))),
+            };
+            let mut rest = &request[..];
+            for buf in bufs {
+                let len = std::cmp::min(buf.len(), rest.len());
+                buf[..len].copy_from_slice(&rest[..len]);
+                rest = &rest[len..];
+            }
+            assert!(rest.is_empty(), "the request overflows the buffer");
+            Poll::Ready(Ok(request.len()))
+        }
+    }
+
+    impl AsyncWrite for Channel {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut task::Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.get_mut().replies.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_write_vectored(
+            self: Pin<&mut Self>,
+            _: &mut task::Context<'_>,
+            bufs: &[IoSlice<'_>],
+        ) -> Poll<io::Result<usize>> {
+            let me = self.get_mut();
+            for buf in bufs {
+                me.replies.extend_from_slice(buf);
+            }
+            Poll::Ready(Ok(bufs.iter().map(|buf| buf.len()).sum()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// The kernel side of a FUSE connection, sending the requests to the
+    /// filesystem in the wire format.
+    struct Kernel {
+        session: Session,
+        channel: Channel,
+        unique: u64,
+    }
+
+    impl Kernel {
+        async fn new() -> Self {
+            let mut channel = Channel::default();
+            let init = [7u32, 29, 0x2_0000, 0]
+                .iter()
+                .flat_map(|field| field.to_ne_bytes().to_vec())
+                .collect::<Vec<u8>>();
+            channel.requests.push_back(request(FUSE_INIT, 0, 0, &init));
+            let session = SessionInitializer::default()
+                .init(&mut channel)
+                .await
+                .unwrap();
+            channel.replies.clear();
+            Self {
+                session,
+                channel,
+                unique: 0,
+            }
+        }
+
+        /// Send a request, returning the payload of the reply or the error.
+        async fn call(
+            &mut self,
+            fs: &GistFs,
+            opcode: u32,
+            nodeid: u64,
+            arg: &[u8],
+        ) -> Result<Vec<u8>, i32> {
+            self.unique += 1;
+            let request = request(opcode, self.unique, nodeid, arg);
+            self.channel.requests.push_back(request);
+
+            let mut buf = BytesBuffer::new(self.session.buffer_size());
+            self.session
+                .receive(&mut self.channel, &mut buf, &Notifier::new())
+                .await
+                .unwrap();
+            self.session
+                .process(fs, &mut buf, &mut self.channel)
+                .await
+                .unwrap();
+
+            let reply = std::mem::take(&mut self.channel.replies);
+            assert!(reply.len() >= 16, "no reply to the request");
+            match i32::from_ne_bytes([reply[4], reply[5], reply[6], reply[7]]) {
+                0 => Ok(reply[16..].to_vec()),
+                error => Err(-error),
+            }
+        }
+
+        async fn lookup(&mut self, fs: &GistFs, name: &str) -> Result<u64, i32> {
+            let arg = [name.as_bytes(), b"\0"].concat();
+            let entry = self.call(fs, FUSE_LOOKUP, 1, &arg).await?;
+            // The missing entry is cached as the node ID 0.
+            match u64::from_ne_bytes(entry[..8].try_into().unwrap()) {
+                0 => Err(libc::ENOENT),
+                nodeid => Ok(nodeid),
+            }
+        }
+
+        async fn open(&mut self, fs: &GistFs, ino: u64, flags: i32) -> Result<u64, i32> {
+            let arg = [flags.to_ne_bytes(), [0; 4]].concat();
+            let open = self.call(fs, FUSE_OPEN, ino, &arg).await?;
+            Ok(u64::from_ne_bytes(open[..8].try_into().unwrap()))
+        }
+
+        async fn read(
+            &mut self,
+            fs: &GistFs,
+            ino: u64,
+            fh: u64,
+            offset: u64,
+            size: u32,
+        ) -> Result<Vec<u8>, i32> {
+            let arg = [
+                &fh.to_ne_bytes()[..],
+                &offset.to_ne_bytes(),
+                &size.to_ne_bytes(),
+                &[0; 20],
+            ]
+            .concat();
+            self.call(fs, FUSE_READ, ino, &arg).await
+        }
+
+        async fn write(
+            &mut self,
+            fs: &GistFs,
+            ino: u64,
+            fh: u64,
+            offset: u64,
+            data: &[u8],
+        ) -> Result<u32, i32> {
+            let arg = [
+                &fh.to_ne_bytes()[..],
+                &offset.to_ne_bytes(),
+                &(data.len() as u32).to_ne_bytes(),
+                &[0; 20],
+                data,
+            ]
+            .concat();
+            let write = self.call(fs, FUSE_WRITE, ino, &arg).await?;
+            Ok(u32::from_ne_bytes(write[..4].try_into().unwrap()))
+        }
+
+        async fn flush(&mut self, fs: &GistFs, ino: u64, fh: u64) -> Result<(), i32> {
+            let arg = [&fh.to_ne_bytes()[..], &[0; 16]].concat();
+            self.call(fs, FUSE_FLUSH, ino, &arg).await.map(drop)
+        }
+
+        async fn fsync(&mut self, fs: &GistFs, ino: u64, fh: u64) -> Result<(), i32> {
+            let arg = [&fh.to_ne_bytes()[..], &[0; 8]].concat();
+            self.call(fs, FUSE_FSYNC, ino, &arg).await.map(drop)
+        }
+
+        async fn create(&mut self, fs: &GistFs, name: &str) -> Result<u64, i32> {
+            let arg = [
+                &(libc::O_WRONLY as u32).to_ne_bytes()[..],
+                &0o644u32.to_ne_bytes(),
+                &0o022u32.to_ne_bytes(),
+                &[0; 4],
+                name.as_bytes(),
+                b"\0",
+            ]
+            .concat();
+            let entry = self.call(fs, FUSE_CREATE, 1, &arg).await?;
+            Ok(u64::from_ne_bytes(entry[..8].try_into().unwrap()))
+        }
+
+        async fn rename(
+            &mut self,
+            fs: &GistFs,
+            name: &str,
+            newname: &str,
+            flags: u32,
+        ) -> Result<(), i32> {
+            let arg = [
+                &1u64.to_ne_bytes()[..],
+                &flags.to_ne_bytes(),
+                &[0; 4],
+                name.as_bytes(),
+                b"\0",
+                newname.as_bytes(),
+                b"\0",
+            ]
+            .concat();
+            self.call(fs, FUSE_RENAME2, 1, &arg).await.map(drop)
+        }
+
+        async fn getxattr(&mut self, fs: &GistFs, ino: u64, name: &str) -> Result<Vec<u8>, i32> {
+            let arg = [&4096u32.to_ne_bytes()[..], &[0; 4], name.as_bytes(), b"\0"].concat();
+            self.call(fs, FUSE_GETXATTR, ino, &arg).await
+        }
+
+        async fn setxattr(
+            &mut self,
+            fs: &GistFs,
+            ino: u64,
+            name: &str,
+            value: &[u8],
+        ) -> Result<(), i32> {
+            let arg = [
+                &(value.len() as u32).to_ne_bytes()[..],
+                &[0; 4],
+                name.as_bytes(),
+                b"\0",
+                value,
+            ]
+            .concat();
+            self.call(fs, FUSE_SETXATTR, ino, &arg).await.map(drop)
+        }
+
+        /// Truncate the file, returning the new size in the attributes.
+        async fn truncate(&mut self, fs: &GistFs, ino: u64, size: u64) -> Result<u64, i32> {
+            let arg = [
+                &FATTR_SIZE.to_ne_bytes()[..],
+                &[0; 12],
+                &size.to_ne_bytes(),
+                &[0; 64],
+            ]
+            .concat();
+            let attr = self.call(fs, FUSE_SETATTR, ino, &arg).await?;
+            // `fuse_attr_out` starts with the timeout, followed by the inode.
+            Ok(u64::from_ne_bytes(attr[24..32].try_into().unwrap()))
+        }
+    }
+
+    /// Encode a request in the wire format, sent by the current user.
+    fn request(opcode: u32, unique: u64, nodeid: u64, arg: &[u8]) -> Vec<u8> {
+        let len = (40 + arg.len()) as u32;
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        [
+            &len.to_ne_bytes()[..],
+            &opcode.to_ne_bytes(),
+            &unique.to_ne_bytes(),
+            &nodeid.to_ne_bytes(),
+            &uid.to_ne_bytes(),
+            &gid.to_ne_bytes(),
+            &[0; 8],
+            arg,
+        ]
+        .concat()
+    }
+
+    /// Build a filesystem serving the files, without the network.
+    async fn mount(builder: GistFsBuilder, files: &[(&str, &str)]) -> GistFs {
+        let fs = builder.build().await.unwrap();
+        fs.load_gist(gist(files)).await.unwrap();
+        fs
+    }
+
+    /// Return a builder of the filesystem spawning no background tasks.
+    fn builder() -> GistFsBuilder {
+        let mut builder = GistFs::builder(Client::new(None), "0123abc".into());
+        builder.max_dirty_age(None);
+        builder
+    }
+
+    #[test]
+    fn assigned_filenames_of_the_empty_names() {
+        // The Gist assigns `gistfileN.txt` to the files with empty names.
+        let gist = gist(&[
+            ("a.txt", "a"),
+            ("gistfile1.txt", "first"),
+            ("gistfile2.txt", "second"),
+        ]);
+        let requested = [("", Some("second")), (" ", Some("first"))];
+        let assigned = assigned_filenames(&requested[..], &gist, |name| name == "a.txt");
+        assert_eq!(
+            assigned,
+            [
+                ("".to_owned(), "gistfile2.txt".to_owned()),
+                (" ".to_owned(), "gistfile1.txt".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn assigned_filenames_skip_the_known_names() {
+        let gist = gist(&[("gistfile1.txt", "known"), ("gistfile2.txt", "new")]);
+        let assigned = assigned_filenames(&[("", None)], &gist, |name| name == "gistfile1.txt");
+        assert_eq!(assigned, [("".to_owned(), "gistfile2.txt".to_owned())]);
+    }
+
+    #[test]
+    fn assigned_filenames_without_a_match() {
+        // The content does not tell which of the candidates is assigned.
+        let gist = gist(&[("gistfile1.txt", "a"), ("gistfile2.txt", "b")]);
+        let assigned = assigned_filenames(&[("", Some("c"))], &gist, |_| false);
+        assert!(assigned.is_empty());
+
+        // Nothing is assigned when the requested name is kept.
+        let gist = self::gist(&[("a.txt", "a")]);
+        assert!(assigned_filenames(&[("a.txt", Some("a"))], &gist, |_| false).is_empty());
+    }
+
+    #[test]
+    fn auto_names() {
+        for &name in &["gistfile1.txt", "gistfile10.txt"] {
+            assert!(is_auto_name(name), "{:?}", name);
+        }
+        for &name in &["gistfile.txt", "gistfile1.md", "gistfilex.txt", "a.txt"] {
+            assert!(!is_auto_name(name), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn root_nlink_follows_the_files() {
+        block_on(async {
+            let node_table = node_table();
+            let files = GistFiles::default();
+            assert_eq!(files.root_nlink().await, 2);
+
+            add_file(&node_table, &files, "a.txt").await;
+            add_file(&node_table, &files, "b.txt").await;
+            assert_eq!(files.root_nlink().await, 4);
+
+            files.unlink(&node_table, "a.txt").await.unwrap();
+            assert_eq!(files.root_nlink().await, 3);
+        });
+    }
+
+    #[test]
+    fn rename_replaces_the_target() {
+        block_on(async {
+            let node_table = node_table();
+            let files = GistFiles::default();
+            add_file(&node_table, &files, "a.txt").await;
+            add_file(&node_table, &files, "b.txt").await;
+            let a = files.find("a.txt").await.unwrap();
+            let b = files.find("b.txt").await.unwrap();
+
+            let rename = files
+                .begin_rename(&node_table, a.clone(), "b.txt", false)
+                .await
+                .unwrap();
+            assert_eq!(rename.replaced_remote().as_deref(), Some("b.txt"));
+            rename.commit().await;
+
+            let node = node_table.lookup(1, OsStr::new("b.txt")).await.unwrap();
+            assert_eq!(node.nodeid(), a.node.nodeid());
+            assert!(node_table.lookup(1, OsStr::new("a.txt")).await.is_none());
+            assert!(files.get(b.node.nodeid()).await.is_none());
+            assert_eq!(b.node.attr().nlink(), 0);
+        });
+    }
+
+    #[test]
+    fn rename_rollback_restores_the_target() {
+        block_on(async {
+            let node_table = node_table();
+            let files = GistFiles::default();
+            add_file(&node_table, &files, "a.txt").await;
+            add_file(&node_table, &files, "b.txt").await;
+            let a = files.find("a.txt").await.unwrap();
+            let b = files.find("b.txt").await.unwrap();
+
+            let rename = files
+                .begin_rename(&node_table, a.clone(), "b.txt", false)
+                .await
+                .unwrap();
+            rename.rollback().await;
+
+            let node = node_table.lookup(1, OsStr::new("a.txt")).await.unwrap();
+            assert_eq!(node.nodeid(), a.node.nodeid());
+            let node = node_table.lookup(1, OsStr::new("b.txt")).await.unwrap();
+            assert_eq!(node.nodeid(), b.node.nodeid());
+            assert_eq!(b.node.attr().nlink(), 1);
+            assert_eq!(&*a.filename(), "a.txt");
+            assert!(files.get(b.node.nodeid()).await.is_some());
+        });
+    }
+
+    #[test]
+    fn rename_uploads_the_remote_name() {
+        block_on(async {
+            let node_table = node_table();
+            let control = ControlDir::new(&node_table, OwnerIds::current())
+                .await
+                .unwrap();
+            let policy = ExecPolicy::default();
+            let files = GistFiles::default();
+            let fake = FakeRemote::new(&[("a.txt", "a")]);
+            files
+                .update(fake.gist(), None, &node_table, &control, &policy)
+                .await
+                .unwrap();
+            let a = files.find("a.txt").await.unwrap();
+
+            let rename = files
+                .begin_rename(&node_table, a.clone(), "b.txt", false)
+                .await
+                .unwrap();
+            rename.upload(&fake, "0123abc", &node_table).await.unwrap();
+            rename.commit().await;
+
+            let uploaded = fake.files.lock().unwrap().clone();
+            assert_eq!(uploaded.keys().collect::<Vec<_>>(), ["b.txt"]);
+            assert_eq!(uploaded["b.txt"], "a");
+            assert_eq!(a.remote().as_deref(), Some("b.txt"));
+        });
+    }
+
+    #[test]
+    fn rename_of_the_file_never_uploaded_is_left_to_the_flush() {
+        block_on(async {
+            let node_table = node_table();
+            let control = ControlDir::new(&node_table, OwnerIds::current())
+                .await
+                .unwrap();
+            let policy = ExecPolicy::default();
+            let files = GistFiles::default();
+            let fake = FakeRemote::new(&[("b.txt", "b")]);
+            files
+                .update(fake.gist(), None, &node_table, &control, &policy)
+                .await
+                .unwrap();
+            add_file(&node_table, &files, "a.txt").await;
+            let a = files.find("a.txt").await.unwrap();
+            a.set_remote(None);
+
+            // Only the deletion of the replaced file is uploaded.
+            let rename = files
+                .begin_rename(&node_table, a.clone(), "b.txt", false)
+                .await
+                .unwrap();
+            rename.upload(&fake, "0123abc", &node_table).await.unwrap();
+            rename.commit().await;
+
+            assert!(fake.files.lock().unwrap().is_empty());
+            assert_eq!(&*a.filename(), "b.txt");
+            assert_eq!(a.remote(), None);
+        });
+    }
+
+    #[test]
+    fn rename_noreplace_keeps_the_target() {
+        block_on(async {
+            let node_table = node_table();
+            let files = GistFiles::default();
+            add_file(&node_table, &files, "a.txt").await;
+            add_file(&node_table, &files, "b.txt").await;
+            let a = files.find("a.txt").await.unwrap();
+
+            let rename = files.begin_rename(&node_table, a.clone(), "b.txt", true);
+            assert_eq!(rename.await.err(), Some(libc::EEXIST));
+            let rename = files.begin_rename(&node_table, a.clone(), "a.txt", true);
+            assert_eq!(rename.await.err(), Some(libc::EEXIST));
+            assert_eq!(&*a.filename(), "a.txt");
+            assert!(node_table.lookup(1, OsStr::new("b.txt")).await.is_some());
+
+            let rename = files
+                .begin_rename(&node_table, a.clone(), "c.txt", true)
+                .await
+                .unwrap();
+            rename.commit().await;
+            assert_eq!(&*a.filename(), "c.txt");
+        });
+    }
+
+    #[test]
+    fn rename_exchange_is_rejected() {
+        block_on(async {
+            let fs = mount(builder(), &[("a.txt", "a"), ("b.txt", "b")]).await;
+            let mut kernel = Kernel::new().await;
+
+            let renamed = kernel.rename(&fs, "a.txt", "b.txt", libc::RENAME_EXCHANGE);
+            assert_eq!(renamed.await, Err(libc::EINVAL));
+            let renamed = kernel.rename(&fs, "a.txt", "b.txt", libc::RENAME_WHITEOUT);
+            assert_eq!(renamed.await, Err(libc::EINVAL));
+            assert_eq!(&*fs.files.find("a.txt").await.unwrap().filename(), "a.txt");
+        });
+    }
+
+    #[test]
+    fn case_insensitive_lookup() {
+        block_on(async {
+            let files = [("a.txt", "a"), ("B.txt", "b")];
+            let mut insensitive = builder();
+            insensitive.case_insensitive(true);
+            let fs = mount(insensitive, &files).await;
+            let mut kernel = Kernel::new().await;
+            let a = kernel.lookup(&fs, "a.txt").await.unwrap();
+            assert_eq!(kernel.lookup(&fs, "A.TXT").await, Ok(a));
+            let b = kernel.lookup(&fs, "B.txt").await.unwrap();
+            assert_eq!(kernel.lookup(&fs, "b.txt").await, Ok(b));
+            assert_eq!(kernel.lookup(&fs, "c.txt").await, Err(libc::ENOENT));
+
+            let fs = mount(builder(), &files).await;
+            let mut kernel = Kernel::new().await;
+            assert!(kernel.lookup(&fs, "a.txt").await.is_ok());
+            assert_eq!(kernel.lookup(&fs, "A.TXT").await, Err(libc::ENOENT));
+        });
+    }
+
+    #[test]
+    fn case_insensitive_create_rejects_the_folded_name() {
+        block_on(async {
+            let mut insensitive =
+                GistFs::builder(Client::new(Some("token".into())), "0123abc".into());
+            insensitive.max_dirty_age(None).case_insensitive(true);
+            let fs = mount(insensitive, &[("a.txt", "a")]).await;
+            let mut kernel = Kernel::new().await;
+            assert_eq!(kernel.create(&fs, "A.TXT").await, Err(libc::EEXIST));
+            assert_eq!(kernel.create(&fs, "a.txt").await, Err(libc::EEXIST));
+            assert!(fs.files.find("A.TXT").await.is_none());
+        });
+    }
+
+    #[test]
+    fn case_sensitive_names_coexist() {
+        block_on(async {
+            let fs = mount(builder(), &[("a.txt", "lower"), ("A.txt", "upper")]).await;
+            let mut kernel = Kernel::new().await;
+            let lower = kernel.lookup(&fs, "a.txt").await.unwrap();
+            let upper = kernel.lookup(&fs, "A.txt").await.unwrap();
+            assert_ne!(lower, upper);
+            let fh = kernel.open(&fs, upper, libc::O_RDONLY).await.unwrap();
+            assert_eq!(
+                kernel.read(&fs, upper, fh, 0, 16).await,
+                Ok(b"upper".to_vec())
+            );
+        });
+    }
+
+    #[test]
+    fn rename_collides_with_the_folded_name() {
+        block_on(async {
+            let node_table = node_table();
+            let files = GistFiles::default();
+            add_file(&node_table, &files, "a.txt").await;
+            add_file(&node_table, &files, "B.txt").await;
+            let a = files.find("a.txt").await.unwrap();
+            let b = files.find("B.txt").await.unwrap();
+
+            assert!(files.collides_folded(&a, "b.txt").await);
+            assert!(files.collides_folded(&a, "B.TXT").await);
+            // The exact name is replaced, and the case of the own name changed.
+            assert!(!files.collides_folded(&a, "B.txt").await);
+            assert!(!files.collides_folded(&a, "A.txt").await);
+            assert!(!files.collides_folded(&b, "b.txt").await);
+            assert!(!files.collides_folded(&a, "c.txt").await);
+        });
+    }
+
+    #[test]
+    fn sanitize_trims_the_trailing_ascii_whitespace() {
+        assert_eq!(sanitize_filename("a.txt \t\r\n"), "a.txt");
+        assert_eq!(sanitize_filename(" a b.txt"), " a b.txt");
+        assert_eq!(sanitize_filename("a.txt\u{3000}"), "a.txt\u{3000}");
+        assert_eq!(sanitize_filename(" \n"), "");
+    }
+
+    #[test]
+    fn update_trims_the_names_unless_taken() {
+        block_on(async {
+            let node_table = node_table();
+            let control = ControlDir::new(&node_table, OwnerIds::current())
+                .await
+                .unwrap();
+            let files = GistFiles {
+                sanitize_filenames: true,
+                ..GistFiles::default()
+            };
+            let gist = gist(&[
+                ("a.txt  ", "a"),
+                ("b.txt", "b"),
+                ("b.txt ", "b with space"),
+                ("\n", "blank"),
+            ]);
+            files
+                .update(gist, None, &node_table, &control, &ExecPolicy::default())
+                .await
+                .unwrap();
+
+            let a = files.find("a.txt").await.unwrap();
+            assert_eq!(a.remote().as_deref(), Some("a.txt  "));
+            assert!(files.find("a.txt  ").await.is_none());
+            let b = files.find("b.txt").await.unwrap();
+            assert_eq!(b.remote().as_deref(), Some("b.txt"));
+            let spaced = files.find("b.txt ").await.unwrap();
+            assert_eq!(spaced.remote().as_deref(), Some("b.txt "));
+            assert!(files.find("\n").await.is_some());
+        });
+    }
+
+    #[test]
+    fn sanitized_lookup() {
+        block_on(async {
+            let mut sanitized = builder();
+            sanitized.sanitize_filenames(true);
+            let fs = mount(sanitized, &[("myfile", "content")]).await;
+            let mut kernel = Kernel::new().await;
+            let ino = kernel.lookup(&fs, "myfile").await.unwrap();
+            assert_eq!(kernel.lookup(&fs, "myfile  ").await, Ok(ino));
+            assert_eq!(kernel.lookup(&fs, "myfile\n").await, Ok(ino));
+
+            let fs = mount(builder(), &[("myfile", "content")]).await;
+            let mut kernel = Kernel::new().await;
+            assert_eq!(kernel.lookup(&fs, "myfile  ").await, Err(libc::ENOENT));
+        });
+    }
+
+    #[test]
+    fn flushes_wait_for_the_upload_limit() {
+        // The flushes beyond the limit arm the timers, spawned on the runtime.
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let mut limited = GistFs::builder(Client::new(Some("token".into())), "0123abc".into());
+            limited.max_dirty_age(None).max_uploads_per_minute(2);
+            let mut fs = mount(limited, &[("a.log", "start\n")]).await;
+            let fake = Arc::new(FakeRemote::new(&[("a.log", "start\n")]));
+            fs.remote = fake.clone();
+
+            let mut kernel = Kernel::new().await;
+            let ino = kernel.lookup(&fs, "a.log").await.unwrap();
+            let fh = kernel.open(&fs, ino, libc::O_WRONLY).await.unwrap();
+            for i in 0..10 {
+                let written = kernel.write(&fs, ino, fh, 6 * (i + 1), b"line\n").await;
+                assert_eq!(written, Ok(5));
+                assert_eq!(kernel.flush(&fs, ino, fh).await, Ok(()));
+            }
+            assert_eq!(fake.updates.load(), 2);
+            assert!(fs.files.find("a.log").await.unwrap().is_dirty());
+
+            // The fsync waits for the next token rather than exceed the limit.
+            assert!(kernel.fsync(&fs, ino, fh).now_or_never().is_none());
+            assert_eq!(fake.updates.load(), 2);
+        });
+    }
+
+    #[test]
+    fn flush_skips_the_file_failing_the_policy() {
+        block_on(async {
+            let node_table = node_table();
+            let control = ControlDir::new(&node_table, OwnerIds::current())
+                .await
+                .unwrap();
+            let policy = ExecPolicy::default();
+            let files = GistFiles {
+                content_policy: Box::new(NoNulBytesPolicy),
+                ..GistFiles::default()
+            };
+            let fake = FakeRemote::new(&[("a.txt", "a\n"), ("b.txt", "b\n")]);
+            files
+                .update(fake.gist(), None, &node_table, &control, &policy)
+                .await
+                .unwrap();
+            let a = files.find("a.txt").await.unwrap();
+            let b = files.find("b.txt").await.unwrap();
+            assert!(a.write(0, b"\0", &policy).await.unwrap());
+            assert!(b.write(0, b"B", &policy).await.unwrap());
+
+            let flushed = files.flush(&fake, "0123abc", &node_table, FlushReason::Timer);
+            assert!(flushed.await.is_ok());
+            assert_eq!(fake.files.lock().unwrap()["a.txt"], "a\n");
+            assert_eq!(fake.files.lock().unwrap()["b.txt"], "B\n");
+            assert!(a.is_dirty());
+            assert!(!b.is_dirty());
+            assert_eq!(files.errors.len().await, 1);
+
+            // The caller waiting for the file takes the error instead.
+            let reason = FlushReason::Close(a.node.nodeid());
+            let err = files
+                .flush(&fake, "0123abc", &node_table, reason)
+                .await
+                .unwrap_err();
+            let violation = err.downcast_ref::<PolicyViolation>().unwrap();
+            assert_eq!(violation.error_code, libc::EINVAL);
+            assert_eq!(files.errors.len().await, 1);
+            assert_eq!(fake.updates.load(), 1);
+        });
+    }
+
+    #[test]
+    fn zero_size_read_skips_the_content_lock() {
+        block_on(async {
+            let fs = mount(builder(), &[("a.txt", "content")]).await;
+            let mut kernel = Kernel::new().await;
+            let ino = kernel.lookup(&fs, "a.txt").await.unwrap();
+            let fh = kernel.open(&fs, ino, libc::O_RDONLY).await.unwrap();
+            assert_eq!(kernel.read(&fs, ino, fh, 0, 4).await, Ok(b"cont".to_vec()));
+
+            let file = fs.files.get(ino).await.unwrap();
+            let _content = file.content.lock().await;
+            let read = kernel.read(&fs, ino, fh, 0, 0).now_or_never();
+            assert_eq!(read, Some(Ok(vec![])));
+            let read = kernel.read(&fs, ino, fh, 0, 4).now_or_never();
+            assert!(read.is_none(), "the read bypasses the content lock");
+        });
+    }
+
+    #[test]
+    fn visibility_is_readable_but_not_settable() {
+        block_on(async {
+            let mut builder = GistFs::builder(Client::new(Some("token".into())), "0123abc".into());
+            builder.max_dirty_age(None);
+            let fs = mount(builder, &[("a.txt", "a")]).await;
+            let mut kernel = Kernel::new().await;
+            assert_eq!(
+                kernel.getxattr(&fs, 1, PUBLIC_XATTR).await.as_deref(),
+                Ok(&b"false"[..])
+            );
+            assert_eq!(
+                kernel.setxattr(&fs, 1, PUBLIC_XATTR, b"true").await,
+                Err(libc::ENOTSUP)
+            );
+            assert_eq!(
+                kernel.getxattr(&fs, 1, PUBLIC_XATTR).await.as_deref(),
+                Ok(&b"false"[..])
+            );
+        });
+    }
+
+    #[test]
+    fn writes_beyond_the_maximum_size_fail_with_efbig() {
+        block_on(async {
+            let mut builder = GistFs::builder(Client::new(Some("token".into())), "0123abc".into());
+            builder.max_dirty_age(None);
+            let fs = mount(builder, &[("a.txt", "content")]).await;
+            let mut kernel = Kernel::new().await;
+            let ino = kernel.lookup(&fs, "a.txt").await.unwrap();
+            let fh = kernel.open(&fs, ino, libc::O_WRONLY).await.unwrap();
+
+            let write = kernel.write(&fs, ino, fh, u64::MAX - 1, b"data").await;
+            assert_eq!(write, Err(libc::EFBIG));
+            let write = kernel
+                .write(&fs, ino, fh, MAX_CONTENT_SIZE - 3, b"data")
+                .await;
+            assert_eq!(write, Err(libc::EFBIG));
+            let size = kernel.truncate(&fs, ino, MAX_CONTENT_SIZE + 1).await;
+            assert_eq!(size, Err(libc::EFBIG));
+            let size = kernel.truncate(&fs, ino, u64::MAX).await;
+            assert_eq!(size, Err(libc::EFBIG));
+
+            let file = fs.files.get(ino).await.unwrap();
+            assert_eq!(file.node.attr().size(), 7);
+            assert_eq!(file.content.lock().await.bytes()[..], b"content"[..]);
+        });
+    }
+
+    #[test]
+    fn valid_mtime_boundaries() {
+        block_on(async {
+            let mut guarded = builder();
+            guarded.max_mtime_offset(Some(Duration::from_secs(60 * 60)));
+            let fs = mount(guarded, &[]).await;
+            let now = Utc::now().timestamp() as u64;
+            assert!(fs.is_valid_mtime(0));
+            assert!(fs.is_valid_mtime(now));
+            assert!(fs.is_valid_mtime(now + 60 * 60 - 60));
+            assert!(!fs.is_valid_mtime(now + 60 * 60 + 60));
+            // The kernel passes the times before the epoch as negative values.
+            assert!(!fs.is_valid_mtime(-1i64 as u64));
+            assert!(!fs.is_valid_mtime(i64::MIN as u64));
+            assert!(!fs.is_valid_mtime(u64::MAX));
+
+            let mut unguarded = builder();
+            unguarded.max_mtime_offset(None);
+            let fs = mount(unguarded, &[]).await;
+            assert!(fs.is_valid_mtime(now));
+            assert!(fs.is_valid_mtime(253_402_300_799)); // 9999-12-31T23:59:59Z
+            assert!(fs.is_valid_mtime(i64::MAX as u64));
+            assert!(!fs.is_valid_mtime(i64::MAX as u64 + 1));
+            assert!(!fs.is_valid_mtime(u64::MAX));
+        });
+    }
+
+    /// The files of the Gist in the operation sequences.
+    const NAMES: [&str; 3] = ["a.txt", "b.txt", "c.txt"];
+
+    /// An operation on the mount, or on the Gist by another writer.
+    #[derive(Debug, Clone)]
+    enum Op {
+        Write {
+            file: usize,
+            offset: usize,
+            data: String,
+        },
+        Truncate {
+            file: usize,
+            size: usize,
+        },
+        Read {
+            file: usize,
+            offset: u64,
+            size: u32,
+        },
+        Unlink {
+            file: usize,
+        },
+        Flush,
+        /// Another writer replaces or deletes the file, and the mount is refreshed.
+        Refresh {
+            file: usize,
+            content: Option<String>,
+        },
+    }
+
+    /// A xorshift generator, so that a failing sequence is reproduced by its seed.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+
+        fn text(&mut self, max_len: usize) -> String {
+            let len = 1 + self.below(max_len);
+            (0..len)
+                .map(|_| (b'a' + self.below(26) as u8) as char)
+                .collect()
+        }
+    }
+
+    fn generate(rng: &mut Rng, len: usize) -> Vec<Op> {
+        (0..len)
+            .map(|_| {
+                let file = rng.below(NAMES.len());
+                match rng.below(12) {
+                    0..=3 => Op::Write {
+                        file,
+                        offset: rng.below(12),
+                        data: rng.text(6),
+                    },
+                    4 => Op::Truncate {
+                        file,
+                        size: rng.below(10),
+                    },
+                    5..=6 => Op::Read {
+                        file,
+                        offset: rng.below(12) as u64,
+                        size: rng.below(12) as u32,
+                    },
+                    7 => Op::Unlink { file },
+                    8..=9 => Op::Flush,
+                    10 => Op::Refresh {
+                        file,
+                        content: Some(rng.text(10)),
+                    },
+                    _ => Op::Refresh {
+                        file,
+                        content: None,
+                    },
+                }
+            })
+            .collect()
+    }
+
+    /// The observable state of the mount and the Gist, with the same
+    /// upload semantics as the filesystem.
+    #[derive(Debug)]
+    struct Model {
+        local: BTreeMap<String, Vec<u8>>,
+        dirty: BTreeSet<String>,
+        unlinked: BTreeSet<String>,
+        remote: BTreeMap<String, String>,
+    }
+
+    impl Model {
+        fn new(remote: &BTreeMap<String, String>) -> Self {
+            Self {
+                local: remote
+                    .iter()
+                    .map(|(name, content)| (name.clone(), content.clone().into_bytes()))
+                    .collect(),
+                dirty: BTreeSet::new(),
+                unlinked: BTreeSet::new(),
+                remote: remote.clone(),
+            }
+        }
+
+        fn write(&mut self, name: &str, offset: usize, data: &[u8]) -> bool {
+            let content = match self.local.get_mut(name) {
+                Some(content) => content,
+                None => return false,
+            };
+            let end = offset + data.len();
+            if content.len() < end {
+                content.resize(end, 0);
+            }
+            content[offset..end].copy_from_slice(data);
+            self.dirty.insert(name.to_owned());
+            true
+        }
+
+        fn truncate(&mut self, name: &str, size: usize) -> bool {
+            let content = match self.local.get_mut(name) {
+                Some(content) => content,
+                None => return false,
+            };
+            content.resize(size, 0);
+            self.dirty.insert(name.to_owned());
+            true
+        }
+
+        fn read(&self, name: &str, offset: u64, size: u32) -> Option<Vec<u8>> {
+            let content = self.local.get(name)?;
+            let start = std::cmp::min(offset as usize, content.len());
+            let end = std::cmp::min(start + size as usize, content.len());
+            Some(content[start..end].to_vec())
+        }
+
+        fn unlink(&mut self, name: &str) -> Result<(), i32> {
+            if !self.local.contains_key(name) {
+                return Err(libc::ENOENT);
+            }
+            if self.local.len() == 1 {
+                return Err(libc::EPERM);
+            }
+            self.local.remove(name);
+            self.dirty.remove(name);
+            self.unlinked.insert(name.to_owned());
+            Ok(())
+        }
+
+        /// Upload the changes, returning whether the upload is sent.
+        fn flush(&mut self) -> bool {
+            if !self.unlinked.is_empty() && self.local.is_empty() {
+                // The deletions would leave the Gist without files.
+                return false;
+            }
+            let local = &self.local;
+            let remote = &mut self.remote;
+            self.dirty.retain(|name| {
+                let content = &local[name];
+                // The empty content is kept unsent.
+                if content.is_empty() {
+                    return true;
+                }
+                remote.insert(name.clone(), String::from_utf8(content.clone()).unwrap());
+                false
+            });
+            for name in std::mem::take(&mut self.unlinked) {
+                self.remote.remove(&name);
+            }
+            true
+        }
+
+        fn refresh(&mut self, remote: &BTreeMap<String, String>) {
+            self.remote = remote.clone();
+            let removed: Vec<String> = self
+                .local
+                .keys()
+                .filter(|name| !remote.contains_key(*name))
+                .cloned()
+                .collect();
+            for name in removed {
+                self.local.remove(&name);
+                self.dirty.remove(&name);
+            }
+            for (name, content) in remote {
+                if self.unlinked.contains(name) || self.dirty.contains(name) {
+                    continue;
+                }
+                self.local
+                    .insert(name.clone(), content.clone().into_bytes());
+            }
+        }
+    }
+
+    /// A Gist on the other end of the uploads, kept in memory.
+    #[derive(Debug)]
+    struct FakeRemote {
+        files: std::sync::Mutex<BTreeMap<String, String>>,
+        updates: AtomicCell<usize>,
+    }
+
+    impl FakeRemote {
+        fn new(files: &[(&str, &str)]) -> Self {
+            let files = files
+                .iter()
+                .map(|&(name, content)| (name.to_owned(), content.to_owned()))
+                .collect();
+            Self {
+                files: std::sync::Mutex::new(files),
+                updates: AtomicCell::new(0),
+            }
+        }
+
+        fn gist(&self) -> Gist {
+            let files = self.files.lock().unwrap();
+            let files: Vec<(&str, &str)> = files
+                .iter()
+                .map(|(name, content)| (&**name, &**content))
+                .collect();
+            gist(&files[..])
+        }
+    }
+
+    impl Remote for FakeRemote {
+        fn update_gist<'a>(
+            &'a self,
+            _: &'a str,
+            _: Option<&'a ETag>,
+            patch: GistPatch<'a>,
+        ) -> BoxFuture<'a, anyhow::Result<(Gist, Option<ETag>)>> {
+            self.updates.fetch_add(1);
+            let mut files = self.files.lock().unwrap();
+            for (name, file) in patch.files {
+                let old = files.remove(*name);
+                if let Some(file) = file {
+                    let content = file.content.map(str::to_owned).or(old).unwrap_or_default();
+                    files.insert(file.filename.unwrap_or(name).to_owned(), content);
+                }
+            }
+            drop(files);
+            future::ready(Ok((self.gist(), None))).boxed()
+        }
+
+        fn fetch_gist<'a>(&'a self, _: &'a str) -> BoxFuture<'a, anyhow::Result<Fetched>> {
+            future::ready(Ok(Some((self.gist(), None)))).boxed()
+        }
+    }
+
+    /// Run the operations against the files and the model, returning the
+    /// first difference observed.
+    fn run(ops: &[Op]) -> Result<(), String> {
+        block_on(async {
+            let node_table = node_table();
+            let control = ControlDir::new(&node_table, OwnerIds::current())
+                .await
+                .unwrap();
+            let policy = ExecPolicy::default();
+            let clock = Arc::new(MockClock::new());
+            let files = GistFiles {
+                clock: SharedClock::new(clock.clone()),
+                ..GistFiles::default()
+            };
+            let contents: Vec<String> = NAMES
+                .iter()
+                .map(|name| format!("{} content\n", name))
+                .collect();
+            let initial: Vec<(&str, &str)> = NAMES
+                .iter()
+                .zip(&contents)
+                .map(|(&name, content)| (name, &**content))
+                .collect();
+            let fake = FakeRemote::new(&initial);
+            let mut model = Model::new(&fake.files.lock().unwrap());
+            files
+                .update(fake.gist(), None, &node_table, &control, &policy)
+                .await
+                .unwrap();
+
+            for (i, op) in ops.iter().enumerate() {
+                let fail = |msg: String| format!("op #{} {:?}: {}", i, op, msg);
+                clock.advance(Duration::from_secs(1));
+                match *op {
+                    Op::Write {
+                        file,
+                        offset,
+                        ref data,
+                    } => {
+                        let found = files.find(NAMES[file]).await;
+                        if let Some(ref found) = found {
+                            found
+                                .write(offset as u64, data.as_bytes(), &policy)
+                                .await
+                                .unwrap();
+                        }
+                        let expected = model.write(NAMES[file], offset, data.as_bytes());
+                        if found.is_some() != expected {
+                            return Err(fail(format!("found={}", found.is_some())));
+                        }
+                    }
+                    Op::Truncate { file, size } => {
+                        let found = files.find(NAMES[file]).await;
+                        if let Some(ref found) = found {
+                            found.truncate(size as u64, &policy).await.unwrap();
+                        }
+                        let expected = model.truncate(NAMES[file], size);
+                        if found.is_some() != expected {
+                            return Err(fail(format!("found={}", found.is_some())));
+                        }
+                    }
+                    Op::Read { file, offset, size } => {
+                        let data = match files.find(NAMES[file]).await {
+                            Some(found) => {
+                                let content = found.content.lock().await.bytes_for_read();
+                                Some(read_range(&content, offset, size).to_vec())
+                            }
+                            None => None,
+                        };
+                        let expected = model.read(NAMES[file], offset, size);
+                        if data != expected {
+                            return Err(fail(format!("read {:?}, expected {:?}", data, expected)));
+                        }
+                    }
+                    Op::Unlink { file } => {
+                        let result = files.unlink(&node_table, NAMES[file]).await.map(drop);
+                        let expected = model.unlink(NAMES[file]);
+                        if result != expected {
+                            return Err(fail(format!("{:?}, expected {:?}", result, expected)));
+                        }
+                    }
+                    Op::Flush => {
+                        let result = files
+                            .flush(&fake, "0123abc", &node_table, FlushReason::Timer)
+                            .await;
+                        let expected = model.flush();
+                        if result.is_ok() != expected {
+                            return Err(fail(format!("{:?}, expected ok={}", result, expected)));
+                        }
+                    }
+                    Op::Refresh { file, ref content } => {
+                        {
+                            let mut remote = fake.files.lock().unwrap();
+                            match content {
+                                Some(content) => {
+                                    remote.insert(NAMES[file].to_owned(), content.clone());
+                                }
+                                // The Gist is never left without files.
+                                None if remote.len() > 1 => {
+                                    remote.remove(NAMES[file]);
+                                }
+                                None => (),
+                            }
+                        }
+                        files
+                            .update(fake.gist(), None, &node_table, &control, &policy)
+                            .await
+                            .map_err(|err| fail(format!("refresh failed: {:#}", err)))?;
+                        model.refresh(&fake.files.lock().unwrap());
+                    }
+                }
+                check(&files, &node_table, &fake, &model)
+                    .await
+                    .map_err(fail)?;
+            }
+
+            // Everything left is uploaded in the end.
+            let result = files
+                .flush(&fake, "0123abc", &node_table, FlushReason::Unmount)
+                .await;
+            let expected = model.flush();
+            if result.is_ok() != expected {
+                return Err(format!(
+                    "the last flush: {:?}, expected ok={}",
+                    result, expected
+                ));
+            }
+            check(&files, &node_table, &fake, &model)
+                .await
+                .map_err(|msg| format!("after the last flush: {}", msg))
+        })
+    }
+
+    async fn check(
+        files: &GistFiles,
+        node_table: &NodeTable,
+        fake: &FakeRemote,
+        model: &Model,
+    ) -> Result<(), String> {
+        for &name in &NAMES {
+            let found = files.find(name).await;
+            let entry = node_table.lookup(1, OsStr::new(name)).await;
+            let expected = model.local.get(name);
+            if found.is_some() != expected.is_some() || entry.is_some() != expected.is_some() {
+                return Err(format!(
+                    "{}: file={}, entry={}, expected={}",
+                    name,
+                    found.is_some(),
+                    entry.is_some(),
+                    expected.is_some()
+                ));
+            }
+            let (found, expected) = match (found, expected) {
+                (Some(found), Some(expected)) => (found, expected),
+                _ => continue,
+            };
+            let (content, _) = found.snapshot().await;
+            if *content != *expected {
+                return Err(format!(
+                    "{}: content {:?}, expected {:?}",
+                    name, content, expected
+                ));
+            }
+            let size = found.node.attr().size();
+            if size != expected.len() as u64 {
+                return Err(format!(
+                    "{}: size {}, expected {}",
+                    name,
+                    size,
+                    expected.len()
+                ));
+            }
+            if found.is_dirty() != model.dirty.contains(name) {
+                return Err(format!("{}: dirty={}", name, found.is_dirty()));
+            }
+        }
+        let remote = fake.files.lock().unwrap();
+        if *remote != model.remote {
+            return Err(format!(
+                "uploaded {:?}, expected {:?}",
+                remote, model.remote
+            ));
+        }
+        Ok(())
+    }
+
+    /// Remove the operations one at a time as long as the sequence still fails.
+    fn shrink(mut ops: Vec<Op>, fails: impl Fn(&[Op]) -> bool) -> Vec<Op> {
+        loop {
+            let shrunk = (0..ops.len()).find_map(|i| {
+                let mut candidate = ops.clone();
+                candidate.remove(i);
+                if fails(&candidate) {
+                    Some(candidate)
+                } else {
+                    None
+                }
+            });
+            match shrunk {
+                Some(shrunk) => ops = shrunk,
+                None => return ops,
+            }
+        }
+    }
+
+    #[test]
+    fn operation_sequences_follow_the_model() {
+        for seed in 1..=200 {
+            let ops = generate(&mut Rng(seed), 40);
+            if let Err(err) = run(&ops) {
+                let ops = shrink(ops, |ops| run(ops).is_err());
+                panic!(
+                    "seed {}: {}\nminimal sequence: {:#?}\n{}",
+                    seed,
+                    err,
+                    ops,
+                    run(&ops).unwrap_err()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn shrink_to_the_failing_operation() {
+        let ops = generate(&mut Rng(7), 40);
+        let flushes = ops.iter().filter(|op| matches!(op, Op::Flush)).count();
+        assert!(flushes > 1);
+
+        let fails = |ops: &[Op]| ops.iter().any(|op| matches!(op, Op::Flush));
+        let shrunk = shrink(ops, fails);
+        assert!(matches!(shrunk[..], [Op::Flush]));
+    }
+
+    #[test]
+    fn regression_sequences() {
+        // A write past the end leaves zeros in the gap, and is read back
+        // from the requested offset.
+        run(&[
+            Op::Write {
+                file: 0,
+                offset: 20,
+                data: "xyz".to_owned(),
+            },
+            Op::Read {
+                file: 0,
+                offset: 12,
+                size: 16,
+            },
+            Op::Flush,
+        ])
+        .unwrap();
+
+        // A refresh never replaces the size of a dirty file with the remote one.
+        run(&[
+            Op::Truncate { file: 1, size: 2 },
+            Op::Refresh {
+                file: 1,
+                content: Some("a longer remote content".to_owned()),
+            },
+            Op::Read {
+                file: 1,
+                offset: 0,
+                size: 64,
+            },
+        ])
+        .unwrap();
+    }
+}
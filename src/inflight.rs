@@ -0,0 +1,116 @@
+//! Registry of the FUSE requests being processed, for debugging hangs.
+
+use polyfuse::Operation;
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// The operations whose replies have not been sent, keyed by the ID
+/// assigned on arrival, since polyfuse does not expose the unique ID of
+/// the request.
+///
+/// The lock is never held across an await point.
+#[derive(Debug, Default)]
+pub(crate) struct InflightOps {
+    ops: Mutex<HashMap<u64, Inflight>>,
+    next_id: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Inflight {
+    name: &'static str,
+    ino: u64,
+    started: Instant,
+}
+
+impl InflightOps {
+    /// Register the operation until the returned guard is dropped.
+    ///
+    /// The guard is dropped however the handler returns.
+    pub(crate) fn register(&self, name: &'static str, ino: u64) -> InflightGuard<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.ops.lock().unwrap().insert(
+            id,
+            Inflight {
+                name,
+                ino,
+                started: Instant::now(),
+            },
+        );
+        InflightGuard { ops: self, id }
+    }
+
+    /// Render the operations as lines of the ID, the operation,
+    /// the inode number and the age, the oldest first.
+    pub(crate) fn render(&self) -> String {
+        let now = Instant::now();
+        let mut ops: Vec<(u64, &'static str, u64, Duration)> = self
+            .ops
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, op)| (id, op.name, op.ino, now - op.started))
+            .collect();
+        ops.sort_by_key(|op| Reverse(op.3));
+
+        let mut rendered = String::new();
+        for (id, name, ino, age) in ops {
+            let _ = writeln!(rendered, "{}\t{}\t{}\t{}ms", id, name, ino, age.as_millis());
+        }
+        rendered
+    }
+}
+
+/// Removes the operation from the registry on drop.
+pub(crate) struct InflightGuard<'a> {
+    ops: &'a InflightOps,
+    id: u64,
+}
+
+impl InflightGuard<'_> {
+    /// Return the ID assigned to the operation.
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Drop for InflightGuard<'_> {
+    fn drop(&mut self) {
+        self.ops.ops.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Return the name of the operation and the inode it is applied to.
+///
+/// The operations on the entries of a directory report the parent.
+pub(crate) fn describe<T>(op: &Operation<'_, T>) -> (&'static str, u64) {
+    match op {
+        Operation::Lookup(op) => ("lookup", op.parent()),
+        Operation::Forget(..) => ("forget", 0),
+        Operation::Getattr(op) => ("getattr", op.ino()),
+        Operation::Setattr(op) => ("setattr", op.ino()),
+        Operation::Opendir(op) => ("opendir", op.ino()),
+        Operation::Readdir(op) => ("readdir", op.ino()),
+        Operation::Create(op) => ("create", op.parent()),
+        Operation::Rename(op) => ("rename", op.parent()),
+        Operation::Unlink(op) => ("unlink", op.parent()),
+        Operation::Link(op) => ("link", op.ino()),
+        Operation::Open(op) => ("open", op.ino()),
+        Operation::Read(op) => ("read", op.ino()),
+        Operation::Write(op, ..) => ("write", op.ino()),
+        Operation::Flush(op) => ("flush", op.ino()),
+        Operation::Fsync(op) => ("fsync", op.ino()),
+        Operation::Release(op) => ("release", op.ino()),
+        Operation::Access(op) => ("access", op.ino()),
+        Operation::Getxattr(op) => ("getxattr", op.ino()),
+        Operation::Setxattr(op) => ("setxattr", op.ino()),
+        _ => ("other", 0),
+    }
+}
@@ -0,0 +1,88 @@
+//! Classification of the operations that do not match the kind of the inode.
+
+use polyfuse::FileAttr;
+
+/// The kind of an inode.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum InodeKind {
+    Dir,
+    File,
+}
+
+impl InodeKind {
+    pub(crate) fn of(attr: &FileAttr) -> Self {
+        if attr.mode() & libc::S_IFMT == libc::S_IFDIR {
+            InodeKind::Dir
+        } else {
+            InodeKind::File
+        }
+    }
+}
+
+/// The kind of an operation, in terms of the inode it is applied to.
+///
+/// The operations on the entries of a directory, such as `Lookup` and
+/// `Create`, are applied to the parent.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum OpKind {
+    Lookup,
+    Create,
+    Opendir,
+    Readdir,
+    Open,
+    Read,
+    Write,
+}
+
+/// Return the error of applying the operation to the kind of inode, if any.
+///
+/// The operations expecting a directory fail with `ENOTDIR` and the ones
+/// expecting a regular file fail with `EISDIR`.
+pub(crate) fn mismatch(kind: InodeKind, op: OpKind) -> Option<i32> {
+    match (kind, op) {
+        (InodeKind::File, OpKind::Lookup)
+        | (InodeKind::File, OpKind::Create)
+        | (InodeKind::File, OpKind::Opendir)
+        | (InodeKind::File, OpKind::Readdir) => Some(libc::ENOTDIR),
+        (InodeKind::Dir, OpKind::Open)
+        | (InodeKind::Dir, OpKind::Read)
+        | (InodeKind::Dir, OpKind::Write) => Some(libc::EISDIR),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mismatch() {
+        use InodeKind::{Dir, File};
+        let enotdir = Some(libc::ENOTDIR);
+        let eisdir = Some(libc::EISDIR);
+        let cases = [
+            (OpKind::Lookup, None, enotdir),
+            (OpKind::Create, None, enotdir),
+            (OpKind::Opendir, None, enotdir),
+            (OpKind::Readdir, None, enotdir),
+            (OpKind::Open, eisdir, None),
+            (OpKind::Read, eisdir, None),
+            (OpKind::Write, eisdir, None),
+        ];
+        for &(op, on_dir, on_file) in &cases {
+            assert_eq!(mismatch(Dir, op), on_dir, "{:?} on a directory", op);
+            assert_eq!(mismatch(File, op), on_file, "{:?} on a file", op);
+        }
+    }
+
+    #[test]
+    fn test_inode_kind_of() {
+        let mut attr = FileAttr::default();
+        attr.set_mode(libc::S_IFDIR | 0o755);
+        assert_eq!(InodeKind::of(&attr), InodeKind::Dir);
+        attr.set_mode(libc::S_IFREG | 0o644);
+        assert_eq!(InodeKind::of(&attr), InodeKind::File);
+        attr.set_mode(libc::S_IFLNK | 0o777);
+        assert_eq!(InodeKind::of(&attr), InodeKind::File);
+    }
+}
@@ -0,0 +1,181 @@
+//! Reduction of the pending changes of the files into a single patch.
+
+use gist_client::GistPatchFile;
+use std::collections::{BTreeMap, HashSet};
+
+/// The changes made to a file since its last upload.
+#[derive(Debug, Copy, Clone)]
+pub struct Pending<'a> {
+    /// The name of the file on the Gist, or `None` if it has never been uploaded.
+    pub remote: Option<&'a str>,
+
+    /// The current name of the file, or `None` if it has been deleted.
+    pub local: Option<&'a str>,
+
+    /// The content to be uploaded, if modified.
+    pub content: Option<&'a str>,
+}
+
+/// Compute the minimal patch equivalent to the pending changes.
+///
+/// A file deleted and then re-created under the same name turns into
+/// an update of its content, and a file deleted before it has ever been
+/// uploaded produces no entry. The entries are sorted by the filename.
+pub fn reduce<'a>(pending: &[Pending<'a>]) -> Vec<(&'a str, Option<GistPatchFile<'a>>)> {
+    let mut deleted: HashSet<&str> = pending
+        .iter()
+        .filter(|p| p.local.is_none())
+        .filter_map(|p| p.remote)
+        .collect();
+
+    let mut patch = BTreeMap::new();
+    for p in pending {
+        let local = match p.local {
+            Some(local) => local,
+            None => continue,
+        };
+
+        let remote = match p.remote {
+            Some(remote) => Some(remote),
+            None if deleted.remove(local) => Some(local),
+            None => None,
+        };
+
+        match remote {
+            Some(remote) => {
+                let filename = if remote != local { Some(local) } else { None };
+                if filename.is_some() || p.content.is_some() {
+                    patch.insert(
+                        remote,
+                        Some(GistPatchFile {
+                            filename,
+                            content: p.content,
+                        }),
+                    );
+                }
+            }
+            None => {
+                // The Gist requires the content of new files.
+                patch.insert(
+                    local,
+                    Some(GistPatchFile {
+                        filename: None,
+                        content: Some(p.content.unwrap_or("")),
+                    }),
+                );
+            }
+        }
+    }
+
+    for remote in deleted {
+        patch.insert(remote, None);
+    }
+
+    patch.into_iter().collect()
+}
+
+/// Fail if the patch deletes the files while none of the files are left,
+/// since a Gist cannot be left without files.
+///
+/// `remaining` is the number of the files kept on the mount.
+pub fn ensure_not_emptied(
+    patch: &[(&str, Option<GistPatchFile<'_>>)],
+    remaining: usize,
+) -> anyhow::Result<()> {
+    let deletes = patch.iter().any(|(_, file)| file.is_none());
+    anyhow::ensure!(
+        !deletes || remaining > 0,
+        "the Gist would be left without files"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending<'a>(
+        remote: Option<&'a str>,
+        local: Option<&'a str>,
+        content: Option<&'a str>,
+    ) -> Pending<'a> {
+        Pending {
+            remote,
+            local,
+            content,
+        }
+    }
+
+    fn rename(filename: &str) -> Option<GistPatchFile<'_>> {
+        Some(GistPatchFile {
+            filename: Some(filename),
+            content: None,
+        })
+    }
+
+    fn content(content: &str) -> Option<GistPatchFile<'_>> {
+        Some(GistPatchFile {
+            filename: None,
+            content: Some(content),
+        })
+    }
+
+    #[test]
+    fn test_reduce() {
+        let cases = vec![
+            (
+                "create then unlink",
+                vec![pending(None, None, None)],
+                vec![],
+            ),
+            (
+                "rename then rename",
+                vec![pending(Some("a.txt"), Some("c.txt"), None)],
+                vec![("a.txt", rename("c.txt"))],
+            ),
+            (
+                "rename then unlink",
+                vec![pending(Some("a.txt"), None, None)],
+                vec![("a.txt", None)],
+            ),
+            (
+                "unlink then create",
+                vec![
+                    pending(Some("a.txt"), None, None),
+                    pending(None, Some("a.txt"), Some("new")),
+                ],
+                vec![("a.txt", content("new"))],
+            ),
+            (
+                "create without content",
+                vec![pending(None, Some("a.txt"), None)],
+                vec![("a.txt", content(""))],
+            ),
+            (
+                "rename onto a deleted file",
+                vec![
+                    pending(Some("a.txt"), Some("b.txt"), None),
+                    pending(Some("b.txt"), None, None),
+                ],
+                vec![("a.txt", rename("b.txt")), ("b.txt", None)],
+            ),
+            (
+                "unchanged",
+                vec![pending(Some("a.txt"), Some("a.txt"), None)],
+                vec![],
+            ),
+        ];
+
+        for (name, pending, expected) in cases {
+            assert_eq!(reduce(&pending[..]), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_ensure_not_emptied() {
+        let patch = [("a.txt", None)];
+        assert!(ensure_not_emptied(&patch[..], 0).is_err());
+        assert!(ensure_not_emptied(&patch[..], 1).is_ok());
+        assert!(ensure_not_emptied(&[("a.txt", content("new"))][..], 0).is_ok());
+    }
+}
@@ -0,0 +1,183 @@
+//! Per-host lock preventing concurrent writable mounts of the same Gist.
+
+use std::{
+    fs::{self, DirBuilder, File, OpenOptions},
+    io,
+    os::unix::{
+        fs::{DirBuilderExt as _, MetadataExt as _, OpenOptionsExt as _},
+        io::AsRawFd,
+    },
+    path::{Path, PathBuf},
+};
+
+/// An advisory lock held by the writable mount of a Gist.
+///
+/// The lock is released when this value is dropped or the process exits.
+#[derive(Debug)]
+pub struct MountLock {
+    file: File,
+}
+
+impl MountLock {
+    /// Try to acquire the lock for the specified Gist.
+    ///
+    /// Returns `None` if another mount on this host already holds it.
+    pub fn try_acquire(gist_id: &str) -> io::Result<Option<Self>> {
+        Self::try_acquire_in(&lock_dir(), gist_id)
+    }
+
+    fn try_acquire_in(dir: &Path, gist_id: &str) -> io::Result<Option<Self>> {
+        // The ID names the lock file, so it must not escape the directory.
+        if gist_id.is_empty()
+            || !gist_id
+                .bytes()
+                .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid Gist ID: {:?}", gist_id),
+            ));
+        }
+        prepare_lock_dir(dir)?;
+
+        // Another user must not redirect the lock to an arbitrary file.
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(dir.join(format!("{}.lock", gist_id)))?;
+
+        let rc = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if rc != 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::EWOULDBLOCK) => Ok(None),
+                _ => Err(err),
+            };
+        }
+
+        Ok(Some(Self { file }))
+    }
+}
+
+/// Return the directory of the locks, separated per user since the
+/// temporary directory is shared.
+fn lock_dir() -> PathBuf {
+    let uid = unsafe { libc::getuid() };
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join(format!("gist-fs-{}", uid))
+}
+
+/// Create the lock directory private to the current user, refusing the
+/// one created by someone else.
+fn prepare_lock_dir(dir: &Path) -> io::Result<()> {
+    match DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => (),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => (),
+        Err(err) => return Err(err),
+    }
+
+    let metadata = fs::symlink_metadata(dir)?;
+    let uid = unsafe { libc::getuid() };
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("the lock directory is not private to the user: {:?}", dir),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{symlink, PermissionsExt as _};
+
+    /// Return a fresh directory path for the locks of a test.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("gist-fs-lock-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn held_until_dropped() {
+        let dir = scratch_dir("held");
+        let lock = MountLock::try_acquire_in(&dir, "0123abc").unwrap();
+        assert!(lock.is_some());
+        assert!(MountLock::try_acquire_in(&dir, "0123abc")
+            .unwrap()
+            .is_none());
+        assert!(MountLock::try_acquire_in(&dir, "4567def")
+            .unwrap()
+            .is_some());
+
+        drop(lock);
+        assert!(MountLock::try_acquire_in(&dir, "0123abc")
+            .unwrap()
+            .is_some());
+
+        let mode = fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_the_ids_other_than_hex() {
+        let dir = scratch_dir("ids");
+        for id in &[
+            "",
+            "../0123abc",
+            "0123/abc",
+            "0123ABC",
+            "0123abc.x",
+            "0123abg",
+        ] {
+            let err = MountLock::try_acquire_in(&dir, id).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{:?}", id);
+        }
+        assert!(!dir.exists(), "the directory is created for an invalid ID");
+    }
+
+    #[test]
+    fn rejects_the_shared_directory() {
+        let dir = scratch_dir("shared");
+        fs::create_dir(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o777)).unwrap();
+
+        let err = MountLock::try_acquire_in(&dir, "0123abc").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_the_symlinked_directory() {
+        let dir = scratch_dir("linked");
+        let target = scratch_dir("linked-target");
+        DirBuilder::new().mode(0o700).create(&target).unwrap();
+        symlink(&target, &dir).unwrap();
+
+        let err = MountLock::try_acquire_in(&dir, "0123abc").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        fs::remove_file(&dir).unwrap();
+        fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[test]
+    fn does_not_follow_the_symlinked_lock() {
+        let dir = scratch_dir("symlink");
+        DirBuilder::new().mode(0o700).create(&dir).unwrap();
+        let victim = dir.join("victim");
+        symlink(&victim, dir.join("0123abc.lock")).unwrap();
+
+        let err = MountLock::try_acquire_in(&dir, "0123abc").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ELOOP));
+        assert!(!victim.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
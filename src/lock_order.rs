@@ -0,0 +1,196 @@
+//! The order in which the locks of the files are acquired, checked in the
+//! debug builds so that a deadlock shows up as a panic.
+//!
+//! A task holding a lock may only acquire the locks of the later levels:
+//!
+//! 1. `GistFiles::flush_lock`, held across the uploads
+//! 2. `GistFiles::files`, held for the whole swap of the files by a refresh
+//!    and briefly by the others
+//! 3. `GistFiles::filename_to_ino`
+//! 4. `GistFiles::links`
+//! 5. `GistFiles::unlinked`
+//! 6. `GistFileNode::content`
+//!
+//! The other locks are leaves, never held while acquiring another one.
+//! None of them is needed to read the attributes, which the node table
+//! keeps in atomic cells, so `getattr` never waits for an upload.
+
+use futures::lock::{Mutex, MutexGuard};
+use std::{
+    fmt,
+    future::Future,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+};
+
+/// A level in the lock order.
+pub(crate) trait Level {
+    const LEVEL: u8;
+    const NAME: &'static str;
+}
+
+macro_rules! levels {
+    ($($name:ident = $level:expr,)*) => {$(
+        #[derive(Debug)]
+        pub(crate) enum $name {}
+
+        impl Level for $name {
+            const LEVEL: u8 = $level;
+            const NAME: &'static str = stringify!($name);
+        }
+    )*};
+}
+
+levels! {
+    Flush = 1,
+    Files = 2,
+    FilenameToIno = 3,
+    Links = 4,
+    Unlinked = 5,
+    Content = 6,
+}
+
+#[cfg(debug_assertions)]
+tokio::task_local! {
+    /// The levels of the locks held by the task.
+    static HELD: std::cell::RefCell<Vec<(u8, &'static str)>>;
+}
+
+/// Run the future with its locks checked against the order in the debug builds.
+///
+/// The locks acquired outside of this scope are not checked.
+pub(crate) async fn scope<F: Future>(fut: F) -> F::Output {
+    #[cfg(debug_assertions)]
+    {
+        HELD.scope(Default::default(), fut).await
+    }
+    #[cfg(not(debug_assertions))]
+    {
+        fut.await
+    }
+}
+
+/// A mutex acquired in the order of its level.
+pub(crate) struct OrderedMutex<T, L> {
+    inner: Mutex<T>,
+    _level: PhantomData<fn() -> L>,
+}
+
+impl<T, L> fmt::Debug for OrderedMutex<T, L>
+where
+    L: Level,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OrderedMutex")
+            .field("level", &L::NAME)
+            .finish()
+    }
+}
+
+impl<T, L> Default for OrderedMutex<T, L>
+where
+    T: Default,
+{
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T, L> OrderedMutex<T, L> {
+    pub(crate) fn new(value: T) -> Self {
+        Self {
+            inner: Mutex::new(value),
+            _level: PhantomData,
+        }
+    }
+}
+
+impl<T, L> OrderedMutex<T, L>
+where
+    L: Level,
+{
+    pub(crate) async fn lock(&self) -> OrderedGuard<'_, T> {
+        let held = Held::acquire::<L>();
+        OrderedGuard {
+            guard: self.inner.lock().await,
+            _held: held,
+        }
+    }
+
+    /// Acquire the lock if it is not held by anyone.
+    pub(crate) fn try_lock(&self) -> Option<OrderedGuard<'_, T>> {
+        let held = Held::acquire::<L>();
+        Some(OrderedGuard {
+            guard: self.inner.try_lock()?,
+            _held: held,
+        })
+    }
+}
+
+/// The guard of `OrderedMutex`, releasing the lock before its level.
+pub(crate) struct OrderedGuard<'a, T> {
+    guard: MutexGuard<'a, T>,
+    _held: Held,
+}
+
+impl<T> Deref for OrderedGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for OrderedGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+/// The record of a lock held by the task.
+struct Held {
+    #[cfg(debug_assertions)]
+    level: Option<u8>,
+}
+
+impl Held {
+    #[cfg(debug_assertions)]
+    fn acquire<L: Level>() -> Self {
+        let recorded = HELD.try_with(|held| {
+            let mut held = held.borrow_mut();
+            if let Some(&(level, name)) = held.iter().max() {
+                assert!(
+                    L::LEVEL > level,
+                    "the lock order is violated: {} is acquired while holding {}",
+                    L::NAME,
+                    name
+                );
+            }
+            held.push((L::LEVEL, L::NAME));
+        });
+        Self {
+            level: recorded.ok().map(|()| L::LEVEL),
+        }
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn acquire<L: Level>() -> Self {
+        Self {}
+    }
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        {
+            if let Some(level) = self.level {
+                let _ = HELD.try_with(|held| {
+                    let mut held = held.borrow_mut();
+                    if let Some(pos) = held.iter().rposition(|&(l, _)| l == level) {
+                        held.remove(pos);
+                    }
+                });
+            }
+        }
+    }
+}
@@ -1,34 +1,1004 @@
-use gist_client::Client;
-use gist_fs::GistFs;
+use anyhow::Context as _;
+use gist_client::{Client, Gist, NewGist};
+use gist_fs::{
+    convert_visibility, mountpoint, privilege, rlimit, undo, ConflictCommand, ConflictStrategy,
+    Consistency, Credentials, Error, ExecPolicy, FileOrder, GistFs, MountLock, ScanOptions,
+    TimeFormat, Transport, Visibility, CONTROL_DIR, CONTROL_DIR_FALLBACK,
+};
 use pico_args::Arguments;
-use std::path::PathBuf;
+use regex::Regex;
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fmt,
+    future::Future,
+    io::Write as _,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+const HELP: &str = "\
+Mount a Gist as a filesystem.
+
+USAGE:
+    gist-fs --gist-id <ID> [OPTIONS] <MOUNTPOINT>
+    gist-fs create --from-dir <DIR> --mount <MOUNTPOINT> [CREATE OPTIONS] [OPTIONS]
+    gist-fs --snapshot <PATH> [OPTIONS] <MOUNTPOINT>
+    gist-fs pull --json --gist-id <ID>
+    gist-fs restore [--steps <N>] <FILE>
+    gist-fs convert-visibility <ID> --to <public|secret> [--delete-original]
+
+OPTIONS:
+    --gist-id <ID>                  The ID of the Gist to mount
+    --exec-extensions <EXTS>        Comma-separated extensions of the executable files
+    --readonly-file <GLOB>          Refuse the local changes to the matching files (repeatable)
+    --exec-shebang                  Mark the files starting with `#!` as executable
+    --normalize-unicode             Apply NFC normalization to the uploaded content
+    --force-writable                Mount as writable even if mounted elsewhere on this host
+    --writable-group <GID>          Allow the members of the group to modify the files
+    --state-socket <PATH>           Serve the mount state as JSON on a Unix socket
+    --max-mtime-offset-days <DAYS>  How far in the future mtime may be set (0 disables)
+    --streaming                     Read the files too large for the API from their raw URLs
+    --transport <TRANSPORT>         How the content is fetched: rest (default) or git,
+                                    which requires the git-transport feature
+    --consistency <MODE>            How fresh the content is: cached (default) or strict,
+                                    which revalidates the clean files on every open
+    --case-insensitive              Look up the files ignoring case
+    --local-hard-links              Allow hard links, which are never uploaded
+    --refresh-on-opendir            Fetch the whole Gist again on every `ls` of the mountpoint
+    --sanitize-filenames            Trim the trailing whitespace of the filenames
+    --no-noise-filter               Look up the names probed by desktop tools, e.g. .DS_Store
+    --no-empty-file-placeholder     Keep the empty files unsent rather than uploading a newline
+    --fork-on-write                 Mount read-only, and fork the Gist on the first write
+    --mime-filter <PATTERN>         Mount only the files whose MIME type matches the regex
+    --sort-files <ORDER>            The order of the files in the listing:
+                                    none (default), name, natural or updated
+    --mark-unsynced                 List the modified files also as <NAME>.unsynced until uploaded
+    --show-diff                     Prepend the pending changes to the modified text files on read
+    --local-time                    Render the timestamps in the local time zone
+    --time-format <FORMAT>          Render the timestamps with a strftime format
+    --audit-log <PATH>              Append a JSON line to the file on every write
+    --backup-dir <DIR>              Save the content of the files closed after writing
+    --backup-versions <N>           How many backups are kept per file (default 5, 0 keeps all)
+    --undo-dir <DIR>                Keep the uploaded versions of the files in DIR, served under
+                                    .gistfs/undo (default: ~/.cache/gist-fs/undo)
+    --undo-versions <N>             How many uploaded versions are kept per file (default 5,
+                                    0 disables)
+    --export-snapshot <DIR>         Save the files into DIR on SIGHUP
+    --import-snapshot <DIR>         Serve the files saved in DIR before fetching the Gist,
+                                    which also provides the Gist ID
+    --snapshot <PATH>               Mount the Gist saved by `pull --json` read-only,
+                                    without any network access
+    --min-write-size <BYTES>        Defer the upload until the file reaches BYTES or is closed
+    --min-write-count <N>           Defer the upload until N writes or the file is closed
+    --compress-threshold <BYTES>    Compress the cached files larger than BYTES (0 disables)
+    --conflict <STRATEGY>           How to reconcile the edits by another writer:
+                                    fail (default), merge, local or remote
+    --on-conflict <COMMAND>         Resolve the conflicts left by the merge with COMMAND,
+                                    e.g. `meld %base %local %remote --output %merged`,
+                                    adopting %merged if it succeeds (implies --conflict merge)
+    --setuid <USER>                 Switch to the user after mounting
+    --setgid <GROUP>                Switch to the group after mounting
+    --allow-root                    Keep running as root after mounting
+    --shutdown-grace <SECS>         How long the uploads may take on SIGINT/SIGTERM (default: 5)
+    --max-dirty-age <SECS>          Upload the files kept open and dirty for longer than SECS
+                                    (default: 300, 0 disables)
+    --max-open-handles <N>          The maximum number of open files (default: 4096)
+    --max-uploads-per-minute <N>    Queue the uploads beyond the rate (0 disables)
+    --rate-limit-share <PERCENT>    Consume at most PERCENT of the remaining rate limit per hour
+    --max-response-size <BYTES>     Refuse the API responses larger than BYTES (default: 50 MiB)
+    --follow-symlink                Allow the mountpoint to be a symbolic link to a directory
+    --remount                       Unmount the filesystem already mounted at the mountpoint
+    -o <OPTIONS>                    Pass the mount options to FUSE, e.g. max_read=131072
+    -h, --help                      Print this message
+
+PULL OPTIONS:
+    --json                          Print the response of the API for the Gist, to be
+                                    mounted by --snapshot later
+
+RESTORE OPTIONS:
+    --steps <N>                     Write back the version uploaded N uploads before the
+                                    last one into FILE on the mount (default: 1)
+
+CONVERT-VISIBILITY OPTIONS:
+    --to <VISIBILITY>               Copy the Gist into a new public or secret Gist, with the
+                                    same files and description, and print the new ID
+    --delete-original               Delete the original Gist after the copy is created
+
+CREATE OPTIONS:
+    --from-dir <DIR>                Create a Gist from the text files in the directory
+    --mount <MOUNTPOINT>            Where the created Gist is mounted
+    --include-hidden                Include the files whose names start with `.`
+    --description <TEXT>            The description of the Gist
+    --public                        Create a public Gist
+    --secret                        Create a secret Gist (default)
+
+ENVIRONMENT:
+    GITHUB_TOKEN    The access token, also read from `.env`
+    GIST_FS_*       The options not specified in the arguments, e.g. GIST_FS_GIST_ID for
+                    --gist-id, GIST_FS_MOUNTPOINT and GIST_FS_FUSE_OPTIONS for -o.
+                    The switches take 1/true/yes or 0/false/no, and the repeatable
+                    options take comma-separated values.
+
+SIGNALS:
+    SIGINT, SIGTERM
+               Unmount after uploading the pending changes, or exit with the
+               status 130 without mounting if received before the mount
+    SIGUSR2    Re-read GITHUB_TOKEN from `.env` or the environment and use it
+               for the subsequent requests
+";
+
+/// The exit status when interrupted before the mount, as the shells
+/// report a process killed by SIGINT.
+const EXIT_ABORTED: i32 = 130;
+
+fn main() -> anyhow::Result<()> {
+    // Nothing opened by the parent, e.g. a shell running as root,
+    // should leak into the process serving the filesystem.
+    privilege::close_inherited_fds()?;
+
+    let mut runtime = tokio::runtime::Runtime::new()?;
+    match runtime.block_on(run()) {
+        // Everything acquired by the setup, e.g. the mount lock, has been
+        // released when `run` returns.
+        Err(ref err) if matches!(err.downcast_ref(), Some(Error::Aborted)) => {
+            eprintln!("aborted");
+            std::process::exit(EXIT_ABORTED)
+        }
+        result => result,
+    }
+}
+
+async fn run() -> anyhow::Result<()> {
     dotenv::dotenv().ok();
     tracing_subscriber::fmt::init();
 
-    let mut args = Arguments::from_env();
+    let mut args: Vec<_> = std::env::args_os().skip(1).collect();
+    let create = args.first().is_some_and(|arg| arg == "create");
+    let pull = args.first().is_some_and(|arg| arg == "pull");
+    let restore = args.first().is_some_and(|arg| arg == "restore");
+    let convert = args.first().is_some_and(|arg| arg == "convert-visibility");
+    if create || pull || restore || convert {
+        args.remove(0);
+    }
+    let mut args = Arguments::from_vec(args);
+
+    if args.contains(["-h", "--help"]) {
+        print!("{}", HELP);
+        return Ok(());
+    }
+    let mut args = Options::new(args);
+
+    if pull {
+        return pull_gist(&mut args).await;
+    }
+    if restore {
+        return restore_file(&mut args);
+    }
+    if convert {
+        return convert_gist(&mut args).await;
+    }
+
+    let create = if create {
+        let public = args.contains("--public")?;
+        anyhow::ensure!(
+            !(public && args.contains("--secret")?),
+            "--public and --secret are exclusive"
+        );
+        let mut scan = ScanOptions::new();
+        scan.include_hidden(args.contains("--include-hidden")?);
+        Some(CreateOptions {
+            from_dir: args.value_from_str("--from-dir")?,
+            mountpoint: args.value_from_str("--mount")?,
+            description: args.opt_value_from_str("--description")?,
+            public,
+            scan,
+        })
+    } else {
+        None
+    };
+    let import_snapshot: Option<PathBuf> = args.opt_value_from_str("--import-snapshot")?;
+    let snapshot: Option<Gist> = match args.opt_value_from_str::<PathBuf>("--snapshot")? {
+        Some(path) => {
+            anyhow::ensure!(
+                create.is_none() && import_snapshot.is_none(),
+                "--snapshot cannot be combined with create or --import-snapshot"
+            );
+            let json = std::fs::read(&path)
+                .with_context(|| format!("failed to read the snapshot {:?}", path))?;
+            Some(Gist::from_json(&json).with_context(|| format!("invalid snapshot {:?}", path))?)
+        }
+        None => None,
+    };
+    let gist_id: Option<String> = match (&create, import_snapshot.is_some() || snapshot.is_some()) {
+        (Some(..), _) => None,
+        // The ID is read from the snapshot unless specified.
+        (None, true) => args.opt_value_from_str("--gist-id")?,
+        (None, false) => Some(args.value_from_str("--gist-id")?),
+    };
+
+    let mut exec_policy = ExecPolicy::new();
+    if let Some(extensions) = args.opt_value_from_str::<String>("--exec-extensions")? {
+        exec_policy.extensions(extensions.split(',').map(str::trim));
+    }
+    exec_policy.read_only_files(args.values_from_str::<String>("--readonly-file")?);
+    exec_policy.shebang(args.contains("--exec-shebang")?);
+
+    let normalize_unicode = args.contains("--normalize-unicode")?;
+    let case_insensitive = args.contains("--case-insensitive")?;
+    let refresh_on_opendir = args.contains("--refresh-on-opendir")?;
+    let local_hard_links = args.contains("--local-hard-links")?;
+    let sanitize_filenames = args.contains("--sanitize-filenames")?;
+    let noise_filter = !args.contains("--no-noise-filter")?;
+    let empty_file_placeholder = !args.contains("--no-empty-file-placeholder")?;
+    let show_diff = args.contains("--show-diff")?;
+    let mark_unsynced = args.contains("--mark-unsynced")?;
+    let fork_on_write = args.contains("--fork-on-write")?;
+    let follow_symlink = args.contains("--follow-symlink")?;
+    let remount = args.contains("--remount")?;
+    let mime_filter: Option<Regex> = args.opt_value_from_str("--mime-filter")?;
+    let file_order: Option<FileOrder> = args.opt_value_from_str("--sort-files")?;
+    let streaming = args.contains("--streaming")?;
+    let transport: Option<Transport> = args.opt_value_from_str("--transport")?;
+    let consistency: Option<Consistency> = args.opt_value_from_str("--consistency")?;
+    let conflict_resolution: Option<ConflictStrategy> = args.opt_value_from_str("--conflict")?;
+    let conflict_command: Option<ConflictCommand> = args.opt_value_from_str("--on-conflict")?;
+    let force_writable = args.contains("--force-writable")?;
+    let writable_group: Option<u32> = args.opt_value_from_str("--writable-group")?;
+    let state_socket: Option<PathBuf> = args.opt_value_from_str("--state-socket")?;
+    let audit_log: Option<PathBuf> = args.opt_value_from_str("--audit-log")?;
+    let backup_dir: Option<PathBuf> = args.opt_value_from_str("--backup-dir")?;
+    let export_snapshot: Option<PathBuf> = args.opt_value_from_str("--export-snapshot")?;
+    let backup_versions: Option<usize> = args.opt_value_from_str("--backup-versions")?;
+    let undo_dir: Option<PathBuf> = args.opt_value_from_str("--undo-dir")?;
+    let undo_versions: Option<usize> = args.opt_value_from_str("--undo-versions")?;
+    let min_write_size: Option<usize> = args.opt_value_from_str("--min-write-size")?;
+    let min_write_count: Option<u32> = args.opt_value_from_str("--min-write-count")?;
+    let compress_threshold: Option<usize> = args.opt_value_from_str("--compress-threshold")?;
+    let setuid: Option<String> = args.opt_value_from_str("--setuid")?;
+    let setgid: Option<String> = args.opt_value_from_str("--setgid")?;
+    let allow_root = args.contains("--allow-root")?;
+    let fuse_options: Vec<OsString> = args.values_from_str("-o")?;
+    let max_open_handles: Option<usize> = args.opt_value_from_str("--max-open-handles")?;
+    let max_uploads_per_minute: Option<u32> =
+        args.opt_value_from_str("--max-uploads-per-minute")?;
+    let rate_limit_share: Option<u8> = args.opt_value_from_str("--rate-limit-share")?;
+    let max_response_size: Option<usize> = args.opt_value_from_str("--max-response-size")?;
+    let shutdown_grace: Option<u64> = args.opt_value_from_str("--shutdown-grace")?;
+    let max_dirty_age: Option<u64> = args.opt_value_from_str("--max-dirty-age")?;
+    let max_mtime_offset_days: Option<u64> = args.opt_value_from_str("--max-mtime-offset-days")?;
+
+    let mut time_format = TimeFormat::new();
+    time_format.local(args.contains("--local-time")?);
+    if let Some(format) = args.opt_value_from_str::<String>("--time-format")? {
+        time_format.format(format)?;
+    }
+
+    let mountpoint: PathBuf = match create {
+        Some(ref create) => create.mountpoint.clone(),
+        None => args
+            .free_from_str("MOUNTPOINT")?
+            .ok_or_else(|| anyhow::anyhow!("missing mountpoint"))?,
+    };
+    let mountpoint = validate_mountpoint(mountpoint, follow_symlink, remount)?;
+
+    let credentials = if setuid.is_some() || setgid.is_some() {
+        Some(Credentials::resolve(setuid.as_deref(), setgid.as_deref())?)
+    } else {
+        anyhow::ensure!(
+            !privilege::is_root() || allow_root,
+            "refusing to run as root; specify --setuid/--setgid or --allow-root"
+        );
+        None
+    };
+
+    match rlimit::raise_nofile_limit() {
+        Ok(limit) if limit < 1024 => {
+            tracing::warn!("the limit of open files is only {}", limit)
+        }
+        Ok(..) => (),
+        Err(err) => tracing::warn!("failed to raise the limit of open files: {}", err),
+    }
+
+    // Installed before any network activity, so that an interrupt during
+    // the setup aborts it without mounting anything.
+    let interrupt = Interrupt::install()?;
+
+    let mut client = Client::new(read_token());
+    if let Some(size) = max_response_size {
+        client.set_max_response_size(size);
+    }
 
-    let gist_id = args.value_from_str("--gist-id")?;
+    let gist_id = match (gist_id, create, &import_snapshot) {
+        (Some(gist_id), _, _) => gist_id,
+        (None, Some(create), _) => interrupt.abortable(create_gist(&client, &create)).await?,
+        (None, None, Some(path)) => GistFs::snapshot_gist_id(path).await?,
+        (None, None, None) => match snapshot {
+            Some(ref gist) => gist.id.clone(),
+            None => unreachable!(),
+        },
+    };
 
-    let mountpoint: PathBuf = args
-        .free_from_str()?
-        .ok_or_else(|| anyhow::anyhow!("missing mountpoint"))?;
-    anyhow::ensure!(mountpoint.is_dir(), "the mountpoint must be a directory");
+    // The offline mount never writes, so it does not compete for the lock.
+    let mount_lock = match snapshot {
+        Some(..) => {
+            anyhow::ensure!(
+                !fork_on_write,
+                "--snapshot cannot be combined with --fork-on-write"
+            );
+            None
+        }
+        None => MountLock::try_acquire(&gist_id)?,
+    };
+    let read_only = (mount_lock.is_none() && !force_writable) || fork_on_write;
+    if mount_lock.is_none() && snapshot.is_none() {
+        if force_writable {
+            tracing::warn!("the Gist is already mounted as writable on this host");
+        } else {
+            tracing::warn!(
+                "the Gist is already mounted as writable on this host; mounting as read-only"
+            );
+        }
+    }
 
-    let token = std::env::var("GITHUB_TOKEN").ok();
-    let client = Client::new(token);
+    let mut builder = GistFs::builder(client, gist_id);
+    builder.exec_policy(exec_policy);
+    builder.normalize_unicode(normalize_unicode);
+    builder.read_only(read_only);
+    builder.writable_group(writable_group);
+    builder.time_format(time_format);
+    builder.case_insensitive(case_insensitive);
+    builder.always_refresh_on_opendir(refresh_on_opendir);
+    builder.local_hard_links(local_hard_links);
+    builder.sanitize_filenames(sanitize_filenames);
+    builder.noise_filter(noise_filter);
+    builder.empty_file_placeholder(empty_file_placeholder);
+    builder.show_diff(show_diff);
+    builder.mark_unsynced(mark_unsynced);
+    builder.fork_on_write(fork_on_write);
+    builder.offline(snapshot.is_some());
+    builder.mime_filter(mime_filter);
+    builder.file_order(file_order.unwrap_or_default());
+    builder.streaming(streaming);
+    builder.transport(transport.unwrap_or_default());
+    builder.consistency(consistency.unwrap_or_default());
+    if let Some(max) = max_open_handles {
+        builder.max_open_handles(max);
+    }
+    builder.max_uploads_per_minute(max_uploads_per_minute.unwrap_or(0));
+    if let Some(percent) = rate_limit_share {
+        anyhow::ensure!(percent <= 100, "--rate-limit-share must be at most 100");
+        builder.rate_limit_share(percent);
+    }
+    if let Some(secs) = shutdown_grace {
+        builder.shutdown_grace(Duration::from_secs(secs));
+    }
+    if let Some(secs) = max_dirty_age {
+        let age = if secs > 0 {
+            Some(Duration::from_secs(secs))
+        } else {
+            None
+        };
+        builder.max_dirty_age(age);
+    }
+    builder.fuse_session_options(
+        fuse_options
+            .into_iter()
+            .flat_map(|option| vec!["-o".into(), option])
+            .collect(),
+    );
+    // The command resolves the conflicts left by the merge.
+    let default_strategy = match conflict_command {
+        Some(..) => ConflictStrategy::ThreeWayMerge,
+        None => ConflictStrategy::default(),
+    };
+    builder.conflict_resolution(conflict_resolution.unwrap_or(default_strategy));
+    if let Some(command) = conflict_command {
+        builder.conflict_command(command);
+    }
+    builder.min_write_size(min_write_size.unwrap_or(0));
+    builder.min_write_count(min_write_count.unwrap_or(0));
+    builder.compress_threshold_bytes(compress_threshold.unwrap_or(0));
+    if let Some(path) = audit_log {
+        builder.audit_log(path);
+    }
+    builder.backup_on_release(backup_dir);
+    builder.backup_versions(backup_versions.unwrap_or(5));
+    builder.undo_dir(undo_dir.or_else(undo::default_dir));
+    builder.undo_versions(undo_versions.unwrap_or(5));
+    if let Some(credentials) = credentials {
+        // The files are owned by the identity serving them.
+        builder.owner(credentials.uid, credentials.gid);
+    }
+    if let Some(days) = max_mtime_offset_days {
+        // 0 disables the check.
+        builder.max_mtime_offset(match days {
+            0 => None,
+            days => Some(Duration::from_secs(days * 24 * 60 * 60)),
+        });
+    }
+    let fs = Arc::new(builder.build().await?);
+    {
+        // The initial population fails with `Error::Aborted` on an interrupt.
+        let fs = fs.clone();
+        let interrupt = interrupt.clone();
+        tokio::spawn(async move {
+            interrupt.wait().await;
+            fs.abort();
+        });
+    }
+    match (snapshot, import_snapshot) {
+        (Some(gist), _) => fs.load_gist(gist).await?,
+        (None, Some(path)) => {
+            fs.import_snapshot(path).await?;
+            // Without a token, e.g. offline, the sync is left to the next refresh.
+            if fs.client().is_authenticated() {
+                let fs = fs.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = fs.fetch_gist().await {
+                        tracing::error!("failed to sync with the Gist: {}", err);
+                    }
+                });
+            }
+        }
+        (None, None) => fs.fetch_gist().await?,
+    }
 
-    let fs = GistFs::new(client, gist_id);
-    fs.fetch_gist().await?;
+    let mut sigusr2 = signal(SignalKind::user_defined2())?;
+    {
+        let fs = fs.clone();
+        tokio::spawn(async move {
+            while let Some(()) = sigusr2.recv().await {
+                refresh_token(&fs);
+            }
+        });
+    }
 
-    polyfuse_tokio::mount(
-        fs, //
-        mountpoint,
-        &["-o".as_ref(), "fsname=gistfs".as_ref()],
-    )
-    .await?;
+    if let Some(path) = export_snapshot {
+        let mut sighup = signal(SignalKind::hangup())?;
+        let fs = fs.clone();
+        tokio::spawn(async move {
+            while let Some(()) = sighup.recv().await {
+                if let Err(err) = fs.export_snapshot(path.clone()).await {
+                    tracing::error!("failed to export the snapshot: {}", err);
+                }
+            }
+        });
+    }
+
+    if interrupt.is_set() {
+        return Err(Error::Aborted.into());
+    }
+    let options = fs.fuse_session_options();
+    let options: Vec<&OsStr> = options.iter().map(|option| &**option).collect();
+    let server = polyfuse_tokio::Server::mount(mountpoint, &options[..]).await?;
+
+    // Mounting may need the privileges, but serving the requests does not.
+    if let Some(credentials) = credentials {
+        credentials.drop_privileges()?;
+    }
+    privilege::set_no_new_privs()?;
+
+    // Bound after the drop, so the socket is owned by the serving user.
+    let _state_socket = match state_socket {
+        Some(path) => Some(fs.serve_state(path)?),
+        None => None,
+    };
+
+    // Stop serving on SIGINT/SIGTERM, cancel the pending downloads and upload
+    // the pending changes, since the filesystem is not destroyed until it is unmounted.
+    match server
+        .run_until(fs.clone(), Box::pin(interrupt.wait()))
+        .await?
+    {
+        Some(()) => {
+            tracing::info!("interrupted; uploading the pending changes");
+            fs.shutdown().await?;
+        }
+        // The session ends without a destroy request once unmounted, and
+        // the pending changes are uploaded on a best-effort basis.
+        None => {
+            if let Err(err) = fs.flush_all().await {
+                tracing::error!("flush on unmount failed: {}", err);
+            }
+        }
+    }
 
     Ok(())
 }
+
+/// The notification of SIGINT/SIGTERM, shared by the setup and the mount.
+#[derive(Clone)]
+struct Interrupt {
+    rx: watch::Receiver<bool>,
+}
+
+impl Interrupt {
+    fn install() -> anyhow::Result<Self> {
+        let mut sigint = signal(SignalKind::interrupt())?;
+        let mut sigterm = signal(SignalKind::terminate())?;
+        let (tx, rx) = watch::channel(false);
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = sigint.recv() => (),
+                _ = sigterm.recv() => (),
+            }
+            let _ = tx.broadcast(true);
+        });
+        Ok(Self { rx })
+    }
+
+    fn is_set(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// Wait until interrupted.
+    async fn wait(self) {
+        let mut rx = self.rx;
+        // The sender is dropped only after the interrupt is broadcast.
+        while let Some(false) = rx.recv().await {}
+    }
+
+    /// Run a step of the setup, failing with `Error::Aborted` if
+    /// interrupted before it completes.
+    async fn abortable<F, T>(&self, fut: F) -> anyhow::Result<T>
+    where
+        F: Future<Output = anyhow::Result<T>>,
+    {
+        tokio::select! {
+            result = fut => result,
+            _ = self.clone().wait() => Err(Error::Aborted.into()),
+        }
+    }
+}
+
+/// Resolve the mountpoint to the canonical path, refusing the symbolic links
+/// unless `follow_symlink` and the existing mounts unless `remount`.
+fn validate_mountpoint(
+    path: PathBuf,
+    follow_symlink: bool,
+    remount: bool,
+) -> anyhow::Result<PathBuf> {
+    let canonical = mountpoint::canonicalize(&path)?;
+    if mountpoint::is_symlink(&path).unwrap_or(false) {
+        anyhow::ensure!(
+            follow_symlink,
+            "the mountpoint {} is a symbolic link to {}; specify --follow-symlink to mount there",
+            path.display(),
+            canonical.display()
+        );
+        tracing::warn!(
+            "the mountpoint {} is a symbolic link; mounting at {}",
+            path.display(),
+            canonical.display()
+        );
+    }
+
+    match mountpoint::read_mountinfo() {
+        Ok(mounts) => {
+            if let Some(mount) = mountpoint::find_mount(&mounts, &canonical) {
+                anyhow::ensure!(
+                    remount,
+                    "{} is already mounted ({} from {}), use --remount to replace",
+                    canonical.display(),
+                    mount.fs_type,
+                    mount.source
+                );
+                tracing::info!("unmounting {} to remount", canonical.display());
+                mountpoint::unmount(&canonical)?;
+            }
+            let parent = canonical.parent().unwrap_or(&canonical);
+            if let Some(mount) = mountpoint::containing_mount(&mounts, parent) {
+                if mount.is_network_fs() {
+                    tracing::warn!(
+                        "the mountpoint is on a network filesystem ({}), \
+                         where the FUSE mounts may behave poorly",
+                        mount.fs_type
+                    );
+                }
+            }
+        }
+        Err(err) => tracing::warn!("failed to read the existing mounts: {}", err),
+    }
+
+    anyhow::ensure!(canonical.is_dir(), "the mountpoint must be a directory");
+    Ok(canonical)
+}
+
+/// The command line arguments, falling back to the `GIST_FS_*` environment
+/// variables for the options not specified.
+struct Options {
+    args: Arguments,
+    env: HashMap<OsString, OsString>,
+}
+
+impl Options {
+    fn new(args: Arguments) -> Self {
+        Self::with_env(args, std::env::vars_os().collect())
+    }
+
+    fn with_env(args: Arguments, env: HashMap<OsString, OsString>) -> Self {
+        Self { args, env }
+    }
+
+    fn contains(&mut self, flag: &'static str) -> anyhow::Result<bool> {
+        if self.args.contains(flag) {
+            return Ok(true);
+        }
+        match self.env_value(flag)? {
+            Some((name, value)) => parse_bool(&name, &value),
+            None => Ok(false),
+        }
+    }
+
+    fn opt_value_from_str<T>(&mut self, flag: &'static str) -> anyhow::Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        if let Some(value) = self.args.opt_value_from_str(flag)? {
+            return Ok(Some(value));
+        }
+        match self.env_value(flag)? {
+            Some((name, value)) => parse_value(&name, &value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn value_from_str<T>(&mut self, flag: &'static str) -> anyhow::Result<T>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        self.opt_value_from_str(flag)?
+            .ok_or_else(|| anyhow::anyhow!("missing {} (or {})", flag, env_name(flag)))
+    }
+
+    fn values_from_str<T>(&mut self, flag: &'static str) -> anyhow::Result<Vec<T>>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        let values: Vec<T> = self.args.values_from_str(flag)?;
+        if !values.is_empty() {
+            return Ok(values);
+        }
+        match self.env_value(flag)? {
+            Some((name, value)) => value
+                .split(',')
+                .filter(|value| !value.is_empty())
+                .map(|value| parse_value(&name, value))
+                .collect(),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Take the positional argument, or the environment variable named
+    /// `GIST_FS_<NAME>`.
+    fn free_from_str<T>(&mut self, name: &str) -> anyhow::Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        if let Some(value) = self.args.free_from_str()? {
+            return Ok(Some(value));
+        }
+        let name = format!("GIST_FS_{}", name);
+        match self.read_env(&name)? {
+            Some(value) => parse_value(&name, &value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn env_value(&self, flag: &str) -> anyhow::Result<Option<(String, String)>> {
+        let name = env_name(flag);
+        Ok(self.read_env(&name)?.map(|value| (name, value)))
+    }
+
+    fn read_env(&self, name: &str) -> anyhow::Result<Option<String>> {
+        match self.env.get(OsStr::new(name)) {
+            Some(value) => match value.to_str() {
+                Some(value) => Ok(Some(value.to_owned())),
+                None => anyhow::bail!("{} is not valid UTF-8", name),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+/// Return the name of the environment variable corresponding to the option,
+/// e.g. `GIST_FS_GIST_ID` for `--gist-id`.
+fn env_name(flag: &str) -> String {
+    let name = match flag {
+        "-o" => "fuse-options",
+        flag => flag.trim_start_matches('-'),
+    };
+    format!("GIST_FS_{}", name.replace('-', "_").to_uppercase())
+}
+
+fn parse_value<T>(name: &str, value: &str) -> anyhow::Result<T>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|err| anyhow::anyhow!("invalid value of {}: {}", name, err))
+}
+
+fn parse_bool(name: &str, value: &str) -> anyhow::Result<bool> {
+    match &*value.trim().to_ascii_lowercase() {
+        "1" | "true" | "yes" => Ok(true),
+        "0" | "false" | "no" | "" => Ok(false),
+        _ => anyhow::bail!(
+            "invalid value of {}: expected 1/true/yes or 0/false/no, got {:?}",
+            name,
+            value
+        ),
+    }
+}
+
+struct CreateOptions {
+    from_dir: PathBuf,
+    mountpoint: PathBuf,
+    description: Option<String>,
+    public: bool,
+    scan: ScanOptions,
+}
+
+/// Create a Gist from the files in a directory and return its ID.
+///
+/// The ID is printed immediately, so it is not lost even if the mount fails.
+async fn create_gist(client: &Client, create: &CreateOptions) -> anyhow::Result<String> {
+    let files = create.scan.scan(&create.from_dir)?;
+    anyhow::ensure!(
+        !files.is_empty(),
+        "no text files found in {:?}",
+        create.from_dir
+    );
+    let files: Vec<(&str, &str)> = files
+        .iter()
+        .map(|(filename, content)| (&**filename, &**content))
+        .collect();
+
+    let (gist, _etag) = client
+        .create_gist(NewGist {
+            files: &files[..],
+            description: create.description.as_deref(),
+            public: create.public,
+        })
+        .await?;
+    println!("{}\t{}", gist.id, gist.html_url);
+
+    Ok(gist.id)
+}
+
+/// Print the response of the API for the Gist as it is, to be mounted
+/// by `--snapshot` later.
+async fn pull_gist(args: &mut Options) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        args.contains("--json")?,
+        "pull requires --json, the only supported output"
+    );
+    let gist_id: String = args.value_from_str("--gist-id")?;
+    let mut client = Client::new(read_token());
+    if let Some(size) = args.opt_value_from_str("--max-response-size")? {
+        client.set_max_response_size(size);
+    }
+
+    let json = client.fetch_gist_json(&gist_id).await?;
+    let mut stdout = std::io::stdout();
+    stdout.write_all(&json)?;
+    stdout.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Write a version kept by the undo history back into the file on the
+/// mount, which uploads it as a new change.
+fn restore_file(args: &mut Options) -> anyhow::Result<()> {
+    let steps: usize = args.opt_value_from_str("--steps")?.unwrap_or(1);
+    let path: PathBuf = args
+        .free_from_str("FILE")?
+        .ok_or_else(|| anyhow::anyhow!("missing the file to restore"))?;
+    let filename = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("invalid file {:?}", path))?;
+    let mountpoint = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    let dir = [CONTROL_DIR, CONTROL_DIR_FALLBACK]
+        .iter()
+        .map(|control| mountpoint.join(control).join("undo").join(filename))
+        .find(|dir| dir.is_dir())
+        .ok_or_else(|| anyhow::anyhow!("no uploaded versions of {:?} are kept", path))?;
+    let mut versions = vec![];
+    for entry in std::fs::read_dir(&dir)? {
+        versions.push(entry?.file_name());
+    }
+    versions.sort();
+
+    // The newest version is the content uploaded last.
+    anyhow::ensure!(
+        steps < versions.len(),
+        "only {} version(s) of {:?} before the last upload are kept",
+        versions.len().saturating_sub(1),
+        path
+    );
+    let version = &versions[versions.len() - 1 - steps];
+    let content = std::fs::read(dir.join(version))?;
+    std::fs::write(&path, content).with_context(|| format!("failed to write {:?}", path))?;
+    println!(
+        "restored {} to the version uploaded at {}",
+        path.display(),
+        version.to_string_lossy()
+    );
+    Ok(())
+}
+
+/// Copy the Gist into a new one with the other visibility, which the API
+/// does not allow to change in place.
+async fn convert_gist(args: &mut Options) -> anyhow::Result<()> {
+    let visibility: Visibility = args.value_from_str("--to")?;
+    let delete_original = args.contains("--delete-original")?;
+    let gist_id: String = args
+        .free_from_str("ID")?
+        .ok_or_else(|| anyhow::anyhow!("missing the ID of the Gist to convert"))?;
+    let mut client = Client::new(read_token());
+    if let Some(size) = args.opt_value_from_str("--max-response-size")? {
+        client.set_max_response_size(size);
+    }
+
+    let gist = convert_visibility(&client, &gist_id, visibility, delete_original).await?;
+    println!("{}\t{}", gist.id, gist.html_url);
+    Ok(())
+}
+
+/// Read the access token, preferring `.env` since the environment
+/// of the running process never changes.
+// `from_path` never overrides the variables loaded at startup, so the
+// iterator is the only way to see the updated `.env`.
+#[allow(deprecated)]
+fn read_token() -> Option<String> {
+    let from_dotenv = dotenv::dotenv_iter().ok().and_then(|iter| {
+        iter.filter_map(Result::ok)
+            .find(|(key, _)| key == "GITHUB_TOKEN")
+            .map(|(_, value)| value)
+    });
+    from_dotenv.or_else(|| std::env::var("GITHUB_TOKEN").ok())
+}
+
+fn refresh_token(fs: &GistFs) {
+    let token = read_token();
+    if token.is_none() {
+        tracing::warn!("GITHUB_TOKEN is not set; the Gist can no longer be modified");
+    }
+    fs.refresh_token(token);
+    tracing::info!("the access token has been reloaded");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(args: &[&str], env: &[(&str, &str)]) -> Options {
+        Options::with_env(
+            Arguments::from_vec(args.iter().map(Into::into).collect()),
+            env.iter()
+                .map(|&(name, value)| (name.into(), value.into()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn flag_overrides_env() {
+        let mut args = options(
+            &["--test-flag-over-env", "flag"],
+            &[("GIST_FS_TEST_FLAG_OVER_ENV", "env")],
+        );
+        let value: Option<String> = args.opt_value_from_str("--test-flag-over-env").unwrap();
+        assert_eq!(value.as_deref(), Some("flag"));
+    }
+
+    #[test]
+    fn env_is_used_without_flag() {
+        let mut args = options(&[], &[("GIST_FS_TEST_ENV_ONLY", "42")]);
+        let value: u32 = args.value_from_str("--test-env-only").unwrap();
+        assert_eq!(value, 42);
+
+        let missing = args
+            .value_from_str::<u32>("--test-env-missing")
+            .unwrap_err();
+        assert!(missing.to_string().contains("GIST_FS_TEST_ENV_MISSING"));
+    }
+
+    #[test]
+    fn invalid_env_is_reported() {
+        let mut args = options(&[], &[("GIST_FS_TEST_ENV_INVALID", "forty-two")]);
+        let err = args
+            .value_from_str::<u32>("--test-env-invalid")
+            .unwrap_err();
+        assert!(err.to_string().contains("GIST_FS_TEST_ENV_INVALID"));
+    }
+
+    #[test]
+    fn non_utf8_env_is_reported() {
+        use std::os::unix::ffi::OsStringExt as _;
+
+        let mut env = HashMap::new();
+        env.insert(
+            OsString::from("GIST_FS_TEST_ENV_NON_UTF8"),
+            OsString::from_vec(vec![0xff]),
+        );
+        let mut args = Options::with_env(Arguments::from_vec(vec![]), env);
+        let err = args
+            .opt_value_from_str::<String>("--test-env-non-utf8")
+            .unwrap_err();
+        assert!(err.to_string().contains("not valid UTF-8"));
+    }
+
+    #[test]
+    fn switch_from_flag_or_env() {
+        let mut args = options(
+            &["--test-switch-off"],
+            &[
+                ("GIST_FS_TEST_SWITCH_ON", "yes"),
+                ("GIST_FS_TEST_SWITCH_OFF", "0"),
+                ("GIST_FS_TEST_SWITCH_INVALID", "maybe"),
+            ],
+        );
+        assert!(args.contains("--test-switch-on").unwrap());
+        // The flag wins over the environment disabling it.
+        assert!(args.contains("--test-switch-off").unwrap());
+        assert!(!args.contains("--test-switch-unset").unwrap());
+        assert!(args.contains("--test-switch-invalid").is_err());
+    }
+
+    #[test]
+    fn values_from_flags_or_env() {
+        let mut args = options(&[], &[("GIST_FS_TEST_VALUES", "a,,b")]);
+        let values: Vec<String> = args.values_from_str("--test-values").unwrap();
+        assert_eq!(values, ["a", "b"]);
+
+        let mut args = options(
+            &["--test-values", "c", "--test-values", "d"],
+            &[("GIST_FS_TEST_VALUES", "a,,b")],
+        );
+        let values: Vec<String> = args.values_from_str("--test-values").unwrap();
+        assert_eq!(values, ["c", "d"]);
+    }
+
+    #[test]
+    fn free_from_argument_or_env() {
+        let mut args = options(&["arg"], &[("GIST_FS_TEST_FREE", "env")]);
+        let value: Option<String> = args.free_from_str("TEST_FREE").unwrap();
+        assert_eq!(value.as_deref(), Some("arg"));
+        let value: Option<String> = args.free_from_str("TEST_FREE").unwrap();
+        assert_eq!(value.as_deref(), Some("env"));
+    }
+
+    #[test]
+    fn test_env_name() {
+        assert_eq!(env_name("--gist-id"), "GIST_FS_GIST_ID");
+        assert_eq!(env_name("--no-noise-filter"), "GIST_FS_NO_NOISE_FILTER");
+        assert_eq!(env_name("-o"), "GIST_FS_FUSE_OPTIONS");
+    }
+
+    #[test]
+    fn test_parse_bool() {
+        for &value in &["1", "true", "YES", " yes "] {
+            assert!(parse_bool("NAME", value).unwrap(), "{:?}", value);
+        }
+        for &value in &["0", "false", "No", ""] {
+            assert!(!parse_bool("NAME", value).unwrap(), "{:?}", value);
+        }
+        assert!(parse_bool("NAME", "on").is_err());
+    }
+}
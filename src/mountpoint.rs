@@ -0,0 +1,195 @@
+//! Validation of the mountpoint before mounting.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// The filesystem types on which the FUSE mounts are known to behave poorly.
+const NETWORK_FS_TYPES: &[&str] = &[
+    "nfs",
+    "nfs4",
+    "cifs",
+    "smb3",
+    "smbfs",
+    "9p",
+    "afs",
+    "ceph",
+    "glusterfs",
+    "fuse.sshfs",
+];
+
+/// An entry of `/proc/self/mountinfo`.
+#[derive(Debug, Clone)]
+pub struct MountInfo {
+    pub mount_point: PathBuf,
+    pub fs_type: String,
+    pub source: String,
+}
+
+impl MountInfo {
+    /// Return whether the filesystem is accessed over the network.
+    pub fn is_network_fs(&self) -> bool {
+        NETWORK_FS_TYPES.contains(&&*self.fs_type)
+    }
+}
+
+/// Read the mounts visible to the process.
+pub fn read_mountinfo() -> io::Result<Vec<MountInfo>> {
+    fs::read_to_string("/proc/self/mountinfo").map(|s| parse_mountinfo(&s))
+}
+
+/// Parse the content of `/proc/self/mountinfo`, skipping the malformed lines.
+///
+/// Each line is of the form
+/// `<id> <parent> <major:minor> <root> <mount point> <options> [<optional>...] - <type> <source> <super options>`.
+pub fn parse_mountinfo(s: &str) -> Vec<MountInfo> {
+    s.lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let mount_point = fields.nth(4)?;
+            let mut fields = fields.skip_while(|&field| field != "-").skip(1);
+            let fs_type = fields.next()?;
+            let source = fields.next()?;
+            Some(MountInfo {
+                mount_point: PathBuf::from(unescape(mount_point)),
+                fs_type: unescape(fs_type),
+                source: unescape(source),
+            })
+        })
+        .collect()
+}
+
+/// Decode the octal escapes (e.g. `\040` for a space) used in mountinfo.
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 4 <= bytes.len() {
+            let escaped = std::str::from_utf8(&bytes[i + 1..i + 4])
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+            if let Some(b) = escaped {
+                decoded.push(b);
+                i += 4;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Return the mount whose mount point is exactly the path.
+///
+/// The last one wins, since it shadows the earlier ones at the same path.
+pub fn find_mount<'a>(mounts: &'a [MountInfo], path: &Path) -> Option<&'a MountInfo> {
+    mounts.iter().rev().find(|mount| mount.mount_point == path)
+}
+
+/// Return the mount containing the path.
+pub fn containing_mount<'a>(mounts: &'a [MountInfo], path: &Path) -> Option<&'a MountInfo> {
+    mounts
+        .iter()
+        .rev()
+        .filter(|mount| path.starts_with(&mount.mount_point))
+        .max_by_key(|mount| mount.mount_point.components().count())
+}
+
+/// Return whether the path itself is a symbolic link.
+pub fn is_symlink(path: &Path) -> io::Result<bool> {
+    fs::symlink_metadata(path).map(|metadata| metadata.file_type().is_symlink())
+}
+
+/// Resolve the path to the absolute one without the symbolic links.
+///
+/// A stale FUSE mount cannot be resolved (`ENOTCONN`), so the path is only
+/// made absolute in that case for it to be found in mountinfo.
+pub fn canonicalize(path: &Path) -> io::Result<PathBuf> {
+    match fs::canonicalize(path) {
+        Ok(path) => Ok(path),
+        Err(ref err) if err.raw_os_error() == Some(libc::ENOTCONN) => {
+            Ok(std::env::current_dir()?.join(path))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Unmount the FUSE filesystem at the path.
+pub fn unmount(path: &Path) -> anyhow::Result<()> {
+    let status = Command::new("fusermount").arg("-u").arg(path).status()?;
+    anyhow::ensure!(
+        status.success(),
+        "failed to unmount {}: fusermount exited with {}",
+        path.display(),
+        status
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTINFO: &str = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+36 22 0:31 / /home/user/My\\040Gists rw,nosuid - fuse.gistfs gistfs rw,user_id=1000
+37 22 0:32 / /mnt/tab\\011and\\134backslash rw - nfs4 server:/export\\040dir rw
+38 22 0:33 / /mnt/no-optional rw - cifs //server/share rw
+malformed line
+";
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let mounts = parse_mountinfo(MOUNTINFO);
+        assert_eq!(mounts.len(), 4);
+
+        assert_eq!(mounts[0].mount_point, Path::new("/"));
+        assert_eq!(mounts[0].fs_type, "ext4");
+        assert_eq!(mounts[0].source, "/dev/sda1");
+
+        assert_eq!(mounts[1].mount_point, Path::new("/home/user/My Gists"));
+        assert_eq!(mounts[1].fs_type, "fuse.gistfs");
+        assert!(!mounts[1].is_network_fs());
+
+        assert_eq!(mounts[2].mount_point, Path::new("/mnt/tab\tand\\backslash"));
+        assert_eq!(mounts[2].source, "server:/export dir");
+        assert!(mounts[2].is_network_fs());
+
+        assert_eq!(mounts[3].fs_type, "cifs");
+    }
+
+    #[test]
+    fn test_unescape() {
+        let cases = [
+            ("plain", "plain"),
+            ("a\\040b", "a b"),
+            ("\\012", "\n"),
+            ("\\134\\134", "\\\\"),
+            // The incomplete or non-octal escapes are kept as they are.
+            ("a\\04", "a\\04"),
+            ("a\\09b", "a\\09b"),
+            ("trailing\\", "trailing\\"),
+        ];
+        for &(field, expected) in &cases {
+            assert_eq!(unescape(field), expected, "{:?}", field);
+        }
+    }
+
+    #[test]
+    fn test_find_mount() {
+        let mounts = parse_mountinfo(MOUNTINFO);
+        let path = Path::new("/home/user/My Gists");
+        assert_eq!(find_mount(&mounts, path).unwrap().fs_type, "fuse.gistfs");
+        assert!(find_mount(&mounts, Path::new("/home/user")).is_none());
+
+        let path = Path::new("/home/user/My Gists/a.txt");
+        let mount = containing_mount(&mounts, path).unwrap();
+        assert_eq!(mount.fs_type, "fuse.gistfs");
+        let mount = containing_mount(&mounts, Path::new("/home/user")).unwrap();
+        assert_eq!(mount.fs_type, "ext4");
+    }
+}
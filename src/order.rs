@@ -0,0 +1,152 @@
+//! The order of the files in the listings.
+
+#[cfg(feature = "fuse")]
+use polyfuse::FileAttr;
+use std::{cmp::Ordering, iter::Peekable, str::Chars, str::FromStr};
+
+/// How the files are ordered in the directory listings.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum FileOrder {
+    /// By the filename, compared byte by byte.
+    Name,
+
+    /// By the filename, comparing the runs of digits as numbers, so that
+    /// `2-setup.md` comes before `10-usage.md`.
+    Natural,
+
+    /// The most recently modified first, then by the natural order.
+    Updated,
+
+    /// In the order the files were added to the mount.
+    #[default]
+    None,
+}
+
+impl FromStr for FileOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(FileOrder::Name),
+            "natural" => Ok(FileOrder::Natural),
+            "updated" => Ok(FileOrder::Updated),
+            "none" => Ok(FileOrder::None),
+            s => anyhow::bail!("unknown file order: {:?}", s),
+        }
+    }
+}
+
+impl FileOrder {
+    /// Compare the names, or return `None` if the order keeps them as they are.
+    ///
+    /// The modification times are not compared, so `Updated` falls back
+    /// to the natural order.
+    pub fn compare_names(self, a: &str, b: &str) -> Option<Ordering> {
+        match self {
+            FileOrder::Name => Some(a.cmp(b)),
+            FileOrder::Natural | FileOrder::Updated => Some(natural_cmp(a, b)),
+            FileOrder::None => None,
+        }
+    }
+
+    /// Compare the directory entries, or return `None` if the order keeps
+    /// them as they are.
+    #[cfg(feature = "fuse")]
+    pub(crate) fn compare_entries(
+        self,
+        (a, a_attr): (&std::ffi::OsStr, &FileAttr),
+        (b, b_attr): (&std::ffi::OsStr, &FileAttr),
+    ) -> Option<Ordering> {
+        let names = |a: &std::ffi::OsStr, b: &std::ffi::OsStr| {
+            self.compare_names(&a.to_string_lossy(), &b.to_string_lossy())
+        };
+        match self {
+            FileOrder::Updated => {
+                let mtime = |attr: &FileAttr| attr.mtime();
+                let newer_first = mtime(b_attr).cmp(&mtime(a_attr));
+                names(a, b).map(|names| newer_first.then(names))
+            }
+            _ => names(a, b),
+        }
+    }
+}
+
+/// Compare the strings in the natural order, where the runs of the ASCII
+/// digits are compared by their numeric values.
+///
+/// The strings equal as numbers, e.g. `01` and `1`, are ordered by their
+/// bytes, so the order is total.
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut lhs, mut rhs) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        match (lhs.peek().copied(), rhs.peek().copied()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(..)) => return Ordering::Less,
+            (Some(..), None) => return Ordering::Greater,
+            (Some(l), Some(r)) if l.is_ascii_digit() && r.is_ascii_digit() => {
+                let ordering = compare_numbers(&take_digits(&mut lhs), &take_digits(&mut rhs));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(l), Some(r)) => {
+                if l != r {
+                    return l.cmp(&r);
+                }
+                lhs.next();
+                rhs.next();
+            }
+        }
+    }
+}
+
+fn take_digits(chars: &mut Peekable<Chars<'_>>) -> String {
+    let mut digits = String::new();
+    while let Some(c) = chars.peek().copied().filter(char::is_ascii_digit) {
+        digits.push(c);
+        chars.next();
+    }
+    digits
+}
+
+/// Compare the runs of digits by their values, without parsing them
+/// into integers which may overflow.
+fn compare_numbers(a: &str, b: &str) -> Ordering {
+    let a = a.trim_start_matches('0');
+    let b = b.trim_start_matches('0');
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_natural_cmp() {
+        let cases = [
+            // The runs of digits are compared by their values.
+            ("file2.txt", "file10.txt", Ordering::Less),
+            ("file10.txt", "file9.txt", Ordering::Greater),
+            ("1", "99999999999999999999999", Ordering::Less),
+            ("a1b2", "a1b10", Ordering::Less),
+            // The leading zeros do not change the value, and the tie is
+            // broken by the bytes.
+            ("file007", "file7", Ordering::Less),
+            ("file007", "file8", Ordering::Less),
+            ("file01", "file01", Ordering::Equal),
+            // The letters are compared case-sensitively.
+            ("File.txt", "file.txt", Ordering::Less),
+            ("a10", "B2", Ordering::Greater),
+            ("abc", "aBd", Ordering::Greater),
+            // A string is ordered before the ones it is a prefix of.
+            ("file", "file.txt", Ordering::Less),
+            ("file1", "file1a", Ordering::Less),
+            ("", "a", Ordering::Less),
+            ("", "", Ordering::Equal),
+        ];
+        for &(a, b, expected) in &cases {
+            assert_eq!(natural_cmp(a, b), expected, "{:?} vs {:?}", a, b);
+            assert_eq!(natural_cmp(b, a), expected.reverse(), "{:?} vs {:?}", b, a);
+        }
+    }
+}
@@ -0,0 +1,47 @@
+//! Access control for the requests from users other than the owner of the mount.
+
+use polyfuse::FileAttr;
+
+/// The rules deciding which users may access the files.
+///
+/// Only the user who mounted the filesystem (and optionally the members
+/// of a group) may modify the files. The other accesses follow the mode bits.
+#[derive(Debug, Clone)]
+pub struct Permissions {
+    owner: u32,
+    writable_group: Option<u32>,
+}
+
+impl Permissions {
+    pub fn new(owner: u32, writable_group: Option<u32>) -> Self {
+        Self {
+            owner,
+            writable_group,
+        }
+    }
+
+    /// Return whether the user may modify the files.
+    pub fn may_write(&self, uid: u32, gid: u32) -> bool {
+        uid == self.owner || self.writable_group == Some(gid)
+    }
+
+    /// Return whether the user is granted the access specified by `mask`,
+    /// which is a combination of `R_OK`, `W_OK` and `X_OK`.
+    pub fn check(&self, attr: &FileAttr, uid: u32, gid: u32, mask: i32) -> bool {
+        if mask & libc::W_OK != 0 && !self.may_write(uid, gid) {
+            return false;
+        }
+
+        let mode = attr.mode();
+        let bits = if uid == attr.uid() {
+            mode >> 6
+        } else if gid == attr.gid() {
+            mode >> 3
+        } else {
+            mode
+        };
+
+        let requested = (mask & (libc::R_OK | libc::W_OK | libc::X_OK)) as u32;
+        bits & requested == requested
+    }
+}
@@ -0,0 +1,159 @@
+//! Policies deciding the local attributes of Gist files.
+
+use std::path::Path;
+
+/// A policy that decides which files are marked as executable.
+///
+/// The permission bits are local to the mount and are never uploaded
+/// to the Gist.
+#[derive(Debug, Default, Clone)]
+pub struct ExecPolicy {
+    extensions: Vec<String>,
+    shebang: bool,
+    read_only_files: Vec<String>,
+}
+
+impl ExecPolicy {
+    /// Create a new `ExecPolicy` that marks no files as executable.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mark the files with one of the specified extensions as executable.
+    pub fn extensions<I, S>(&mut self, extensions: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.extensions = extensions.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Mark the files whose content starts with `#!` as executable.
+    pub fn shebang(&mut self, enabled: bool) -> &mut Self {
+        self.shebang = enabled;
+        self
+    }
+
+    /// Protect the files matching one of the glob patterns from local changes.
+    ///
+    /// The patterns support `*` and `?`, matched against the whole filename.
+    pub fn read_only_files<I, S>(&mut self, patterns: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.read_only_files = patterns.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Return whether the file is protected from local changes.
+    pub fn is_read_only(&self, filename: &str) -> bool {
+        let name: Vec<char> = filename.chars().collect();
+        self.read_only_files.iter().any(|pattern| {
+            let pattern: Vec<char> = pattern.chars().collect();
+            glob_match(&pattern, &name)
+        })
+    }
+
+    /// Return whether the file should be marked as executable.
+    pub fn is_executable(&self, filename: &str, content: &[u8]) -> bool {
+        let matches_extension = Path::new(filename)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| self.extensions.iter().any(|e| e == ext));
+
+        matches_extension || (self.shebang && content.starts_with(b"#!"))
+    }
+
+    /// Return the permission bits of the file.
+    pub(crate) fn permissions(&self, filename: &str, content: &[u8]) -> u32 {
+        let mode = if self.is_executable(filename, content) {
+            0o755
+        } else {
+            0o644
+        };
+        if self.is_read_only(filename) {
+            mode & !0o222
+        } else {
+            mode
+        }
+    }
+}
+
+/// Match the name against a glob pattern with `*` and `?`.
+fn glob_match(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        // Let the star absorb as many characters as needed.
+        Some(('*', rest)) => (0..=name.len()).any(|i| glob_match(rest, &name[i..])),
+        Some(('?', rest)) => !name.is_empty() && glob_match(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && glob_match(rest, &name[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn executable_by_extension() {
+        let mut policy = ExecPolicy::new();
+        policy.extensions(vec!["sh", "py"]);
+        assert!(policy.is_executable("run.sh", b""));
+        assert!(policy.is_executable("dir.d/main.py", b""));
+        assert!(!policy.is_executable("run.shx", b""));
+        assert!(!policy.is_executable("sh", b""));
+        assert!(!policy.is_executable("README.SH", b""));
+        assert!(!policy.is_executable("notes.txt", b"#!/bin/sh\n"));
+    }
+
+    #[test]
+    fn executable_by_shebang() {
+        let mut policy = ExecPolicy::new();
+        assert!(!policy.is_executable("run", b"#!/bin/sh\n"));
+
+        policy.shebang(true);
+        assert!(policy.is_executable("run", b"#!/bin/sh\n"));
+        assert!(policy.is_executable("run", b"#!"));
+        assert!(!policy.is_executable("run", b"#"));
+        assert!(!policy.is_executable("run", b" #!/bin/sh\n"));
+        assert!(!policy.is_executable("run", b"echo '#!'\n"));
+        assert!(!policy.is_executable("run", b""));
+    }
+
+    #[test]
+    fn read_only_patterns() {
+        let mut policy = ExecPolicy::new();
+        policy.read_only_files(vec!["*.lock", "config.?", "README.md"]);
+        assert!(policy.is_read_only("Cargo.lock"));
+        assert!(policy.is_read_only(".lock"));
+        assert!(policy.is_read_only("config.h"));
+        assert!(policy.is_read_only("README.md"));
+        assert!(!policy.is_read_only("Cargo.lock.bak"));
+        assert!(!policy.is_read_only("config.hh"));
+        assert!(!policy.is_read_only("config."));
+        assert!(!policy.is_read_only("readme.md"));
+    }
+
+    #[test]
+    fn glob_stars_backtrack() {
+        let pattern: Vec<char> = "a*b*c".chars().collect();
+        let name = |s: &str| s.chars().collect::<Vec<char>>();
+        assert!(glob_match(&pattern, &name("abc")));
+        assert!(glob_match(&pattern, &name("abbbcbc")));
+        assert!(!glob_match(&pattern, &name("abcb")));
+        assert!(glob_match(&['*'], &name("")));
+        assert!(!glob_match(&['?'], &name("")));
+    }
+
+    #[test]
+    fn permissions_combine_the_policies() {
+        let mut policy = ExecPolicy::new();
+        policy.shebang(true).read_only_files(vec!["*.lock"]);
+        assert_eq!(policy.permissions("a.txt", b"text"), 0o644);
+        assert_eq!(policy.permissions("run", b"#!/bin/sh\n"), 0o755);
+        assert_eq!(policy.permissions("a.lock", b"text"), 0o444);
+        assert_eq!(policy.permissions("run.lock", b"#!/bin/sh\n"), 0o555);
+    }
+}
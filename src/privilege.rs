@@ -0,0 +1,204 @@
+//! Hardening of the process started with the superuser privileges.
+
+use std::{ffi::CString, fs, io, os::unix::io::RawFd};
+
+/// The identity the process switches to after mounting the filesystem.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Credentials {
+    /// Resolve the user and the group, given by names or numeric IDs.
+    ///
+    /// The missing one is taken from the primary group of the user,
+    /// or from the current process.
+    pub fn resolve(user: Option<&str>, group: Option<&str>) -> anyhow::Result<Self> {
+        let (uid, primary_gid) = match user {
+            Some(user) => lookup_user(user)?,
+            None => unsafe { (libc::getuid(), libc::getgid()) },
+        };
+        let gid = match group {
+            Some(group) => lookup_group(group)?,
+            None => primary_gid,
+        };
+        Ok(Self { uid, gid })
+    }
+
+    /// Switch to this identity for the rest of the process lifetime.
+    ///
+    /// The supplementary groups are cleared, and the drop is verified by
+    /// trying to regain the superuser privileges.
+    pub fn drop_privileges(&self) -> anyhow::Result<()> {
+        unsafe {
+            if libc::setgroups(0, std::ptr::null()) != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            if libc::setgid(self.gid) != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            if libc::setuid(self.uid) != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+
+        if self.uid != 0 {
+            anyhow::ensure!(
+                unsafe { libc::setuid(0) } != 0,
+                "the superuser privileges could be regained after the drop"
+            );
+        }
+
+        tracing::info!("switched to uid={}, gid={}", self.uid, self.gid);
+        Ok(())
+    }
+}
+
+/// Return whether the process runs with the superuser privileges.
+pub fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Prevent the process and its children from gaining privileges by `execve(2)`.
+pub fn set_no_new_privs() -> io::Result<()> {
+    let rc = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Close the file descriptors inherited from the parent, except the standard ones.
+///
+/// This must be called before any file is opened.
+pub fn close_inherited_fds() -> io::Result<()> {
+    let fds: Vec<RawFd> = fs::read_dir("/proc/self/fd")?
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+        .filter(|&fd| fd > 2)
+        .collect();
+    for fd in fds {
+        // The descriptor used to read the directory is already closed.
+        unsafe {
+            libc::close(fd);
+        }
+    }
+    Ok(())
+}
+
+fn lookup_user(user: &str) -> anyhow::Result<(u32, u32)> {
+    let name = CString::new(user)?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if !passwd.is_null() {
+        return Ok(unsafe { ((*passwd).pw_uid, (*passwd).pw_gid) });
+    }
+
+    let uid: u32 = user
+        .parse()
+        .map_err(|_| anyhow::anyhow!("unknown user: {:?}", user))?;
+    let passwd = unsafe { libc::getpwuid(uid) };
+    anyhow::ensure!(
+        !passwd.is_null(),
+        "the user {} has no primary group; specify it with --setgid",
+        uid
+    );
+    Ok((uid, unsafe { (*passwd).pw_gid }))
+}
+
+fn lookup_group(group: &str) -> anyhow::Result<u32> {
+    let name = CString::new(group)?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if !entry.is_null() {
+        return Ok(unsafe { (*entry).gr_gid });
+    }
+
+    group
+        .parse()
+        .map_err(|_| anyhow::anyhow!("unknown group: {:?}", group))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs::File, os::unix::io::AsRawFd as _, panic::AssertUnwindSafe};
+
+    const NOBODY: u32 = 65534;
+
+    /// Run the check in a forked child, so that the identity and the
+    /// descriptors of the test process are left intact.
+    fn in_child(check: impl FnOnce() -> bool) -> bool {
+        match unsafe { libc::fork() } {
+            -1 => panic!("fork failed: {}", io::Error::last_os_error()),
+            0 => {
+                let passed = std::panic::catch_unwind(AssertUnwindSafe(check)).unwrap_or(false);
+                unsafe { libc::_exit(if passed { 0 } else { 1 }) }
+            }
+            pid => {
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0
+            }
+        }
+    }
+
+    #[test]
+    fn resolve_the_numeric_ids() {
+        let credentials = Credentials::resolve(Some("0"), Some("12345")).unwrap();
+        assert_eq!(credentials, Credentials { uid: 0, gid: 12345 });
+        let credentials = Credentials::resolve(Some("root"), None).unwrap();
+        assert_eq!(credentials, Credentials { uid: 0, gid: 0 });
+        assert!(Credentials::resolve(Some("no-such-user-of-gist-fs"), None).is_err());
+        assert!(Credentials::resolve(None, Some("no-such-group-of-gist-fs")).is_err());
+    }
+
+    #[test]
+    fn dropped_privileges_are_not_regained() {
+        if !is_root() {
+            return;
+        }
+        assert!(in_child(|| {
+            let credentials = Credentials {
+                uid: NOBODY,
+                gid: NOBODY,
+            };
+            credentials.drop_privileges().unwrap();
+            unsafe {
+                libc::getuid() == NOBODY
+                    && libc::geteuid() == NOBODY
+                    && libc::getgid() == NOBODY
+                    && libc::getgroups(0, std::ptr::null_mut()) == 0
+                    && libc::setuid(0) != 0
+            }
+        }));
+    }
+
+    #[test]
+    fn drop_fails_without_privileges() {
+        assert!(in_child(|| {
+            if is_root() {
+                unsafe {
+                    assert_eq!(libc::setgid(NOBODY), 0);
+                    assert_eq!(libc::setuid(NOBODY), 0);
+                }
+            }
+            let credentials = Credentials { uid: 0, gid: 0 };
+            credentials.drop_privileges().is_err() && !is_root()
+        }));
+    }
+
+    #[test]
+    fn inherited_fds_are_closed() {
+        assert!(in_child(|| {
+            let file = File::open("/proc/self/status").unwrap();
+            let fd = file.as_raw_fd();
+            std::mem::forget(file);
+            close_inherited_fds().unwrap();
+
+            let closed = unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1
+                && io::Error::last_os_error().raw_os_error() == Some(libc::EBADF);
+            let stdio = (0..=2).all(|fd| unsafe { libc::fcntl(fd, libc::F_GETFD) } != -1);
+            closed && stdio
+        }));
+    }
+}
@@ -0,0 +1,383 @@
+//! Token buckets limiting the rate of the requests to the API.
+
+use crate::{clock::SharedClock, state::BudgetState};
+use crossbeam::atomic::AtomicCell;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// A token bucket refilled at a fixed number of uploads per minute.
+///
+/// The bucket holds up to a minute of tokens, so a burst after an idle
+/// period is uploaded without waiting.
+#[derive(Debug)]
+pub struct UploadLimiter {
+    per_minute: u32,
+    bucket: Mutex<Bucket>,
+    queued: AtomicCell<usize>,
+    clock: SharedClock,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl Bucket {
+    fn new(tokens: f64, now: Instant) -> Self {
+        Self {
+            tokens,
+            refilled_at: now,
+        }
+    }
+
+    /// Add the tokens accumulated since the last refill, up to the capacity.
+    fn refill(&mut self, capacity: f64, per_sec: f64, now: Instant) {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_sec).min(capacity);
+        self.refilled_at = now;
+    }
+
+    /// Take a token, or return how long to wait for the next one.
+    fn take(&mut self, per_sec: f64) -> Result<(), Duration> {
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / per_sec))
+        }
+    }
+}
+
+impl UploadLimiter {
+    /// Create a limiter, which is disabled if `per_minute` is zero.
+    pub(crate) fn new(per_minute: u32, clock: SharedClock) -> Self {
+        Self {
+            per_minute,
+            bucket: Mutex::new(Bucket::new(per_minute as f64, clock.now())),
+            queued: AtomicCell::new(0),
+            clock,
+        }
+    }
+
+    /// Wait until a token is available and take it.
+    ///
+    /// The token is returned when the slot is dropped without being committed.
+    pub async fn acquire(&self) -> UploadSlot<'_> {
+        let slot = UploadSlot {
+            limiter: self,
+            committed: false,
+        };
+        if self.per_minute == 0 {
+            return slot;
+        }
+
+        // The count is restored even if the waiting upload is cancelled.
+        self.queued.fetch_add(1);
+        let _queued = Queued(&self.queued);
+        loop {
+            match self.try_acquire() {
+                Ok(()) => return slot,
+                Err(wait) => self.clock.sleep(wait).await,
+            }
+        }
+    }
+
+    /// Take a token without waiting, or return `None` if the bucket is empty.
+    pub fn acquire_now(&self) -> Option<UploadSlot<'_>> {
+        if self.per_minute != 0 && self.try_acquire().is_err() {
+            return None;
+        }
+        Some(UploadSlot {
+            limiter: self,
+            committed: false,
+        })
+    }
+
+    /// Return the token taken by an upload with nothing to upload.
+    fn release(&self) {
+        if self.per_minute == 0 {
+            return;
+        }
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.tokens = (bucket.tokens + 1.0).min(self.per_minute as f64);
+    }
+
+    /// Return the number of the uploads waiting for a token.
+    pub fn queued(&self) -> usize {
+        self.queued.load()
+    }
+
+    /// Take a token, or return how long to wait for the next one.
+    fn try_acquire(&self) -> Result<(), Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let per_sec = self.per_minute as f64 / 60.0;
+        bucket.refill(self.per_minute as f64, per_sec, self.clock.now());
+        bucket.take(per_sec)
+    }
+}
+
+/// A token taken from an `UploadLimiter`.
+///
+/// The token is returned on drop, unless the upload has been started.
+#[derive(Debug)]
+#[must_use]
+pub struct UploadSlot<'a> {
+    limiter: &'a UploadLimiter,
+    committed: bool,
+}
+
+impl UploadSlot<'_> {
+    /// Consume the token for the upload about to be sent.
+    pub fn commit(mut self) {
+        self.committed = true;
+    }
+}
+
+impl Drop for UploadSlot<'_> {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.limiter.release();
+        }
+    }
+}
+
+/// The share of the API rate limit this mount may consume per hour,
+/// cooperating with the other mounts using the same token.
+///
+/// The allowance is recomputed from the remaining requests reported by
+/// the API, so it shrinks as the other mounts consume the limit.
+#[derive(Debug)]
+pub struct RequestBudget {
+    /// The percentage of the remaining requests, or zero for no limit.
+    share: u8,
+    bucket: Mutex<Bucket>,
+    consumed: AtomicCell<u64>,
+    borrowed: AtomicCell<u64>,
+    clock: SharedClock,
+}
+
+impl RequestBudget {
+    /// The number of requests per hour assumed before the first response.
+    const DEFAULT_LIMIT: usize = 5000;
+
+    pub(crate) fn new(share: u8, clock: SharedClock) -> Self {
+        let share = share.min(100);
+        Self {
+            share,
+            bucket: Mutex::new(Bucket::new(allowance(share, None), clock.now())),
+            consumed: AtomicCell::new(0),
+            borrowed: AtomicCell::new(0),
+            clock,
+        }
+    }
+
+    /// Take a request from the budget, returning `false` if the share is exhausted.
+    pub fn try_consume(&self, remaining: Option<usize>) -> bool {
+        if self.share == 0 {
+            return true;
+        }
+        match self.refill(remaining).take(1.0) {
+            Ok(()) => {
+                self.consumed.fetch_add(1);
+                true
+            }
+            Err(..) => false,
+        }
+    }
+
+    /// Wait until the budget allows a request and take it.
+    pub async fn consume(&self, remaining: impl Fn() -> Option<usize>) {
+        if self.share == 0 {
+            return;
+        }
+        loop {
+            let remaining = remaining();
+            let per_sec = allowance(self.share, remaining) / 3600.0;
+            let wait = match self.refill(remaining).take(per_sec) {
+                Ok(()) => break,
+                Err(wait) => wait,
+            };
+            self.clock.sleep(wait).await;
+        }
+        self.consumed.fetch_add(1);
+    }
+
+    /// Record a request sent beyond the share, e.g. on an explicit fsync(2).
+    pub fn borrow(&self) {
+        self.consumed.fetch_add(1);
+        self.borrowed.fetch_add(1);
+    }
+
+    /// Return the state of the budget, or `None` if unlimited.
+    pub fn state(&self, remaining: Option<usize>) -> Option<BudgetState> {
+        if self.share == 0 {
+            return None;
+        }
+        let tokens = self.refill(remaining).tokens;
+        Some(BudgetState {
+            share: self.share,
+            available: tokens as u64,
+            allowance: allowance(self.share, remaining) as u64,
+            consumed: self.consumed.load(),
+            borrowed: self.borrowed.load(),
+        })
+    }
+
+    fn refill(&self, remaining: Option<usize>) -> std::sync::MutexGuard<'_, Bucket> {
+        let capacity = allowance(self.share, remaining);
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(capacity, capacity / 3600.0, self.clock.now());
+        bucket
+    }
+}
+
+/// Return the number of requests per hour allowed for the share.
+fn allowance(share: u8, remaining: Option<usize>) -> f64 {
+    let remaining = remaining.unwrap_or(RequestBudget::DEFAULT_LIMIT);
+    (remaining as f64 * share as f64 / 100.0).max(1.0)
+}
+
+struct Queued<'a>(&'a AtomicCell<usize>);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use futures::FutureExt as _;
+    use std::sync::Arc;
+
+    fn mock_clock() -> (Arc<MockClock>, SharedClock) {
+        let clock = Arc::new(MockClock::new());
+        (clock.clone(), SharedClock::new(clock))
+    }
+
+    #[test]
+    fn upload_limiter_allows_a_burst() {
+        let (_, clock) = mock_clock();
+        let limiter = UploadLimiter::new(3, clock);
+        for _ in 0..3 {
+            limiter.acquire().now_or_never().unwrap().commit();
+        }
+        assert!(limiter.try_acquire().is_err());
+    }
+
+    #[test]
+    fn upload_limiter_waits_for_the_refill() {
+        let (mock, clock) = mock_clock();
+        let limiter = UploadLimiter::new(60, clock);
+        for _ in 0..60 {
+            assert!(limiter.try_acquire().is_ok());
+        }
+
+        let mut acquire = Box::pin(limiter.acquire());
+        assert!((&mut acquire).now_or_never().is_none());
+        assert_eq!(limiter.queued(), 1);
+
+        // One token is refilled per second.
+        mock.advance(Duration::from_millis(500));
+        assert!((&mut acquire).now_or_never().is_none());
+        mock.advance(Duration::from_millis(500));
+        (&mut acquire).now_or_never().unwrap().commit();
+        drop(acquire);
+        assert_eq!(limiter.queued(), 0);
+    }
+
+    #[test]
+    fn upload_limiter_returns_the_uncommitted_slots() {
+        let (_, clock) = mock_clock();
+        let limiter = UploadLimiter::new(1, clock);
+
+        let slot = limiter.acquire().now_or_never().unwrap();
+        assert!(limiter.try_acquire().is_err());
+        drop(slot);
+        assert!(limiter.try_acquire().is_ok());
+    }
+
+    #[test]
+    fn upload_limiter_keeps_the_capacity() {
+        let (mock, clock) = mock_clock();
+        let limiter = UploadLimiter::new(2, clock);
+        mock.advance(Duration::from_secs(3600));
+        assert!(limiter.try_acquire().is_ok());
+        assert!(limiter.try_acquire().is_ok());
+        assert!(limiter.try_acquire().is_err());
+
+        limiter.release();
+        assert!(limiter.try_acquire().is_ok());
+    }
+
+    #[test]
+    fn upload_limiter_counts_the_cancelled_waits() {
+        let (_, clock) = mock_clock();
+        let limiter = UploadLimiter::new(1, clock);
+        assert!(limiter.try_acquire().is_ok());
+
+        let mut acquire = Box::pin(limiter.acquire());
+        assert!((&mut acquire).now_or_never().is_none());
+        assert_eq!(limiter.queued(), 1);
+        drop(acquire);
+        assert_eq!(limiter.queued(), 0);
+    }
+
+    #[test]
+    fn request_budget_is_exhausted() {
+        let (mock, clock) = mock_clock();
+        // One percent of the 200 remaining requests per hour.
+        let budget = RequestBudget::new(1, clock);
+        assert!(budget.try_consume(Some(200)));
+        assert!(budget.try_consume(Some(200)));
+        assert!(!budget.try_consume(Some(200)));
+
+        // A token is refilled every half an hour.
+        mock.advance(Duration::from_secs(1800));
+        assert!(budget.try_consume(Some(200)));
+        assert!(!budget.try_consume(Some(200)));
+
+        let state = budget.state(Some(200)).unwrap();
+        assert_eq!(state.consumed, 3);
+        assert_eq!(state.available, 0);
+    }
+
+    #[test]
+    fn request_budget_waits_for_the_refill() {
+        let (mock, clock) = mock_clock();
+        let budget = RequestBudget::new(1, clock);
+        while budget.try_consume(Some(200)) {}
+
+        let mut consume = Box::pin(budget.consume(|| Some(200)));
+        assert!((&mut consume).now_or_never().is_none());
+        mock.advance(Duration::from_secs(1800));
+        assert!((&mut consume).now_or_never().is_some());
+    }
+
+    #[test]
+    fn request_budget_borrows_beyond_the_share() {
+        let (_, clock) = mock_clock();
+        let budget = RequestBudget::new(1, clock);
+        while budget.try_consume(Some(200)) {}
+
+        budget.borrow();
+        let state = budget.state(Some(200)).unwrap();
+        assert_eq!(state.consumed, 3);
+        assert_eq!(state.borrowed, 1);
+    }
+
+    #[test]
+    fn request_budget_without_share_is_unlimited() {
+        let (_, clock) = mock_clock();
+        let budget = RequestBudget::new(0, clock);
+        for _ in 0..1000 {
+            assert!(budget.try_consume(Some(0)));
+        }
+        assert!(budget.state(Some(0)).is_none());
+    }
+}
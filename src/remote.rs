@@ -0,0 +1,37 @@
+//! The requests sent by the uploads, replaceable to run them without the
+//! network.
+
+use futures::future::{BoxFuture, FutureExt};
+use gist_client::{Client, ETag, Gist, GistPatch};
+
+/// The latest Gist and its entity tag, if the Gist exists.
+pub(crate) type Fetched = Option<(Gist, Option<ETag>)>;
+
+/// The Gist the local changes are uploaded to.
+pub(crate) trait Remote: Send + Sync {
+    /// Apply the patch, returning the updated Gist and its entity tag.
+    fn update_gist<'a>(
+        &'a self,
+        gist_id: &'a str,
+        etag: Option<&'a ETag>,
+        patch: GistPatch<'a>,
+    ) -> BoxFuture<'a, anyhow::Result<(Gist, Option<ETag>)>>;
+
+    /// Fetch the latest Gist, used to resolve the conflicts of an upload.
+    fn fetch_gist<'a>(&'a self, gist_id: &'a str) -> BoxFuture<'a, anyhow::Result<Fetched>>;
+}
+
+impl Remote for Client {
+    fn update_gist<'a>(
+        &'a self,
+        gist_id: &'a str,
+        etag: Option<&'a ETag>,
+        patch: GistPatch<'a>,
+    ) -> BoxFuture<'a, anyhow::Result<(Gist, Option<ETag>)>> {
+        Client::update_gist(self, gist_id, etag, patch).boxed()
+    }
+
+    fn fetch_gist<'a>(&'a self, gist_id: &'a str) -> BoxFuture<'a, anyhow::Result<Fetched>> {
+        Client::fetch_gist(self, gist_id, None).boxed()
+    }
+}
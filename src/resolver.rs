@@ -0,0 +1,97 @@
+//! Transformation of the names looked up on the mount.
+
+use std::{borrow::Cow, ffi::OsStr, fmt};
+
+/// A transformation applied to the names before they are looked up.
+pub trait NameResolver: fmt::Debug + Send + Sync {
+    fn resolve<'a>(&self, name: &'a OsStr) -> Cow<'a, OsStr>;
+}
+
+/// Look up the names as they are.
+#[derive(Debug, Default)]
+pub struct IdentityResolver;
+
+impl NameResolver for IdentityResolver {
+    fn resolve<'a>(&self, name: &'a OsStr) -> Cow<'a, OsStr> {
+        Cow::Borrowed(name)
+    }
+}
+
+/// Look up the names in lower case, for the Gists whose filenames are all
+/// in lower case.
+///
+/// The names which are not valid UTF-8 are looked up as they are.
+#[derive(Debug, Default)]
+pub struct LowerCaseResolver;
+
+impl NameResolver for LowerCaseResolver {
+    fn resolve<'a>(&self, name: &'a OsStr) -> Cow<'a, OsStr> {
+        match name.to_str() {
+            Some(s) if s.chars().any(char::is_uppercase) => Cow::Owned(s.to_lowercase().into()),
+            _ => Cow::Borrowed(name),
+        }
+    }
+}
+
+/// Look up the names without the control characters, which never appear
+/// in the filenames of a Gist.
+///
+/// The names which are not valid UTF-8 are looked up as they are.
+#[derive(Debug, Default)]
+pub struct SanitizingResolver;
+
+impl NameResolver for SanitizingResolver {
+    fn resolve<'a>(&self, name: &'a OsStr) -> Cow<'a, OsStr> {
+        match name.to_str() {
+            Some(s) if s.chars().any(char::is_control) => {
+                let sanitized: String = s.chars().filter(|c| !c.is_control()).collect();
+                Cow::Owned(sanitized.into())
+            }
+            _ => Cow::Borrowed(name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::ffi::OsStrExt as _;
+
+    fn non_utf8() -> &'static OsStr {
+        OsStr::from_bytes(b"A\xff\n")
+    }
+
+    #[test]
+    fn identity_keeps_the_names() {
+        for name in &[OsStr::new("README.md"), OsStr::new("a\tb"), non_utf8()] {
+            let resolved = IdentityResolver.resolve(name);
+            assert!(matches!(resolved, Cow::Borrowed(..)));
+            assert_eq!(resolved, *name);
+        }
+    }
+
+    #[test]
+    fn lower_case_folds_the_upper_case() {
+        let resolved = LowerCaseResolver.resolve(OsStr::new("README.Md"));
+        assert_eq!(resolved, OsStr::new("readme.md"));
+        let resolved = LowerCaseResolver.resolve(OsStr::new("ÄÖ.txt"));
+        assert_eq!(resolved, OsStr::new("äö.txt"));
+
+        let resolved = LowerCaseResolver.resolve(OsStr::new("readme.md"));
+        assert!(matches!(resolved, Cow::Borrowed(..)));
+        let resolved = LowerCaseResolver.resolve(non_utf8());
+        assert!(matches!(resolved, Cow::Borrowed(..)));
+        assert_eq!(resolved, non_utf8());
+    }
+
+    #[test]
+    fn sanitizing_strips_the_control_characters() {
+        let resolved = SanitizingResolver.resolve(OsStr::new("a\tb\u{7f}.txt\n"));
+        assert_eq!(resolved, OsStr::new("ab.txt"));
+        let resolved = SanitizingResolver.resolve(OsStr::new("a b.txt"));
+        assert!(matches!(resolved, Cow::Borrowed(..)));
+        let resolved = SanitizingResolver.resolve(non_utf8());
+        assert!(matches!(resolved, Cow::Borrowed(..)));
+        assert_eq!(resolved, non_utf8());
+    }
+}
@@ -0,0 +1,211 @@
+//! Read-only views of the files at a past revision, looked up as `name@{sha}`,
+//! of the local links frozen by a write to the linked file, and of the
+//! versions kept by the undo history.
+
+use crate::{
+    attr::{self, OwnerIds},
+    undo::{self, UndoHistory},
+};
+use futures::lock::Mutex;
+use node_table::Node;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    ffi::OsStr,
+    sync::Arc,
+};
+
+/// A file pinned to a revision of the Gist.
+#[derive(Debug)]
+pub struct RevisionFile {
+    pub node: Node,
+    pub content: Arc<Vec<u8>>,
+}
+
+/// The cache of the files at past revisions.
+///
+/// Revisions are immutable, so the entries are never invalidated.
+#[derive(Debug, Default)]
+pub struct Revisions {
+    files: Mutex<HashMap<u64, Arc<RevisionFile>>>,
+    names: Mutex<HashMap<(String, String), u64>>,
+}
+
+impl Revisions {
+    pub async fn get(&self, ino: u64) -> Option<Arc<RevisionFile>> {
+        self.files.lock().await.get(&ino).cloned()
+    }
+
+    pub async fn find(&self, filename: &str, sha: &str) -> Option<Arc<RevisionFile>> {
+        let ino = *self
+            .names
+            .lock()
+            .await
+            .get(&(filename.to_owned(), sha.to_owned()))?;
+        self.get(ino).await
+    }
+
+    /// Register a frozen copy, which is reached by the inode number only.
+    pub async fn insert_copy(&self, file: Arc<RevisionFile>) {
+        let ino = file.node.nodeid();
+        self.files.lock().await.insert(ino, file);
+    }
+
+    /// Unregister a file reached by the inode number only.
+    pub async fn remove_copy(&self, ino: u64) {
+        self.files.lock().await.remove(&ino);
+    }
+
+    pub async fn insert(&self, filename: &str, sha: &str, file: Arc<RevisionFile>) {
+        let ino = file.node.nodeid();
+        self.files.lock().await.insert(ino, file);
+        self.names
+            .lock()
+            .await
+            .insert((filename.to_owned(), sha.to_owned()), ino);
+    }
+}
+
+/// The directory of a file with the versions in it.
+type FileVersions = (Node, HashMap<String, u64>);
+
+/// The versions kept by the undo history, served as
+/// `.gistfs/undo/<filename>/<timestamp>`.
+#[derive(Debug)]
+pub struct UndoTree {
+    dir: Node,
+    history: UndoHistory,
+    owner: OwnerIds,
+    revisions: Arc<Revisions>,
+
+    /// The directory of each file, with the inode numbers of the versions
+    /// by their timestamps.
+    files: Mutex<HashMap<String, FileVersions>>,
+}
+
+impl UndoTree {
+    pub fn new(
+        dir: Node,
+        history: UndoHistory,
+        owner: OwnerIds,
+        revisions: Arc<Revisions>,
+    ) -> Self {
+        Self {
+            dir,
+            history,
+            owner,
+            revisions,
+            files: Mutex::default(),
+        }
+    }
+
+    /// Add the versions kept by the previous mounts.
+    pub async fn load(&self) -> anyhow::Result<()> {
+        for (filename, timestamps) in self.history.list().await? {
+            for timestamp in timestamps {
+                let content = self.history.read(&filename, &timestamp).await?;
+                self.add(&filename, timestamp, content).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Save the uploaded content as the newest version of the file,
+    /// removing the oldest ones beyond the bound.
+    pub async fn record(&self, filename: &str, content: &[u8], secret: bool) -> anyhow::Result<()> {
+        let (timestamp, removed) = self.history.record(filename, content, secret).await?;
+        self.add(filename, timestamp, content.to_vec()).await?;
+
+        let mut files = self.files.lock().await;
+        if let Some((dir, versions)) = files.get_mut(filename) {
+            for timestamp in removed {
+                if let Some(ino) = versions.remove(&timestamp) {
+                    dir.remove_child(OsStr::new(&timestamp)).await?;
+                    self.revisions.remove_copy(ino).await;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn add(&self, filename: &str, timestamp: String, content: Vec<u8>) -> anyhow::Result<()> {
+        let mut files = self.files.lock().await;
+        let (dir, versions) = match files.entry(filename.to_owned()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let attr = attr::new_attr(libc::S_IFDIR | 0o555, 2, self.owner);
+                let dir = self.dir.new_child(filename.into(), attr).await?;
+                entry.insert((dir, HashMap::new()))
+            }
+        };
+
+        let mut attr = attr::new_attr(libc::S_IFREG | 0o444, 1, self.owner);
+        attr.set_size(content.len() as u64);
+        if let Some(time) = undo::parse_timestamp(&timestamp) {
+            attr::set_times(&mut attr, time);
+        }
+        let node = dir.new_child(timestamp.clone().into(), attr).await?;
+        versions.insert(timestamp, node.nodeid());
+        let version = RevisionFile {
+            node,
+            content: Arc::new(content),
+        };
+        self.revisions.insert_copy(Arc::new(version)).await;
+        Ok(())
+    }
+}
+
+/// Split a name of the form `name@{sha}` into the file name and the revision.
+///
+/// Returns `None` if the name does not end with a well-formed revision suffix.
+pub fn parse_name(name: &str) -> Option<(&str, &str)> {
+    if !name.ends_with('}') {
+        return None;
+    }
+    let pos = name.rfind("@{")?;
+    let filename = &name[..pos];
+    let sha = &name[pos + 2..name.len() - 1];
+
+    let is_sha = sha.len() >= 7 && sha.len() <= 40 && sha.bytes().all(|b| b.is_ascii_hexdigit());
+    if filename.is_empty() || !is_sha {
+        return None;
+    }
+
+    Some((filename, sha))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_name() {
+        let cases = [
+            ("foo.txt@{0123abc}", Some(("foo.txt", "0123abc"))),
+            (
+                "foo.txt@{0123456789abcdef0123456789ABCDEF01234567}",
+                Some(("foo.txt", "0123456789abcdef0123456789ABCDEF01234567")),
+            ),
+            // The last `@{` separates the revision.
+            (
+                "me@example.com@{0123abc}",
+                Some(("me@example.com", "0123abc")),
+            ),
+            ("a@{b}@{0123abc}", Some(("a@{b}", "0123abc"))),
+            // The empty or the missing parts.
+            ("foo.txt@{}", None),
+            ("@{0123abc}", None),
+            ("foo.txt", None),
+            // The missing braces.
+            ("foo.txt@{0123abc", None),
+            ("foo.txt@0123abc}", None),
+            // The revisions not in hex or of the invalid lengths.
+            ("foo.txt@{0123abg}", None),
+            ("foo.txt@{master}", None),
+            ("foo.txt@{012345}", None),
+            ("foo.txt@{0123456789abcdef0123456789abcdef012345678}", None),
+        ];
+        for &(name, expected) in &cases {
+            assert_eq!(parse_name(name), expected, "{:?}", name);
+        }
+    }
+}
@@ -0,0 +1,38 @@
+//! Adjustment of the resource limits of the process.
+
+use std::io;
+
+/// The number of file descriptors below which the soft limit is raised.
+///
+/// The FUSE device and the HTTP connections need some headroom.
+const MIN_NOFILE: libc::rlim_t = 1024;
+
+/// The number of file descriptors the soft limit is raised to, if allowed.
+const WANTED_NOFILE: libc::rlim_t = 4096;
+
+/// Raise the soft limit of the open file descriptors toward the hard limit
+/// if it is too small, returning the resulting soft limit.
+pub fn raise_nofile_limit() -> io::Result<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if limit.rlim_cur >= MIN_NOFILE {
+        return Ok(limit.rlim_cur);
+    }
+
+    let soft = limit.rlim_cur;
+    limit.rlim_cur = std::cmp::min(WANTED_NOFILE, limit.rlim_max);
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    tracing::info!(
+        "raised the limit of open files from {} to {}",
+        soft,
+        limit.rlim_cur
+    );
+    Ok(limit.rlim_cur)
+}
@@ -0,0 +1,168 @@
+//! Collection of the local files uploaded to a new Gist.
+
+use gist_client::MAX_FILE_SIZE;
+use std::{fs, path::Path};
+
+/// How the files in a directory are collected.
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    include_hidden: bool,
+    max_size: u64,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            include_hidden: false,
+            max_size: MAX_FILE_SIZE,
+        }
+    }
+}
+
+impl ScanOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Include the files whose names start with `.`.
+    pub fn include_hidden(&mut self, enabled: bool) -> &mut Self {
+        self.include_hidden = enabled;
+        self
+    }
+
+    /// Skip the files larger than the specified number of bytes.
+    pub fn max_size(&mut self, max_size: u64) -> &mut Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Collect the text files directly under the directory as pairs of
+    /// the filename and the content, sorted by the filename.
+    ///
+    /// The filenames are sanitized as on the mount, and the subdirectories,
+    /// the binary files and the files too large are skipped.
+    pub fn scan(&self, dir: &Path) -> anyhow::Result<Vec<(String, String)>> {
+        let mut files = vec![];
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(name) => {
+                    tracing::warn!("skip the file with a non-UTF-8 name: {:?}", name);
+                    continue;
+                }
+            };
+            if name.starts_with('.') && !self.include_hidden {
+                continue;
+            }
+
+            let metadata = fs::metadata(entry.path())?;
+            if !metadata.is_file() {
+                continue;
+            }
+            if metadata.len() > self.max_size {
+                tracing::warn!(
+                    "skip the file larger than {} bytes: {:?}",
+                    self.max_size,
+                    name
+                );
+                continue;
+            }
+
+            let content = match String::from_utf8(fs::read(entry.path())?) {
+                Ok(content) => content,
+                Err(..) => {
+                    tracing::warn!("skip the binary file: {:?}", name);
+                    continue;
+                }
+            };
+
+            let filename = crate::sanitize_filename(&name);
+            if filename.is_empty() {
+                tracing::warn!("skip the file with a blank name: {:?}", name);
+                continue;
+            }
+            files.push((filename.to_owned(), content));
+        }
+
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+        if let Some(dup) = files.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            anyhow::bail!("multiple files are sanitized into {:?}", dup[0].0);
+        }
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A directory of files removed on drop.
+    struct Fixture(PathBuf);
+
+    impl Fixture {
+        fn new(name: &str, files: &[(&str, &[u8])]) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "gist-fs-scan-test-{}-{}",
+                std::process::id(),
+                name
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir(&dir).unwrap();
+            for (name, content) in files {
+                fs::write(dir.join(name), content).unwrap();
+            }
+            Self(dir)
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn names(files: &[(String, String)]) -> Vec<&str> {
+        files.iter().map(|(name, _)| &**name).collect()
+    }
+
+    #[test]
+    fn collect_the_text_files() {
+        let fixture = Fixture::new(
+            "text",
+            &[
+                ("b.txt", b"b"),
+                ("a.sh  ", b"#!/bin/sh\n"),
+                (".hidden", b"hidden"),
+                ("binary", b"\xff\xfe"),
+                ("large.txt", b"0123456789ab"),
+                ("  ", b"blank"),
+            ],
+        );
+        fs::create_dir(fixture.0.join("dir")).unwrap();
+
+        let mut options = ScanOptions::new();
+        options.max_size(11);
+        let files = options.scan(&fixture.0).unwrap();
+        assert_eq!(names(&files), ["a.sh", "b.txt"]);
+        assert_eq!(files[0].1, "#!/bin/sh\n");
+
+        options.include_hidden(true).max_size(12);
+        let files = options.scan(&fixture.0).unwrap();
+        assert_eq!(names(&files), [".hidden", "a.sh", "b.txt", "large.txt"]);
+    }
+
+    #[test]
+    fn reject_the_names_colliding_after_sanitized() {
+        let fixture = Fixture::new("collision", &[("a.txt", b"a"), ("a.txt\t", b"tab")]);
+        let err = ScanOptions::new().scan(&fixture.0).unwrap_err();
+        assert!(err.to_string().contains("\"a.txt\""), "{}", err);
+    }
+
+    #[test]
+    fn missing_directory() {
+        let fixture = Fixture::new("missing", &[]);
+        assert!(ScanOptions::new().scan(&fixture.0.join("missing")).is_err());
+    }
+}
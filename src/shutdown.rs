@@ -0,0 +1,65 @@
+//! Cancellation of the network operations while the mount is torn down.
+
+use crossbeam::atomic::AtomicCell;
+use futures::future::{self, Either};
+use std::future::Future;
+use tokio::sync::watch;
+
+/// A signal broadcast to the handlers and the background tasks on shutdown.
+#[derive(Debug)]
+pub(crate) struct Shutdown {
+    tx: watch::Sender<bool>,
+    rx: watch::Receiver<bool>,
+    triggered: AtomicCell<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        let (tx, rx) = watch::channel(false);
+        Self {
+            tx,
+            rx,
+            triggered: AtomicCell::new(false),
+        }
+    }
+}
+
+impl Shutdown {
+    pub(crate) fn trigger(&self) {
+        self.triggered.store(true);
+        let _ = self.tx.broadcast(true);
+    }
+
+    pub(crate) fn is_triggered(&self) -> bool {
+        self.triggered.load()
+    }
+
+    /// Wait until the shutdown is triggered.
+    pub(crate) async fn wait(&self) {
+        let mut rx = self.rx.clone();
+        while let Some(triggered) = rx.recv().await {
+            if triggered {
+                return;
+            }
+        }
+    }
+
+    /// Run the future unless the shutdown is triggered before it completes.
+    ///
+    /// Returns `None` if cancelled.
+    pub(crate) async fn run<F>(&self, fut: F) -> Option<F::Output>
+    where
+        F: Future,
+    {
+        if self.is_triggered() {
+            return None;
+        }
+        futures::pin_mut!(fut);
+        let wait = self.wait();
+        futures::pin_mut!(wait);
+        match future::select(fut, wait).await {
+            Either::Left((output, _)) => Some(output),
+            Either::Right(..) => None,
+        }
+    }
+}
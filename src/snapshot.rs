@@ -0,0 +1,103 @@
+//! Export and import of the files on the mount through a local directory.
+
+use crate::state::GistMetadata;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// The name of the file describing the Gist in a snapshot.
+pub(crate) const METADATA_FILE: &str = "__gist_metadata__.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SnapshotMetadata {
+    pub(crate) gist_id: String,
+    /// The fingerprint of the access token the files were fetched with.
+    ///
+    /// The snapshots written before it was recorded have none, and are
+    /// imported under any identity.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) identity: Option<String>,
+    #[serde(flatten)]
+    pub(crate) gist: Option<GistMetadata>,
+    pub(crate) exported_at: DateTime<Utc>,
+}
+
+/// Write the files and the metadata into the directory, replacing it as a whole.
+///
+/// The snapshot is written into a temporary directory next to `path` and
+/// renamed into place, so that a partial snapshot is never seen.
+pub(crate) async fn write(
+    path: &Path,
+    metadata: &SnapshotMetadata,
+    files: &[(Arc<str>, Arc<Vec<u8>>)],
+) -> io::Result<()> {
+    let temp = sibling(path, "tmp");
+    if tokio::fs::metadata(&temp).await.is_ok() {
+        tokio::fs::remove_dir_all(&temp).await?;
+    }
+    tokio::fs::create_dir_all(&temp).await?;
+
+    for (filename, content) in files {
+        if !is_plain_filename(filename) || **filename == *METADATA_FILE {
+            tracing::warn!("skip the file in the snapshot: {:?}", filename);
+            continue;
+        }
+        tokio::fs::write(temp.join(&**filename), &content[..]).await?;
+    }
+    let metadata = serde_json::to_vec_pretty(metadata)?;
+    tokio::fs::write(temp.join(METADATA_FILE), metadata).await?;
+
+    // A directory cannot be renamed over a non-empty one, so the previous
+    // snapshot is moved out of the way first.
+    let old = sibling(path, "old");
+    let replaced = tokio::fs::rename(path, &old).await.is_ok();
+    tokio::fs::rename(&temp, path).await?;
+    if replaced {
+        tokio::fs::remove_dir_all(&old).await?;
+    }
+    Ok(())
+}
+
+/// Read the metadata of the snapshot in the directory.
+pub(crate) async fn read_metadata(path: &Path) -> io::Result<SnapshotMetadata> {
+    let metadata = tokio::fs::read(path.join(METADATA_FILE)).await?;
+    Ok(serde_json::from_slice(&metadata)?)
+}
+
+/// Read the files in the snapshot, sorted by the filename.
+pub(crate) async fn read_files(path: &Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let mut files = vec![];
+    let mut entries = tokio::fs::read_dir(path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if !entry.file_type().await?.is_file() {
+            continue;
+        }
+        let filename = match entry.file_name().into_string() {
+            Ok(filename) if filename != METADATA_FILE => filename,
+            Ok(..) => continue,
+            Err(filename) => {
+                tracing::warn!("skip the file with a non-UTF-8 name: {:?}", filename);
+                continue;
+            }
+        };
+        let content = tokio::fs::read(entry.path()).await?;
+        files.push((filename, content));
+    }
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(files)
+}
+
+/// Return the path next to `path` with the suffix appended to its name.
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}.{}", suffix, std::process::id()));
+    path.with_file_name(name)
+}
+
+fn is_plain_filename(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains('/')
+}
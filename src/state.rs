@@ -0,0 +1,262 @@
+//! Machine-readable state of the mount.
+
+use crate::timefmt::{self, TimeFormat};
+use chrono::{DateTime, Utc};
+use futures::future::Future;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, Permissions},
+    io,
+    os::unix::fs::PermissionsExt,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{io::AsyncWriteExt, net::UnixListener, sync::watch};
+
+/// The outcome of a background operation.
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct Outcome {
+    pub at: DateTime<Utc>,
+    pub ok: bool,
+}
+
+impl Outcome {
+    pub fn new(ok: bool) -> Self {
+        Self { at: Utc::now(), ok }
+    }
+}
+
+/// A file modified locally but not uploaded yet.
+#[derive(Debug, Serialize)]
+pub struct DirtyFile {
+    pub filename: String,
+    pub size: usize,
+}
+
+/// The attributes of the Gist other than its files.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GistMetadata {
+    pub description: String,
+    pub public: bool,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A snapshot of the mount state.
+///
+/// This is the single source of both `.gistfs/stats` and the state socket.
+#[derive(Debug, Serialize)]
+pub struct MountState {
+    pub gist_id: String,
+
+    /// The original Gist and when it was forked, if the mount has
+    /// switched to its fork on the first write.
+    pub forked_from: Option<String>,
+    pub forked_at: Option<DateTime<Utc>>,
+
+    pub read_only: bool,
+    pub degraded: bool,
+    pub errors: usize,
+    pub files: usize,
+    pub dirty_files: Vec<DirtyFile>,
+    pub last_refresh: Option<Outcome>,
+    pub last_flush: Option<Outcome>,
+    pub rate_remaining: Option<usize>,
+    pub another_writer: bool,
+    pub orphaned: bool,
+    pub metadata: Option<GistMetadata>,
+
+    /// The number of bytes used to store the directory entries.
+    pub entries_bytes: usize,
+
+    /// The estimated offset of the server clock from the local one, in seconds.
+    pub clock_skew_secs: Option<i64>,
+
+    /// The number of the open file handles, and the largest number ever opened.
+    pub open_handles: usize,
+    pub max_open_handles: usize,
+
+    /// The number of the uploads waiting for the rate limit.
+    pub queued_uploads: usize,
+
+    /// The share of the rate limit allowed for this mount, if limited.
+    pub request_budget: Option<BudgetState>,
+
+    /// The numbers of the HTTP connections opened and reused by the requests.
+    pub connections_opened: u64,
+    pub connections_reused: u64,
+}
+
+/// The consumption of the share of the rate limit allowed for a mount.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct BudgetState {
+    /// The percentage of the remaining requests allowed per hour.
+    pub share: u8,
+    /// The number of the requests available now, and per hour.
+    pub available: u64,
+    pub allowance: u64,
+    /// The number of the requests sent, including the ones beyond the share.
+    pub consumed: u64,
+    pub borrowed: u64,
+}
+
+impl MountState {
+    /// Render the identity of the mounted Gist as the content of `.gistfs/info`.
+    pub fn render_info(&self, time_format: &TimeFormat) -> String {
+        let mut info = format!("gist_id: {}\n", self.gist_id);
+        if let (Some(original), Some(at)) = (&self.forked_from, self.forked_at) {
+            info += &format!(
+                "forked_from: {}\nforked_at: {}\n",
+                original,
+                time_format.render(at)
+            );
+        }
+        info
+    }
+
+    /// Render the state as the content of `.gistfs/stats`.
+    pub fn render_stats(&self, time_format: &TimeFormat) -> String {
+        let render_outcome = |outcome: Option<Outcome>| match outcome {
+            Some(outcome) => format!(
+                "{} ({} ago, {})",
+                time_format.render(outcome.at),
+                timefmt::humanize(Utc::now() - outcome.at),
+                if outcome.ok { "ok" } else { "failed" },
+            ),
+            None => "never".to_owned(),
+        };
+        let mut stats = format!(
+            "degraded: {}\nerrors: {}\nfiles: {}\ndirty: {}\nread_only: {}\nanother_writer: {}\norphaned: {}\nlast_refresh: {}\nlast_flush: {}\nentries_bytes: {}\nclock_skew: {}\nopen_handles: {} (max {})\nqueued_uploads: {}\n",
+            self.degraded as u8,
+            self.errors,
+            self.files,
+            self.dirty_files.len(),
+            self.read_only as u8,
+            self.another_writer as u8,
+            self.orphaned as u8,
+            render_outcome(self.last_refresh),
+            render_outcome(self.last_flush),
+            self.entries_bytes,
+            match self.clock_skew_secs {
+                Some(secs) => timefmt::humanize(chrono::Duration::seconds(secs)),
+                None => "unknown".to_owned(),
+            },
+            self.open_handles,
+            self.max_open_handles,
+            self.queued_uploads,
+        );
+        stats += &format!(
+            "connections: {} opened, {} reused\n",
+            self.connections_opened, self.connections_reused,
+        );
+        if let Some(budget) = self.request_budget {
+            stats += &format!(
+                "request_budget: {}/{} ({}% of the remaining, {} consumed, {} borrowed)\n",
+                budget.available, budget.allowance, budget.share, budget.consumed, budget.borrowed,
+            );
+        }
+        if !self.dirty_files.is_empty() {
+            let filenames: Vec<&str> = self
+                .dirty_files
+                .iter()
+                .map(|file| &*file.filename)
+                .collect();
+            stats += &format!("unsynced: {}\n", filenames.join(", "));
+        }
+        stats
+    }
+}
+
+/// A Unix domain socket serving the mount state as a JSON document per connection.
+///
+/// The socket file is removed when this value is dropped.
+#[derive(Debug)]
+pub struct StateSocket {
+    path: PathBuf,
+}
+
+/// The interval at which the snapshot served by the state socket is refreshed.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(1);
+
+impl StateSocket {
+    pub(crate) fn bind<F, Fut>(path: PathBuf, snapshot: F) -> io::Result<Self>
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = MountState> + Send,
+    {
+        // Create the socket file inaccessible to the others from the start,
+        // rather than narrowing its permissions after it is already connectable.
+        let mut listener = {
+            let _umask = Umask::set(0o077);
+            UnixListener::bind(&path)?
+        };
+        fs::set_permissions(&path, Permissions::from_mode(0o600))?;
+
+        // Serve the snapshot taken in the background, so that the clients
+        // connecting in a tight loop cannot contend with the file operations.
+        let (tx, mut rx) = watch::channel(None::<Arc<[u8]>>);
+        tokio::spawn(async move {
+            loop {
+                let state = snapshot().await;
+                match serde_json::to_vec(&state) {
+                    Ok(body) => {
+                        if tx.broadcast(Some(body.into())).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => tracing::error!("failed to serialize the mount state: {}", err),
+                }
+                tokio::time::delay_for(SNAPSHOT_INTERVAL).await;
+            }
+        });
+
+        tokio::spawn(async move {
+            // Do not accept the connections until the first snapshot is taken.
+            while let Some(None) = rx.recv().await {}
+            loop {
+                let mut stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(err) => {
+                        tracing::error!("failed to accept a connection: {}", err);
+                        continue;
+                    }
+                };
+
+                let body = match &*rx.borrow() {
+                    Some(body) => body.clone(),
+                    None => continue,
+                };
+                tokio::spawn(async move {
+                    if let Err(err) = stream.write_all(&body).await {
+                        tracing::debug!("failed to send the mount state: {}", err);
+                    }
+                });
+            }
+        });
+
+        Ok(Self { path })
+    }
+}
+
+/// Restores the previous file mode creation mask when dropped.
+struct Umask(libc::mode_t);
+
+impl Umask {
+    fn set(mask: libc::mode_t) -> Self {
+        Self(unsafe { libc::umask(mask) })
+    }
+}
+
+impl Drop for Umask {
+    fn drop(&mut self) {
+        unsafe {
+            libc::umask(self.0);
+        }
+    }
+}
+
+impl Drop for StateSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
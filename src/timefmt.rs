@@ -0,0 +1,118 @@
+//! Rendering of the timestamps and the durations in the virtual files.
+
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, Duration, Local, SecondsFormat, Utc,
+};
+
+/// How the timestamps are rendered.
+///
+/// By default, the timestamps are rendered in RFC 3339 in UTC.
+#[derive(Debug, Clone, Default)]
+pub struct TimeFormat {
+    local: bool,
+    format: Option<String>,
+}
+
+impl TimeFormat {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Render the timestamps in the local time zone.
+    pub fn local(&mut self, enabled: bool) -> &mut Self {
+        self.local = enabled;
+        self
+    }
+
+    /// Render the timestamps with the specified strftime format.
+    ///
+    /// Fails if the format contains an invalid specifier.
+    pub fn format(&mut self, format: impl Into<String>) -> anyhow::Result<&mut Self> {
+        let format = format.into();
+        anyhow::ensure!(
+            StrftimeItems::new(&format).all(|item| item != Item::Error),
+            "invalid time format: {:?}",
+            format
+        );
+        self.format = Some(format);
+        Ok(self)
+    }
+
+    pub fn render(&self, time: DateTime<Utc>) -> String {
+        match (&self.format, self.local) {
+            (Some(format), false) => time.format(format).to_string(),
+            (Some(format), true) => time.with_timezone(&Local).format(format).to_string(),
+            (None, false) => time.to_rfc3339_opts(SecondsFormat::Secs, true),
+            (None, true) => time
+                .with_timezone(&Local)
+                .to_rfc3339_opts(SecondsFormat::Secs, false),
+        }
+    }
+}
+
+/// Render a duration in the form of `1d2h3m4s`, omitting the zero units.
+///
+/// Negative durations, e.g. caused by clock skew, are prefixed with `-`.
+pub fn humanize(duration: Duration) -> String {
+    let (sign, secs) = match duration.num_seconds() {
+        secs if secs < 0 => ("-", secs.checked_neg().unwrap_or(i64::MAX)),
+        secs => ("", secs),
+    };
+
+    let mut rendered = sign.to_owned();
+    let mut rest = secs;
+    for &(unit, len) in &[("d", 86400), ("h", 3600), ("m", 60)] {
+        if rest >= len {
+            rendered += &format!("{}{}", rest / len, unit);
+            rest %= len;
+        }
+    }
+    if rest > 0 || secs == 0 {
+        rendered += &format!("{}s", rest);
+    }
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone as _;
+
+    fn time() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2020, 1, 2, 3, 4, 5).unwrap()
+    }
+
+    #[test]
+    fn render_rfc3339_by_default() {
+        assert_eq!(TimeFormat::new().render(time()), "2020-01-02T03:04:05Z");
+    }
+
+    #[test]
+    fn render_with_format() {
+        let mut format = TimeFormat::new();
+        format.format("%Y/%m/%d %H:%M").unwrap();
+        assert_eq!(format.render(time()), "2020/01/02 03:04");
+    }
+
+    #[test]
+    fn reject_invalid_format() {
+        assert!(TimeFormat::new().format("%Y-%!").is_err());
+    }
+
+    #[test]
+    fn test_humanize() {
+        let cases = [
+            (0, "0s"),
+            (4, "4s"),
+            (60, "1m"),
+            (3 * 60 + 4, "3m4s"),
+            (86400 + 2 * 3600 + 3 * 60 + 4, "1d2h3m4s"),
+            (86400 + 4, "1d4s"),
+            (-90, "-1m30s"),
+        ];
+        for &(secs, expected) in &cases {
+            assert_eq!(humanize(Duration::seconds(secs)), expected, "{}", secs);
+        }
+    }
+}
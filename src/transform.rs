@@ -0,0 +1,147 @@
+//! Processing of the content of the files before they are uploaded.
+
+use std::fmt;
+
+/// A transformation applied to the content of each file on upload.
+///
+/// The local content is left as it is, and is replaced with the uploaded
+/// one on the next refresh.
+pub trait ContentTransformer: fmt::Debug + Send + Sync {
+    fn transform(&self, filename: &str, content: &[u8]) -> anyhow::Result<Vec<u8>>;
+}
+
+impl Default for Box<dyn ContentTransformer> {
+    fn default() -> Self {
+        Box::new(IdentityTransformer)
+    }
+}
+
+/// Upload the content as it is.
+#[derive(Debug, Default)]
+pub struct IdentityTransformer;
+
+impl ContentTransformer for IdentityTransformer {
+    fn transform(&self, _: &str, content: &[u8]) -> anyhow::Result<Vec<u8>> {
+        Ok(content.to_vec())
+    }
+}
+
+/// Convert the line endings from CRLF to LF.
+#[derive(Debug, Default)]
+pub struct LineFeedNormalizer;
+
+impl ContentTransformer for LineFeedNormalizer {
+    fn transform(&self, _: &str, content: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut normalized = Vec::with_capacity(content.len());
+        let mut bytes = content.iter().peekable();
+        while let Some(&b) = bytes.next() {
+            if b == b'\r' && bytes.peek() == Some(&&b'\n') {
+                continue;
+            }
+            normalized.push(b);
+        }
+        Ok(normalized)
+    }
+}
+
+/// Remove the spaces and the tabs at the end of each line.
+#[derive(Debug, Default)]
+pub struct TrailingWhitespaceTrimmer;
+
+impl ContentTransformer for TrailingWhitespaceTrimmer {
+    fn transform(&self, _: &str, content: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut trimmed = Vec::with_capacity(content.len());
+        for (i, line) in content.split(|&b| b == b'\n').enumerate() {
+            if i > 0 {
+                trimmed.push(b'\n');
+            }
+            // The CR of a CRLF line ending is kept after the trimmed line.
+            let (line, cr) = match line.split_last() {
+                Some((b'\r', line)) => (line, true),
+                _ => (line, false),
+            };
+            let len = line
+                .iter()
+                .rposition(|&b| b != b' ' && b != b'\t')
+                .map_or(0, |pos| pos + 1);
+            trimmed.extend_from_slice(&line[..len]);
+            if cr {
+                trimmed.push(b'\r');
+            }
+        }
+        Ok(trimmed)
+    }
+}
+
+/// Refuse to upload the content which is not valid UTF-8.
+#[derive(Debug, Default)]
+pub struct Utf8Validator;
+
+impl ContentTransformer for Utf8Validator {
+    fn transform(&self, filename: &str, content: &[u8]) -> anyhow::Result<Vec<u8>> {
+        match std::str::from_utf8(content) {
+            Ok(..) => Ok(content.to_vec()),
+            Err(err) => Err(anyhow::anyhow!(
+                "the content of {:?} is not valid UTF-8: {}",
+                filename,
+                err
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transform(transformer: &dyn ContentTransformer, content: &[u8]) -> Vec<u8> {
+        transformer.transform("a.txt", content).unwrap()
+    }
+
+    #[test]
+    fn identity_round_trip() {
+        for content in &[&b""[..], b"a\r\nb \n", b"\xff\xfe"] {
+            assert_eq!(transform(&IdentityTransformer, content), *content);
+        }
+    }
+
+    #[test]
+    fn line_feeds_are_normalized() {
+        assert_eq!(transform(&LineFeedNormalizer, b"a\r\nb\r\n"), b"a\nb\n");
+        // The lone CRs are not line endings.
+        assert_eq!(
+            transform(&LineFeedNormalizer, b"a\rb\r\r\n\r"),
+            b"a\rb\r\n\r"
+        );
+        // The normalized content is left as it is.
+        assert_eq!(transform(&LineFeedNormalizer, b"a\nb\n"), b"a\nb\n");
+        assert_eq!(transform(&LineFeedNormalizer, b""), b"");
+    }
+
+    #[test]
+    fn trailing_whitespace_is_trimmed() {
+        let trimmed = transform(&TrailingWhitespaceTrimmer, b"a \t\n  b  \n\t\nc");
+        assert_eq!(trimmed, b"a\n  b\n\nc");
+        let trimmed = transform(&TrailingWhitespaceTrimmer, b"a \r\nb\t\r\n");
+        assert_eq!(trimmed, b"a\r\nb\r\n");
+        // The trimmed content is left as it is, with the final newline.
+        assert_eq!(transform(&TrailingWhitespaceTrimmer, b"a\nb\n"), b"a\nb\n");
+        assert_eq!(transform(&TrailingWhitespaceTrimmer, b"  "), b"");
+    }
+
+    #[test]
+    fn invalid_utf8_is_refused() {
+        assert_eq!(
+            transform(&Utf8Validator, "αβ\n".as_bytes()),
+            "αβ\n".as_bytes()
+        );
+        let err = Utf8Validator.transform("a.bin", b"a\xffb").unwrap_err();
+        assert!(err.to_string().contains("\"a.bin\""), "{}", err);
+    }
+
+    #[test]
+    fn default_is_identity() {
+        let transformer = Box::<dyn ContentTransformer>::default();
+        assert_eq!(transform(&*transformer, b"a \r\n"), b"a \r\n");
+    }
+}
@@ -0,0 +1,34 @@
+//! Selection of the protocol used to fetch the content of the Gist.
+
+use std::str::FromStr;
+
+/// How the content of the files is fetched.
+///
+/// The metadata of the Gist is always fetched through the REST API, and
+/// the local changes are uploaded through it regardless of the transport.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Transport {
+    /// Fetch through the REST API, which truncates the large files and
+    /// the file list beyond 300 files.
+    #[default]
+    Rest,
+
+    /// Fetch with a shallow clone of the git repository of the Gist.
+    #[cfg(feature = "git-transport")]
+    Git,
+}
+
+impl FromStr for Transport {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rest" => Ok(Transport::Rest),
+            #[cfg(feature = "git-transport")]
+            "git" => Ok(Transport::Git),
+            #[cfg(not(feature = "git-transport"))]
+            "git" => anyhow::bail!("the git transport requires the git-transport feature"),
+            s => anyhow::bail!("unknown transport: {:?}", s),
+        }
+    }
+}
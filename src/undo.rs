@@ -0,0 +1,249 @@
+//! The recently uploaded versions of the files, kept locally to undo an
+//! unwanted upload without the revisions of the Gist.
+
+use chrono::{DateTime, NaiveDateTime, TimeZone as _, Utc};
+use std::{
+    fs::Permissions,
+    io,
+    os::unix::fs::{OpenOptionsExt as _, PermissionsExt as _},
+    path::PathBuf,
+};
+use tokio::io::AsyncWriteExt as _;
+
+/// The names of the versions, sortable in time order.
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%S%6fZ";
+
+/// Return the directory of the undo histories, `$XDG_CACHE_HOME/gist-fs/undo`
+/// or `~/.cache/gist-fs/undo`.
+pub fn default_dir() -> Option<PathBuf> {
+    let cache_dir = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+    Some(cache_dir.join("gist-fs").join("undo"))
+}
+
+/// The uploaded versions of the files, as `<dir>/<gist_id>/<filename>/<timestamp>`.
+#[derive(Debug)]
+pub struct UndoHistory {
+    dir: PathBuf,
+    versions: usize,
+}
+
+impl UndoHistory {
+    /// Keep at most `versions` versions per file, which must not be zero.
+    pub fn new(dir: PathBuf, gist_id: &str, versions: usize) -> Self {
+        debug_assert!(versions > 0);
+        Self {
+            dir: dir.join(gist_id),
+            versions,
+        }
+    }
+
+    /// Save the uploaded content as the newest version of the file,
+    /// returning its timestamp and the ones of the versions removed to
+    /// stay within the bound.
+    ///
+    /// The versions of the secret Gists are readable only by the owner.
+    pub async fn record(
+        &self,
+        filename: &str,
+        content: &[u8],
+        secret: bool,
+    ) -> io::Result<(String, Vec<String>)> {
+        let dir = self.dir.join(filename);
+        tokio::fs::create_dir_all(&dir).await?;
+        if secret {
+            tokio::fs::set_permissions(&self.dir, Permissions::from_mode(0o700)).await?;
+            tokio::fs::set_permissions(&dir, Permissions::from_mode(0o700)).await?;
+        }
+
+        let timestamp = Utc::now().format(TIMESTAMP_FORMAT).to_string();
+        let mut options = std::fs::OpenOptions::new();
+        options
+            .write(true)
+            .create_new(true)
+            .mode(if secret { 0o600 } else { 0o644 });
+        let mut file = tokio::fs::OpenOptions::from(options)
+            .open(dir.join(&timestamp))
+            .await?;
+        file.write_all(content).await?;
+
+        let mut versions = self.versions_of(filename).await?;
+        let stale = versions.len().saturating_sub(self.versions);
+        let removed: Vec<String> = versions.drain(..stale).collect();
+        for timestamp in &removed {
+            tracing::debug!("remove the old version: {:?}@{}", filename, timestamp);
+            tokio::fs::remove_file(dir.join(timestamp)).await?;
+        }
+
+        Ok((timestamp, removed))
+    }
+
+    /// Return the files with the versions, each the oldest first.
+    pub async fn list(&self) -> io::Result<Vec<(String, Vec<String>)>> {
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+        let mut files = vec![];
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry.file_type().await?.is_dir() {
+                continue;
+            }
+            if let Some(filename) = entry.file_name().to_str() {
+                let versions = self.versions_of(filename).await?;
+                if !versions.is_empty() {
+                    files.push((filename.to_owned(), versions));
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    pub async fn read(&self, filename: &str, timestamp: &str) -> io::Result<Vec<u8>> {
+        tokio::fs::read(self.dir.join(filename).join(timestamp)).await
+    }
+
+    /// List the timestamps of the versions of the file, the oldest first.
+    async fn versions_of(&self, filename: &str) -> io::Result<Vec<String>> {
+        let mut versions = vec![];
+        let mut entries = tokio::fs::read_dir(self.dir.join(filename)).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some(name) = entry.file_name().to_str().filter(|name| is_timestamp(name)) {
+                versions.push(name.to_owned());
+            }
+        }
+        versions.sort();
+        Ok(versions)
+    }
+}
+
+/// Return the time a version was uploaded at, from its timestamp.
+pub fn parse_timestamp(s: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(s, TIMESTAMP_FORMAT)
+        .ok()
+        .map(|time| Utc.from_utc_datetime(&time))
+}
+
+fn is_timestamp(s: &str) -> bool {
+    parse_timestamp(s).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+
+    /// Return a fresh directory for the histories of a test.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("gist-fs-undo-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(future)
+    }
+
+    /// Record the versions one by one, so that their timestamps differ.
+    async fn record_all(history: &UndoHistory, filename: &str, contents: &[&str]) -> Vec<String> {
+        let mut timestamps = vec![];
+        for content in contents {
+            std::thread::sleep(std::time::Duration::from_millis(2));
+            let (timestamp, _) = history
+                .record(filename, content.as_bytes(), false)
+                .await
+                .unwrap();
+            timestamps.push(timestamp);
+        }
+        timestamps
+    }
+
+    #[test]
+    fn versions_are_listed_oldest_first() {
+        let dir = scratch_dir("order");
+        let history = UndoHistory::new(dir.clone(), "0123abc", 5);
+        block_on(async {
+            assert!(history.list().await.unwrap().is_empty());
+
+            let timestamps = record_all(&history, "a.txt", &["one", "two", "three"]).await;
+            record_all(&history, "b.txt", &["other"]).await;
+
+            let mut files = history.list().await.unwrap();
+            files.sort();
+            assert_eq!(files.len(), 2);
+            assert_eq!(files[0], ("a.txt".to_owned(), timestamps.clone()));
+            assert_eq!(files[1].1.len(), 1);
+
+            assert_eq!(history.read("a.txt", &timestamps[0]).await.unwrap(), b"one");
+            assert_eq!(
+                history.read("a.txt", &timestamps[2]).await.unwrap(),
+                b"three"
+            );
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn oldest_versions_are_pruned_at_the_limit() {
+        let dir = scratch_dir("prune");
+        let history = UndoHistory::new(dir.clone(), "0123abc", 2);
+        block_on(async {
+            let timestamps = record_all(&history, "a.txt", &["one", "two"]).await;
+
+            std::thread::sleep(std::time::Duration::from_millis(2));
+            let (newest, removed) = history.record("a.txt", b"three", false).await.unwrap();
+            assert_eq!(removed, &timestamps[..1]);
+
+            let files = history.list().await.unwrap();
+            assert_eq!(
+                files,
+                [("a.txt".to_owned(), vec![timestamps[1].clone(), newest])]
+            );
+            assert!(history.read("a.txt", &timestamps[0]).await.is_err());
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn secret_versions_are_private() {
+        let dir = scratch_dir("secret");
+        let history = UndoHistory::new(dir.clone(), "0123abc", 5);
+        let (timestamp, _) = block_on(history.record("a.txt", b"secret", true)).unwrap();
+
+        let mode = |path: PathBuf| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(dir.join("0123abc")), 0o700);
+        assert_eq!(mode(dir.join("0123abc").join("a.txt")), 0o700);
+        assert_eq!(
+            mode(dir.join("0123abc").join("a.txt").join(timestamp)),
+            0o600
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn stray_files_are_not_versions() {
+        let dir = scratch_dir("stray");
+        let history = UndoHistory::new(dir.clone(), "0123abc", 5);
+        block_on(async {
+            let timestamps = record_all(&history, "a.txt", &["one"]).await;
+            std::fs::write(dir.join("0123abc").join("a.txt").join("notes"), "x").unwrap();
+            std::fs::write(dir.join("0123abc").join("stray"), "x").unwrap();
+
+            let files = history.list().await.unwrap();
+            assert_eq!(files, [("a.txt".to_owned(), timestamps)]);
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        let time = parse_timestamp("20200102T030405123456Z").unwrap();
+        assert_eq!(time.to_rfc3339(), "2020-01-02T03:04:05.123456+00:00");
+        assert!(parse_timestamp("notes").is_none());
+        assert!(parse_timestamp("2020-01-02").is_none());
+    }
+}
@@ -0,0 +1,248 @@
+//! The conversion of a Gist between public and secret, which the API
+//! does not allow in place, by copying its files into a new Gist.
+
+use anyhow::Context as _;
+use gist_client::{Client, Gist, GistFile, NewGist};
+use std::{fmt, str::FromStr};
+
+/// The size of the chunks in which the truncated files are read.
+const RAW_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The visibility of a Gist.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Visibility {
+    Public,
+    Secret,
+}
+
+impl Visibility {
+    fn of(gist: &Gist) -> Self {
+        if gist.public {
+            Visibility::Public
+        } else {
+            Visibility::Secret
+        }
+    }
+}
+
+impl FromStr for Visibility {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "public" => Ok(Visibility::Public),
+            "secret" => Ok(Visibility::Secret),
+            s => Err(anyhow::anyhow!(
+                "invalid visibility {:?}: expected public or secret",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Visibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Visibility::Public => f.write_str("public"),
+            Visibility::Secret => f.write_str("secret"),
+        }
+    }
+}
+
+/// Copy the Gist into a new one with the visibility, returning the new Gist.
+///
+/// The files keep the order of the listing and the description is copied
+/// as it is. The original is deleted only after the copy is created.
+pub async fn convert_visibility(
+    client: &Client,
+    gist_id: &str,
+    visibility: Visibility,
+    delete_original: bool,
+) -> anyhow::Result<Gist> {
+    let (gist, _etag) = client
+        .fetch_gist(gist_id, None)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("the Gist {} is not returned", gist_id))?;
+    let files = files_to_copy(&gist, visibility)?;
+    let mut contents = Vec::with_capacity(files.len());
+    for file in &files {
+        contents.push(read_content(client, file).await?);
+    }
+    let files: Vec<(&str, &str)> = files
+        .iter()
+        .zip(&contents)
+        .map(|(file, content)| (&*file.filename, &**content))
+        .collect();
+
+    let (new_gist, _etag) = client
+        .create_gist(NewGist {
+            files: &files[..],
+            description: Some(&gist.description),
+            public: visibility == Visibility::Public,
+        })
+        .await
+        .context("failed to create the copy of the Gist")?;
+    if let Some(&(filename, _)) = files
+        .iter()
+        .find(|(filename, _)| !new_gist.files.contains_key(*filename))
+    {
+        tracing::warn!(
+            "{:?} is not found in the new Gist {}; the original is kept",
+            filename,
+            new_gist.id
+        );
+        return Ok(new_gist);
+    }
+
+    if delete_original {
+        client
+            .delete_gist(gist_id)
+            .await
+            .with_context(|| format!("failed to delete the original Gist {}", gist_id))?;
+    }
+
+    Ok(new_gist)
+}
+
+/// Return the files to be copied into a Gist with the visibility, in the
+/// order of the listing.
+fn files_to_copy(gist: &Gist, visibility: Visibility) -> anyhow::Result<Vec<&GistFile>> {
+    anyhow::ensure!(
+        Visibility::of(gist) != visibility,
+        "the Gist {} is already {}",
+        gist.id,
+        visibility
+    );
+    anyhow::ensure!(
+        !gist.truncated,
+        "the Gist {} has too many files to be listed, which cannot be copied",
+        gist.id
+    );
+
+    // The Gist lists the files in the order of the filenames.
+    let mut files: Vec<&GistFile> = gist.files.values().collect();
+    files.sort_by(|a, b| a.filename.cmp(&b.filename));
+    Ok(files)
+}
+
+/// Return the entire content of the file, fetching it from the raw URL
+/// if truncated in the response.
+async fn read_content(client: &Client, file: &GistFile) -> anyhow::Result<String> {
+    if !file.truncated {
+        return String::from_utf8(file.content_bytes().to_vec())
+            .with_context(|| format!("{:?} is not a text file", file.filename));
+    }
+
+    let mut reader = client
+        .fetch_raw(&file.raw_url, 0)
+        .await
+        .with_context(|| format!("failed to fetch {:?}", file.filename))?;
+    let mut content = Vec::with_capacity(file.size as usize);
+    loop {
+        let chunk = reader.read_at(reader.position(), RAW_CHUNK_SIZE).await?;
+        content.extend_from_slice(&chunk);
+        if chunk.len() < RAW_CHUNK_SIZE {
+            break;
+        }
+    }
+    anyhow::ensure!(
+        content.len() as u64 == file.size,
+        "{:?} is fetched partially: {} of {} bytes",
+        file.filename,
+        content.len(),
+        file.size
+    );
+
+    String::from_utf8(content).with_context(|| format!("{:?} is not a text file", file.filename))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gist(public: bool, truncated: bool, files: &[(&str, &str)]) -> Gist {
+        let files: serde_json::Map<_, _> = files
+            .iter()
+            .map(|&(filename, content)| {
+                let file = serde_json::json!({
+                    "filename": filename,
+                    "type": "text/plain",
+                    "language": "Text",
+                    "raw_url": format!("https://gist.githubusercontent.com/raw/{}", filename),
+                    "size": content.len(),
+                    "truncated": false,
+                    "content": content,
+                });
+                (filename.to_owned(), file)
+            })
+            .collect();
+        let gist = serde_json::json!({
+            "id": "0123abc",
+            "html_url": "https://gist.github.com/0123abc",
+            "description": "notes",
+            "public": public,
+            "created_at": "2020-01-02T03:04:05Z",
+            "updated_at": "2020-01-02T03:04:05Z",
+            "files": files,
+            "truncated": truncated,
+        });
+        Gist::from_json(gist.to_string().as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn parse_and_display() {
+        for &visibility in &[Visibility::Public, Visibility::Secret] {
+            assert_eq!(
+                visibility.to_string().parse::<Visibility>().unwrap(),
+                visibility
+            );
+        }
+        assert!("private".parse::<Visibility>().is_err());
+        assert!("Public".parse::<Visibility>().is_err());
+    }
+
+    #[test]
+    fn visibility_of_the_gist() {
+        assert_eq!(Visibility::of(&gist(true, false, &[])), Visibility::Public);
+        assert_eq!(Visibility::of(&gist(false, false, &[])), Visibility::Secret);
+    }
+
+    #[test]
+    fn files_are_copied_in_the_order_of_the_listing() {
+        let secret = gist(
+            false,
+            false,
+            &[("c.txt", "c"), ("a.txt", "a"), ("b.md", "b")],
+        );
+        let files = files_to_copy(&secret, Visibility::Public).unwrap();
+        let filenames: Vec<&str> = files.iter().map(|file| &*file.filename).collect();
+        assert_eq!(filenames, ["a.txt", "b.md", "c.txt"]);
+    }
+
+    #[test]
+    fn conversion_to_the_same_visibility_is_refused() {
+        let public = gist(true, false, &[("a.txt", "a")]);
+        let err = files_to_copy(&public, Visibility::Public).unwrap_err();
+        assert!(err.to_string().contains("already public"), "{}", err);
+        files_to_copy(&public, Visibility::Secret).unwrap();
+
+        let secret = gist(false, false, &[("a.txt", "a")]);
+        let err = files_to_copy(&secret, Visibility::Secret).unwrap_err();
+        assert!(err.to_string().contains("already secret"), "{}", err);
+    }
+
+    #[test]
+    fn truncated_listing_is_refused() {
+        let truncated = gist(false, true, &[("a.txt", "a")]);
+        let err = files_to_copy(&truncated, Visibility::Public).unwrap_err();
+        assert!(err.to_string().contains("too many files"), "{}", err);
+    }
+
+    #[test]
+    fn complete_content_is_copied_without_fetching() {
+        let secret = gist(false, false, &[("a.txt", "content")]);
+        let client = Client::new(None);
+        let content = futures::executor::block_on(read_content(&client, &secret.files["a.txt"]));
+        assert_eq!(content.unwrap(), "content");
+    }
+}